@@ -1,3 +1,15 @@
 pub const CELLS_PER_PAGE: usize = 16;
 pub const PAGES_PER_BLOCK: usize = 128;
+pub const SPARE_BYTES_PER_PAGE: usize = 64;
 pub const TOTAL_BLOCK: usize = 1024;
+pub const SLC_CACHE_PERCENT: usize = 10;
+pub const MAX_BIT_ERROR_RATE: f64 = 0.001;
+pub const GC_FREE_BLOCKS_WATERMARK: usize = 2;
+pub const OVER_PROVISIONING_PERCENT: usize = 7;
+pub const BAD_BLOCK_RESERVE_PERCENT: usize = 2;
+pub const METADATA_BLOCKS: usize = 1;
+pub const WRITE_FRONTIERS: usize = 2;
+pub const CHANNELS: usize = 2;
+pub const DIES: usize = 4;
+pub const PLANES_PER_DIE: usize = 2;
+pub const CHECKPOINT_INTERVAL: u64 = 4096;
@@ -1,6 +1,21 @@
-mod byte_encoder;
-mod memory_controller;
-mod memory_state;
+mod block_health;
+pub mod byte_encoder;
+pub mod capacity;
+mod checkpoint;
+mod deallocations;
+mod die_pipeline;
+mod erase_queue;
+mod free_pool;
+mod frontier;
+pub mod kv_controller;
+mod mapping;
+pub mod memory_controller;
+pub mod memory_state;
+mod slc_cache;
+mod spare;
+mod temperature;
+pub mod zoned_controller;
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum CellType {
     Single,
     Double,
@@ -18,9 +33,71 @@ impl CellType {
             CellType::Penta => 5,
         }
     }
+
+    // logical pages sharing one wordline are Gray coded, so page 0 (lower page)
+    // needs a single threshold and every next page doubles the amount of sensing
+    pub fn sense_count(&self, page_index: usize) -> u32 {
+        if page_index >= self.multiplier() as usize {
+            panic!("page index out of wordline")
+        }
+        1 << page_index
+    }
+
+    pub fn lower(&self) -> Option<CellType> {
+        match self {
+            CellType::Single => None,
+            CellType::Double => Some(CellType::Single),
+            CellType::Triple => Some(CellType::Double),
+            CellType::Quadro => Some(CellType::Triple),
+            CellType::Penta => Some(CellType::Quadro),
+        }
+    }
+
+    // program/erase cycles a block survives when it is run in this mode
+    pub fn endurance(&self) -> u32 {
+        match self {
+            CellType::Single => 100_000,
+            CellType::Double => 10_000,
+            CellType::Triple => 3_000,
+            CellType::Quadro => 1_000,
+            CellType::Penta => 300,
+        }
+    }
+
+    pub fn threshold_count(&self) -> u32 {
+        (1 << self.multiplier()) - 1
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgramScheme {
+    OneShot,
+    MultiPass,
+    FoggyFine,
+}
+
+impl ProgramScheme {
+    pub fn passes(&self, cell_type: CellType) -> usize {
+        match self {
+            ProgramScheme::OneShot => 1,
+            ProgramScheme::MultiPass => cell_type.multiplier() as usize,
+            ProgramScheme::FoggyFine => 2,
+        }
+    }
 }
 
-enum CellState {
+// how garbage collection moves fully valid wordlines
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CopybackMode {
+    Disabled,
+    // data never leaves the die, errors of the source are programmed again
+    Unchecked,
+    // data is also read out, so the controller catches propagated errors before remapping
+    Checked,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CellState {
     Empty,
     Set(CellType),
     ResetPending,
@@ -51,3 +128,58 @@ fn operation_time(cell_type: CellType, operation_type: OperationType) -> u32 {
         (CellType::Penta, OperationType::Delete) => 3000,
     }
 }
+
+// moving one logical page over the bus between a die and the controller, ECC included
+const PAGE_TRANSFER_TIME: u32 = 10;
+
+// the whole wordline is sensed into the page buffer and programmed back on the die
+fn copyback_time(cell_type: CellType) -> u32 {
+    operation_time(cell_type, OperationType::Read) + operation_time(cell_type, OperationType::Write)
+}
+
+fn page_read_time(cell_type: CellType, page_index: usize) -> u32 {
+    let full_read = operation_time(cell_type, OperationType::Read);
+    let sensed = cell_type.sense_count(page_index);
+    let total = cell_type.threshold_count();
+    (full_read * sensed).div_ceil(total)
+}
+
+fn program_pass_time(cell_type: CellType, scheme: ProgramScheme, pass: usize) -> u32 {
+    let full_program = operation_time(cell_type, OperationType::Write);
+    match scheme {
+        ProgramScheme::OneShot => full_program,
+        ProgramScheme::MultiPass => {
+            let total = cell_type.threshold_count();
+            (full_program * cell_type.sense_count(pass)).div_ceil(total)
+        }
+        ProgramScheme::FoggyFine if pass == 0 => full_program,
+        ProgramScheme::FoggyFine => full_program / 2,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn page_read_time_should_grow_with_sensed_thresholds() {
+        let lower = page_read_time(CellType::Triple, 0);
+        let middle = page_read_time(CellType::Triple, 1);
+        let upper = page_read_time(CellType::Triple, 2);
+
+        assert!(lower < middle && middle < upper);
+        assert_eq!(
+            operation_time(CellType::Single, OperationType::Read),
+            page_read_time(CellType::Single, 0)
+        );
+    }
+
+    #[test]
+    fn program_pass_time_should_cover_full_program_for_multi_pass() {
+        let total: u32 = (0..ProgramScheme::MultiPass.passes(CellType::Quadro))
+            .map(|pass| program_pass_time(CellType::Quadro, ProgramScheme::MultiPass, pass))
+            .sum();
+
+        assert!(total >= operation_time(CellType::Quadro, OperationType::Write));
+    }
+}
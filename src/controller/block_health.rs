@@ -0,0 +1,153 @@
+use crate::config::PAGES_PER_BLOCK;
+use crate::controller::CellType;
+use std::collections::HashMap;
+
+// bit errors seen by program verify since the last erase, and the densest mode each block may still run in;
+// only blocks with errors or a lowered density are kept
+pub struct BlockHealth {
+    max_bit_error_rate: f64,
+    native: CellType,
+    density: HashMap<usize, Option<CellType>>,
+    // programmed bits and bit errors
+    errors: HashMap<usize, (u64, u64)>,
+    usable_pages: usize,
+}
+
+impl BlockHealth {
+    pub fn new(block_count: usize, native: CellType, max_bit_error_rate: f64) -> BlockHealth {
+        BlockHealth {
+            max_bit_error_rate,
+            native,
+            density: HashMap::new(),
+            errors: HashMap::new(),
+            usable_pages: block_count * BlockHealth::pages(Some(native)),
+        }
+    }
+
+    fn pages(density: Option<CellType>) -> usize {
+        density.map_or(0, |density| density.multiplier() as usize * PAGES_PER_BLOCK)
+    }
+
+    // None once the block is retired
+    pub fn density(&self, block: usize) -> Option<CellType> {
+        self.density
+            .get(&block)
+            .copied()
+            .unwrap_or(Some(self.native))
+    }
+
+    fn set_density(&mut self, block: usize, density: Option<CellType>) {
+        self.usable_pages = self.usable_pages + BlockHealth::pages(density)
+            - BlockHealth::pages(self.density(block));
+        self.density.insert(block, density);
+    }
+
+    pub fn record(&mut self, block: usize, bits: usize, errors: usize) {
+        let (programmed_bits, bit_errors) = self.errors.entry(block).or_insert((0, 0));
+        *programmed_bits += bits as u64;
+        *bit_errors += errors as u64;
+    }
+
+    pub fn error_rate(&self, block: usize) -> f64 {
+        match self.errors.get(&block) {
+            Some((programmed_bits, bit_errors)) if *programmed_bits > 0 => {
+                *bit_errors as f64 / *programmed_bits as f64
+            }
+            _ => 0.0,
+        }
+    }
+
+    // called on erase with the mode the block ran in, returns true when block density was lowered
+    pub fn review(&mut self, block: usize, mode: CellType, worn_out: bool) -> bool {
+        let too_many_errors = self.error_rate(block) > self.max_bit_error_rate;
+        self.errors.remove(&block);
+
+        let current = match self.density(block) {
+            Some(current) => current,
+            None => return false,
+        };
+        // a block failing in some mode may only run sparser than that mode from now on
+        let density = if too_many_errors {
+            mode.lower().map(|lower| {
+                if lower.multiplier() < current.multiplier() {
+                    lower
+                } else {
+                    current
+                }
+            })
+        } else if worn_out {
+            current.lower()
+        } else {
+            return false;
+        };
+        self.set_density(block, density);
+        density != Some(current)
+    }
+
+    // factory bad or otherwise unusable block, it stores nothing from now on
+    pub fn retire(&mut self, block: usize) {
+        self.set_density(block, None);
+    }
+
+    // logical pages the blocks can store at their current density
+    pub fn usable_pages(&self) -> usize {
+        self.usable_pages
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn review_should_keep_density_of_healthy_block() {
+        let mut target = BlockHealth::new(2, CellType::Quadro, 0.01);
+
+        target.record(0, 1000, 1);
+
+        assert!(!target.review(0, CellType::Quadro, false));
+        assert_eq!(Some(CellType::Quadro), target.density(0));
+    }
+
+    #[test]
+    fn review_should_step_down_density_on_errors() {
+        let mut target = BlockHealth::new(2, CellType::Quadro, 0.01);
+        let before = target.usable_pages();
+
+        target.record(1, 100, 5);
+
+        assert!(target.review(1, CellType::Quadro, false));
+        assert_eq!(Some(CellType::Triple), target.density(1));
+        assert_eq!(before - PAGES_PER_BLOCK, target.usable_pages());
+        assert_eq!(0.0, target.error_rate(1));
+    }
+
+    #[test]
+    fn review_should_retire_block_failing_as_slc() {
+        let mut target = BlockHealth::new(2, CellType::Quadro, 0.01);
+
+        target.record(0, 100, 5);
+
+        assert!(target.review(0, CellType::Single, false));
+        assert_eq!(None, target.density(0));
+        assert_eq!(4 * PAGES_PER_BLOCK, target.usable_pages());
+    }
+
+    #[test]
+    fn review_should_step_down_worn_out_block() {
+        let mut target = BlockHealth::new(1, CellType::Double, 0.01);
+
+        assert!(target.review(0, CellType::Double, true));
+        assert_eq!(Some(CellType::Single), target.density(0));
+    }
+
+    #[test]
+    fn retire_should_drop_block_from_usable_pages() {
+        let mut target = BlockHealth::new(2, CellType::Quadro, 0.01);
+
+        target.retire(1);
+
+        assert_eq!(None, target.density(1));
+        assert_eq!(4 * PAGES_PER_BLOCK, target.usable_pages());
+    }
+}
@@ -1,16 +1,33 @@
 use crate::controller::CellType;
+use crate::controller::ProgramScheme;
 use std::convert::TryInto;
 
 pub trait ByteEncoder<const PS: usize> {
     fn encode_bytes_to_page(&self, bits: Vec<bool>, cell_type: CellType) -> [u8; PS];
 
     fn decode_page_to_bytes(&self, cells: [u8; PS], cell_type: CellType) -> Vec<bool>;
+
+    // pages[i] holds one bit per cell of the i-th logical page of the wordline (0 is lower page)
+    fn encode_wordline_pass(
+        &self,
+        pages: &[Vec<bool>],
+        cell_type: CellType,
+        scheme: ProgramScheme,
+        pass: usize,
+    ) -> [u8; PS];
+
+    fn decode_wordline_page(
+        &self,
+        cells: [u8; PS],
+        cell_type: CellType,
+        page_index: usize,
+    ) -> Vec<bool>;
 }
 
-struct ByteEncoderImpl {}
+pub struct ByteEncoderImpl {}
 
 impl ByteEncoderImpl {
-    fn new() -> ByteEncoderImpl {
+    pub fn new() -> ByteEncoderImpl {
         ByteEncoderImpl {}
     }
     fn bit_slice_to_int(slice: &[bool]) -> u8 {
@@ -41,6 +58,24 @@ impl ByteEncoderImpl {
         res.append(&mut temporary_vec);
         res
     }
+
+    fn gray_page_bit(level: u8, page_index: usize, cell_type: CellType) -> bool {
+        let gray = level ^ (level >> 1);
+        let bit = cell_type.multiplier() as usize - 1 - page_index;
+        (gray >> bit) & 1 == 1
+    }
+
+    // lowest level whose Gray code matches every already known page bit, so that
+    // targets of the following passes can only add charge to the cell
+    fn lowest_matching_level(known_bits: &[bool], cell_type: CellType) -> u8 {
+        (0..(1u8 << cell_type.multiplier()))
+            .find(|level| {
+                known_bits.iter().enumerate().all(|(page_index, bit)| {
+                    ByteEncoderImpl::gray_page_bit(*level, page_index, cell_type) == *bit
+                })
+            })
+            .unwrap_or_else(|| panic!("no level for given bits"))
+    }
 }
 
 impl<const PS: usize> ByteEncoder<PS> for ByteEncoderImpl {
@@ -83,12 +118,103 @@ impl<const PS: usize> ByteEncoder<PS> for ByteEncoderImpl {
         }
         res
     }
+
+    fn encode_wordline_pass(
+        &self,
+        pages: &[Vec<bool>],
+        cell_type: CellType,
+        scheme: ProgramScheme,
+        pass: usize,
+    ) -> [u8; PS] {
+        let pages_in_wordline = cell_type.multiplier() as usize;
+        if pass >= scheme.passes(cell_type) {
+            panic!("pass is out of program scheme")
+        }
+        let known_pages = match scheme {
+            ProgramScheme::MultiPass => pass + 1,
+            ProgramScheme::OneShot | ProgramScheme::FoggyFine => pages_in_wordline,
+        };
+        if pages.len() < known_pages || pages.len() > pages_in_wordline {
+            panic!("mismatch pages amount and multiplier")
+        }
+        if pages.iter().any(|page| page.len() != PS) {
+            panic!("mismatch page size and cells amount")
+        }
+
+        let size_of_section = 255 / (1 << cell_type.multiplier()) + 1;
+        let is_foggy = scheme == ProgramScheme::FoggyFine && pass == 0;
+        let mut temporary_vec = Vec::new();
+        for cell in 0..PS {
+            let known_bits: Vec<bool> = pages[..known_pages].iter().map(|p| p[cell]).collect();
+            let level = ByteEncoderImpl::lowest_matching_level(&known_bits, cell_type);
+            let section_start = level * size_of_section;
+            let center = section_start + (size_of_section - 1) / 2;
+            temporary_vec.push(if is_foggy {
+                center - size_of_section / 4
+            } else {
+                center
+            })
+        }
+
+        temporary_vec
+            .try_into()
+            .unwrap_or_else(|_| panic!("vec size is wrong"))
+    }
+
+    fn decode_wordline_page(
+        &self,
+        cells: [u8; PS],
+        cell_type: CellType,
+        page_index: usize,
+    ) -> Vec<bool> {
+        let size_of_section: u16 = 255 / (1 << cell_type.multiplier()) + 1;
+        let sensed_thresholds: Vec<u16> = (1..(1u8 << cell_type.multiplier()))
+            .filter(|level| {
+                ByteEncoderImpl::gray_page_bit(*level, page_index, cell_type)
+                    != ByteEncoderImpl::gray_page_bit(level - 1, page_index, cell_type)
+            })
+            .map(|level| level as u16 * size_of_section)
+            .collect();
+
+        cells
+            .iter()
+            .map(|cell| {
+                let passed = sensed_thresholds
+                    .iter()
+                    .filter(|threshold| *cell as u16 >= **threshold)
+                    .count();
+                passed % 2 == 1
+            })
+            .collect()
+    }
+}
+
+// most significant bit of every byte goes first
+pub fn bytes_to_bits(bytes: &[u8]) -> Vec<bool> {
+    bytes
+        .iter()
+        .flat_map(|byte| (0..8).rev().map(move |i| byte >> i & 1 == 1))
+        .collect()
+}
+
+pub fn bits_to_bytes(bits: &[bool]) -> Vec<u8> {
+    bits.chunks(8)
+        .map(|bits| bits.iter().fold(0, |byte, bit| byte << 1 | *bit as u8))
+        .collect()
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn bits_to_bytes_should_reverse_bytes_to_bits() {
+        let bits = bytes_to_bits(&[0x80, 0x05]);
+
+        assert!(bits[0] && !bits[1] && bits[13] && bits[15]);
+        assert_eq!(vec![0x80, 0x05], bits_to_bytes(&bits));
+    }
+
     #[test]
     fn bit_slice_to_int_should_convert_zero() {
         let in_arg = &[false];
@@ -302,4 +428,110 @@ mod test {
         let res_3 = target.decode_page_to_bytes(pages_3, CellType::Penta);
         assert_eq!(bits, res_3);
     }
+
+    #[test]
+    fn decode_wordline_page_should_sense_gray_thresholds() {
+        let target: ByteEncoderImpl = ByteEncoderImpl::new();
+
+        // TLC levels 0..8 centers, Gray coded lower page flips only once (at level 4)
+        let cells = [15, 47, 79, 111, 143, 175, 207, 239];
+        let lower = target.decode_wordline_page(cells, CellType::Triple, 0);
+        assert_eq!(
+            vec![false, false, false, false, true, true, true, true],
+            lower
+        );
+
+        let middle = target.decode_wordline_page(cells, CellType::Triple, 1);
+        assert_eq!(
+            vec![false, false, true, true, true, true, false, false],
+            middle
+        );
+
+        let upper = target.decode_wordline_page(cells, CellType::Triple, 2);
+        assert_eq!(
+            vec![false, true, true, false, false, true, true, false],
+            upper
+        );
+    }
+
+    #[test]
+    fn one_shot_wordline_should_decode_every_page() {
+        let target: ByteEncoderImpl = ByteEncoderImpl::new();
+        let pages = vec![
+            vec![true, false, true, false],
+            vec![false, false, true, true],
+            vec![true, true, false, false],
+            vec![false, true, false, true],
+        ];
+
+        let cells: [u8; 4] =
+            target.encode_wordline_pass(&pages, CellType::Quadro, ProgramScheme::OneShot, 0);
+
+        for (page_index, page) in pages.iter().enumerate() {
+            let res = target.decode_wordline_page(cells, CellType::Quadro, page_index);
+            assert_eq!(*page, res);
+        }
+    }
+
+    #[test]
+    fn multi_pass_wordline_should_only_add_charge_and_keep_known_pages() {
+        let target: ByteEncoderImpl = ByteEncoderImpl::new();
+        let pages = [
+            vec![true, false, true, false],
+            vec![false, false, true, true],
+            vec![true, true, false, true],
+        ];
+
+        let mut previous = [0; 4];
+        for pass in 0..ProgramScheme::MultiPass.passes(CellType::Triple) {
+            let cells: [u8; 4] = target.encode_wordline_pass(
+                &pages[..pass + 1],
+                CellType::Triple,
+                ProgramScheme::MultiPass,
+                pass,
+            );
+            for (cell, before) in cells.iter().zip(previous.iter()) {
+                assert!(cell >= before);
+            }
+            for (page_index, page) in pages.iter().enumerate().take(pass + 1) {
+                let res = target.decode_wordline_page(cells, CellType::Triple, page_index);
+                assert_eq!(*page, res);
+            }
+            previous = cells;
+        }
+    }
+
+    #[test]
+    fn foggy_pass_should_stay_below_fine_pass() {
+        let target: ByteEncoderImpl = ByteEncoderImpl::new();
+        let pages = vec![
+            vec![true, false],
+            vec![false, true],
+            vec![true, true],
+            vec![false, false],
+        ];
+
+        let foggy: [u8; 2] =
+            target.encode_wordline_pass(&pages, CellType::Quadro, ProgramScheme::FoggyFine, 0);
+        let fine: [u8; 2] =
+            target.encode_wordline_pass(&pages, CellType::Quadro, ProgramScheme::FoggyFine, 1);
+
+        for (f, c) in foggy.iter().zip(fine.iter()) {
+            assert!(f < c);
+        }
+        for (page_index, page) in pages.iter().enumerate() {
+            let res = target.decode_wordline_page(fine, CellType::Quadro, page_index);
+            assert_eq!(*page, res);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "mismatch pages amount and multiplier")]
+    fn encode_wordline_pass_should_panic_when_pages_missing() {
+        let target: ByteEncoderImpl = ByteEncoderImpl::new();
+        let pages = vec![vec![true; 2]; 2];
+
+        let _: [u8; 2] =
+            target.encode_wordline_pass(&pages, CellType::Triple, ProgramScheme::OneShot, 0);
+    }
 }
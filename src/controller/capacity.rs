@@ -0,0 +1,93 @@
+use crate::config::BAD_BLOCK_RESERVE_PERCENT;
+use crate::config::METADATA_BLOCKS;
+use crate::config::OVER_PROVISIONING_PERCENT;
+use crate::config::PAGES_PER_BLOCK;
+use crate::controller::CellType;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CapacityConfig {
+    pub over_provisioning_percent: usize,
+    pub bad_block_reserve_percent: usize,
+    pub metadata_blocks: usize,
+}
+
+impl CapacityConfig {
+    pub fn new(
+        over_provisioning_percent: usize,
+        bad_block_reserve_percent: usize,
+        metadata_blocks: usize,
+    ) -> CapacityConfig {
+        if over_provisioning_percent >= 100 || bad_block_reserve_percent >= 100 {
+            panic!("reserve percent must be below 100")
+        }
+        CapacityConfig {
+            over_provisioning_percent,
+            bad_block_reserve_percent,
+            metadata_blocks,
+        }
+    }
+
+    // erased blocks kept aside to replace retired ones
+    pub fn reserve_blocks(&self, good_blocks: usize) -> usize {
+        good_blocks * self.bad_block_reserve_percent / 100
+    }
+
+    // blocks left for user data after metadata and bad block reserve
+    pub fn data_blocks(&self, good_blocks: usize) -> usize {
+        good_blocks
+            .checked_sub(self.metadata_blocks + self.reserve_blocks(good_blocks))
+            .unwrap_or_else(|| panic!("Not enough blocks for reservation"))
+    }
+
+    // logical pages visible to the host
+    pub fn user_pages(&self, good_blocks: usize, native: CellType) -> usize {
+        let data_pages =
+            self.data_blocks(good_blocks) * PAGES_PER_BLOCK * native.multiplier() as usize;
+        data_pages * (100 - self.over_provisioning_percent) / 100
+    }
+}
+
+impl Default for CapacityConfig {
+    fn default() -> CapacityConfig {
+        CapacityConfig::new(
+            OVER_PROVISIONING_PERCENT,
+            BAD_BLOCK_RESERVE_PERCENT,
+            METADATA_BLOCKS,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn user_pages_should_subtract_every_reservation() {
+        let target = CapacityConfig::new(25, 10, 2);
+
+        // 100 good blocks - 2 metadata - 10 reserve = 88 data blocks
+        assert_eq!(88, target.data_blocks(100));
+        assert_eq!(
+            88 * PAGES_PER_BLOCK * 3 * 75 / 100,
+            target.user_pages(100, CellType::Triple)
+        );
+    }
+
+    #[test]
+    fn user_pages_should_equal_raw_without_reservation() {
+        let target = CapacityConfig::new(0, 0, 0);
+
+        assert_eq!(
+            8 * PAGES_PER_BLOCK * 4,
+            target.user_pages(8, CellType::Quadro)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough blocks for reservation")]
+    fn data_blocks_should_panic_when_reservation_too_big() {
+        let target = CapacityConfig::new(7, 0, 4);
+
+        target.data_blocks(3);
+    }
+}
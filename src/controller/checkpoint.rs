@@ -0,0 +1,305 @@
+use crate::config::PAGES_PER_BLOCK;
+use crate::controller::spare;
+use crate::physic_level::memory::Address;
+use crate::physic_level::memory::Memory;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::ops::Range;
+
+const VERSION: u32 = 4;
+// unmapped pages go either as ranges or as a bitmap over their span, whichever is shorter
+const RANGES: u8 = 0;
+const BITMAP: u8 = 1;
+
+// what the spare area cannot tell after a power loss
+#[derive(Debug, PartialEq)]
+pub struct Checkpoint {
+    // next write sequence number, the spare area may only hold lower ones
+    pub sequence: u32,
+    pub logical_pages: usize,
+    // in write order, the checkpoint is in the last ones
+    pub metadata_blocks: Vec<usize>,
+    // bad block reserve left as runs of blocks, handed out as blocks get retired
+    pub reserve_blocks: Vec<Range<usize>>,
+    // sequence number at the latest deallocation of page ranges partly written again since,
+    // see `Deallocations`
+    pub deallocations: Vec<(Range<usize>, u32)>,
+    // deallocated pages in ascending order and without data, every copy of them in the spare
+    // area is older than `sequence`
+    pub unmapped: Vec<Range<usize>>,
+}
+
+impl Checkpoint {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for value in [VERSION, self.sequence, self.logical_pages as u32] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&(self.metadata_blocks.len() as u32).to_le_bytes());
+        for block in self.metadata_blocks.iter() {
+            bytes.extend_from_slice(&(*block as u32).to_le_bytes());
+        }
+        write_ranges(&mut bytes, self.reserve_blocks.iter());
+        write_ranges(
+            &mut bytes,
+            self.deallocations.iter().map(|(range, _)| range),
+        );
+        for (_, sequence) in self.deallocations.iter() {
+            bytes.extend_from_slice(&sequence.to_le_bytes());
+        }
+        let span = match (self.unmapped.first(), self.unmapped.last()) {
+            (Some(first), Some(last)) => first.start..last.end,
+            _ => 0..0,
+        };
+        if self.unmapped.len() * 8 <= span.len().div_ceil(8) + 8 {
+            bytes.push(RANGES);
+            write_ranges(&mut bytes, self.unmapped.iter());
+        } else {
+            let mut bitmap = vec![0u8; span.len().div_ceil(8)];
+            for lpn in self.unmapped.iter().flat_map(|range| range.clone()) {
+                let bit = lpn - span.start;
+                bitmap[bit / 8] |= 1 << (bit % 8);
+            }
+            bytes.push(BITMAP);
+            write_ranges(&mut bytes, [span].iter());
+            bytes.extend_from_slice(&bitmap);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Checkpoint {
+        let mut reader = Reader { bytes, offset: 0 };
+        if reader.u32() != VERSION {
+            panic!("Unsupported checkpoint version")
+        }
+        let sequence = reader.u32();
+        let logical_pages = reader.u32() as usize;
+        let count = reader.u32() as usize;
+        let metadata_blocks = (0..count).map(|_| reader.u32() as usize).collect();
+        let reserve_blocks = reader.ranges();
+        let deallocations = reader
+            .ranges()
+            .into_iter()
+            .map(|range| (range, reader.u32()))
+            .collect();
+        let unmapped = match reader.u8() {
+            RANGES => reader.ranges(),
+            _ => {
+                let span = reader.ranges().pop().unwrap_or(0..0);
+                let bitmap = reader.take(span.len().div_ceil(8));
+                let mut unmapped: Vec<Range<usize>> = Vec::new();
+                for bit in (0..span.len()).filter(|bit| bitmap[bit / 8] & 1 << (bit % 8) != 0) {
+                    let lpn = span.start + bit;
+                    match unmapped.last_mut() {
+                        Some(range) if range.end == lpn => range.end += 1,
+                        _ => unmapped.push(lpn..lpn + 1),
+                    }
+                }
+                unmapped
+            }
+        };
+        Checkpoint {
+            sequence,
+            logical_pages,
+            metadata_blocks,
+            reserve_blocks,
+            deallocations,
+            unmapped,
+        }
+    }
+}
+
+fn write_ranges<'a>(bytes: &mut Vec<u8>, ranges: impl ExactSizeIterator<Item = &'a Range<usize>>) {
+    bytes.extend_from_slice(&(ranges.len() as u32).to_le_bytes());
+    for range in ranges {
+        bytes.extend_from_slice(&(range.start as u32).to_le_bytes());
+        bytes.extend_from_slice(&(range.end as u32).to_le_bytes());
+    }
+}
+
+// newest checkpoint with every piece on the media, the generation the next one should use
+// and the block holding its first piece
+pub fn latest(memory: &dyn Memory) -> Option<(Checkpoint, u32, usize)> {
+    let (bytes, next_generation, addresses) = latest_bytes(memory)?;
+    Some((
+        Checkpoint::from_bytes(&bytes),
+        next_generation,
+        addresses[0].0,
+    ))
+}
+
+// payload of a metadata piece and the page holding it, None until it is found
+type Piece = Option<(Address, Vec<u8>)>;
+
+// payload of the newest generation of metadata pieces with every piece on the media, the
+// generation the next one should use and the pages of its pieces in order
+pub fn latest_bytes(memory: &dyn Memory) -> Option<(Vec<u8>, u32, Vec<Address>)> {
+    let mut pieces: HashMap<u32, Vec<Piece>> = HashMap::new();
+    for block in 0..memory.block_count() {
+        if memory.block_mode(block).is_none() {
+            continue;
+        }
+        for page in 0..PAGES_PER_BLOCK {
+            let address = Address(block, page);
+            let piece = match spare::metadata_piece(memory.read_spare(address)) {
+                Some(piece) if piece.index < piece.count => piece,
+                _ => continue,
+            };
+            let slots = pieces
+                .entry(piece.generation)
+                .or_insert_with(|| vec![None; piece.count]);
+            if let Some(slot) = slots.get_mut(piece.index) {
+                *slot = Some((address, piece.payload));
+            }
+        }
+    }
+    let next_generation = pieces.keys().max()? + 1;
+    let (_, slots) = pieces
+        .into_iter()
+        .filter(|(_, slots)| slots.iter().all(|slot| slot.is_some()))
+        .max_by_key(|(generation, _)| *generation)?;
+    let (addresses, payloads): (Vec<Address>, Vec<Vec<u8>>) = slots.into_iter().flatten().unzip();
+    Some((payloads.concat(), next_generation, addresses))
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, count: usize) -> &[u8] {
+        let end = self.offset + count;
+        if end > self.bytes.len() {
+            panic!("Checkpoint is truncated")
+        }
+        let slice = &self.bytes[self.offset..end];
+        self.offset = end;
+        slice
+    }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.take(4).try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> u8 {
+        self.take(1)[0]
+    }
+
+    fn ranges(&mut self) -> Vec<Range<usize>> {
+        let count = self.u32() as usize;
+        (0..count)
+            .map(|_| self.u32() as usize..self.u32() as usize)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::CELLS_PER_PAGE;
+    use crate::controller::CellType;
+    use crate::physic_level::memory::MemoryImpl;
+    use crate::physic_level::memory_components::ZeroFluctuate;
+
+    #[test]
+    fn from_bytes_should_read_back_checkpoint() {
+        let target = Checkpoint {
+            sequence: 77,
+            logical_pages: 300,
+            metadata_blocks: vec![7],
+            reserve_blocks: vec![2..3, 5..7],
+            deallocations: vec![(0..12, 3), (40..300, 70)],
+            unmapped: vec![12..20, 30..31],
+        };
+
+        let res = Checkpoint::from_bytes(&target.to_bytes());
+
+        assert_eq!(target, res);
+    }
+
+    #[test]
+    fn from_bytes_should_read_back_fragmented_unmapped_pages_from_bitmap() {
+        let target = Checkpoint {
+            sequence: 5,
+            logical_pages: 1 << 30,
+            metadata_blocks: vec![7],
+            reserve_blocks: vec![],
+            deallocations: vec![],
+            unmapped: (5001..6001).step_by(2).map(|lpn| lpn..lpn + 1).collect(),
+        };
+
+        let bytes = target.to_bytes();
+
+        assert!(bytes.len() < 1000 / 8 + 64);
+        assert_eq!(target, Checkpoint::from_bytes(&bytes));
+    }
+
+    #[test]
+    #[should_panic(expected = "Checkpoint is truncated")]
+    fn from_bytes_should_panic_on_truncated_data() {
+        let bytes = Checkpoint {
+            sequence: 1,
+            logical_pages: 4,
+            metadata_blocks: vec![],
+            reserve_blocks: vec![],
+            deallocations: vec![(0..2, 1), (3..4, 1)],
+            unmapped: vec![],
+        }
+        .to_bytes();
+
+        Checkpoint::from_bytes(&bytes[..bytes.len() - 1]);
+    }
+
+    #[test]
+    fn latest_should_skip_checkpoint_with_missing_piece() {
+        let mut target = MemoryImpl::new(Box::new(ZeroFluctuate), 4, CellType::Quadro);
+        target.open_block(2, CellType::Single);
+        let older = checkpoint(10);
+        let newer = checkpoint(20);
+        write_pieces(&mut target, 0, 5, &older.to_bytes(), usize::MAX);
+        write_pieces(&mut target, 60, 6, &newer.to_bytes(), 1);
+
+        let res = latest(&target);
+
+        assert_eq!(Some((older, 7, 2)), res);
+    }
+
+    fn checkpoint(sequence: u32) -> Checkpoint {
+        Checkpoint {
+            sequence,
+            logical_pages: 1000,
+            metadata_blocks: vec![2, 3],
+            reserve_blocks: vec![1..2, 4..5],
+            // fragmented enough to need several pieces
+            deallocations: (0..1000).step_by(5).map(|lpn| (lpn..lpn + 1, 1)).collect(),
+            unmapped: vec![],
+        }
+    }
+
+    fn write_pieces(
+        memory: &mut MemoryImpl,
+        first_page: usize,
+        generation: u32,
+        bytes: &[u8],
+        skipped: usize,
+    ) {
+        let chunks: Vec<&[u8]> = bytes.chunks(spare::METADATA_PAYLOAD_BYTES).collect();
+        for (index, payload) in chunks.iter().enumerate() {
+            if index == skipped {
+                continue;
+            }
+            let address = Address(2, first_page + index);
+            memory.program(address, [0; CELLS_PER_PAGE]);
+            memory.program_spare(
+                address,
+                spare::metadata_spare(&spare::MetadataPiece {
+                    generation,
+                    index,
+                    count: chunks.len(),
+                    payload: payload.to_vec(),
+                }),
+            );
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+struct Run {
+    end: usize,
+    sequence: u32,
+    // pages of the run written again since, their copies are newer than the deallocation
+    remapped: usize,
+}
+
+// write sequence number at the latest deallocation of page ranges, copies of a page older
+// than it are stale; a range is dropped once all of its pages are written again, so it only
+// grows with the deallocated pages still without data
+pub struct Deallocations {
+    runs: BTreeMap<usize, Run>,
+}
+
+impl Deallocations {
+    pub fn new() -> Deallocations {
+        Deallocations {
+            runs: BTreeMap::new(),
+        }
+    }
+
+    // `mapped` counts the pages of a range holding data
+    pub fn from_runs(
+        runs: Vec<(Range<usize>, u32)>,
+        mapped: impl Fn(Range<usize>) -> usize,
+    ) -> Deallocations {
+        let mut deallocations = Deallocations::new();
+        for (range, sequence) in runs {
+            deallocations.record(range, sequence, &mapped);
+        }
+        deallocations
+    }
+
+    pub fn runs(&self) -> Vec<(Range<usize>, u32)> {
+        self.runs
+            .iter()
+            .map(|(start, run)| (*start..run.end, run.sequence))
+            .collect()
+    }
+
+    // ranges with pages written again, they keep the sequence number of their deallocation
+    pub fn remapped_runs(&self) -> Vec<(Range<usize>, u32)> {
+        self.runs
+            .iter()
+            .filter(|(_, run)| run.remapped > 0)
+            .map(|(start, run)| (*start..run.end, run.sequence))
+            .collect()
+    }
+
+    // ranges without any data, any later sequence number is as good as theirs
+    pub fn unmapped_runs(&self) -> Vec<Range<usize>> {
+        self.runs
+            .iter()
+            .filter(|(_, run)| run.remapped == 0)
+            .map(|(start, run)| *start..run.end)
+            .collect()
+    }
+
+    // 0 for a page never deallocated
+    pub fn sequence(&self, lpn: usize) -> u32 {
+        match self.runs.range(..=lpn).next_back() {
+            Some((_, run)) if lpn < run.end => run.sequence,
+            _ => 0,
+        }
+    }
+
+    pub fn record(
+        &mut self,
+        range: Range<usize>,
+        sequence: u32,
+        mapped: impl Fn(Range<usize>) -> usize,
+    ) {
+        if range.start >= range.end {
+            return;
+        }
+        let overlapping: Vec<usize> = self
+            .runs
+            .range(..range.end)
+            .rev()
+            .take_while(|(_, run)| run.end > range.start)
+            .map(|(start, _)| *start)
+            .collect();
+        for start in overlapping {
+            let run = self.runs.remove(&start).unwrap();
+            if start < range.start {
+                self.insert(start..range.start, run.sequence, &mapped);
+            }
+            if run.end > range.end {
+                self.insert(range.end..run.end, run.sequence, &mapped);
+            }
+        }
+        let mut start = range.start;
+        let mut remapped = mapped(range.clone());
+        if let Some((left, run)) = self.runs.range(..start).next_back() {
+            if run.end == start && run.sequence == sequence {
+                start = *left;
+                remapped += run.remapped;
+            }
+        }
+        let mut end = range.end;
+        if self
+            .runs
+            .get(&end)
+            .is_some_and(|run| run.sequence == sequence)
+        {
+            let run = self.runs.remove(&end).unwrap();
+            end = run.end;
+            remapped += run.remapped;
+        }
+        self.runs.insert(
+            start,
+            Run {
+                end,
+                sequence,
+                remapped,
+            },
+        );
+        self.drop_if_remapped(start);
+    }
+
+    fn insert(
+        &mut self,
+        range: Range<usize>,
+        sequence: u32,
+        mapped: impl Fn(Range<usize>) -> usize,
+    ) {
+        let remapped = mapped(range.clone());
+        self.runs.insert(
+            range.start,
+            Run {
+                end: range.end,
+                sequence,
+                remapped,
+            },
+        );
+        self.drop_if_remapped(range.start);
+    }
+
+    // a page without data got written again
+    pub fn remap(&mut self, lpn: usize) {
+        let start = match self.runs.range_mut(..=lpn).next_back() {
+            Some((start, run)) if lpn < run.end => {
+                run.remapped += 1;
+                *start
+            }
+            _ => return,
+        };
+        self.drop_if_remapped(start);
+    }
+
+    fn drop_if_remapped(&mut self, start: usize) {
+        if self
+            .runs
+            .get(&start)
+            .is_some_and(|run| run.remapped >= run.end - start)
+        {
+            self.runs.remove(&start);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn record_should_overwrite_older_deallocation_of_overlapping_pages() {
+        let mut target = Deallocations::new();
+
+        target.record(0..10, 5, |_| 0);
+        target.record(4..6, 9, |_| 0);
+
+        assert_eq!(vec![(0..4, 5), (4..6, 9), (6..10, 5)], target.runs());
+        assert_eq!(9, target.sequence(5));
+        assert_eq!(5, target.sequence(9));
+        assert_eq!(0, target.sequence(10));
+    }
+
+    #[test]
+    fn record_should_merge_touching_ranges_of_same_command() {
+        let mut target = Deallocations::new();
+
+        target.record(4..6, 3, |_| 0);
+        target.record(0..4, 3, |_| 0);
+        target.record(6..1 << 40, 3, |_| 0);
+
+        assert_eq!(vec![(0..1 << 40, 3)], target.runs());
+    }
+
+    #[test]
+    fn record_should_cover_every_run_inside_range() {
+        let mut target = Deallocations::from_runs(vec![(0..2, 1), (3..5, 2), (7..9, 3)], |_| 0);
+
+        target.record(1..8, 4, |_| 0);
+
+        assert_eq!(vec![(0..1, 1), (1..8, 4), (8..9, 3)], target.runs());
+    }
+
+    #[test]
+    fn remap_should_drop_range_once_every_page_is_written_again() {
+        let mut target = Deallocations::new();
+        target.record(0..2, 1, |_| 0);
+        target.record(5..6, 1, |_| 0);
+
+        target.remap(0);
+        target.remap(5);
+
+        assert_eq!(vec![(0..2, 1)], target.runs());
+        assert_eq!(vec![(0..2, 1)], target.remapped_runs());
+        assert!(target.unmapped_runs().is_empty());
+        target.remap(1);
+        assert!(target.runs().is_empty());
+    }
+
+    #[test]
+    fn from_runs_should_skip_ranges_holding_data() {
+        let target = Deallocations::from_runs(vec![(0..2, 1), (4..8, 1)], |range| {
+            if range.start == 0 {
+                2
+            } else {
+                1
+            }
+        });
+
+        assert_eq!(vec![(4..8, 1)], target.runs());
+    }
+}
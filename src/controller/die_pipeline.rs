@@ -0,0 +1,195 @@
+use crate::config::DIES;
+use crate::controller::mapping::PageLocation;
+
+// page read ahead of the host into the cache register by a sequential cache read
+struct Prefetch {
+    location: PageLocation,
+    ready: u32,
+}
+
+// page register feeding the array and cache register facing the bus of every die, with cache
+// operations the bus transfer of one page overlaps the array operation of another
+pub struct DiePipeline {
+    cached: bool,
+    transfer_time: u32,
+    // the array of the die finishes its current operation
+    array_free: Vec<u32>,
+    // the cache register of the die can take the next page
+    cache_free: Vec<u32>,
+    prefetch: Vec<Option<Prefetch>>,
+}
+
+impl DiePipeline {
+    pub fn new(transfer_time: u32) -> DiePipeline {
+        DiePipeline {
+            cached: false,
+            transfer_time,
+            array_free: vec![0; DIES],
+            cache_free: vec![0; DIES],
+            prefetch: (0..DIES).map(|_| None).collect(),
+        }
+    }
+
+    pub fn set_cached(&mut self, cached: bool) {
+        self.cached = cached;
+    }
+
+    // time the controller may go on after handing a page over to the die; with cache program it
+    // only waits for the cache register, the array keeps programming in the background
+    pub fn program(&mut self, die: usize, time: u32, program_time: u32) -> u32 {
+        self.prefetch[die] = None;
+        if !self.cached {
+            let end = time.max(self.array_free[die]) + self.transfer_time + program_time;
+            self.array_free[die] = end;
+            self.cache_free[die] = end;
+            return end;
+        }
+        let loaded = time.max(self.cache_free[die]) + self.transfer_time;
+        let start = loaded.max(self.array_free[die]);
+        self.array_free[die] = start + program_time;
+        self.cache_free[die] = start;
+        start
+    }
+
+    // time the page arrives at the controller; with cache read the die senses the next page
+    // while this one is on the bus, an unused read ahead is dropped by the next command
+    pub fn read(
+        &mut self,
+        die: usize,
+        time: u32,
+        location: PageLocation,
+        read_time: u32,
+        next: Option<PageLocation>,
+    ) -> u32 {
+        let ready = match self.prefetch[die].take() {
+            Some(prefetch) if prefetch.location == location => prefetch.ready,
+            _ => time.max(self.array_free[die]) + read_time,
+        };
+        let end = time.max(ready).max(self.cache_free[die]) + self.transfer_time;
+        if !self.cached {
+            self.array_free[die] = end;
+            self.cache_free[die] = end;
+            return end;
+        }
+        self.array_free[die] = ready;
+        self.cache_free[die] = end;
+        self.prefetch[die] = next.map(|location| Prefetch {
+            location,
+            ready: ready + read_time,
+        });
+        end
+    }
+
+    // the array erases once the die is done with its commands, returns the end of the erase
+    pub fn erase(&mut self, die: usize, time: u32, erase_time: u32) -> u32 {
+        let end = self.wait(die, time) + erase_time;
+        self.array_free[die] = end;
+        end
+    }
+
+    // the running erase stops at the given time to let a read through, returns the erase time
+    // left
+    pub fn suspend(&mut self, die: usize, time: u32) -> u32 {
+        self.prefetch[die] = None;
+        let left = self.array_free[die].saturating_sub(time);
+        self.array_free[die] = self.array_free[die].min(time);
+        left
+    }
+
+    // the suspended erase goes on once the array is done with the read, returns its new end
+    pub fn resume(&mut self, die: usize, left: u32) -> u32 {
+        self.prefetch[die] = None;
+        self.array_free[die] += left;
+        self.array_free[die]
+    }
+
+    // other commands wait until the array and the cache register of the die are done
+    pub fn wait(&mut self, die: usize, time: u32) -> u32 {
+        self.prefetch[die] = None;
+        time.max(self.array_free[die]).max(self.cache_free[die])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::physic_level::memory::Address;
+
+    #[test]
+    fn cache_program_should_hide_transfer_behind_array() {
+        let mut serial = DiePipeline::new(10);
+        let mut target = DiePipeline::new(10);
+        target.set_cached(true);
+
+        let (mut serial_time, mut time) = (0, 0);
+        for _ in 0..4 {
+            serial_time = serial.program(0, serial_time, 20);
+            time = target.program(0, time, 20);
+        }
+
+        assert_eq!(120, serial_time);
+        assert_eq!(70, time);
+        assert_eq!(90, target.wait(0, time));
+    }
+
+    #[test]
+    fn erase_should_keep_array_busy_for_cached_program() {
+        let mut target = DiePipeline::new(10);
+        target.set_cached(true);
+
+        let finish = target.erase(0, 0, 100);
+        let res = target.program(0, 5, 20);
+
+        assert_eq!(100, finish);
+        // the page waits in the cache register until the erase is over
+        assert_eq!(100, res);
+        assert_eq!(120, target.wait(0, res));
+    }
+
+    #[test]
+    fn suspend_should_let_read_through_and_push_erase_back() {
+        let mut target = DiePipeline::new(10);
+        let finish = target.erase(0, 0, 100);
+
+        let left = target.suspend(0, 40);
+        let time = target.read(0, 40, location(0), 25, None);
+        let res = target.resume(0, left);
+
+        assert_eq!(100, finish);
+        assert_eq!(75, time);
+        assert_eq!(135, res);
+        assert_eq!(135, target.wait(0, time));
+    }
+
+    #[test]
+    fn cache_read_should_sense_next_page_during_transfer() {
+        let mut target = DiePipeline::new(10);
+        target.set_cached(true);
+
+        let mut time = 0;
+        for page in 0..3 {
+            time = target.read(0, time, location(page), 25, Some(location(page + 1)));
+        }
+
+        // 25 + 10, then the second page is sensed while the first is on the bus
+        assert_eq!(35 + 25 + 25, time);
+    }
+
+    #[test]
+    fn cache_read_should_drop_read_ahead_on_random_read() {
+        let mut target = DiePipeline::new(10);
+        target.set_cached(true);
+
+        let time = target.read(0, 0, location(0), 25, Some(location(1)));
+        let res = target.read(0, time, location(7), 25, Some(location(8)));
+
+        assert_eq!(35 + 35, res);
+    }
+
+    fn location(page: usize) -> PageLocation {
+        PageLocation {
+            address: Address(0, page),
+            slot: 0,
+        }
+    }
+}
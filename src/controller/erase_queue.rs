@@ -0,0 +1,152 @@
+use crate::config::DIES;
+use crate::physic_level::memory;
+use std::collections::VecDeque;
+
+// suspending a running erase to serve a read and resuming it afterwards
+pub const ERASE_SUSPEND_TIME: u32 = 20;
+
+struct InFlight {
+    block: usize,
+    finish: u32,
+}
+
+// reclaimed blocks wait here until they are erased, at most one erase runs per die
+pub struct EraseQueue {
+    block_count: usize,
+    pending: VecDeque<usize>,
+    in_flight: Vec<Option<InFlight>>,
+}
+
+impl EraseQueue {
+    pub fn new(block_count: usize) -> EraseQueue {
+        EraseQueue {
+            block_count,
+            pending: VecDeque::new(),
+            in_flight: (0..DIES).map(|_| None).collect(),
+        }
+    }
+
+    pub fn die_of(&self, block: usize) -> usize {
+        memory::die_of(block, self.block_count)
+    }
+
+    pub fn push(&mut self, block: usize) {
+        self.pending.push_back(block);
+    }
+
+    pub fn contains(&self, block: usize) -> bool {
+        self.pending.contains(&block)
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    // no block is waiting for or going through an erase
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty() && self.in_flight.iter().all(|erase| erase.is_none())
+    }
+
+    pub fn pop_pending(&mut self) -> Option<usize> {
+        self.pending.pop_front()
+    }
+
+    // oldest pending block whose die has no erase running
+    pub fn pop_startable(&mut self) -> Option<usize> {
+        let position = self
+            .pending
+            .iter()
+            .position(|block| self.in_flight[self.die_of(*block)].is_none())?;
+        self.pending.remove(position)
+    }
+
+    pub fn start(&mut self, block: usize, finish: u32) {
+        let die = self.die_of(block);
+        if self.in_flight[die].is_some() {
+            panic!("Die is already erasing")
+        }
+        self.in_flight[die] = Some(InFlight { block, finish });
+    }
+
+    // removes erases completed by the given time and returns their blocks
+    pub fn finish_before(&mut self, time: u32) -> Vec<usize> {
+        let mut finished = Vec::new();
+        for slot in self.in_flight.iter_mut() {
+            if slot.as_ref().is_some_and(|erase| erase.finish <= time) {
+                finished.push(slot.take().unwrap().block);
+            }
+        }
+        finished
+    }
+
+    pub fn next_finish(&self) -> Option<u32> {
+        self.in_flight
+            .iter()
+            .flatten()
+            .map(|erase| erase.finish)
+            .min()
+    }
+
+    // time when the erase running on the die ends, if it is still running
+    pub fn busy_until(&self, die: usize, time: u32) -> Option<u32> {
+        self.in_flight[die]
+            .as_ref()
+            .map(|erase| erase.finish)
+            .filter(|finish| *finish > time)
+    }
+
+    // erase of the die got suspended and now ends at the given time
+    pub fn reschedule(&mut self, die: usize, finish: u32) {
+        if let Some(erase) = self.in_flight[die].as_mut() {
+            erase.finish = finish;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pop_startable_should_skip_busy_dies() {
+        let mut target = EraseQueue::new(8);
+        target.push(0);
+        target.push(1);
+        target.push(2);
+
+        assert_eq!(Some(0), target.pop_startable());
+        target.start(0, 100);
+
+        assert_eq!(Some(2), target.pop_startable());
+        assert!(target.contains(1));
+        assert_eq!(1, target.pending_count());
+    }
+
+    #[test]
+    fn finish_before_should_return_completed_erases_only() {
+        let mut target = EraseQueue::new(8);
+        target.start(0, 100);
+        target.start(6, 300);
+
+        assert_eq!(Some(100), target.next_finish());
+        assert_eq!(Vec::<usize>::new(), target.finish_before(99));
+        assert_eq!(vec![0], target.finish_before(100));
+        assert_eq!(Some(300), target.next_finish());
+        assert_eq!(vec![6], target.finish_before(300));
+        assert!(target.is_empty());
+    }
+
+    #[test]
+    fn reschedule_should_postpone_running_erase() {
+        let mut target = EraseQueue::new(8);
+        target.start(2, 100);
+
+        assert_eq!(Some(100), target.busy_until(1, 50));
+        assert_eq!(None, target.busy_until(0, 50));
+
+        target.reschedule(1, 130);
+
+        assert_eq!(Some(130), target.busy_until(1, 100));
+        assert_eq!(None, target.busy_until(1, 130));
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::VecDeque;
+use std::ops::Range;
+
+// erased blocks ready to be opened, kept as runs of consecutive blocks so a new device
+// with millions of blocks takes a single entry
+pub struct FreePool {
+    runs: VecDeque<Range<usize>>,
+    len: usize,
+}
+
+impl FreePool {
+    pub fn new() -> FreePool {
+        FreePool {
+            runs: VecDeque::new(),
+            len: 0,
+        }
+    }
+
+    pub fn from_runs(runs: Vec<Range<usize>>) -> FreePool {
+        let mut pool = FreePool::new();
+        for run in runs {
+            pool.len += run.len();
+            pool.runs.push_back(run);
+        }
+        pool
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn runs(&self) -> Vec<Range<usize>> {
+        self.runs.iter().cloned().collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.runs.iter().flat_map(|run| run.clone())
+    }
+
+    pub fn contains(&self, block: usize) -> bool {
+        self.runs.iter().any(|run| run.contains(&block))
+    }
+
+    pub fn push_back(&mut self, block: usize) {
+        self.len += 1;
+        match self.runs.back_mut() {
+            Some(run) if run.end == block => run.end += 1,
+            _ => self.runs.push_back(block..block + 1),
+        }
+    }
+
+    pub fn extend(&mut self, blocks: impl IntoIterator<Item = usize>) {
+        for block in blocks {
+            self.push_back(block);
+        }
+    }
+
+    pub fn pop_front(&mut self) -> Option<usize> {
+        let run = self.runs.front_mut()?;
+        let block = run.next()?;
+        if run.start == run.end {
+            self.runs.pop_front();
+        }
+        self.len -= 1;
+        Some(block)
+    }
+
+    pub fn pop_back(&mut self) -> Option<usize> {
+        let run = self.runs.back_mut()?;
+        let block = run.next_back()?;
+        if run.start == run.end {
+            self.runs.pop_back();
+        }
+        self.len -= 1;
+        Some(block)
+    }
+
+    // the last blocks of the pool, in pool order
+    pub fn split_off_back(&mut self, count: usize) -> FreePool {
+        let mut tail = FreePool::new();
+        while tail.len < count {
+            let run = match self.runs.back_mut() {
+                Some(run) => run,
+                None => break,
+            };
+            let taken = run.len().min(count - tail.len);
+            tail.runs.push_front(run.end - taken..run.end);
+            tail.len += taken;
+            self.len -= taken;
+            run.end -= taken;
+            if run.start == run.end {
+                self.runs.pop_back();
+            }
+        }
+        tail
+    }
+
+    // takes the block out of the pool, splitting the run it is in
+    pub fn remove(&mut self, block: usize) -> bool {
+        let index = match self.runs.iter().position(|run| run.contains(&block)) {
+            Some(index) => index,
+            None => return false,
+        };
+        let run = self.runs.remove(index).unwrap();
+        if block + 1 < run.end {
+            self.runs.insert(index, block + 1..run.end);
+        }
+        if run.start < block {
+            self.runs.insert(index, run.start..block);
+        }
+        self.len -= 1;
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.runs.clear();
+        self.len = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn push_back_should_merge_consecutive_blocks() {
+        let mut target = FreePool::new();
+
+        target.extend(0..1_000_000);
+        target.push_back(5);
+
+        assert_eq!(vec![0..1_000_000, 5..6], target.runs());
+        assert_eq!(1_000_001, target.len());
+    }
+
+    #[test]
+    fn pop_should_take_blocks_from_both_ends() {
+        let mut target = FreePool::from_runs(vec![0..3, 7..8]);
+
+        assert_eq!(Some(0), target.pop_front());
+        assert_eq!(Some(7), target.pop_back());
+        assert_eq!(Some(2), target.pop_back());
+        assert_eq!(vec![1], target.iter().collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn split_off_back_should_keep_order_across_runs() {
+        let mut target = FreePool::from_runs(vec![0..4, 6..8]);
+
+        let res = target.split_off_back(3);
+
+        assert_eq!(vec![3, 6, 7], res.iter().collect::<Vec<usize>>());
+        assert_eq!(vec![0..3], target.runs());
+        assert_eq!(3, target.len());
+    }
+
+    #[test]
+    fn remove_should_split_run() {
+        let mut target = FreePool::new();
+        target.extend(0..10);
+
+        assert!(target.remove(3));
+
+        assert_eq!(vec![0..3, 4..10], target.runs());
+        assert_eq!(9, target.len());
+        assert!(!target.remove(3));
+    }
+}
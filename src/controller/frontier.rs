@@ -0,0 +1,100 @@
+use crate::config::PAGES_PER_BLOCK;
+use crate::controller::CellType;
+use crate::physic_level::memory::Address;
+
+// open block that receives appended logical pages, wordline by wordline
+pub struct Frontier {
+    pub block: usize,
+    pub cell_type: CellType,
+    page: usize,
+    wordline: Vec<Vec<bool>>,
+}
+
+impl Frontier {
+    pub fn new(block: usize, cell_type: CellType) -> Frontier {
+        Frontier {
+            block,
+            cell_type,
+            page: 0,
+            wordline: Vec::new(),
+        }
+    }
+
+    pub fn address(&self) -> Address {
+        Address(self.block, self.page)
+    }
+
+    // next free logical page index inside the open wordline
+    pub fn slot(&self) -> usize {
+        self.wordline.len()
+    }
+
+    // logical pages the frontier can still take
+    pub fn remaining_slots(&self) -> usize {
+        (PAGES_PER_BLOCK - self.page) * self.cell_type.multiplier() as usize - self.wordline.len()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.page >= PAGES_PER_BLOCK
+    }
+
+    // logical pages already stored in the open wordline, needed by the next program pass
+    pub fn push(&mut self, bits: Vec<bool>) -> &[Vec<bool>] {
+        if self.is_full() {
+            panic!("Cannot append to full frontier")
+        }
+        self.wordline.push(bits);
+        &self.wordline
+    }
+
+    pub fn complete_wordline(&mut self) {
+        if self.wordline.len() == self.cell_type.multiplier() as usize {
+            self.page += 1;
+            self.wordline.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn complete_wordline_should_move_to_next_page_when_all_slots_set() {
+        let mut target = Frontier::new(3, CellType::Double);
+
+        target.push(vec![true]);
+        target.complete_wordline();
+        assert_eq!(Address(3, 0), target.address());
+        assert_eq!(1, target.slot());
+
+        target.push(vec![false]);
+        target.complete_wordline();
+        assert_eq!(Address(3, 1), target.address());
+        assert_eq!(0, target.slot());
+    }
+
+    #[test]
+    fn is_full_should_be_true_after_last_wordline() {
+        let mut target = Frontier::new(0, CellType::Single);
+
+        for _ in 0..PAGES_PER_BLOCK {
+            assert!(!target.is_full());
+            target.push(vec![true]);
+            target.complete_wordline();
+        }
+
+        assert!(target.is_full());
+    }
+
+    #[test]
+    fn remaining_slots_should_count_open_wordline() {
+        let mut target = Frontier::new(0, CellType::Triple);
+        assert_eq!(PAGES_PER_BLOCK * 3, target.remaining_slots());
+
+        target.push(vec![true]);
+        target.complete_wordline();
+
+        assert_eq!(PAGES_PER_BLOCK * 3 - 1, target.remaining_slots());
+    }
+}
@@ -0,0 +1,796 @@
+use crate::config::CELLS_PER_PAGE;
+use crate::config::GC_FREE_BLOCKS_WATERMARK;
+use crate::config::PAGES_PER_BLOCK;
+use crate::controller::byte_encoder::bits_to_bytes;
+use crate::controller::byte_encoder::bytes_to_bits;
+use crate::controller::byte_encoder::ByteEncoder;
+use crate::controller::checkpoint;
+use crate::controller::operation_time;
+use crate::controller::page_read_time;
+use crate::controller::spare;
+use crate::controller::CellType;
+use crate::controller::OperationType;
+use crate::controller::ProgramScheme;
+use crate::metric::metric_storage::MetricStorage;
+use crate::metric::MetricType;
+use crate::physic_level::memory::Address;
+use crate::physic_level::memory::Memory;
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::convert::TryInto;
+
+const PAGE_BYTES: usize = CELLS_PER_PAGE / 8;
+// key length and value length, both u16
+const RECORD_HEADER_BYTES: usize = 4;
+// index blocks kept between two writes of the index, more are taken while it does not fit
+const INDEX_BLOCKS: usize = 3;
+
+// record of a key occupies consecutive logical pages of one block
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct RecordLocation {
+    block: usize,
+    first: usize,
+    pages: usize,
+}
+
+// block taking appended records, pages of the unfinished wordline wait in the buffer
+struct LogBlock {
+    block: usize,
+    next: usize,
+    buffer: Vec<Vec<bool>>,
+}
+
+// key-value interface stored straight in flash pages, without the logical block layer
+pub trait KvController {
+    fn put(&mut self, key: &[u8], value: &[u8]);
+    fn get(&mut self, key: &[u8]) -> Option<Vec<u8>>;
+    // returns false when the key was not stored
+    fn delete(&mut self, key: &[u8]) -> bool;
+    // pairs whose key starts with the prefix, in key order
+    fn iterate(&mut self, prefix: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)>;
+    // programs the buffered wordline and persists the index, only flushed pairs are sure to
+    // survive power loss
+    fn flush(&mut self);
+}
+
+// index pieces fill SLC pages of the index blocks one after another, in write order the
+// latest index is in the last `span` ones and the others are reused once it no longer fits
+struct IndexRing {
+    blocks: Vec<usize>,
+    span: usize,
+    // next page of the last block
+    page: usize,
+    generation: u32,
+}
+
+// values are appended to a log of flash blocks, the index keeps the newest record of every key
+// and garbage collection moves live records out of the block with least of them
+pub struct KvControllerImpl<const PS: usize> {
+    byte_encoder: Box<dyn ByteEncoder<PS>>,
+    metric_storage: Box<dyn MetricStorage>,
+    memory: Box<dyn Memory>,
+    time: u32,
+    index: BTreeMap<Vec<u8>, RecordLocation>,
+    // live record pages of every block
+    valid: Vec<usize>,
+    free_blocks: VecDeque<usize>,
+    // collected blocks, erased once the persisted index no longer points into them
+    victims: Vec<usize>,
+    index_ring: IndexRing,
+    log: Option<LogBlock>,
+    host_bytes: u64,
+    flash_bytes: u64,
+    collecting: bool,
+}
+
+impl KvControllerImpl<CELLS_PER_PAGE> {
+    pub fn new(
+        byte_encoder: Box<dyn ByteEncoder<CELLS_PER_PAGE>>,
+        metric_storage: Box<dyn MetricStorage>,
+        memory: Box<dyn Memory>,
+    ) -> KvControllerImpl<CELLS_PER_PAGE> {
+        let free_blocks: VecDeque<usize> = (0..memory.block_count())
+            .filter(|b| !memory.is_bad(*b))
+            .collect();
+        let index_ring = IndexRing {
+            blocks: Vec::new(),
+            span: 0,
+            page: 0,
+            generation: 0,
+        };
+        KvControllerImpl::assemble(
+            byte_encoder,
+            metric_storage,
+            memory,
+            BTreeMap::new(),
+            free_blocks,
+            index_ring,
+        )
+    }
+
+    // restart from the last persisted index, blocks without live records are erased
+    pub fn recover(
+        byte_encoder: Box<dyn ByteEncoder<CELLS_PER_PAGE>>,
+        metric_storage: Box<dyn MetricStorage>,
+        mut memory: Box<dyn Memory>,
+    ) -> KvControllerImpl<CELLS_PER_PAGE> {
+        memory.power_on();
+        let (bytes, generation, addresses) = checkpoint::latest_bytes(&*memory)
+            .unwrap_or_else(|| panic!("No index to recover from"));
+        let (index, blocks) = index_from_bytes(&bytes);
+        let good: Vec<usize> = (0..memory.block_count())
+            .filter(|b| !memory.is_bad(*b))
+            .collect();
+        let span = blocks.len()
+            - blocks
+                .iter()
+                .position(|block| *block == addresses[0].0)
+                .unwrap_or_else(|| panic!("Index is outside its blocks"));
+        // pieces of an index torn by the power cut stay where they are
+        let Address(last, mut page) = addresses[addresses.len() - 1];
+        page += 1;
+        while page < PAGES_PER_BLOCK
+            && spare::metadata_piece(memory.read_spare(Address(last, page))).is_some()
+        {
+            page += 1;
+        }
+        let index_ring = IndexRing {
+            blocks,
+            span,
+            page,
+            generation,
+        };
+
+        let mut live = vec![false; memory.block_count()];
+        for location in index.values() {
+            live[location.block] = true;
+        }
+        let mut free_blocks = VecDeque::new();
+        for block in good {
+            if live[block] || index_ring.blocks.contains(&block) {
+                continue;
+            }
+            if memory.block_mode(block).is_some() {
+                memory.reset(block);
+            }
+            free_blocks.push_back(block);
+        }
+        KvControllerImpl::assemble(
+            byte_encoder,
+            metric_storage,
+            memory,
+            index,
+            free_blocks,
+            index_ring,
+        )
+    }
+
+    fn assemble(
+        byte_encoder: Box<dyn ByteEncoder<CELLS_PER_PAGE>>,
+        metric_storage: Box<dyn MetricStorage>,
+        memory: Box<dyn Memory>,
+        index: BTreeMap<Vec<u8>, RecordLocation>,
+        free_blocks: VecDeque<usize>,
+        index_ring: IndexRing,
+    ) -> KvControllerImpl<CELLS_PER_PAGE> {
+        let mut valid = vec![0; memory.block_count()];
+        for location in index.values() {
+            valid[location.block] += location.pages;
+        }
+        KvControllerImpl {
+            byte_encoder,
+            metric_storage,
+            memory,
+            time: 0,
+            index,
+            valid,
+            free_blocks,
+            victims: Vec::new(),
+            index_ring,
+            log: None,
+            host_bytes: 0,
+            flash_bytes: 0,
+            collecting: false,
+        }
+    }
+
+    // all volatile state is dropped, only the media survives
+    pub fn power_off(self) -> Box<dyn Memory> {
+        self.memory
+    }
+
+    pub fn metric_storage(&self) -> &dyn MetricStorage {
+        &*self.metric_storage
+    }
+
+    // flash bytes programmed per byte of keys and values put by the host
+    pub fn write_amplification(&self) -> f64 {
+        if self.host_bytes == 0 {
+            return 0.0;
+        }
+        self.flash_bytes as f64 / self.host_bytes as f64
+    }
+
+    fn pages_per_wordline(&self) -> usize {
+        self.memory.native_cell_type().multiplier() as usize
+    }
+
+    fn pages_per_block(&self) -> usize {
+        PAGES_PER_BLOCK * self.pages_per_wordline()
+    }
+
+    fn log_has_room(&self, pages: usize) -> bool {
+        match &self.log {
+            Some(log) => log.next + pages <= self.pages_per_block(),
+            None => false,
+        }
+    }
+
+    fn append(&mut self, key: &[u8], value: &[u8]) -> RecordLocation {
+        let mut record = Vec::with_capacity(RECORD_HEADER_BYTES + key.len() + value.len());
+        record.extend_from_slice(&(key.len() as u16).to_le_bytes());
+        record.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        record.extend_from_slice(key);
+        record.extend_from_slice(value);
+        record.resize(record.len().div_ceil(PAGE_BYTES) * PAGE_BYTES, 0);
+        let pages = record.len() / PAGE_BYTES;
+        if pages > self.pages_per_block() {
+            panic!("Record does not fit a block")
+        }
+
+        if !self.log_has_room(pages) {
+            self.program_log_buffer();
+            self.log = None;
+            if !self.collecting && self.free_blocks.len() < GC_FREE_BLOCKS_WATERMARK {
+                self.collect();
+            }
+        }
+        // relocated records may have opened a log block with room left
+        if !self.log_has_room(pages) {
+            self.program_log_buffer();
+            if self.free_blocks.is_empty() {
+                self.erase_victims();
+            }
+            let block = self
+                .free_blocks
+                .pop_front()
+                .unwrap_or_else(|| panic!("No free blocks left"));
+            self.log = Some(LogBlock {
+                block,
+                next: 0,
+                buffer: Vec::new(),
+            });
+        }
+
+        let log = self.log.as_mut().unwrap();
+        let location = RecordLocation {
+            block: log.block,
+            first: log.next,
+            pages,
+        };
+        let per_wordline = self.pages_per_wordline();
+        for chunk in record.chunks(PAGE_BYTES) {
+            let log = self.log.as_mut().unwrap();
+            log.buffer.push(bytes_to_bits(chunk));
+            log.next += 1;
+            if log.buffer.len() == per_wordline {
+                self.program_log_buffer();
+            }
+        }
+        self.valid[location.block] += pages;
+        location
+    }
+
+    // an unfinished wordline is padded, the log goes on at the next wordline
+    fn program_log_buffer(&mut self) {
+        let per_wordline = self.pages_per_wordline();
+        let cell_type = self.memory.native_cell_type();
+        let log = match self.log.as_mut() {
+            Some(log) if !log.buffer.is_empty() => log,
+            _ => return,
+        };
+        let mut pages = std::mem::take(&mut log.buffer);
+        let address = Address(log.block, (log.next - 1) / per_wordline);
+        log.next = log.next.div_ceil(per_wordline) * per_wordline;
+        pages.resize(per_wordline, vec![false; CELLS_PER_PAGE]);
+
+        if self.memory.block_mode(address.0).is_none() {
+            self.memory.open_block(address.0, cell_type);
+        }
+        let cells =
+            self.byte_encoder
+                .encode_wordline_pass(&pages, cell_type, ProgramScheme::OneShot, 0);
+        self.memory.program(address, cells);
+        self.flash_bytes += (per_wordline * PAGE_BYTES) as u64;
+        self.time += operation_time(cell_type, OperationType::Write);
+    }
+
+    fn read_page(&mut self, block: usize, page: usize) -> Vec<bool> {
+        let per_wordline = self.pages_per_wordline();
+        if let Some(log) = &self.log {
+            let buffered = log.next - log.buffer.len();
+            if log.block == block && page >= buffered {
+                return log.buffer[page - buffered].clone();
+            }
+        }
+        let cell_type = self.memory.native_cell_type();
+        let slot = page % per_wordline;
+        let cells = *self.memory.read(Address(block, page / per_wordline));
+        self.time += page_read_time(cell_type, slot);
+        self.byte_encoder
+            .decode_wordline_page(cells, cell_type, slot)
+    }
+
+    // the value of the record, which has to be the one of the key
+    fn read_record(&mut self, key: &[u8], location: RecordLocation) -> Vec<u8> {
+        let bits: Vec<bool> = (location.first..location.first + location.pages)
+            .flat_map(|page| self.read_page(location.block, page))
+            .collect();
+        let record = bits_to_bytes(&bits);
+        let key_len = u16::from_le_bytes(record[0..2].try_into().unwrap()) as usize;
+        let value_len = u16::from_le_bytes(record[2..4].try_into().unwrap()) as usize;
+        let value_start = RECORD_HEADER_BYTES + key_len;
+        let stored_key = record.get(RECORD_HEADER_BYTES..value_start);
+        let value = match record.get(value_start..value_start + value_len) {
+            Some(value) if stored_key == Some(key) => value.to_vec(),
+            _ => panic!("Record does not match its key"),
+        };
+        self.metric_storage
+            .put_metric("kv_read", bits.len() as u32, self.time, MetricType::Read);
+        value
+    }
+
+    // closed block with least live pages, None when no block would free any space
+    fn gc_victim(&self) -> Option<usize> {
+        let open = self.log.as_ref().map(|log| log.block);
+        (0..self.valid.len())
+            .filter(|b| Some(*b) != open && !self.free_blocks.contains(b))
+            .filter(|b| !self.index_ring.blocks.contains(b) && !self.victims.contains(b))
+            .filter(|b| self.memory.block_mode(*b).is_some())
+            .filter(|b| self.valid[*b] < self.pages_per_block())
+            .min_by_key(|b| self.valid[*b])
+    }
+
+    // the index is persisted once for all blocks of the pass
+    fn collect(&mut self) {
+        self.collecting = true;
+        while self.free_blocks.len() + self.victims.len() < GC_FREE_BLOCKS_WATERMARK {
+            let victim = match self.gc_victim() {
+                Some(victim) => victim,
+                None => break,
+            };
+            let live: Vec<(Vec<u8>, RecordLocation)> = self
+                .index
+                .iter()
+                .filter(|(_, location)| location.block == victim)
+                .map(|(key, location)| (key.clone(), *location))
+                .collect();
+            let mut moved = 0;
+            for (key, location) in live {
+                let value = self.read_record(&key, location);
+                let relocated = self.append(&key, &value);
+                self.index.insert(key, relocated);
+                self.valid[victim] -= location.pages;
+                moved += location.pages;
+            }
+            self.victims.push(victim);
+            self.metric_storage.put_metric(
+                "kv_gc",
+                (moved * CELLS_PER_PAGE) as u32,
+                self.time,
+                MetricType::Write,
+            );
+        }
+        self.erase_victims();
+        self.collecting = false;
+    }
+
+    // the persisted index must not point into a block once it takes new records
+    fn erase_victims(&mut self) {
+        if self.victims.is_empty() {
+            return;
+        }
+        self.persist_index();
+        let cell_type = self.memory.native_cell_type();
+        for victim in std::mem::take(&mut self.victims) {
+            self.memory.reset(victim);
+            self.time += operation_time(cell_type, OperationType::Delete);
+            self.free_blocks.push_back(victim);
+        }
+    }
+
+    // programs the buffered wordline and writes the index after the last one, blocks of the
+    // last one are only erased once the new one is complete
+    fn persist_index(&mut self) {
+        self.program_log_buffer();
+        let ring = &self.index_ring;
+        let room = if ring.blocks.is_empty() {
+            0
+        } else {
+            PAGES_PER_BLOCK - ring.page
+        };
+        let reusable = ring.blocks.len().saturating_sub(ring.span.max(1));
+        let mut reused = 0;
+        let mut added: Vec<usize> = Vec::new();
+        // the block list is part of the index, so blocks are added until it fits
+        let (released, kept, bytes) = loop {
+            let mut blocks: Vec<usize> = self.index_ring.blocks[reused..].to_vec();
+            blocks.extend(added.iter().copied());
+            let span = added.len() + if room > 0 { 1 } else { 0 };
+            let released = (blocks.len() - span).min(blocks.len().saturating_sub(INDEX_BLOCKS));
+            let kept = blocks.split_off(released);
+            let bytes = index_to_bytes(&self.index, &kept);
+            if bytes.len().div_ceil(spare::METADATA_PAYLOAD_BYTES)
+                <= room + added.len() * PAGES_PER_BLOCK
+            {
+                break (blocks, kept, bytes);
+            }
+            if reused < reusable {
+                added.push(self.index_ring.blocks[reused]);
+                reused += 1;
+            } else {
+                let block = self
+                    .free_blocks
+                    .pop_front()
+                    .unwrap_or_else(|| panic!("No free blocks left"));
+                added.push(block);
+            }
+        };
+
+        let mut addresses = Vec::new();
+        if room > 0 {
+            let block = self.index_ring.blocks[self.index_ring.blocks.len() - 1];
+            addresses
+                .extend((self.index_ring.page..PAGES_PER_BLOCK).map(|page| Address(block, page)));
+        }
+        for block in added.iter() {
+            addresses.extend((0..PAGES_PER_BLOCK).map(|page| Address(*block, page)));
+        }
+        let pieces: Vec<&[u8]> = bytes.chunks(spare::METADATA_PAYLOAD_BYTES).collect();
+        for (index, payload) in pieces.iter().enumerate() {
+            let address = addresses[index];
+            if address.1 == 0 {
+                if self.memory.block_mode(address.0).is_some() {
+                    self.memory.reset(address.0);
+                    self.time += operation_time(CellType::Single, OperationType::Delete);
+                }
+                self.memory.open_block(address.0, CellType::Single);
+            }
+            let cells = self
+                .byte_encoder
+                .encode_bytes_to_page(vec![false; CELLS_PER_PAGE], CellType::Single);
+            self.memory.program(address, cells);
+            self.memory.program_spare(
+                address,
+                spare::metadata_spare(&spare::MetadataPiece {
+                    generation: self.index_ring.generation,
+                    index,
+                    count: pieces.len(),
+                    payload: payload.to_vec(),
+                }),
+            );
+            self.time += operation_time(CellType::Single, OperationType::Write);
+        }
+        // every piece takes a whole spare payload
+        self.flash_bytes += (pieces.len() * spare::METADATA_PAYLOAD_BYTES) as u64;
+        let Address(last, page) = addresses[pieces.len() - 1];
+        debug_assert_eq!(Some(&last), kept.last());
+        self.index_ring.span = added.len() + if room > 0 { 1 } else { 0 };
+        self.index_ring.page = page + 1;
+        self.index_ring.blocks = kept;
+        self.index_ring.generation += 1;
+        for block in released {
+            if self.memory.block_mode(block).is_some() {
+                self.memory.reset(block);
+                self.time += operation_time(CellType::Single, OperationType::Delete);
+            }
+            self.free_blocks.push_back(block);
+        }
+        self.metric_storage.put_metric(
+            "kv_index",
+            bytes.len() as u32,
+            self.time,
+            MetricType::Write,
+        );
+    }
+
+    fn invalidate(&mut self, key: &[u8]) -> bool {
+        match self.index.remove(key) {
+            Some(location) => {
+                self.valid[location.block] -= location.pages;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl KvController for KvControllerImpl<CELLS_PER_PAGE> {
+    fn put(&mut self, key: &[u8], value: &[u8]) {
+        if key.is_empty() || key.len() > u16::MAX as usize || value.len() > u16::MAX as usize {
+            panic!("Key or value length is out of range")
+        }
+        // the old record stays indexed while GC may persist the index during the append
+        let location = self.append(key, value);
+        self.invalidate(key);
+        self.index.insert(key.to_vec(), location);
+        self.host_bytes += (key.len() + value.len()) as u64;
+        self.metric_storage.put_metric(
+            "kv_put",
+            ((key.len() + value.len()) * 8) as u32,
+            self.time,
+            MetricType::Write,
+        );
+    }
+
+    fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        let location = *self.index.get(key)?;
+        Some(self.read_record(key, location))
+    }
+
+    fn delete(&mut self, key: &[u8]) -> bool {
+        let deleted = self.invalidate(key);
+        if deleted {
+            self.metric_storage
+                .put_metric("kv_delete", 1, self.time, MetricType::Delete);
+        }
+        deleted
+    }
+
+    fn iterate(&mut self, prefix: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let locations: Vec<(Vec<u8>, RecordLocation)> = self
+            .index
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, location)| (key.clone(), *location))
+            .collect();
+        locations
+            .into_iter()
+            .map(|(key, location)| {
+                let value = self.read_record(&key, location);
+                (key, value)
+            })
+            .collect()
+    }
+
+    fn flush(&mut self) {
+        self.persist_index();
+    }
+}
+
+// the index blocks in write order, then the records of the keys
+fn index_to_bytes(index: &BTreeMap<Vec<u8>, RecordLocation>, blocks: &[usize]) -> Vec<u8> {
+    let mut bytes = (blocks.len() as u32).to_le_bytes().to_vec();
+    for block in blocks {
+        bytes.extend_from_slice(&(*block as u32).to_le_bytes());
+    }
+    bytes.extend_from_slice(&(index.len() as u32).to_le_bytes());
+    for (key, location) in index {
+        bytes.extend_from_slice(&(key.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(key);
+        for value in [location.block, location.first, location.pages] {
+            bytes.extend_from_slice(&(value as u32).to_le_bytes());
+        }
+    }
+    bytes
+}
+
+fn index_from_bytes(bytes: &[u8]) -> (BTreeMap<Vec<u8>, RecordLocation>, Vec<usize>) {
+    let u32_at = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+    let blocks: Vec<usize> = (0..u32_at(0) as usize)
+        .map(|i| u32_at(4 + i * 4) as usize)
+        .collect();
+    let mut index = BTreeMap::new();
+    let mut offset = 8 + blocks.len() * 4;
+    for _ in 0..u32_at(offset - 4) {
+        let key_len = u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap()) as usize;
+        let key = bytes[offset + 2..offset + 2 + key_len].to_vec();
+        offset += 2 + key_len;
+        let location = RecordLocation {
+            block: u32_at(offset) as usize,
+            first: u32_at(offset + 4) as usize,
+            pages: u32_at(offset + 8) as usize,
+        };
+        offset += 12;
+        index.insert(key, location);
+    }
+    (index, blocks)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::controller::byte_encoder::ByteEncoderImpl;
+    use crate::controller::CellType;
+    use crate::metric::metric_storage::MetricStorageImpl;
+    use crate::physic_level::memory::MemoryImpl;
+    use crate::physic_level::memory_components::ZeroFluctuate;
+
+    #[test]
+    fn get_should_return_newest_value_of_key() {
+        let mut target = setup_target();
+
+        target.put(b"alpha", b"first");
+        target.put(b"beta", b"odd length value");
+        target.put(b"alpha", b"second");
+
+        assert_eq!(Some(b"second".to_vec()), target.get(b"alpha"));
+        assert_eq!(Some(b"odd length value".to_vec()), target.get(b"beta"));
+        assert_eq!(None, target.get(b"gamma"));
+    }
+
+    #[test]
+    fn delete_should_remove_key() {
+        let mut target = setup_target();
+        target.put(b"key", b"value");
+
+        assert!(target.delete(b"key"));
+        assert!(!target.delete(b"key"));
+        assert_eq!(None, target.get(b"key"));
+    }
+
+    #[test]
+    fn iterate_should_return_prefixed_pairs_in_key_order() {
+        let mut target = setup_target();
+        for key in ["user:3", "item:1", "user:1", "user:2"] {
+            target.put(key.as_bytes(), key.to_uppercase().as_bytes());
+        }
+        target.delete(b"user:2");
+
+        let res = target.iterate(b"user:");
+
+        let expected = vec![
+            (b"user:1".to_vec(), b"USER:1".to_vec()),
+            (b"user:3".to_vec(), b"USER:3".to_vec()),
+        ];
+        assert_eq!(expected, res);
+    }
+
+    #[test]
+    fn gc_should_keep_live_values_while_overwriting_more_than_device() {
+        let mut target = setup_target();
+        let device_bytes = 8 * PAGES_PER_BLOCK * 3 * PAGE_BYTES;
+
+        let rounds = 3 * device_bytes / (10 * 100);
+        for round in 0..rounds {
+            for key in 0..10u8 {
+                target.put(&[key], &[key ^ round as u8; 100]);
+            }
+        }
+
+        for key in 0..10u8 {
+            assert_eq!(
+                Some(vec![key ^ (rounds - 1) as u8; 100]),
+                target.get(&[key])
+            );
+        }
+        assert!(!target.metric_storage().get_metric("kv_gc").is_empty());
+        assert!(target.write_amplification() > 1.0);
+    }
+
+    #[test]
+    fn flush_should_count_index_in_write_amplification() {
+        let mut target = setup_target();
+        target.put(b"key", b"value");
+        target.program_log_buffer();
+        let data_only = target.write_amplification();
+
+        target.flush();
+
+        assert!(target.write_amplification() > data_only);
+    }
+
+    #[test]
+    fn recover_should_restore_flushed_pairs() {
+        let mut target = setup_target();
+        target.put(b"kept", b"on flash");
+        target.put(b"removed", b"gone");
+        target.delete(b"removed");
+        target.flush();
+        target.put(b"late", b"never flushed");
+
+        let mut target = KvControllerImpl::recover(
+            Box::new(ByteEncoderImpl::new()),
+            Box::new(MetricStorageImpl::new()),
+            target.power_off(),
+        );
+
+        assert_eq!(Some(b"on flash".to_vec()), target.get(b"kept"));
+        assert_eq!(None, target.get(b"removed"));
+        assert_eq!(None, target.get(b"late"));
+    }
+
+    #[test]
+    fn recover_should_not_follow_index_into_block_erased_by_gc() {
+        let mut target = setup_target();
+        for key in 0..10u8 {
+            target.put(&[key], &[key; 100]);
+        }
+        target.flush();
+        let mut round = 0;
+        while target.metric_storage().get_metric("kv_gc").len() < 3 {
+            round += 1;
+            for key in 0..10u8 {
+                target.put(&[key], &[key ^ round; 100]);
+            }
+        }
+
+        let mut target = KvControllerImpl::recover(
+            Box::new(ByteEncoderImpl::new()),
+            Box::new(MetricStorageImpl::new()),
+            target.power_off(),
+        );
+
+        // the flushed value or a newer one, never a record of another key
+        for key in 0..10u8 {
+            let value = target.get(&[key]).unwrap();
+            assert_eq!(vec![value[0]; 100], value);
+            assert!(value[0] ^ key <= round);
+        }
+    }
+
+    #[test]
+    fn recover_should_restore_index_spanning_several_blocks() {
+        let mut target = KvControllerImpl::new(
+            Box::new(ByteEncoderImpl::new()),
+            Box::new(MetricStorageImpl::new()),
+            Box::new(MemoryImpl::new(
+                Box::new(ZeroFluctuate),
+                64,
+                CellType::Triple,
+            )),
+        );
+        for key in 0..1000u32 {
+            target.put(&key.to_be_bytes(), &key.to_le_bytes());
+        }
+        target.flush();
+        target.put(&0u32.to_be_bytes(), b"new");
+        target.flush();
+
+        let mut target = KvControllerImpl::recover(
+            Box::new(ByteEncoderImpl::new()),
+            Box::new(MetricStorageImpl::new()),
+            target.power_off(),
+        );
+
+        assert!(target.index_ring.span > 1);
+        assert_eq!(Some(b"new".to_vec()), target.get(&0u32.to_be_bytes()));
+        for key in 1..1000u32 {
+            assert_eq!(
+                Some(key.to_le_bytes().to_vec()),
+                target.get(&key.to_be_bytes())
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Record does not match its key")]
+    fn get_should_panic_when_record_belongs_to_other_key() {
+        let mut target = setup_target();
+        target.put(b"key", b"value");
+        let location = target.index[&b"key".to_vec()];
+
+        target.index.insert(b"other".to_vec(), location);
+        target.get(b"other");
+    }
+
+    #[test]
+    #[should_panic(expected = "Record does not fit a block")]
+    fn put_should_panic_on_value_larger_than_block() {
+        let mut target = setup_target();
+
+        target.put(b"big", &vec![0; PAGES_PER_BLOCK * 3 * PAGE_BYTES]);
+    }
+
+    fn setup_target() -> KvControllerImpl<CELLS_PER_PAGE> {
+        KvControllerImpl::new(
+            Box::new(ByteEncoderImpl::new()),
+            Box::new(MetricStorageImpl::new()),
+            Box::new(MemoryImpl::new(
+                Box::new(ZeroFluctuate),
+                8,
+                CellType::Triple,
+            )),
+        )
+    }
+}
@@ -0,0 +1,107 @@
+use crate::physic_level::memory::Address;
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+// slot is the logical page index inside a shared wordline, always 0 for pSLC blocks
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PageLocation {
+    pub address: Address,
+    pub slot: usize,
+}
+
+// only mapped pages take memory, so the size follows the data written rather than the device
+pub struct Mapping {
+    logical_pages: usize,
+    table: BTreeMap<usize, PageLocation>,
+}
+
+impl Mapping {
+    pub fn new(logical_pages: usize) -> Mapping {
+        Mapping {
+            logical_pages,
+            table: BTreeMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.logical_pages
+    }
+
+    pub fn get(&self, lpn: usize) -> Option<PageLocation> {
+        self.table.get(&lpn).copied()
+    }
+
+    // returns the location the logical page was mapped to before
+    pub fn set(&mut self, lpn: usize, location: PageLocation) -> Option<PageLocation> {
+        self.table.insert(lpn, location)
+    }
+
+    pub fn remove(&mut self, lpn: usize) -> Option<PageLocation> {
+        self.table.remove(&lpn)
+    }
+
+    // mapped logical pages of the range in ascending order
+    pub fn mapped(&self, range: Range<usize>) -> Vec<usize> {
+        self.table.range(range).map(|(lpn, _)| *lpn).collect()
+    }
+
+    pub fn count(&self, range: Range<usize>) -> usize {
+        self.table.range(range).count()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, PageLocation)> + '_ {
+        self.table.iter().map(|(lpn, location)| (*lpn, *location))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn set_should_return_previous_location() {
+        let mut target = Mapping::new(4);
+        let first = PageLocation {
+            address: Address(1, 2),
+            slot: 0,
+        };
+        let second = PageLocation {
+            address: Address(3, 0),
+            slot: 2,
+        };
+
+        assert_eq!(None, target.set(2, first));
+        assert_eq!(Some(first), target.set(2, second));
+        assert_eq!(Some(second), target.get(2));
+    }
+
+    #[test]
+    fn remove_should_unmap_page() {
+        let mut target = Mapping::new(4);
+        let location = PageLocation {
+            address: Address(1, 2),
+            slot: 0,
+        };
+
+        target.set(0, location);
+
+        assert_eq!(Some(location), target.remove(0));
+        assert_eq!(None, target.get(0));
+    }
+
+    #[test]
+    fn mapped_should_list_pages_of_range_only() {
+        let mut target = Mapping::new(1 << 40);
+        let location = PageLocation {
+            address: Address(1, 2),
+            slot: 0,
+        };
+
+        for lpn in [3, 7, 1 << 39] {
+            target.set(lpn, location);
+        }
+
+        assert_eq!(vec![3, 7], target.mapped(0..1000));
+        assert_eq!(1 << 40, target.len());
+    }
+}
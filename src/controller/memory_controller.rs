@@ -1,15 +1,2441 @@
-use crate::controller::CellType;
-use crate::physic_level::memory::Address;
+use crate::config::CELLS_PER_PAGE;
+use crate::config::CHECKPOINT_INTERVAL;
+use crate::config::DIES;
+use crate::config::GC_FREE_BLOCKS_WATERMARK;
+use crate::config::MAX_BIT_ERROR_RATE;
+use crate::config::PAGES_PER_BLOCK;
+use crate::config::SLC_CACHE_PERCENT;
+use crate::config::WRITE_FRONTIERS;
+use crate::controller::block_health::BlockHealth;
 use crate::controller::byte_encoder::ByteEncoder;
+use crate::controller::capacity::CapacityConfig;
+use crate::controller::checkpoint;
+use crate::controller::checkpoint::Checkpoint;
+use crate::controller::copyback_time;
+use crate::controller::deallocations::Deallocations;
+use crate::controller::die_pipeline::DiePipeline;
+use crate::controller::erase_queue::EraseQueue;
+use crate::controller::erase_queue::ERASE_SUSPEND_TIME;
+use crate::controller::free_pool::FreePool;
+use crate::controller::frontier::Frontier;
+use crate::controller::mapping::Mapping;
+use crate::controller::mapping::PageLocation;
+use crate::controller::memory_state::MemoryState;
+use crate::controller::memory_state::MemoryStateImpl;
+use crate::controller::operation_time;
+use crate::controller::page_read_time;
+use crate::controller::program_pass_time;
+use crate::controller::slc_cache::SlcCache;
+use crate::controller::spare;
+use crate::controller::temperature::TemperatureClassifier;
+use crate::controller::CellState;
+use crate::controller::CellType;
+use crate::controller::CopybackMode;
+use crate::controller::OperationType;
+use crate::controller::ProgramScheme;
+use crate::controller::PAGE_TRANSFER_TIME;
 use crate::metric::metric_storage::MetricStorage;
+use crate::metric::MetricType;
+use crate::physic_level::memory;
+use crate::physic_level::memory::Address;
 use crate::physic_level::memory::Memory;
-trait MemoryController {
+use std::collections::HashMap;
+use std::ops::Range;
+
+pub trait MemoryController {
     fn write_bits(&mut self, bits: Vec<bool>, adress: Address, cell_type: CellType);
-    fn read_bits(&self, adress: Address, cell_type: CellType) -> Vec<bool>;
+    fn read_bits(&mut self, adress: Address, cell_type: CellType) -> Vec<bool>;
+    fn erase_block(&mut self, block_id: usize);
+
+    // logical page is one bit per cell of a wordline, CELLS_PER_PAGE bits long
+    fn write_page(&mut self, lpn: usize, bits: Vec<bool>);
+    // host tagged write, stream id picks the write frontier instead of the update frequency
+    fn write_stream_page(&mut self, lpn: usize, bits: Vec<bool>, stream_id: usize);
+    fn read_page(&mut self, lpn: usize) -> Vec<bool>;
+    // host no longer needs the logical pages, reads return the deallocated pattern until rewritten
+    fn deallocate(&mut self, ranges: &[Range<usize>]);
+    // runs background work (pending erases, SLC folding) for at most the given amount of time
+    fn idle(&mut self, duration: u32);
+    // returns once every acknowledged write is on the media and the mapping is persisted
+    fn flush(&mut self);
+    // logical pages exposed to the host
+    fn user_capacity(&self) -> usize;
+    // share of endurance spent by the most worn good block
+    fn life_used(&self) -> f64;
+    // controller clock, in the units of the operation times
+    fn time(&self) -> u32;
 }
 
 pub struct MemoryControllerImpl<const PS: usize> {
     byte_encoder: Box<dyn ByteEncoder<PS>>,
     metric_storage: Box<dyn MetricStorage>,
-    memory: Box<dyn Memory>
-}
\ No newline at end of file
+    memory: Box<dyn Memory>,
+    time: u32,
+    mapping: Mapping,
+    memory_state: Box<dyn MemoryState>,
+    free_blocks: FreePool,
+    erase_queue: EraseQueue,
+    erase_suspend: bool,
+    // write frontiers from the coldest to the hottest data
+    frontiers: Vec<Option<Frontier>>,
+    temperature: TemperatureClassifier,
+    slc_cache: SlcCache,
+    block_health: BlockHealth,
+    deallocated_pattern: Vec<bool>,
+    host_pages: u64,
+    flash_pages: u64,
+    collecting: bool,
+    capacity: CapacityConfig,
+    // in write order, the latest checkpoint is in the last `checkpoint_span` ones and the
+    // others are reused once it no longer fits
+    metadata_blocks: Vec<usize>,
+    checkpoint_span: usize,
+    // next page of the last metadata block
+    metadata_page: usize,
+    checkpoint_generation: u32,
+    deallocations: Deallocations,
+    // bad block reserve, out of the free pool until a block is retired
+    reserve_blocks: FreePool,
+    // write sequence number of the next programmed logical page, persisted in the spare area
+    sequence: u32,
+    copyback: CopybackMode,
+    // block and next wordline receiving copybacks, it stays in the plane of the last victim
+    copyback_target: Option<(usize, usize)>,
+    pipeline: DiePipeline,
+}
+
+impl MemoryControllerImpl<CELLS_PER_PAGE> {
+    pub fn new(
+        byte_encoder: Box<dyn ByteEncoder<CELLS_PER_PAGE>>,
+        metric_storage: Box<dyn MetricStorage>,
+        memory: Box<dyn Memory>,
+        capacity: CapacityConfig,
+    ) -> MemoryControllerImpl<CELLS_PER_PAGE> {
+        let native = memory.native_cell_type();
+        let mut free_blocks = FreePool::new();
+        free_blocks.extend((0..memory.block_count()).filter(|b| !memory.is_bad(*b)));
+        let good_blocks = free_blocks.len();
+        let logical_pages = capacity.user_pages(good_blocks, native);
+        let metadata_blocks = free_blocks
+            .split_off_back(capacity.metadata_blocks)
+            .iter()
+            .collect();
+        let reserve_blocks = free_blocks.split_off_back(capacity.reserve_blocks(good_blocks));
+        let mut controller = MemoryControllerImpl::assemble(
+            byte_encoder,
+            metric_storage,
+            memory,
+            capacity,
+            Mapping::new(logical_pages),
+            metadata_blocks,
+        );
+        controller.free_blocks = free_blocks;
+        controller.reserve_blocks = reserve_blocks;
+        controller.write_checkpoint();
+        controller
+    }
+
+    // restart after a power loss: the mapping comes from the last checkpoint updated with
+    // newer versions found in the spare area, every open block is closed and blocks
+    // without valid data are queued for erase
+    pub fn recover(
+        byte_encoder: Box<dyn ByteEncoder<CELLS_PER_PAGE>>,
+        metric_storage: Box<dyn MetricStorage>,
+        mut memory: Box<dyn Memory>,
+        capacity: CapacityConfig,
+    ) -> MemoryControllerImpl<CELLS_PER_PAGE> {
+        memory.power_on();
+        let (checkpoint, generation, first_block) =
+            checkpoint::latest(&*memory).unwrap_or_else(|| panic!("No checkpoint to recover from"));
+        let mut newest: HashMap<usize, (u32, PageLocation)> = HashMap::new();
+        let mut sequence = checkpoint.sequence;
+        for block in 0..memory.block_count() {
+            if memory.block_mode(block).is_none() || checkpoint.metadata_blocks.contains(&block) {
+                continue;
+            }
+            for page in 0..PAGES_PER_BLOCK {
+                let address = Address(block, page);
+                let spare = memory.read_spare(address);
+                // pages of an older metadata block
+                if spare::metadata_piece(spare).is_some() {
+                    continue;
+                }
+                for (slot, entry) in spare::slot_entries(spare).into_iter().enumerate() {
+                    let (lpn, written) = match entry {
+                        Some(entry) => entry,
+                        None => continue,
+                    };
+                    sequence = sequence.max(written + 1);
+                    if lpn < checkpoint.logical_pages
+                        && newest.get(&lpn).is_none_or(|(seen, _)| *seen < written)
+                    {
+                        newest.insert(lpn, (written, PageLocation { address, slot }));
+                    }
+                }
+            }
+        }
+        // copies older than the latest deallocation of their page are stale
+        let mut deallocations = Deallocations::from_runs(checkpoint.deallocations, |_| 0);
+        for range in checkpoint.unmapped {
+            deallocations.record(range, checkpoint.sequence, |_| 0);
+        }
+        let mut mapping = Mapping::new(checkpoint.logical_pages);
+        for (lpn, (written, location)) in newest {
+            if written >= deallocations.sequence(lpn) {
+                mapping.set(lpn, location);
+            }
+        }
+        let deallocations =
+            Deallocations::from_runs(deallocations.runs(), |range| mapping.count(range));
+        let mut controller = MemoryControllerImpl::assemble(
+            byte_encoder,
+            metric_storage,
+            memory,
+            capacity,
+            mapping,
+            checkpoint.metadata_blocks,
+        );
+        controller.sequence = sequence;
+        controller.reserve_blocks = FreePool::from_runs(checkpoint.reserve_blocks);
+        controller.deallocations = deallocations;
+        controller.checkpoint_generation = generation;
+        let blocks = controller.metadata_blocks.len();
+        controller.checkpoint_span = controller
+            .metadata_blocks
+            .iter()
+            .position(|block| *block == first_block)
+            .map_or(blocks, |position| blocks - position);
+        // the next checkpoint goes after the last programmed page, torn ones included
+        let last = controller.metadata_blocks[blocks - 1];
+        controller.metadata_page = (0..PAGES_PER_BLOCK)
+            .rev()
+            .find(|page| !controller.is_erased(Address(last, *page)))
+            .map_or(0, |page| page + 1);
+        controller.rebuild_block_state();
+        controller.write_checkpoint();
+        controller
+    }
+
+    fn assemble(
+        byte_encoder: Box<dyn ByteEncoder<CELLS_PER_PAGE>>,
+        metric_storage: Box<dyn MetricStorage>,
+        memory: Box<dyn Memory>,
+        capacity: CapacityConfig,
+        mapping: Mapping,
+        metadata_blocks: Vec<usize>,
+    ) -> MemoryControllerImpl<CELLS_PER_PAGE> {
+        let block_count = memory.block_count();
+        let native = memory.native_cell_type();
+        let logical_pages = mapping.len();
+        let mut controller = MemoryControllerImpl {
+            byte_encoder,
+            metric_storage,
+            memory,
+            time: 0,
+            mapping,
+            memory_state: Box::new(MemoryStateImpl::new(block_count)),
+            free_blocks: FreePool::new(),
+            erase_queue: EraseQueue::new(block_count),
+            erase_suspend: true,
+            frontiers: (0..WRITE_FRONTIERS).map(|_| None).collect(),
+            temperature: TemperatureClassifier::new(logical_pages),
+            slc_cache: SlcCache::new(SLC_CACHE_PERCENT),
+            block_health: BlockHealth::new(block_count, native, MAX_BIT_ERROR_RATE),
+            deallocated_pattern: vec![false; CELLS_PER_PAGE],
+            host_pages: 0,
+            flash_pages: 0,
+            collecting: false,
+            capacity,
+            metadata_blocks,
+            checkpoint_span: 0,
+            metadata_page: 0,
+            checkpoint_generation: 0,
+            deallocations: Deallocations::new(),
+            reserve_blocks: FreePool::new(),
+            sequence: 0,
+            copyback: CopybackMode::Disabled,
+            copyback_target: None,
+            pipeline: DiePipeline::new(PAGE_TRANSFER_TIME),
+        };
+        for block in 0..block_count {
+            if controller.memory.is_bad(block) {
+                controller.block_health.retire(block);
+            }
+        }
+        controller.put_capacity_metric();
+        controller
+    }
+
+    // page states and validity come from the media and the recovered mapping,
+    // wear statistics and density downgrades are learned again
+    fn rebuild_block_state(&mut self) {
+        for block in 0..self.memory.block_count() {
+            if self.memory.is_bad(block)
+                || self.metadata_blocks.contains(&block)
+                || self.reserve_blocks.contains(block)
+            {
+                continue;
+            }
+            let mode = match self.memory.block_mode(block) {
+                Some(mode) => mode,
+                None => {
+                    self.free_blocks.push_back(block);
+                    continue;
+                }
+            };
+            for page in 0..PAGES_PER_BLOCK {
+                let address = Address(block, page);
+                if !self.is_erased(address) {
+                    self.mark_programmed(address, mode);
+                }
+            }
+        }
+        let locations: Vec<PageLocation> =
+            self.mapping.iter().map(|(_, location)| location).collect();
+        for location in locations {
+            self.memory_state.set_valid(location, true);
+        }
+        for block in 0..self.memory.block_count() {
+            if self.memory.block_mode(block).is_some()
+                && !self.memory.is_bad(block)
+                && !self.metadata_blocks.contains(&block)
+                && self.memory_state.valid_pages(block) == 0
+            {
+                self.release_block(block);
+            }
+        }
+    }
+
+    fn is_erased(&self, address: Address) -> bool {
+        self.memory.read(address).iter().all(|cell| *cell == 0)
+            && self
+                .memory
+                .read_spare(address)
+                .iter()
+                .all(|byte| *byte == 0)
+    }
+
+    pub fn metric_storage(&self) -> &dyn MetricStorage {
+        &*self.metric_storage
+    }
+
+    pub fn set_slc_cache_percent(&mut self, percent_of_free: usize) {
+        self.slc_cache.set_percent_of_free(percent_of_free)
+    }
+
+    // partly written frontier blocks are left closed and collected later
+    pub fn set_write_frontiers(&mut self, count: usize) {
+        if count == 0 {
+            panic!("At least one write frontier is needed")
+        }
+        self.frontiers.resize_with(count, || None);
+    }
+
+    pub fn frontier_block(&self, frontier: usize) -> Option<usize> {
+        self.frontiers[frontier].as_ref().map(|f| f.block)
+    }
+
+    pub fn slc_cache_blocks(&self) -> usize {
+        self.slc_cache.block_count()
+    }
+
+    // logical pages the good blocks can hold at the density they are allowed to run in
+    pub fn usable_capacity(&self) -> usize {
+        self.block_health.usable_pages()
+    }
+
+    // logical pages of every good block at native density, before any reservation
+    pub fn raw_capacity(&self) -> usize {
+        let good_blocks = (0..self.memory.block_count())
+            .filter(|b| !self.memory.is_bad(*b))
+            .count();
+        good_blocks * PAGES_PER_BLOCK * self.memory.native_cell_type().multiplier() as usize
+    }
+
+    pub fn capacity_config(&self) -> CapacityConfig {
+        self.capacity
+    }
+
+    pub fn metadata_blocks(&self) -> &[usize] {
+        &self.metadata_blocks
+    }
+
+    pub fn reserve_blocks(&self) -> Vec<usize> {
+        self.reserve_blocks.iter().collect()
+    }
+
+    pub fn memory_state(&self) -> &dyn MemoryState {
+        &*self.memory_state
+    }
+
+    // reads to a die wait for its running erase unless the erase can be suspended
+    pub fn set_erase_suspend(&mut self, enabled: bool) {
+        self.erase_suspend = enabled;
+    }
+
+    pub fn pending_erases(&self) -> usize {
+        self.erase_queue.pending_count()
+    }
+
+    // the given flash operation from now on is torn by a power cut, see `Memory::cut_power_after`
+    pub fn cut_power_after(&mut self, operations: usize) {
+        self.memory.cut_power_after(operations);
+    }
+
+    // writes returned after the power was cut are not acknowledged
+    pub fn power_lost(&self) -> bool {
+        !self.memory.is_powered()
+    }
+
+    // all volatile state is dropped, only the media survives
+    pub fn power_off(self) -> Box<dyn Memory> {
+        self.memory
+    }
+
+    // logical pages which do not read back the acknowledged data
+    pub fn lost_writes(&mut self, acknowledged: &HashMap<usize, Vec<bool>>) -> Vec<usize> {
+        let mut lost: Vec<usize> = acknowledged
+            .iter()
+            .filter(|(lpn, bits)| self.read_page(**lpn) != **bits)
+            .map(|(lpn, _)| *lpn)
+            .collect();
+        lost.sort_unstable();
+        lost
+    }
+
+    pub fn set_copyback(&mut self, mode: CopybackMode) {
+        self.copyback = mode;
+    }
+
+    // cache program and cache read overlap bus transfers with array operations of the same die
+    pub fn set_cache_operations(&mut self, cached: bool) {
+        self.pipeline.set_cached(cached);
+    }
+
+    pub fn set_deallocated_pattern(&mut self, pattern: Vec<bool>) {
+        if pattern.len() != CELLS_PER_PAGE {
+            panic!("mismatch bits size and page size")
+        }
+        self.deallocated_pattern = pattern;
+    }
+
+    // host write that skips the SLC cache and goes straight to the native density
+    pub fn write_native_page(&mut self, lpn: usize, bits: Vec<bool>) {
+        if lpn >= self.mapping.len() {
+            panic!("Logical page is out of capacity")
+        }
+        self.temperature
+            .record_write(lpn, self.mapping.get(lpn).is_none());
+        let frontier = self.temperature.classify(lpn, self.frontiers.len());
+        self.write_host_page(lpn, bits, frontier, false)
+    }
+
+    // host write that stays in the SLC cache, a full cache gets its oldest block folded to make
+    // room instead of passing the page on to the native density
+    pub fn write_cached_page(&mut self, lpn: usize, bits: Vec<bool>) {
+        if lpn >= self.mapping.len() {
+            panic!("Logical page is out of capacity")
+        }
+        while self.slc_cache.open_frontier().is_none()
+            && !self.slc_cache.can_grow(self.free_blocks.len())
+            && self.fold_step()
+        {}
+        self.write_page(lpn, bits)
+    }
+
+    // pages programmed into flash (host, folding and GC relocation) per page written by host
+    pub fn write_amplification(&self) -> f64 {
+        if self.host_pages == 0 {
+            return 0.0;
+        }
+        self.flash_pages as f64 / self.host_pages as f64
+    }
+
+    pub fn native_cell_type(&self) -> CellType {
+        self.memory.native_cell_type()
+    }
+
+    pub fn block_density(&self, block_id: usize) -> Option<CellType> {
+        self.block_health.density(block_id)
+    }
+
+    fn checkpoint_bytes(&self, metadata_blocks: Vec<usize>) -> Vec<u8> {
+        Checkpoint {
+            sequence: self.sequence,
+            logical_pages: self.mapping.len(),
+            metadata_blocks,
+            reserve_blocks: self.reserve_blocks.runs(),
+            deallocations: self.deallocations.remapped_runs(),
+            unmapped: self.deallocations.unmapped_runs(),
+        }
+        .to_bytes()
+    }
+
+    // the checkpoint is programmed in pSLC pages after the previous one and goes on into
+    // metadata blocks not holding the latest checkpoint, then into blocks from the back of the
+    // free pool; blocks beyond the configured count are handed back once it is complete
+    fn write_checkpoint(&mut self) {
+        let room = if self.metadata_blocks.is_empty() {
+            0
+        } else {
+            PAGES_PER_BLOCK - self.metadata_page
+        };
+        let reusable = self
+            .metadata_blocks
+            .len()
+            .saturating_sub(self.checkpoint_span.max(1));
+        let mut reused = 0;
+        let mut added: Vec<usize> = Vec::new();
+        // the block list is part of the checkpoint, so blocks are added until it fits
+        let (released, span, bytes) = loop {
+            let mut blocks: Vec<usize> = self.metadata_blocks[reused..].to_vec();
+            blocks.extend(added.iter().copied());
+            let span = added.len() + if room > 0 { 1 } else { 0 };
+            let released = (blocks.len() - span)
+                .min(blocks.len().saturating_sub(self.capacity.metadata_blocks));
+            let kept = blocks.split_off(released);
+            let bytes = self.checkpoint_bytes(kept);
+            if bytes.len().div_ceil(spare::METADATA_PAYLOAD_BYTES)
+                <= room + added.len() * PAGES_PER_BLOCK
+            {
+                break (blocks, span, bytes);
+            }
+            if reused < reusable {
+                added.push(self.metadata_blocks[reused]);
+                reused += 1;
+            } else {
+                // a checkpoint of many blocks must not take the ones garbage collection needs
+                self.collect_garbage();
+                let block = match self.free_blocks.pop_back() {
+                    Some(block) => block,
+                    None => self.take_free_block(),
+                };
+                added.push(block);
+            }
+        };
+
+        let mut addresses = Vec::new();
+        if room > 0 {
+            let block = self.metadata_blocks[self.metadata_blocks.len() - 1];
+            addresses
+                .extend((self.metadata_page..PAGES_PER_BLOCK).map(|page| Address(block, page)));
+        }
+        for block in added.iter() {
+            addresses.extend((0..PAGES_PER_BLOCK).map(|page| Address(*block, page)));
+        }
+        let pieces: Vec<&[u8]> = bytes.chunks(spare::METADATA_PAYLOAD_BYTES).collect();
+        for (index, payload) in pieces.iter().enumerate() {
+            let address = addresses[index];
+            if address.1 == 0 {
+                // a reused block, or one whose erase got torn by a power cut
+                if self.memory.block_mode(address.0).is_some() {
+                    self.erase_block(address.0);
+                }
+                self.open_block_for(address.0, CellType::Single);
+            }
+            let cells = self
+                .byte_encoder
+                .encode_bytes_to_page(vec![false; CELLS_PER_PAGE], CellType::Single);
+            self.memory.program(address, cells);
+            self.memory.program_spare(
+                address,
+                spare::metadata_spare(&spare::MetadataPiece {
+                    generation: self.checkpoint_generation,
+                    index,
+                    count: pieces.len(),
+                    payload: payload.to_vec(),
+                }),
+            );
+            self.mark_programmed(address, CellType::Single);
+            self.program_on_die(
+                address.0,
+                operation_time(CellType::Single, OperationType::Write),
+            );
+        }
+        let Address(last, page) = addresses[pieces.len() - 1];
+        self.checkpoint_generation += 1;
+        self.checkpoint_span = span;
+        self.metadata_page = page + 1;
+        self.metadata_blocks.drain(..reused);
+        self.metadata_blocks.extend(added);
+        self.metadata_blocks.drain(..released.len());
+        debug_assert_eq!(Some(&last), self.metadata_blocks.last());
+        for block in released {
+            if self.memory.block_mode(block).is_some() {
+                self.release_block(block)
+            } else {
+                self.free_blocks.push_back(block)
+            }
+        }
+        self.metric_storage.put_metric(
+            "checkpoint",
+            bytes.len() as u32,
+            self.time,
+            MetricType::Write,
+        );
+    }
+
+    fn next_sequence(&mut self) -> u32 {
+        self.sequence += 1;
+        self.sequence - 1
+    }
+
+    fn put_capacity_metric(&mut self) {
+        self.metric_storage.put_metric(
+            "usable_capacity",
+            self.block_health.usable_pages() as u32,
+            self.time,
+            MetricType::Capacity,
+        );
+    }
+
+    // erase cycles the block has left if it keeps running in the given mode
+    pub fn remaining_cycles(&self, block_id: usize, cell_type: CellType) -> u32 {
+        let life_left = 1.0 - self.memory.block_life_used(block_id);
+        (life_left * cell_type.endurance() as f64).max(0.0).round() as u32
+    }
+
+    fn open_block_for(&mut self, block_id: usize, cell_type: CellType) {
+        match self.memory.block_mode(block_id) {
+            None => {
+                if self.remaining_cycles(block_id, cell_type) == 0 {
+                    panic!("Block endurance is exhausted for cell type")
+                }
+                self.memory.open_block(block_id, cell_type)
+            }
+            Some(mode) if mode != cell_type => {
+                panic!("Block is programmed with another cell type")
+            }
+            Some(_) => {}
+        }
+    }
+
+    fn take_free_block(&mut self) -> usize {
+        self.finish_erases();
+        // a reclaimed block may get retired, so keep going while erases are left
+        while self.free_blocks.is_empty() && !self.erase_queue.is_empty() {
+            self.reclaim_block();
+        }
+        self.free_blocks
+            .pop_front()
+            .unwrap_or_else(|| panic!("No free blocks left"))
+    }
+
+    fn write_to_slc_cache(&mut self, lpn: usize, bits: &[bool]) -> Option<PageLocation> {
+        if self.slc_cache.open_frontier().is_none() {
+            if !self.slc_cache.can_grow(self.free_blocks.len()) {
+                return None;
+            }
+            let block = self.take_free_block();
+            self.open_block_for(block, CellType::Single);
+            self.slc_cache.add_block(block);
+        }
+
+        let frontier = self.slc_cache.open_frontier().unwrap();
+        let address = frontier.address();
+        let cells = self.byte_encoder.encode_wordline_pass(
+            frontier.push(bits.to_vec()),
+            CellType::Single,
+            ProgramScheme::OneShot,
+            0,
+        );
+        frontier.complete_wordline();
+        self.memory.program(address, cells);
+        let sequence = self.next_sequence();
+        self.memory
+            .program_spare(address, spare::slot_spare(0, lpn, sequence));
+        self.mark_programmed(address, CellType::Single);
+        self.slc_cache.record(lpn, address);
+        self.flash_pages += 1;
+
+        self.program_on_die(
+            address.0,
+            operation_time(CellType::Single, OperationType::Write),
+        );
+        Some(PageLocation { address, slot: 0 })
+    }
+
+    // the frontier the pages go to, near the watermark free blocks are left to garbage
+    // collection and every temperature shares an open block while there is one
+    fn ensure_dense_frontier(&mut self, index: usize) -> usize {
+        let has_room =
+            |frontier: &Option<Frontier>| frontier.as_ref().is_some_and(|f| !f.is_full());
+        if has_room(&self.frontiers[index]) {
+            return index;
+        }
+        // a full block is no longer a frontier, so garbage collection may pick it
+        self.frontiers[index] = None;
+        if self.free_blocks.len() < GC_FREE_BLOCKS_WATERMARK {
+            if let Some(open) = self.frontiers.iter().position(has_room) {
+                return open;
+            }
+        }
+        let block = self.take_free_block();
+        let cell_type = self
+            .block_health
+            .density(block)
+            .unwrap_or_else(|| panic!("Retired block in free pool"));
+        self.open_block_for(block, cell_type);
+        self.frontiers[index] = Some(Frontier::new(block, cell_type));
+        index
+    }
+
+    // whole wordline goes in one shot, otherwise pages are added pass by pass
+    fn write_to_dense(
+        &mut self,
+        pages: Vec<(usize, Vec<bool>)>,
+        index: usize,
+    ) -> Vec<PageLocation> {
+        let index = self.ensure_dense_frontier(index);
+        self.flash_pages += pages.len() as u64;
+        let frontier = self.frontiers[index].as_mut().unwrap();
+        let cell_type = frontier.cell_type;
+        if frontier.slot() == 0 && pages.len() == cell_type.multiplier() as usize {
+            let address = frontier.address();
+            let mut locations = Vec::new();
+            for (_, bits) in pages.iter() {
+                locations.push(PageLocation {
+                    address,
+                    slot: frontier.slot(),
+                });
+                frontier.push(bits.clone());
+            }
+            frontier.complete_wordline();
+            let bits: Vec<Vec<bool>> = pages.iter().map(|(_, bits)| bits.clone()).collect();
+            let cells =
+                self.byte_encoder
+                    .encode_wordline_pass(&bits, cell_type, ProgramScheme::OneShot, 0);
+            self.memory.program(address, cells);
+            // spare goes last, so a torn program is never picked up on recovery
+            for (slot, (lpn, _)) in pages.iter().enumerate() {
+                let sequence = self.next_sequence();
+                self.memory
+                    .program_spare(address, spare::slot_spare(slot, *lpn, sequence));
+            }
+            self.mark_programmed(address, cell_type);
+            self.program_on_die(
+                address.0,
+                program_pass_time(cell_type, ProgramScheme::OneShot, 0),
+            );
+            return locations;
+        }
+
+        let mut locations = Vec::new();
+        for (lpn, bits) in pages {
+            let index = self.ensure_dense_frontier(index);
+            let frontier = self.frontiers[index].as_mut().unwrap();
+            let cell_type = frontier.cell_type;
+            let address = frontier.address();
+            let slot = frontier.slot();
+            let cells = self.byte_encoder.encode_wordline_pass(
+                frontier.push(bits),
+                cell_type,
+                ProgramScheme::MultiPass,
+                slot,
+            );
+            frontier.complete_wordline();
+            self.memory.program_pass(address, cells);
+            let sequence = self.next_sequence();
+            self.memory
+                .program_spare(address, spare::slot_spare(slot, lpn, sequence));
+            self.mark_programmed(address, cell_type);
+            self.program_on_die(
+                address.0,
+                program_pass_time(cell_type, ProgramScheme::MultiPass, slot),
+            );
+            locations.push(PageLocation { address, slot });
+        }
+        locations
+    }
+
+    // data goes over the bus into the die, the time hidden behind a running program is reported
+    fn program_on_die(&mut self, block_id: usize, program_time: u32) {
+        let die = self.erase_queue.die_of(block_id);
+        let serial = self.time + PAGE_TRANSFER_TIME + program_time;
+        self.time = self.pipeline.program(die, self.time, program_time);
+        if self.time < serial {
+            self.metric_storage.put_metric(
+                "cache_program",
+                serial - self.time,
+                self.time,
+                MetricType::Write,
+            );
+        }
+    }
+
+    fn mark_programmed(&mut self, address: Address, cell_type: CellType) {
+        let Address(block_id, page_id) = address;
+        self.memory_state.set_memory_state(
+            block_id,
+            page_id..page_id + 1,
+            CellState::Set(cell_type),
+        );
+    }
+
+    fn map_page(&mut self, lpn: usize, location: PageLocation) {
+        match self.mapping.set(lpn, location) {
+            Some(previous) => self.memory_state.set_valid(previous, false),
+            None => self.deallocations.remap(lpn),
+        }
+        self.memory_state.set_valid(location, true);
+    }
+
+    fn unmap_page(&mut self, lpn: usize) -> bool {
+        match self.mapping.remove(lpn) {
+            Some(previous) => {
+                self.memory_state.set_valid(previous, false);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn read_location(&mut self, location: PageLocation) -> Vec<bool> {
+        let Address(block_id, _) = location.address;
+        let cell_type = self
+            .memory
+            .block_mode(block_id)
+            .unwrap_or_else(|| panic!("Mapped page is in erased block"));
+        let read_time = page_read_time(cell_type, location.slot);
+        let die = self.erase_queue.die_of(block_id);
+        let suspended = self.wait_for_die(die);
+        let cells = *self.memory.read(location.address);
+        let next = next_location(location, cell_type);
+        let serial = self.time + read_time + PAGE_TRANSFER_TIME;
+        self.time = self
+            .pipeline
+            .read(die, self.time, location, read_time, next);
+        if let Some(left) = suspended {
+            let finish = self.pipeline.resume(die, left);
+            self.erase_queue.reschedule(die, finish);
+        }
+        if self.time < serial {
+            self.metric_storage.put_metric(
+                "cache_read",
+                serial - self.time,
+                self.time,
+                MetricType::Read,
+            );
+        }
+        self.byte_encoder
+            .decode_wordline_page(cells, cell_type, location.slot)
+    }
+
+    // program verify: read the page back without sensing time and count flipped bits
+    fn verify(&mut self, location: PageLocation, bits: &[bool]) -> bool {
+        let Address(block_id, _) = location.address;
+        let cell_type = self.memory.block_mode(block_id).unwrap();
+        let cells = *self.memory.read(location.address);
+        let stored = self
+            .byte_encoder
+            .decode_wordline_page(cells, cell_type, location.slot);
+        let errors = stored.iter().zip(bits).filter(|(s, b)| s != b).count();
+        self.block_health.record(block_id, bits.len(), errors);
+        errors == 0
+    }
+
+    // failed block gets no more pages until it is erased and reviewed
+    fn close_block(&mut self, block_id: usize) {
+        for frontier in self.frontiers.iter_mut() {
+            if frontier.as_ref().map(|f| f.block) == Some(block_id) {
+                *frontier = None;
+            }
+        }
+        self.slc_cache.close(block_id);
+    }
+
+    fn write_to_dense_verified(
+        &mut self,
+        pages: Vec<(usize, Vec<bool>)>,
+        index: usize,
+    ) -> Vec<PageLocation> {
+        let mut locations = self.write_to_dense(pages.clone(), index);
+        loop {
+            let failed: Vec<usize> = (0..pages.len())
+                .filter(|i| !self.verify(locations[*i], &pages[*i].1))
+                .collect();
+            if failed.is_empty() {
+                return locations;
+            }
+            for i in failed.iter() {
+                self.close_block(locations[*i].address.0);
+            }
+            for i in failed {
+                locations[i] = self.write_to_dense(vec![pages[i].clone()], index)[0];
+            }
+        }
+    }
+
+    // reads to a die with a running erase either suspend it or wait until it ends, returns the
+    // erase time left of a suspended erase
+    fn wait_for_die(&mut self, die: usize) -> Option<u32> {
+        let finish = self.erase_queue.busy_until(die, self.time)?;
+        if !self.erase_suspend {
+            self.time = finish;
+            self.finish_erases();
+            return None;
+        }
+        let left = self.pipeline.suspend(die, self.time);
+        self.time += ERASE_SUSPEND_TIME;
+        self.metric_storage
+            .put_metric("erase_suspend", 1, self.time, MetricType::Delete);
+        Some(left)
+    }
+
+    // the erase is deferred, the block waits in the erase queue marked as pending reset
+    fn release_block(&mut self, block_id: usize) {
+        self.memory_state
+            .set_memory_state(block_id, 0..PAGES_PER_BLOCK, CellState::ResetPending);
+        self.erase_queue.push(block_id);
+    }
+
+    // erased block goes back to the free pool unless it got retired
+    fn return_to_pool(&mut self, block_id: usize) {
+        if self.block_health.density(block_id).is_some() {
+            self.free_blocks.push_back(block_id);
+        }
+    }
+
+    fn finish_erases(&mut self) {
+        for block in self.erase_queue.finish_before(self.time) {
+            self.return_to_pool(block);
+        }
+    }
+
+    // starts a background erase on an idle die, the controller does not wait for it
+    fn start_erase(&mut self) -> bool {
+        let block = match self.erase_queue.pop_startable() {
+            Some(block) => block,
+            None => return false,
+        };
+        let die = self.erase_queue.die_of(block);
+        let duration = self.reset_block(block);
+        let finish = self.pipeline.erase(die, self.time, duration);
+        self.erase_queue.start(block, finish);
+        true
+    }
+
+    // foreground reclaim: waits for a running erase or erases a pending block right away,
+    // returns whether a free block came out of it
+    fn reclaim_block(&mut self) -> bool {
+        let free = self.free_blocks.len();
+        if let Some(finish) = self.erase_queue.next_finish() {
+            self.time = self.time.max(finish);
+            self.finish_erases();
+        } else if let Some(block) = self.erase_queue.pop_pending() {
+            self.erase_block(block);
+            self.return_to_pool(block);
+        }
+        self.free_blocks.len() > free
+    }
+
+    // resets the block and reviews its health, returns the erase duration
+    fn reset_block(&mut self, block_id: usize) -> u32 {
+        let programmed_mode = self.memory.block_mode(block_id);
+        let mode = programmed_mode.unwrap_or_else(|| self.memory.native_cell_type());
+        self.memory.reset(block_id);
+        self.memory_state
+            .set_memory_state(block_id, 0..PAGES_PER_BLOCK, CellState::Empty);
+        self.metric_storage
+            .put_metric("erase", 1, self.time, MetricType::Delete);
+
+        if let (Some(mode), Some(density)) = (programmed_mode, self.block_health.density(block_id))
+        {
+            let worn_out = self.remaining_cycles(block_id, density) == 0;
+            if self.block_health.review(block_id, mode, worn_out) {
+                if self.block_health.density(block_id).is_none() {
+                    self.memory.mark_bad(block_id);
+                    self.free_blocks.extend(self.reserve_blocks.pop_back());
+                }
+                self.put_capacity_metric();
+            }
+        }
+        operation_time(mode, OperationType::Delete)
+    }
+
+    // moves up to one dense wordline of cached pages, returns false when nothing is left to fold
+    fn fold_step(&mut self) -> bool {
+        let block = match self.slc_cache.oldest_block() {
+            Some(block) => block,
+            None => return false,
+        };
+        self.slc_cache.close(block);
+
+        let group_size = self.memory.native_cell_type().multiplier() as usize;
+        let mut pages = Vec::new();
+        while pages.len() < group_size {
+            let (lpn, address) = match self.slc_cache.next_entry_of(block) {
+                Some(entry) => entry,
+                None => break,
+            };
+            let location = PageLocation { address, slot: 0 };
+            if self.mapping.get(lpn) == Some(location) {
+                pages.push((lpn, self.read_location(location)));
+            }
+        }
+
+        if !pages.is_empty() {
+            let folded = pages.len() as u32;
+            let classes = self.frontiers.len();
+            let mut by_frontier: Vec<Vec<(usize, Vec<bool>)>> = vec![Vec::new(); classes];
+            for (lpn, bits) in pages {
+                by_frontier[self.temperature.classify(lpn, classes)].push((lpn, bits));
+            }
+            for (index, pages) in by_frontier.into_iter().enumerate() {
+                if !pages.is_empty() {
+                    self.relocate(pages, index);
+                }
+            }
+            self.metric_storage
+                .put_metric("fold", folded, self.time, MetricType::Write);
+        }
+
+        if !self.slc_cache.has_entries_of(block) {
+            self.slc_cache.release_oldest();
+            self.release_block(block);
+        }
+        true
+    }
+
+    // pages already read out to the controller are programmed back
+    fn relocate(&mut self, pages: Vec<(usize, Vec<bool>)>, index: usize) {
+        let lpns: Vec<usize> = pages.iter().map(|(lpn, _)| *lpn).collect();
+        let locations = self.write_to_dense_verified(pages, index);
+        for (lpn, location) in lpns.into_iter().zip(locations) {
+            self.map_page(lpn, location);
+        }
+    }
+
+    // pending erases are reclaimed first, then pSLC cache is folded, then greedy GC frees blocks until the watermark is reached
+    fn collect_garbage(&mut self) {
+        if self.collecting {
+            return;
+        }
+        self.collecting = true;
+        self.finish_erases();
+        while self.free_blocks.len() < GC_FREE_BLOCKS_WATERMARK {
+            if self.reclaim_block() || !self.erase_queue.is_empty() {
+                continue;
+            }
+            if !self.fold_step() && !self.gc_step() {
+                break;
+            }
+        }
+        self.collecting = false;
+    }
+
+    fn write_host_page(&mut self, lpn: usize, bits: Vec<bool>, frontier: usize, cached: bool) {
+        if bits.len() != CELLS_PER_PAGE {
+            panic!("mismatch bits size and page size")
+        }
+
+        self.collect_garbage();
+        self.host_pages += 1;
+        let bit_amount = bits.len() as u32;
+        let location = loop {
+            let cache_location = if cached {
+                self.write_to_slc_cache(lpn, &bits)
+            } else {
+                None
+            };
+            let (location, series) = match cache_location {
+                Some(location) => {
+                    self.slc_cache.mark_exhausted(false);
+                    (location, "slc_cache_write")
+                }
+                None => {
+                    if cached && self.slc_cache.mark_exhausted(true) {
+                        self.metric_storage.put_metric(
+                            "slc_cache_exhausted",
+                            self.slc_cache.block_count() as u32,
+                            self.time,
+                            MetricType::Write,
+                        );
+                    }
+                    (
+                        self.write_to_dense(vec![(lpn, bits.clone())], frontier)[0],
+                        "direct_write",
+                    )
+                }
+            };
+            if self.verify(location, &bits) {
+                self.metric_storage
+                    .put_metric(series, bit_amount, self.time, MetricType::Write);
+                break location;
+            }
+            self.close_block(location.address.0);
+        };
+        self.map_page(lpn, location);
+        if self.host_pages.is_multiple_of(CHECKPOINT_INTERVAL) {
+            self.write_checkpoint();
+        }
+    }
+
+    fn gc_victim(&self) -> Option<usize> {
+        let mut frontiers: Vec<usize> = self.frontiers.iter().flatten().map(|f| f.block).collect();
+        frontiers.extend(self.copyback_target.map(|(block, _)| block));
+        // erased blocks hold nothing to collect
+        self.memory_state
+            .used_blocks()
+            .into_iter()
+            .filter(|b| {
+                !frontiers.contains(b)
+                    && !self.metadata_blocks.contains(b)
+                    && !self.slc_cache.contains(*b)
+                    && !self.erase_queue.contains(*b)
+            })
+            .filter_map(|b| self.memory.block_mode(b).map(|mode| (b, mode)))
+            .filter(|(b, mode)| {
+                self.memory_state.valid_pages(*b) < PAGES_PER_BLOCK * mode.multiplier() as usize
+            })
+            .min_by_key(|(b, _)| (self.memory_state.valid_pages(*b), !self.copyback_ready(*b)))
+            .map(|(b, _)| b)
+    }
+
+    // among equally cheap victims the one sharing a plane with a copyback target or a free block
+    // can skip the transfer through the controller
+    fn copyback_ready(&self, victim: usize) -> bool {
+        if self.copyback == CopybackMode::Disabled {
+            return false;
+        }
+        let block_count = self.memory.block_count();
+        let plane = memory::plane_of(victim, block_count);
+        self.copyback_target
+            .iter()
+            .map(|(block, _)| *block)
+            .chain(self.free_blocks.iter())
+            .any(|block| memory::plane_of(block, block_count) == plane)
+    }
+
+    // next wordline in the plane of the victim able to take a copyback, a new target block is
+    // opened only when the GC frontier can take the rest of the victim without a free block
+    fn copyback_target_for(&mut self, victim: usize, mode: CellType) -> Option<Address> {
+        let block_count = self.memory.block_count();
+        let plane = memory::plane_of(victim, block_count);
+        if let Some((block, page)) = self.copyback_target {
+            if page < PAGES_PER_BLOCK
+                && memory::plane_of(block, block_count) == plane
+                && self.memory.block_mode(block) == Some(mode)
+            {
+                self.copyback_target = Some((block, page + 1));
+                return Some(Address(block, page));
+            }
+        }
+        let frontier_room = self.frontiers[0]
+            .as_ref()
+            .map_or(0, |frontier| frontier.remaining_slots());
+        if self.free_blocks.len() < 2 && frontier_room < self.memory_state.valid_pages(victim) {
+            return None;
+        }
+        let block = self.free_blocks.iter().find(|block| {
+            memory::plane_of(*block, block_count) == plane
+                && self
+                    .block_health
+                    .density(*block)
+                    .is_some_and(|density| density.multiplier() >= mode.multiplier())
+                && self.remaining_cycles(*block, mode) > 0
+        })?;
+        self.free_blocks.remove(block);
+        self.open_block_for(block, mode);
+        self.copyback_target = Some((block, 1));
+        Some(Address(block, 0))
+    }
+
+    // moves a fully valid wordline inside the die, returns false when it has to go through
+    // the controller instead
+    fn copyback_wordline(&mut self, source: Address, mode: CellType) -> bool {
+        if self.copyback == CopybackMode::Disabled {
+            return false;
+        }
+        let target = match self.copyback_target_for(source.0, mode) {
+            Some(target) => target,
+            None => return false,
+        };
+        let lpns: Vec<usize> = spare::slot_lpns(self.memory.read_spare(source))
+            .into_iter()
+            .flatten()
+            .collect();
+        self.memory.copyback(source, target);
+        self.mark_programmed(target, mode);
+        self.flash_pages += lpns.len() as u64;
+        let die = self.erase_queue.die_of(source.0);
+        self.time = self.pipeline.wait(die, self.time) + copyback_time(mode);
+
+        if self.copyback == CopybackMode::Checked {
+            for slot in 0..lpns.len() {
+                let expected = self.read_location(PageLocation {
+                    address: source,
+                    slot,
+                });
+                let copied = self.read_location(PageLocation {
+                    address: target,
+                    slot,
+                });
+                if expected != copied {
+                    self.metric_storage.put_metric(
+                        "copyback_error",
+                        1,
+                        self.time,
+                        MetricType::Write,
+                    );
+                    return false;
+                }
+            }
+        }
+        for (slot, lpn) in lpns.iter().enumerate() {
+            self.map_page(
+                *lpn,
+                PageLocation {
+                    address: target,
+                    slot,
+                },
+            );
+        }
+        self.metric_storage
+            .put_metric("copyback", lpns.len() as u32, self.time, MetricType::Write);
+        true
+    }
+
+    // relocates still mapped pages of the victim found through the spare area and erases it
+    fn gc_step(&mut self) -> bool {
+        let victim = match self.gc_victim() {
+            Some(victim) => victim,
+            None => return false,
+        };
+        let mode = self.memory.block_mode(victim).unwrap();
+        let group_size = mode.multiplier() as usize;
+        let start = self.time;
+        self.metric_storage.put_metric(
+            "gc_victim_valid_pages",
+            self.memory_state.valid_pages(victim) as u32,
+            self.time,
+            MetricType::Write,
+        );
+        let mut relocated = 0;
+        let mut pages = Vec::new();
+        for page in 0..PAGES_PER_BLOCK {
+            if self.memory_state.valid_pages(victim) == pages.len() {
+                break;
+            }
+            let address = Address(victim, page);
+            let valid_slots = (0..group_size)
+                .filter(|slot| {
+                    self.memory_state.is_valid(PageLocation {
+                        address,
+                        slot: *slot,
+                    })
+                })
+                .count();
+            if valid_slots == 0 {
+                continue;
+            }
+            if valid_slots == group_size && self.copyback_wordline(address, mode) {
+                relocated += group_size;
+                continue;
+            }
+            let lpns = spare::slot_lpns(self.memory.read_spare(address));
+            self.time += page_read_time(mode, 0);
+            for (slot, lpn) in lpns.into_iter().enumerate() {
+                let location = PageLocation { address, slot };
+                match lpn {
+                    Some(lpn) if self.mapping.get(lpn) == Some(location) => {
+                        pages.push((lpn, self.read_location(location)))
+                    }
+                    _ => {}
+                }
+            }
+        }
+        while !pages.is_empty() {
+            let rest = pages.split_off(group_size.min(pages.len()));
+            relocated += pages.len();
+            // data surviving garbage collection is the coldest
+            self.relocate(pages, 0);
+            pages = rest;
+        }
+        self.release_block(victim);
+
+        self.metric_storage.put_metric(
+            "gc_relocated",
+            relocated as u32,
+            self.time,
+            MetricType::Write,
+        );
+        self.metric_storage.put_metric(
+            "gc_latency",
+            self.time - start,
+            self.time,
+            MetricType::Write,
+        );
+        true
+    }
+}
+
+// logical page a sequential cache read senses ahead: the next slot of the wordline, then the
+// first slot of the next wordline
+fn next_location(location: PageLocation, cell_type: CellType) -> Option<PageLocation> {
+    let Address(block_id, page_id) = location.address;
+    if location.slot + 1 < cell_type.multiplier() as usize {
+        return Some(PageLocation {
+            address: location.address,
+            slot: location.slot + 1,
+        });
+    }
+    (page_id + 1 < PAGES_PER_BLOCK).then(|| PageLocation {
+        address: Address(block_id, page_id + 1),
+        slot: 0,
+    })
+}
+
+impl MemoryController for MemoryControllerImpl<CELLS_PER_PAGE> {
+    fn write_bits(&mut self, bits: Vec<bool>, adress: Address, cell_type: CellType) {
+        let Address(block_id, _) = adress;
+        self.open_block_for(block_id, cell_type);
+
+        let bit_amount = bits.len() as u32;
+        let cells = self.byte_encoder.encode_bytes_to_page(bits, cell_type);
+        self.memory.program(adress, cells);
+
+        self.time += operation_time(cell_type, OperationType::Write);
+        self.metric_storage
+            .put_metric("write", bit_amount, self.time, MetricType::Write);
+    }
+
+    fn read_bits(&mut self, adress: Address, cell_type: CellType) -> Vec<bool> {
+        let Address(block_id, _) = adress;
+        if let Some(mode) = self.memory.block_mode(block_id) {
+            if mode != cell_type {
+                panic!("Block is programmed with another cell type")
+            }
+        }
+
+        let cells = *self.memory.read(adress);
+        let bits = self.byte_encoder.decode_page_to_bytes(cells, cell_type);
+
+        self.time += operation_time(cell_type, OperationType::Read);
+        self.metric_storage
+            .put_metric("read", bits.len() as u32, self.time, MetricType::Read);
+        bits
+    }
+
+    fn erase_block(&mut self, block_id: usize) {
+        let die = self.erase_queue.die_of(block_id);
+        let duration = self.reset_block(block_id);
+        self.time = self.pipeline.erase(die, self.time, duration);
+    }
+
+    fn write_page(&mut self, lpn: usize, bits: Vec<bool>) {
+        if lpn >= self.mapping.len() {
+            panic!("Logical page is out of capacity")
+        }
+        self.temperature
+            .record_write(lpn, self.mapping.get(lpn).is_none());
+        let frontier = self.temperature.classify(lpn, self.frontiers.len());
+        self.write_host_page(lpn, bits, frontier, true)
+    }
+
+    fn write_stream_page(&mut self, lpn: usize, bits: Vec<bool>, stream_id: usize) {
+        if lpn >= self.mapping.len() {
+            panic!("Logical page is out of capacity")
+        }
+        self.temperature
+            .record_write(lpn, self.mapping.get(lpn).is_none());
+        let frontier = stream_id % self.frontiers.len();
+        self.write_host_page(lpn, bits, frontier, true)
+    }
+
+    fn read_page(&mut self, lpn: usize) -> Vec<bool> {
+        if lpn >= self.mapping.len() {
+            panic!("Logical page is out of capacity")
+        }
+        let bits = match self.mapping.get(lpn) {
+            Some(location) => self.read_location(location),
+            None => self.deallocated_pattern.clone(),
+        };
+        self.metric_storage
+            .put_metric("read_page", bits.len() as u32, self.time, MetricType::Read);
+        bits
+    }
+
+    fn deallocate(&mut self, ranges: &[Range<usize>]) {
+        // a bad range rejects the whole command before anything is unmapped
+        if ranges.iter().any(|range| range.end > self.mapping.len()) {
+            panic!("Logical page is out of capacity")
+        }
+        let mut deallocated = 0;
+        for range in ranges {
+            for lpn in self.mapping.mapped(range.clone()) {
+                if self.unmap_page(lpn) {
+                    deallocated += 1;
+                }
+            }
+            let mapping = &self.mapping;
+            self.deallocations
+                .record(range.clone(), self.sequence, |range| mapping.count(range));
+        }
+        // deallocation leaves nothing in the spare area, it is durable from the next checkpoint on
+        self.metric_storage
+            .put_metric("deallocate", deallocated, self.time, MetricType::Delete);
+    }
+
+    fn idle(&mut self, duration: u32) {
+        // the clock stops at its end rather than wrapping to the start
+        let deadline = self.time.saturating_add(duration);
+        while self.time < deadline {
+            self.finish_erases();
+            if self.start_erase() || self.fold_step() {
+                continue;
+            }
+            match self.erase_queue.next_finish() {
+                Some(finish) if finish <= deadline => self.time = finish,
+                _ => break,
+            }
+        }
+        self.time = self.time.max(deadline);
+        self.finish_erases();
+    }
+
+    // cached programs may still run in the arrays after the controller moved on
+    fn flush(&mut self) {
+        for die in 0..DIES {
+            self.time = self.pipeline.wait(die, self.time);
+        }
+        self.write_checkpoint();
+        self.metric_storage
+            .put_metric("flush", 1, self.time, MetricType::Write);
+    }
+
+    fn user_capacity(&self) -> usize {
+        self.mapping.len()
+    }
+
+    fn life_used(&self) -> f64 {
+        (0..self.memory.block_count())
+            .filter(|b| !self.memory.is_bad(*b))
+            .map(|b| self.memory.block_life_used(b))
+            .fold(0.0, f64::max)
+    }
+
+    fn time(&self) -> u32 {
+        self.time
+    }
+}
+
+// controller over a small device keeping every value as written, for the tests of the layers
+// on top of it
+#[cfg(test)]
+pub fn test_controller() -> MemoryControllerImpl<CELLS_PER_PAGE> {
+    use crate::controller::byte_encoder::ByteEncoderImpl;
+    use crate::metric::metric_storage::MetricStorageImpl;
+    use crate::physic_level::memory::MemoryImpl;
+    use crate::physic_level::memory_components::ZeroFluctuate;
+
+    MemoryControllerImpl::new(
+        Box::new(ByteEncoderImpl::new()),
+        Box::new(MetricStorageImpl::new()),
+        Box::new(MemoryImpl::new(
+            Box::new(ZeroFluctuate),
+            32,
+            CellType::Triple,
+        )),
+        CapacityConfig::default(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::controller::byte_encoder::ByteEncoderImpl;
+    use crate::metric::metric_storage::MetricStorageImpl;
+    use crate::physic_level::memory::MemoryImpl;
+    use crate::physic_level::memory_components::FluctuareT;
+    use crate::physic_level::memory_components::ZeroFluctuate;
+    use crate::physic_level::sparse_memory::SparseMemoryImpl;
+    use rand::rngs::StdRng;
+    use rand::Rng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn write_bits_should_be_read_back_in_pseudo_slc_block() {
+        let mut target = setup_target();
+        let bits: Vec<bool> = (0..CELLS_PER_PAGE).map(|i| i % 3 == 0).collect();
+
+        target.write_bits(bits.clone(), Address(1, 0), CellType::Single);
+
+        assert_eq!(bits, target.read_bits(Address(1, 0), CellType::Single));
+    }
+
+    #[test]
+    fn blocks_should_keep_own_density() {
+        let mut target = setup_target();
+        let slc_bits = vec![true; CELLS_PER_PAGE];
+        let qlc_bits: Vec<bool> = (0..CELLS_PER_PAGE * 4).map(|i| i % 5 == 0).collect();
+
+        target.write_bits(slc_bits.clone(), Address(1, 0), CellType::Single);
+        target.write_bits(qlc_bits.clone(), Address(2, 0), CellType::Quadro);
+
+        assert_eq!(slc_bits, target.read_bits(Address(1, 0), CellType::Single));
+        assert_eq!(qlc_bits, target.read_bits(Address(2, 0), CellType::Quadro));
+    }
+
+    #[test]
+    #[should_panic(expected = "Block is programmed with another cell type")]
+    fn read_bits_should_panic_when_cell_type_differs_from_programmed() {
+        let mut target = setup_target();
+
+        target.write_bits(vec![true; CELLS_PER_PAGE], Address(1, 0), CellType::Single);
+        target.read_bits(Address(1, 0), CellType::Double);
+    }
+
+    #[test]
+    fn erase_block_should_allow_other_density_after_reset() {
+        let mut target = setup_target();
+        let bits = vec![false; CELLS_PER_PAGE * 2];
+
+        target.write_bits(vec![true; CELLS_PER_PAGE], Address(1, 0), CellType::Single);
+        target.erase_block(1);
+        target.write_bits(bits.clone(), Address(1, 0), CellType::Double);
+
+        assert_eq!(bits, target.read_bits(Address(1, 0), CellType::Double));
+    }
+
+    #[test]
+    fn remaining_cycles_should_depend_on_mode() {
+        let mut target = setup_target();
+
+        target.write_bits(
+            vec![true; CELLS_PER_PAGE * 4],
+            Address(1, 0),
+            CellType::Quadro,
+        );
+        target.erase_block(1);
+
+        assert_eq!(
+            CellType::Quadro.endurance() - 1,
+            target.remaining_cycles(1, CellType::Quadro)
+        );
+        assert_eq!(
+            CellType::Single.endurance() - 100,
+            target.remaining_cycles(1, CellType::Single)
+        );
+    }
+
+    #[test]
+    fn write_page_should_be_read_back_from_slc_cache() {
+        let mut target = setup_target();
+        target.set_slc_cache_percent(50);
+        let bits = page_bits(7);
+
+        target.write_page(7, bits.clone());
+
+        assert_eq!(1, target.slc_cache_blocks());
+        assert_eq!(bits, target.read_page(7));
+        assert_eq!(vec![false; CELLS_PER_PAGE], target.read_page(8));
+    }
+
+    #[test]
+    fn write_page_should_go_directly_to_dense_block_without_cache() {
+        let mut target = setup_target();
+        target.set_slc_cache_percent(0);
+
+        for lpn in 0..6 {
+            target.write_page(lpn, page_bits(lpn));
+        }
+
+        assert_eq!(0, target.slc_cache_blocks());
+        for lpn in 0..6 {
+            assert_eq!(page_bits(lpn), target.read_page(lpn));
+        }
+        assert_eq!(6, target.metric_storage().get_metric("direct_write").len());
+    }
+
+    #[test]
+    fn idle_should_fold_cache_into_dense_blocks() {
+        let mut target = setup_target();
+        target.set_slc_cache_percent(50);
+        for lpn in 0..10 {
+            target.write_page(lpn, page_bits(lpn));
+        }
+        target.write_page(3, page_bits(30));
+
+        target.idle(100_000);
+
+        assert_eq!(0, target.slc_cache_blocks());
+        assert_eq!(1, target.metric_storage().get_metric("erase").len());
+        for lpn in 0..10 {
+            let expected = if lpn == 3 {
+                page_bits(30)
+            } else {
+                page_bits(lpn)
+            };
+            assert_eq!(expected, target.read_page(lpn));
+        }
+    }
+
+    #[test]
+    fn idle_should_stop_clock_at_its_end() {
+        let mut target = setup_target();
+        target.write_page(0, page_bits(0));
+
+        target.idle(u32::MAX);
+        target.idle(u32::MAX);
+
+        assert_eq!(u32::MAX, target.time());
+    }
+
+    #[test]
+    fn write_page_should_report_throughput_cliff_when_cache_exhausted() {
+        let mut target = setup_target_with_capacity(8, CapacityConfig::new(7, 0, 0));
+        // one of the blocks takes the checkpoints
+        target.set_slc_cache_percent(30);
+        let cached_pages = 2 * PAGES_PER_BLOCK;
+
+        for lpn in 0..cached_pages + 8 {
+            target.write_page(lpn, page_bits(lpn));
+        }
+
+        let metrics = target.metric_storage();
+        let cached = metrics.get_metric("slc_cache_write");
+        let direct = metrics.get_metric("direct_write");
+        assert_eq!(cached_pages, cached.len());
+        assert_eq!(8, direct.len());
+        assert_eq!(1, metrics.get_metric("slc_cache_exhausted").len());
+
+        let cached_time = cached[cached.len() - 1].0 - cached[cached.len() - 5].0;
+        let direct_time = direct[direct.len() - 1].0 - direct[direct.len() - 5].0;
+        assert!(direct_time > cached_time * 2);
+    }
+
+    #[test]
+    fn failed_program_should_be_rewritten_and_block_downgraded_on_erase() {
+        let mut target = MemoryControllerImpl::new(
+            Box::new(ByteEncoderImpl::new()),
+            Box::new(MetricStorageImpl::new()),
+            Box::new(MemoryImpl::new(
+                Box::new(WornFluctuate),
+                4,
+                CellType::Quadro,
+            )),
+            CapacityConfig::default(),
+        );
+        target.set_slc_cache_percent(0);
+        let capacity = target.usable_capacity();
+        target.erase_block(0);
+
+        target.write_page(5, page_bits(5));
+
+        assert_eq!(page_bits(5), target.read_page(5));
+        target.erase_block(0);
+        assert_eq!(Some(CellType::Triple), target.block_density(0));
+        assert_eq!(capacity - PAGES_PER_BLOCK, target.usable_capacity());
+        let curve = target.metric_storage().get_metric("usable_capacity");
+        assert_eq!(2, curve.len());
+        assert_eq!((capacity - PAGES_PER_BLOCK) as u32, curve[1].1);
+    }
+
+    #[test]
+    fn factory_bad_block_should_not_count_in_usable_capacity() {
+        let mut memory = MemoryImpl::new(Box::new(ZeroFluctuate), 8, CellType::Quadro);
+        memory.mark_bad(3);
+
+        let target = MemoryControllerImpl::new(
+            Box::new(ByteEncoderImpl::new()),
+            Box::new(MetricStorageImpl::new()),
+            Box::new(memory),
+            CapacityConfig::default(),
+        );
+
+        assert_eq!(None, target.block_density(3));
+        assert_eq!(7 * PAGES_PER_BLOCK * 4, target.usable_capacity());
+        let curve = target.metric_storage().get_metric("usable_capacity");
+        assert_eq!((7 * PAGES_PER_BLOCK * 4) as u32, curve[0].1);
+    }
+
+    #[test]
+    fn deallocate_should_return_pattern_for_every_range() {
+        let mut target = setup_target();
+        target.set_slc_cache_percent(50);
+        let pattern: Vec<bool> = (0..CELLS_PER_PAGE).map(|i| i % 2 == 0).collect();
+        target.set_deallocated_pattern(pattern.clone());
+        for lpn in 0..10 {
+            target.write_page(lpn, page_bits(lpn));
+        }
+
+        target.deallocate(&[1..3, 7..9]);
+
+        for lpn in 0..10 {
+            let expected = if (1..3).contains(&lpn) || (7..9).contains(&lpn) {
+                pattern.clone()
+            } else {
+                page_bits(lpn)
+            };
+            assert_eq!(expected, target.read_page(lpn));
+        }
+        let deallocated = target.metric_storage().get_metric("deallocate");
+        assert_eq!(1, deallocated.len());
+        assert_eq!(4, deallocated[0].1);
+    }
+
+    #[test]
+    fn deallocate_should_reject_command_with_range_beyond_capacity() {
+        let mut target = setup_target();
+        for lpn in 0..4 {
+            target.write_page(lpn, page_bits(lpn));
+        }
+        let beyond = target.user_capacity()..target.user_capacity() + 1;
+
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            target.deallocate(&[0..2, beyond.clone()])
+        }));
+
+        assert!(res.is_err());
+        assert_eq!(page_bits(0), target.read_page(0));
+        assert_eq!(page_bits(1), target.read_page(1));
+    }
+
+    #[test]
+    fn deallocated_pages_should_not_be_folded() {
+        let mut target = setup_target();
+        target.set_slc_cache_percent(50);
+        for lpn in 0..8 {
+            target.write_page(lpn, page_bits(lpn));
+        }
+
+        target.deallocate(&[0..2, 2..4]);
+        target.idle(100_000);
+
+        assert_eq!(vec![(0, 4)], fold_points(&target));
+        for lpn in 4..8 {
+            assert_eq!(page_bits(lpn), target.read_page(lpn));
+        }
+    }
+
+    #[test]
+    fn gc_should_keep_data_when_overwriting_beyond_raw_capacity() {
+        let mut target = setup_target();
+        target.set_slc_cache_percent(0);
+        let raw_pages = 4 * PAGES_PER_BLOCK * 4;
+        let mut last_seed = vec![0; 300];
+
+        for i in 0..raw_pages * 2 {
+            target.write_page(i % 300, page_bits(i));
+            last_seed[i % 300] = i;
+        }
+
+        for (lpn, seed) in last_seed.into_iter().enumerate() {
+            assert_eq!(page_bits(seed), target.read_page(lpn));
+        }
+        assert!(!target
+            .metric_storage()
+            .get_metric("gc_relocated")
+            .is_empty());
+    }
+
+    #[test]
+    fn deallocate_should_lower_write_amplification() {
+        let mut trimmed = setup_target_with_blocks(8);
+        let mut untrimmed = setup_target_with_blocks(8);
+
+        for target in [&mut trimmed, &mut untrimmed] {
+            target.set_slc_cache_percent(0);
+            for lpn in 0..1200 {
+                target.write_page(lpn, page_bits(lpn));
+            }
+        }
+        trimmed.deallocate(&[0..300, 300..600]);
+        for target in [&mut trimmed, &mut untrimmed] {
+            let mut seed: usize = 17;
+            for i in 0..6000 {
+                seed = (seed * 1_103_515_245 + 12_345) % (1 << 31);
+                target.write_page(600 + (seed >> 8) % 600, page_bits(i));
+            }
+        }
+
+        assert!(trimmed.write_amplification() < untrimmed.write_amplification());
+        assert_eq!(page_bits(1), untrimmed.read_page(1));
+        assert_eq!(vec![false; CELLS_PER_PAGE], trimmed.read_page(1));
+    }
+
+    #[test]
+    fn user_capacity_should_exclude_reservations() {
+        let target = setup_target_with_capacity(8, CapacityConfig::new(25, 0, 2));
+
+        assert_eq!(8 * PAGES_PER_BLOCK * 4, target.raw_capacity());
+        assert_eq!(6 * PAGES_PER_BLOCK * 3, target.user_capacity());
+        assert_eq!(&[6, 7], target.metadata_blocks());
+    }
+
+    #[test]
+    fn bad_block_reserve_should_stay_unused_until_block_retired() {
+        let mut target = MemoryControllerImpl::new(
+            Box::new(ByteEncoderImpl::new()),
+            Box::new(MetricStorageImpl::new()),
+            Box::new(MemoryImpl::new(
+                Box::new(WornFluctuate),
+                8,
+                CellType::Quadro,
+            )),
+            CapacityConfig::new(7, 25, 0),
+        );
+        target.set_slc_cache_percent(50);
+        assert_eq!(vec![6, 7], target.reserve_blocks());
+        target.erase_block(0);
+
+        // the cache block erased before fails verify and the page goes to the next free block
+        target.write_page(5, page_bits(5));
+        for lpn in 0..PAGES_PER_BLOCK {
+            target.write_page(lpn, page_bits(lpn));
+        }
+        let pages = target.memory_state().get_memory_state(6..8);
+        assert!(pages.values().all(|state| *state == CellState::Empty));
+
+        target.erase_block(0);
+
+        assert_eq!(None, target.block_density(0));
+        assert_eq!(vec![6], target.reserve_blocks());
+    }
+
+    #[test]
+    #[should_panic(expected = "Logical page is out of capacity")]
+    fn write_page_should_panic_beyond_user_capacity() {
+        let mut target = setup_target_with_capacity(4, CapacityConfig::new(50, 0, 1));
+        let lpn = target.user_capacity();
+
+        target.write_page(lpn, page_bits(0));
+    }
+
+    #[test]
+    fn more_over_provisioning_should_lower_write_amplification() {
+        let mut amplification = Vec::new();
+        for over_provisioning_percent in [40, 60] {
+            let capacity = CapacityConfig::new(over_provisioning_percent, 0, 1);
+            let mut target = setup_target_with_capacity(8, capacity);
+            target.set_slc_cache_percent(0);
+            let user_pages = target.user_capacity();
+            let mut seed: usize = 5;
+            for i in 0..user_pages * 3 {
+                seed = (seed * 1_103_515_245 + 12_345) % (1 << 31);
+                target.write_page((seed >> 8) % user_pages, page_bits(i));
+            }
+            assert!(!target.metric_storage().get_metric("gc_latency").is_empty());
+            amplification.push(target.write_amplification());
+        }
+
+        assert!(amplification[1] < amplification[0]);
+    }
+
+    #[test]
+    fn write_stream_page_should_route_streams_to_separate_blocks() {
+        let mut target = setup_target_with_blocks(8);
+        target.set_slc_cache_percent(0);
+        target.set_write_frontiers(3);
+
+        target.write_stream_page(0, page_bits(0), 0);
+        target.write_stream_page(1, page_bits(1), 1);
+        target.write_stream_page(2, page_bits(2), 5);
+
+        let blocks: Vec<Option<usize>> = (0..3).map(|f| target.frontier_block(f)).collect();
+        assert!(blocks.iter().all(|b| b.is_some()));
+        assert_ne!(blocks[0], blocks[2]);
+        assert_eq!(page_bits(2), target.read_page(2));
+    }
+
+    #[test]
+    fn write_page_should_put_updated_pages_to_hot_frontier() {
+        let mut target = setup_target_with_blocks(8);
+        target.set_slc_cache_percent(0);
+
+        target.write_page(0, page_bits(0));
+        target.write_page(1, page_bits(1));
+        target.write_page(1, page_bits(2));
+
+        assert!(target.frontier_block(0).is_some());
+        assert!(target.frontier_block(1).is_some());
+        assert_ne!(target.frontier_block(0), target.frontier_block(1));
+    }
+
+    #[test]
+    fn hot_cold_separation_should_lower_valid_pages_of_gc_victims() {
+        let mut average_valid = Vec::new();
+        for frontiers in [1, 2] {
+            let mut target = setup_target_with_capacity(8, CapacityConfig::new(40, 0, 0));
+            target.set_slc_cache_percent(0);
+            target.set_write_frontiers(frontiers);
+            let user_pages = target.user_capacity();
+            let mut seed: usize = 3;
+            for i in 0..user_pages * 3 {
+                seed = (seed * 1_103_515_245 + 12_345) % (1 << 31);
+                let lpn = if i % 4 == 0 {
+                    (seed >> 8) % user_pages
+                } else {
+                    (seed >> 8) % 64
+                };
+                target.write_page(lpn, page_bits(i));
+            }
+            let victims = target.metric_storage().get_metric("gc_victim_valid_pages");
+            let total: u32 = victims.iter().map(|(_, valid)| valid).sum();
+            average_valid.push(total as f64 / victims.len() as f64);
+        }
+
+        assert!(average_valid[1] < average_valid[0]);
+    }
+
+    #[test]
+    fn memory_state_should_track_programmed_and_valid_pages() {
+        let mut target = setup_target_with_blocks(8);
+        target.set_slc_cache_percent(0);
+        target.set_write_frontiers(1);
+
+        for lpn in 0..6 {
+            target.write_page(lpn, page_bits(lpn));
+        }
+        target.write_page(0, page_bits(10));
+        target.deallocate(&[1..2, 2..3]);
+
+        let block = target.frontier_block(0).unwrap();
+        let state = target.memory_state();
+        assert_eq!(4, state.valid_pages(block));
+        assert!(!state.free_blocks().contains(&block));
+        let pages = state.get_memory_state(block..block + 1);
+        assert_eq!(
+            Some(&CellState::Set(CellType::Quadro)),
+            pages.get(&Address(block, 1))
+        );
+        assert_eq!(Some(&CellState::Empty), pages.get(&Address(block, 2)));
+    }
+
+    #[test]
+    fn folded_cache_block_should_wait_for_erase_until_idle() {
+        let mut target = setup_target_with_capacity(8, CapacityConfig::new(7, 0, 0));
+        target.set_slc_cache_percent(25);
+        target.set_write_frontiers(1);
+        for lpn in 0..8 {
+            target.write_page(lpn, page_bits(lpn));
+        }
+
+        while target.slc_cache_blocks() > 0 {
+            target.idle(1);
+        }
+
+        assert_eq!(1, target.pending_erases());
+        assert_eq!(vec![0], target.memory_state().blocks_pending_reset());
+        assert!(target.metric_storage().get_metric("erase").is_empty());
+
+        target.idle(100_000);
+
+        assert_eq!(0, target.pending_erases());
+        assert!(target.memory_state().free_blocks().contains(&0));
+        assert_eq!(1, target.metric_storage().get_metric("erase").len());
+    }
+
+    #[test]
+    fn take_free_block_should_skip_block_retired_on_reclaim() {
+        let mut target = setup_target_with_blocks(8);
+        for block in [0, 1] {
+            target.open_block_for(block, CellType::Single);
+            target.erase_queue.push(block);
+        }
+        target.block_health.record(0, 100, 100);
+        target.free_blocks.clear();
+
+        assert_eq!(1, target.take_free_block());
+        assert_eq!(None, target.block_density(0));
+    }
+
+    #[test]
+    fn erase_suspend_should_shorten_read_on_erasing_die() {
+        let mut latencies = Vec::new();
+        for suspend in [true, false] {
+            let mut target = setup_target_with_capacity(8, CapacityConfig::new(7, 0, 0));
+            target.set_slc_cache_percent(25);
+            target.set_write_frontiers(1);
+            target.set_erase_suspend(suspend);
+            for lpn in 0..8 {
+                target.write_page(lpn, page_bits(lpn));
+            }
+            // folded pages land on block 1 which shares the die with erased cache block 0
+            while target.pending_erases() > 0 || target.slc_cache_blocks() > 0 {
+                target.idle(1);
+            }
+
+            let start = target.time();
+            assert_eq!(page_bits(0), target.read_page(0));
+            latencies.push(target.time() - start);
+            assert_eq!(
+                suspend,
+                !target
+                    .metric_storage()
+                    .get_metric("erase_suspend")
+                    .is_empty()
+            );
+        }
+
+        assert!(latencies[0] < latencies[1]);
+    }
+
+    #[test]
+    fn recover_should_keep_every_acknowledged_write_after_power_cut() {
+        for cut in [0, 3, 50, 200, 700, 1500, 2500] {
+            let mut target = setup_target_with_blocks(8);
+            target.set_slc_cache_percent(25);
+            let mut acknowledged = HashMap::new();
+            target.cut_power_after(cut);
+            let mut seed: usize = 7;
+            for i in 0..3000 {
+                seed = (seed * 1_103_515_245 + 12_345) % (1 << 31);
+                let lpn = seed % 400;
+                target.write_page(lpn, page_bits(i));
+                if target.power_lost() {
+                    break;
+                }
+                acknowledged.insert(lpn, page_bits(i));
+            }
+
+            let mut target = recover_target(target.power_off());
+
+            assert_eq!(Vec::<usize>::new(), target.lost_writes(&acknowledged));
+            for lpn in 0..50 {
+                target.write_page(lpn, page_bits(lpn + 1));
+                acknowledged.insert(lpn, page_bits(lpn + 1));
+            }
+            assert_eq!(Vec::<usize>::new(), target.lost_writes(&acknowledged));
+        }
+    }
+
+    #[test]
+    fn checkpoint_should_be_programmed_into_metadata_block() {
+        let mut target = setup_target_with_capacity(8, CapacityConfig::new(7, 0, 1));
+        for lpn in 0..20 {
+            target.write_page(lpn, page_bits(lpn));
+        }
+        let pages = target.memory_state().get_memory_state(7..8);
+        assert_eq!(
+            Some(&CellState::Set(CellType::Single)),
+            pages.get(&Address(7, 0))
+        );
+
+        target.deallocate(&[0..3, 3..5]);
+        assert_eq!(1, target.metric_storage().get_metric("checkpoint").len());
+        let start = target.time();
+        target.flush();
+
+        assert!(target.time() - start >= operation_time(CellType::Single, OperationType::Write));
+        assert_eq!(2, target.metric_storage().get_metric("checkpoint").len());
+    }
+
+    #[test]
+    fn metadata_blocks_should_rotate_when_full() {
+        let mut target = setup_target_with_capacity(8, CapacityConfig::new(7, 0, 2));
+        for lpn in 0..20 {
+            target.write_page(lpn, page_bits(lpn));
+        }
+
+        for _ in 0..PAGES_PER_BLOCK {
+            target.flush();
+        }
+        assert_eq!(&[7, 6], target.metadata_blocks());
+        assert!(target.metric_storage().get_metric("erase").is_empty());
+
+        for _ in 0..PAGES_PER_BLOCK {
+            target.flush();
+        }
+
+        // block 7 only held older checkpoints and is erased to take the next ones
+        assert_eq!(&[6, 7], target.metadata_blocks());
+        assert_eq!(1, target.metric_storage().get_metric("erase").len());
+        let mut target = MemoryControllerImpl::recover(
+            Box::new(ByteEncoderImpl::new()),
+            Box::new(MetricStorageImpl::new()),
+            target.power_off(),
+            CapacityConfig::new(7, 0, 2),
+        );
+        assert_eq!(&[6, 7], target.metadata_blocks());
+        for lpn in 0..20 {
+            assert_eq!(page_bits(lpn), target.read_page(lpn));
+        }
+    }
+
+    #[test]
+    fn checkpoint_should_span_blocks_when_larger_than_one() {
+        let mut target = setup_target_with_blocks(16);
+        for lpn in 0..2800 {
+            target.write_page(lpn, page_bits(lpn));
+        }
+        let ranges: Vec<Range<usize>> = (0..700).map(|i| 4 * i..4 * i + 2).collect();
+        target.deallocate(&ranges);
+        for i in 0..700 {
+            target.write_page(4 * i, page_bits(i));
+        }
+
+        target.flush();
+
+        // every trimmed pair is half written again, the runs do not fit one block of pieces
+        assert_eq!(2, target.metadata_blocks().len());
+        let mut target = recover_target(target.power_off());
+        for i in 0..700 {
+            assert_eq!(page_bits(i), target.read_page(4 * i));
+            assert_eq!(vec![false; CELLS_PER_PAGE], target.read_page(4 * i + 1));
+            assert_eq!(page_bits(4 * i + 2), target.read_page(4 * i + 2));
+        }
+    }
+
+    #[test]
+    fn recover_should_not_bring_back_deallocated_pages() {
+        let mut target = setup_target_with_blocks(8);
+        for lpn in 0..20 {
+            target.write_page(lpn, page_bits(lpn));
+        }
+        target.deallocate(&[5..8, 8..10]);
+        target.flush();
+        target.cut_power_after(0);
+        target.write_page(30, page_bits(30));
+
+        let mut target = recover_target(target.power_off());
+
+        assert_eq!(vec![false; CELLS_PER_PAGE], target.read_page(7));
+        assert_eq!(page_bits(4), target.read_page(4));
+        assert_eq!(vec![false; CELLS_PER_PAGE], target.read_page(30));
+    }
+
+    #[test]
+    fn checkpoint_should_not_run_out_of_blocks_under_scattered_trims() {
+        let mut target = setup_target_with_blocks(256);
+        let mut rng = StdRng::seed_from_u64(1);
+        let capacity = target.user_capacity();
+        let mut written = vec![None; capacity];
+        for write in 0..capacity * 3 / 2 {
+            let lpn = rng.gen_range(0..capacity);
+            target.write_page(lpn, page_bits(write));
+            written[lpn] = Some(write);
+            if write % 7 == 6 {
+                let lpn = rng.gen_range(0..capacity);
+                target.deallocate(std::slice::from_ref(&(lpn..lpn + 1)));
+                written[lpn] = None;
+            }
+        }
+        target.flush();
+
+        let mut target = recover_target(target.power_off());
+
+        assert!(target.checkpoint_span > 1);
+        for lpn in (0..capacity).step_by(97) {
+            let expected = written[lpn].map_or(vec![false; CELLS_PER_PAGE], page_bits);
+            assert_eq!(expected, target.read_page(lpn));
+        }
+    }
+
+    #[test]
+    fn recover_should_queue_partially_erased_block_for_erase() {
+        let mut target = setup_target_with_capacity(8, CapacityConfig::new(7, 0, 0));
+        target.set_slc_cache_percent(25);
+        target.set_write_frontiers(1);
+        for lpn in 0..8 {
+            target.write_page(lpn, page_bits(lpn));
+        }
+        while target.slc_cache_blocks() > 0 {
+            target.idle(1);
+        }
+        // the next operation is the erase of the folded cache block
+        target.cut_power_after(0);
+        target.idle(100_000);
+
+        let target = recover_target(target.power_off());
+
+        assert_eq!(1, target.pending_erases());
+        assert_eq!(vec![0], target.memory_state().blocks_pending_reset());
+    }
+
+    #[test]
+    fn recover_should_resume_device_from_saved_image() {
+        let mut target = setup_target_with_blocks(8);
+        for lpn in 0..40 {
+            target.write_page(lpn, page_bits(lpn));
+        }
+        let mut image = Vec::new();
+        target.power_off().save(&mut image).unwrap();
+
+        let memory = MemoryImpl::from_image(Box::new(ZeroFluctuate), &mut &image[..]).unwrap();
+        let mut target = recover_target(Box::new(memory));
+
+        for lpn in 0..40 {
+            assert_eq!(page_bits(lpn), target.read_page(lpn));
+        }
+    }
+
+    #[test]
+    fn write_page_should_work_on_sparse_memory() {
+        let mut target = MemoryControllerImpl::new(
+            Box::new(ByteEncoderImpl::new()),
+            Box::new(MetricStorageImpl::new()),
+            Box::new(SparseMemoryImpl::new(
+                Box::new(ZeroFluctuate),
+                8,
+                CellType::Quadro,
+            )),
+            CapacityConfig::default(),
+        );
+        target.set_slc_cache_percent(25);
+        for i in 0..1500 {
+            target.write_page(i % 300, page_bits(i));
+        }
+
+        for lpn in 0..300 {
+            assert_eq!(page_bits(1200 + lpn), target.read_page(lpn));
+        }
+    }
+
+    #[test]
+    fn large_sparse_device_should_write_deallocate_and_recover() {
+        let blocks = 1 << 20;
+        let mut target = MemoryControllerImpl::new(
+            Box::new(ByteEncoderImpl::new()),
+            Box::new(MetricStorageImpl::new()),
+            Box::new(SparseMemoryImpl::new(
+                Box::new(ZeroFluctuate),
+                blocks,
+                CellType::Quadro,
+            )),
+            CapacityConfig::default(),
+        );
+        let capacity = target.user_capacity();
+        let lpns: Vec<usize> = (0..2000).map(|i| i * (capacity / 2000)).collect();
+        for (i, lpn) in lpns.iter().enumerate() {
+            target.write_page(*lpn, page_bits(i));
+        }
+        target.deallocate(&[0..capacity / 4, capacity / 4..capacity / 2]);
+        target.flush();
+
+        let mut target = recover_target(target.power_off());
+
+        assert_eq!(blocks * PAGES_PER_BLOCK * 4, target.raw_capacity());
+        for (i, lpn) in lpns.iter().enumerate() {
+            let expected = if *lpn < capacity / 2 {
+                vec![false; CELLS_PER_PAGE]
+            } else {
+                page_bits(i)
+            };
+            assert_eq!(expected, target.read_page(*lpn));
+        }
+    }
+
+    #[test]
+    fn cache_operations_should_speed_up_sequential_writes_and_reads() {
+        let mut elapsed = Vec::new();
+        for cached in [false, true] {
+            let mut target = setup_target();
+            target.set_cache_operations(cached);
+
+            let start = target.time();
+            for lpn in 0..200 {
+                target.write_page(lpn, page_bits(lpn));
+            }
+            // cached programs still run in the arrays until the flush drains them
+            target.flush();
+            let written = target.time();
+            for lpn in 0..200 {
+                assert_eq!(page_bits(lpn), target.read_page(lpn));
+            }
+            elapsed.push((written - start, target.time() - written));
+
+            let metrics = target.metric_storage();
+            assert_eq!(cached, !metrics.get_metric("cache_program").is_empty());
+            assert_eq!(cached, !metrics.get_metric("cache_read").is_empty());
+        }
+
+        assert!(elapsed[1].0 < elapsed[0].0);
+        assert!(elapsed[1].1 < elapsed[0].1);
+    }
+
+    #[test]
+    fn life_used_should_follow_most_erased_block() {
+        let mut target = setup_target();
+        assert_eq!(0.0, target.life_used());
+
+        for block in [1, 1, 2] {
+            target.write_bits(page_bits(block), Address(block, 0), CellType::Single);
+            target.erase_block(block);
+        }
+
+        assert_eq!(
+            2.0 / CellType::Single.endurance() as f64,
+            target.life_used()
+        );
+    }
+
+    #[test]
+    fn flush_should_wait_for_cached_programs() {
+        let mut target = setup_target();
+        target.set_cache_operations(true);
+        for lpn in 0..4 {
+            target.write_page(lpn, page_bits(lpn));
+        }
+        let acknowledged = target.time();
+
+        target.flush();
+
+        assert!(target.time() > acknowledged);
+        assert_eq!(1, target.metric_storage().get_metric("flush").len());
+    }
+
+    #[test]
+    fn gc_copyback_should_keep_data_and_shorten_gc() {
+        let mut gc_time = Vec::new();
+        for mode in [CopybackMode::Disabled, CopybackMode::Unchecked] {
+            let mut target = setup_slc_target_for_copyback(Box::new(ZeroFluctuate), mode);
+
+            overwrite_half_of_device(&mut target);
+
+            for lpn in 0..3600 {
+                let seed = if lpn % 2 == 1 { lpn + 1 } else { lpn };
+                assert_eq!(page_bits(seed), target.read_page(lpn));
+            }
+            let copied: u32 = metric_sum(&target, "copyback");
+            assert_eq!(mode == CopybackMode::Unchecked, copied > 0);
+            gc_time.push(
+                metric_sum(&target, "gc_latency") as f64
+                    / metric_sum(&target, "gc_relocated") as f64,
+            );
+        }
+
+        assert!(gc_time[1] < gc_time[0]);
+    }
+
+    #[test]
+    fn checked_copyback_should_stop_propagating_errors() {
+        let mut unchecked =
+            setup_slc_target_for_copyback(Box::new(DriftFluctuate), CopybackMode::Unchecked);
+        let mut checked =
+            setup_slc_target_for_copyback(Box::new(DriftFluctuate), CopybackMode::Checked);
+
+        overwrite_half_of_device(&mut unchecked);
+        overwrite_half_of_device(&mut checked);
+
+        let corrupted = (0..3600)
+            .filter(|lpn| unchecked.read_page(*lpn) != checked.read_page(*lpn))
+            .count();
+        assert!(corrupted > 0);
+        assert!(!checked
+            .metric_storage()
+            .get_metric("copyback_error")
+            .is_empty());
+        for lpn in 0..3600 {
+            let seed = if lpn % 2 == 1 { lpn + 1 } else { lpn };
+            assert_eq!(page_bits(seed), checked.read_page(lpn));
+        }
+    }
+
+    fn setup_slc_target_for_copyback(
+        fluctuator: Box<dyn FluctuareT>,
+        mode: CopybackMode,
+    ) -> MemoryControllerImpl<CELLS_PER_PAGE> {
+        let mut target = MemoryControllerImpl::new(
+            Box::new(ByteEncoderImpl::new()),
+            Box::new(MetricStorageImpl::new()),
+            Box::new(MemoryImpl::new(fluctuator, 32, CellType::Single)),
+            CapacityConfig::default(),
+        );
+        target.set_slc_cache_percent(0);
+        target.set_write_frontiers(1);
+        target.set_copyback(mode);
+        target
+    }
+
+    // trims every second page so all written blocks are equally good GC victims, then writes
+    // the trimmed pages again
+    fn overwrite_half_of_device(target: &mut MemoryControllerImpl<CELLS_PER_PAGE>) {
+        for lpn in 0..3600 {
+            target.write_page(lpn, page_bits(lpn));
+        }
+        let odd: Vec<Range<usize>> = (1..3600).step_by(2).map(|lpn| lpn..lpn + 1).collect();
+        target.deallocate(&odd);
+        for lpn in (1..3600).step_by(2) {
+            target.write_page(lpn, page_bits(lpn + 1));
+        }
+    }
+
+    fn metric_sum(target: &MemoryControllerImpl<CELLS_PER_PAGE>, name: &str) -> u32 {
+        target
+            .metric_storage()
+            .get_metric(name)
+            .into_iter()
+            .map(|(_, value)| value)
+            .sum()
+    }
+
+    #[test]
+    #[should_panic(expected = "No checkpoint to recover from")]
+    fn recover_should_panic_without_checkpoint() {
+        recover_target(Box::new(MemoryImpl::new(
+            Box::new(ZeroFluctuate),
+            8,
+            CellType::Quadro,
+        )));
+    }
+
+    fn recover_target(memory: Box<dyn Memory>) -> MemoryControllerImpl<CELLS_PER_PAGE> {
+        MemoryControllerImpl::recover(
+            Box::new(ByteEncoderImpl::new()),
+            Box::new(MetricStorageImpl::new()),
+            memory,
+            CapacityConfig::default(),
+        )
+    }
+
+    fn fold_points(target: &MemoryControllerImpl<CELLS_PER_PAGE>) -> Vec<(u32, u32)> {
+        target
+            .metric_storage()
+            .get_metric("fold")
+            .into_iter()
+            .map(|(_, folded)| (0, folded))
+            .collect()
+    }
+
+    fn page_bits(seed: usize) -> Vec<bool> {
+        (0..CELLS_PER_PAGE)
+            .map(|i| (i + seed).is_multiple_of(3))
+            .collect()
+    }
+
+    fn setup_target() -> MemoryControllerImpl<CELLS_PER_PAGE> {
+        setup_target_with_blocks(4)
+    }
+
+    fn setup_target_with_blocks(blocks: usize) -> MemoryControllerImpl<CELLS_PER_PAGE> {
+        setup_target_with_capacity(blocks, CapacityConfig::default())
+    }
+
+    fn setup_target_with_capacity(
+        blocks: usize,
+        capacity: CapacityConfig,
+    ) -> MemoryControllerImpl<CELLS_PER_PAGE> {
+        MemoryControllerImpl::new(
+            Box::new(ByteEncoderImpl::new()),
+            Box::new(MetricStorageImpl::new()),
+            Box::new(MemoryImpl::new(
+                Box::new(ZeroFluctuate),
+                blocks,
+                CellType::Quadro,
+            )),
+            capacity,
+        )
+    }
+
+    // stays within the read margin of one program, but not of a copy of a copy
+    struct DriftFluctuate;
+    impl FluctuareT for DriftFluctuate {
+        fn fluctuate(&self, _: u32, value: u8) -> u8 {
+            value.saturating_add(40)
+        }
+    }
+
+    // pushes cells of pages erased at least once over the lower page threshold
+    struct WornFluctuate;
+    impl FluctuareT for WornFluctuate {
+        fn fluctuate(&self, write_count: u32, value: u8) -> u8 {
+            if write_count > 0 {
+                value.saturating_add(130)
+            } else {
+                value
+            }
+        }
+    }
+}
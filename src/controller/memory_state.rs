@@ -1,8 +1,281 @@
+use crate::config::PAGES_PER_BLOCK;
+use crate::controller::mapping::PageLocation;
 use crate::controller::CellState;
+use crate::controller::CellType;
 use crate::physic_level::memory::Address;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::ops::Range;
-trait MemoryState {
+
+pub trait MemoryState {
     fn get_memory_state(&self, block_range: Range<usize>) -> HashMap<Address, CellState>;
     fn set_memory_state(&mut self, block: usize, page_range: Range<usize>, state: CellState);
+
+    fn set_valid(&mut self, location: PageLocation, valid: bool);
+    fn is_valid(&self, location: PageLocation) -> bool;
+    fn valid_pages(&self, block: usize) -> usize;
+    // blocks with every page erased
+    fn free_blocks(&self) -> Vec<usize>;
+    // blocks with a programmed, pending or valid page, in ascending order
+    fn used_blocks(&self) -> Vec<usize>;
+    fn blocks_pending_reset(&self) -> Vec<usize>;
+}
+
+// a wordline holds at most as many logical pages as the densest cell type
+const SLOTS_PER_PAGE: usize = 5;
+const WORDS_PER_BLOCK: usize = (PAGES_PER_BLOCK * SLOTS_PER_PAGE).div_ceil(64);
+
+const EMPTY: u8 = 0;
+const RESET_PENDING: u8 = 6;
+
+// one byte of state per wordline, one bit per logical page slot and per block counters
+// so that GC and wear leveling queries do not scan pages; erased blocks without valid pages
+// are not kept
+pub struct MemoryStateImpl {
+    block_count: usize,
+    blocks: BTreeMap<usize, BlockState>,
+}
+
+struct BlockState {
+    page_states: [u8; PAGES_PER_BLOCK],
+    valid_bits: [u64; WORDS_PER_BLOCK],
+    valid_count: u32,
+    empty_count: u32,
+    pending_count: u32,
+}
+
+impl BlockState {
+    fn new() -> BlockState {
+        BlockState {
+            page_states: [EMPTY; PAGES_PER_BLOCK],
+            valid_bits: [0; WORDS_PER_BLOCK],
+            valid_count: 0,
+            empty_count: PAGES_PER_BLOCK as u32,
+            pending_count: 0,
+        }
+    }
+
+    fn is_erased(&self) -> bool {
+        self.empty_count == PAGES_PER_BLOCK as u32
+    }
+}
+
+impl MemoryStateImpl {
+    pub fn new(block_count: usize) -> MemoryStateImpl {
+        MemoryStateImpl {
+            block_count,
+            blocks: BTreeMap::new(),
+        }
+    }
+
+    fn block_mut(&mut self, block: usize) -> &mut BlockState {
+        if block >= self.block_count {
+            panic!("block is out of memory")
+        }
+        self.blocks.entry(block).or_insert_with(BlockState::new)
+    }
+
+    fn forget_if_unused(&mut self, block: usize) {
+        if self
+            .blocks
+            .get(&block)
+            .is_some_and(|state| state.is_erased() && state.valid_count == 0)
+        {
+            self.blocks.remove(&block);
+        }
+    }
+
+    fn encode(state: CellState) -> u8 {
+        match state {
+            CellState::Empty => EMPTY,
+            CellState::Set(cell_type) => cell_type.multiplier(),
+            CellState::ResetPending => RESET_PENDING,
+        }
+    }
+
+    fn decode(code: u8) -> CellState {
+        match code {
+            EMPTY => CellState::Empty,
+            1 => CellState::Set(CellType::Single),
+            2 => CellState::Set(CellType::Double),
+            3 => CellState::Set(CellType::Triple),
+            4 => CellState::Set(CellType::Quadro),
+            5 => CellState::Set(CellType::Penta),
+            _ => CellState::ResetPending,
+        }
+    }
+
+    fn bit_position(location: PageLocation) -> (usize, u64) {
+        let Address(_, page) = location.address;
+        if location.slot >= SLOTS_PER_PAGE || page >= PAGES_PER_BLOCK {
+            panic!("location is out of block")
+        }
+        let bit = page * SLOTS_PER_PAGE + location.slot;
+        (bit / 64, 1 << (bit % 64))
+    }
+}
+
+impl MemoryState for MemoryStateImpl {
+    fn get_memory_state(&self, block_range: Range<usize>) -> HashMap<Address, CellState> {
+        let mut res = HashMap::new();
+        for block in block_range {
+            let state = self.blocks.get(&block);
+            for page in 0..PAGES_PER_BLOCK {
+                let code = state.map_or(EMPTY, |state| state.page_states[page]);
+                res.insert(Address(block, page), MemoryStateImpl::decode(code));
+            }
+        }
+        res
+    }
+
+    fn set_memory_state(&mut self, block: usize, page_range: Range<usize>, state: CellState) {
+        let code = MemoryStateImpl::encode(state);
+        let block_state = self.block_mut(block);
+        for page in page_range {
+            let previous = std::mem::replace(&mut block_state.page_states[page], code);
+            match previous {
+                EMPTY => block_state.empty_count -= 1,
+                RESET_PENDING => block_state.pending_count -= 1,
+                _ => {}
+            }
+            match code {
+                EMPTY => block_state.empty_count += 1,
+                RESET_PENDING => block_state.pending_count += 1,
+                _ => {}
+            }
+        }
+        self.forget_if_unused(block);
+    }
+
+    fn set_valid(&mut self, location: PageLocation, valid: bool) {
+        let Address(block, _) = location.address;
+        let (word, mask) = MemoryStateImpl::bit_position(location);
+        let block_state = self.block_mut(block);
+        let was_valid = block_state.valid_bits[word] & mask != 0;
+        if valid && !was_valid {
+            block_state.valid_bits[word] |= mask;
+            block_state.valid_count += 1;
+        } else if !valid && was_valid {
+            block_state.valid_bits[word] &= !mask;
+            block_state.valid_count -= 1;
+        }
+        self.forget_if_unused(block);
+    }
+
+    fn is_valid(&self, location: PageLocation) -> bool {
+        let (word, mask) = MemoryStateImpl::bit_position(location);
+        self.blocks
+            .get(&location.address.0)
+            .is_some_and(|state| state.valid_bits[word] & mask != 0)
+    }
+
+    fn valid_pages(&self, block: usize) -> usize {
+        self.blocks
+            .get(&block)
+            .map_or(0, |state| state.valid_count as usize)
+    }
+
+    fn free_blocks(&self) -> Vec<usize> {
+        (0..self.block_count)
+            .filter(|block| self.blocks.get(block).is_none_or(BlockState::is_erased))
+            .collect()
+    }
+
+    fn used_blocks(&self) -> Vec<usize> {
+        self.blocks.keys().copied().collect()
+    }
+
+    fn blocks_pending_reset(&self) -> Vec<usize> {
+        self.blocks
+            .iter()
+            .filter(|(_, state)| state.pending_count > 0)
+            .map(|(block, _)| *block)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn set_memory_state_should_be_returned_for_block_range() {
+        let mut target = MemoryStateImpl::new(4);
+
+        target.set_memory_state(1, 0..2, CellState::Set(CellType::Triple));
+
+        let res = target.get_memory_state(1..3);
+        assert_eq!(2 * PAGES_PER_BLOCK, res.len());
+        assert_eq!(
+            Some(&CellState::Set(CellType::Triple)),
+            res.get(&Address(1, 1))
+        );
+        assert_eq!(Some(&CellState::Empty), res.get(&Address(1, 2)));
+        assert_eq!(Some(&CellState::Empty), res.get(&Address(2, 0)));
+    }
+
+    #[test]
+    fn free_blocks_should_list_fully_erased_blocks() {
+        let mut target = MemoryStateImpl::new(3);
+
+        target.set_memory_state(0, 5..6, CellState::Set(CellType::Single));
+        assert_eq!(vec![1, 2], target.free_blocks());
+
+        target.set_memory_state(0, 0..PAGES_PER_BLOCK, CellState::Empty);
+        assert_eq!(vec![0, 1, 2], target.free_blocks());
+    }
+
+    #[test]
+    fn blocks_pending_reset_should_follow_state_changes() {
+        let mut target = MemoryStateImpl::new(3);
+
+        target.set_memory_state(2, 0..PAGES_PER_BLOCK, CellState::ResetPending);
+        assert_eq!(vec![2], target.blocks_pending_reset());
+        assert!(!target.free_blocks().contains(&2));
+
+        target.set_memory_state(2, 0..PAGES_PER_BLOCK, CellState::Empty);
+        assert!(target.blocks_pending_reset().is_empty());
+    }
+
+    #[test]
+    fn used_blocks_should_forget_erased_block_without_valid_pages() {
+        let mut target = MemoryStateImpl::new(1 << 30);
+        let location = PageLocation {
+            address: Address(7, 0),
+            slot: 0,
+        };
+
+        target.set_memory_state(7, 0..1, CellState::Set(CellType::Single));
+        target.set_valid(location, true);
+        target.set_memory_state(1 << 20, 0..PAGES_PER_BLOCK, CellState::ResetPending);
+        assert_eq!(vec![7, 1 << 20], target.used_blocks());
+
+        target.set_valid(location, false);
+        target.set_memory_state(7, 0..PAGES_PER_BLOCK, CellState::Empty);
+        assert_eq!(vec![1 << 20], target.used_blocks());
+    }
+
+    #[test]
+    fn set_valid_should_count_every_slot_once() {
+        let mut target = MemoryStateImpl::new(2);
+        let first = PageLocation {
+            address: Address(1, PAGES_PER_BLOCK - 1),
+            slot: 4,
+        };
+        let second = PageLocation {
+            address: Address(1, 0),
+            slot: 0,
+        };
+
+        target.set_valid(first, true);
+        target.set_valid(first, true);
+        target.set_valid(second, true);
+        assert_eq!(2, target.valid_pages(1));
+        assert!(target.is_valid(first));
+
+        target.set_valid(first, false);
+        assert_eq!(1, target.valid_pages(1));
+        assert!(!target.is_valid(first));
+        assert_eq!(0, target.valid_pages(0));
+    }
 }
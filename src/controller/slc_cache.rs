@@ -0,0 +1,141 @@
+use crate::controller::frontier::Frontier;
+use crate::controller::CellType;
+use crate::physic_level::memory::Address;
+use std::collections::VecDeque;
+
+// pSLC region absorbing host writes, folded later into dense blocks in write order
+pub struct SlcCache {
+    percent_of_free: usize,
+    blocks: VecDeque<usize>,
+    open: Option<Frontier>,
+    entries: VecDeque<(usize, Address)>,
+    exhausted: bool,
+}
+
+impl SlcCache {
+    pub fn new(percent_of_free: usize) -> SlcCache {
+        SlcCache {
+            percent_of_free,
+            blocks: VecDeque::new(),
+            open: None,
+            entries: VecDeque::new(),
+            exhausted: false,
+        }
+    }
+
+    pub fn set_percent_of_free(&mut self, percent_of_free: usize) {
+        self.percent_of_free = percent_of_free;
+    }
+
+    // cache shrinks together with free space, blocks already cached count as reclaimable
+    pub fn budget(&self, free_blocks: usize) -> usize {
+        (free_blocks + self.blocks.len()) * self.percent_of_free / 100
+    }
+
+    pub fn can_grow(&self, free_blocks: usize) -> bool {
+        free_blocks > 0 && self.blocks.len() < self.budget(free_blocks)
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn contains(&self, block: usize) -> bool {
+        self.blocks.contains(&block)
+    }
+
+    pub fn open_frontier(&mut self) -> Option<&mut Frontier> {
+        self.open.as_mut().filter(|frontier| !frontier.is_full())
+    }
+
+    pub fn add_block(&mut self, block: usize) {
+        self.blocks.push_back(block);
+        self.open = Some(Frontier::new(block, CellType::Single));
+    }
+
+    pub fn record(&mut self, lpn: usize, address: Address) {
+        self.entries.push_back((lpn, address));
+    }
+
+    pub fn oldest_block(&self) -> Option<usize> {
+        self.blocks.front().copied()
+    }
+
+    // stops appending to the block so it can be folded and erased
+    pub fn close(&mut self, block: usize) {
+        if self.open.as_ref().map(|f| f.block) == Some(block) {
+            self.open = None;
+        }
+    }
+
+    pub fn next_entry_of(&mut self, block: usize) -> Option<(usize, Address)> {
+        match self.entries.front() {
+            Some((_, Address(entry_block, _))) if *entry_block == block => self.entries.pop_front(),
+            _ => None,
+        }
+    }
+
+    pub fn has_entries_of(&self, block: usize) -> bool {
+        matches!(self.entries.front(), Some((_, Address(entry_block, _))) if *entry_block == block)
+    }
+
+    pub fn release_oldest(&mut self) -> Option<usize> {
+        let block = self.blocks.pop_front()?;
+        self.close(block);
+        Some(block)
+    }
+
+    // true only on the write that found the cache exhausted first
+    pub fn mark_exhausted(&mut self, exhausted: bool) -> bool {
+        let changed = exhausted && !self.exhausted;
+        self.exhausted = exhausted;
+        changed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn budget_should_follow_free_space() {
+        let mut target = SlcCache::new(25);
+
+        assert_eq!(4, target.budget(16));
+        assert!(target.can_grow(16));
+
+        target.add_block(0);
+        target.add_block(1);
+
+        assert_eq!(2, target.budget(6));
+        assert!(!target.can_grow(6));
+    }
+
+    #[test]
+    fn next_entry_of_should_return_entries_of_oldest_block_only() {
+        let mut target = SlcCache::new(50);
+        target.add_block(4);
+        target.record(10, Address(4, 0));
+        target.record(11, Address(4, 1));
+        target.add_block(6);
+        target.record(12, Address(6, 0));
+
+        assert_eq!(Some(4), target.oldest_block());
+        assert_eq!(Some((10, Address(4, 0))), target.next_entry_of(4));
+        assert_eq!(Some((11, Address(4, 1))), target.next_entry_of(4));
+        assert_eq!(None, target.next_entry_of(4));
+
+        assert_eq!(Some(4), target.release_oldest());
+        assert_eq!(Some(6), target.oldest_block());
+    }
+
+    #[test]
+    fn mark_exhausted_should_report_transition_once() {
+        let mut target = SlcCache::new(50);
+
+        assert!(target.mark_exhausted(true));
+        assert!(!target.mark_exhausted(true));
+        assert!(!target.mark_exhausted(false));
+        assert!(target.mark_exhausted(true));
+    }
+}
@@ -0,0 +1,141 @@
+use crate::config::SPARE_BYTES_PER_PAGE;
+use std::convert::TryInto;
+
+// spare area starts with the logical page number + 1 of every wordline slot, zero means empty slot,
+// followed by the write sequence number of every slot used to order versions on recovery
+const LPN_BYTES: usize = 4;
+const SEQUENCE_BYTES: usize = 4;
+const MAX_SLOTS: usize = 5;
+const SEQUENCE_OFFSET: usize = MAX_SLOTS * LPN_BYTES;
+
+// metadata pages carry a piece of a checkpoint instead of logical pages, data pages never use
+// the last spare byte: generation, piece index, piece count and payload length come first
+const METADATA_MARKER: u8 = 0xC5;
+const METADATA_HEADER_BYTES: usize = 9;
+pub const METADATA_PAYLOAD_BYTES: usize = SPARE_BYTES_PER_PAGE - METADATA_HEADER_BYTES - 1;
+
+#[derive(Debug, PartialEq)]
+pub struct MetadataPiece {
+    pub generation: u32,
+    pub index: usize,
+    pub count: usize,
+    pub payload: Vec<u8>,
+}
+
+pub fn metadata_spare(piece: &MetadataPiece) -> [u8; SPARE_BYTES_PER_PAGE] {
+    if piece.payload.len() > METADATA_PAYLOAD_BYTES {
+        panic!("payload is out of spare area")
+    }
+    let mut spare = [0; SPARE_BYTES_PER_PAGE];
+    spare[..4].copy_from_slice(&piece.generation.to_le_bytes());
+    spare[4..6].copy_from_slice(&(piece.index as u16).to_le_bytes());
+    spare[6..8].copy_from_slice(&(piece.count as u16).to_le_bytes());
+    spare[8] = piece.payload.len() as u8;
+    spare[METADATA_HEADER_BYTES..METADATA_HEADER_BYTES + piece.payload.len()]
+        .copy_from_slice(&piece.payload);
+    spare[SPARE_BYTES_PER_PAGE - 1] = METADATA_MARKER;
+    spare
+}
+
+// None for data and erased pages
+pub fn metadata_piece(spare: &[u8; SPARE_BYTES_PER_PAGE]) -> Option<MetadataPiece> {
+    if spare[SPARE_BYTES_PER_PAGE - 1] != METADATA_MARKER {
+        return None;
+    }
+    let length = (spare[8] as usize).min(METADATA_PAYLOAD_BYTES);
+    Some(MetadataPiece {
+        generation: u32::from_le_bytes(spare[..4].try_into().unwrap()),
+        index: u16::from_le_bytes(spare[4..6].try_into().unwrap()) as usize,
+        count: u16::from_le_bytes(spare[6..8].try_into().unwrap()) as usize,
+        payload: spare[METADATA_HEADER_BYTES..METADATA_HEADER_BYTES + length].to_vec(),
+    })
+}
+
+pub fn slot_spare(slot: usize, lpn: usize, sequence: u32) -> [u8; SPARE_BYTES_PER_PAGE] {
+    if slot >= MAX_SLOTS {
+        panic!("slot is out of spare area")
+    }
+    let mut spare = [0; SPARE_BYTES_PER_PAGE];
+    let offset = slot * LPN_BYTES;
+    spare[offset..offset + LPN_BYTES].copy_from_slice(&(lpn as u32 + 1).to_le_bytes());
+    let offset = SEQUENCE_OFFSET + slot * SEQUENCE_BYTES;
+    spare[offset..offset + SEQUENCE_BYTES].copy_from_slice(&sequence.to_le_bytes());
+    spare
+}
+
+pub fn slot_lpns(spare: &[u8; SPARE_BYTES_PER_PAGE]) -> Vec<Option<usize>> {
+    spare[..SEQUENCE_OFFSET]
+        .chunks(LPN_BYTES)
+        .map(|bytes| {
+            let value = u32::from_le_bytes(bytes.try_into().unwrap());
+            value.checked_sub(1).map(|lpn| lpn as usize)
+        })
+        .collect()
+}
+
+// logical page and write sequence number of every used slot
+pub fn slot_entries(spare: &[u8; SPARE_BYTES_PER_PAGE]) -> Vec<Option<(usize, u32)>> {
+    let sequences = spare[SEQUENCE_OFFSET..SEQUENCE_OFFSET + MAX_SLOTS * SEQUENCE_BYTES]
+        .chunks(SEQUENCE_BYTES)
+        .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()));
+    slot_lpns(spare)
+        .into_iter()
+        .zip(sequences)
+        .map(|(lpn, sequence)| lpn.map(|lpn| (lpn, sequence)))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn slot_lpns_should_read_back_every_slot() {
+        let mut spare = slot_spare(0, 0, 1);
+        let third = slot_spare(2, 70_000, 2);
+        for (byte, other) in spare.iter_mut().zip(third.iter()) {
+            *byte |= *other;
+        }
+
+        let res = slot_lpns(&spare);
+
+        assert_eq!(vec![Some(0), None, Some(70_000), None, None], res);
+    }
+
+    #[test]
+    fn slot_lpns_should_be_empty_for_erased_spare() {
+        let res = slot_lpns(&[0; SPARE_BYTES_PER_PAGE]);
+
+        assert!(res.iter().all(|lpn| lpn.is_none()));
+    }
+
+    #[test]
+    fn metadata_piece_should_read_back_metadata_spare_only() {
+        let piece = MetadataPiece {
+            generation: 70_000,
+            index: 2,
+            count: 3,
+            payload: vec![1, 2, 3],
+        };
+        let spare = metadata_spare(&piece);
+
+        assert_eq!(Some(piece), metadata_piece(&spare));
+        assert_eq!(None, metadata_piece(&slot_spare(4, 9, 300_000)));
+    }
+
+    #[test]
+    fn slot_entries_should_pair_lpn_with_sequence() {
+        let mut spare = slot_spare(1, 5, 0);
+        let last = slot_spare(4, 9, 300_000);
+        for (byte, other) in spare.iter_mut().zip(last.iter()) {
+            *byte |= *other;
+        }
+
+        let res = slot_entries(&spare);
+
+        assert_eq!(
+            vec![None, Some((5, 0)), None, None, Some((9, 300_000))],
+            res
+        );
+    }
+}
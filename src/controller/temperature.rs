@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+
+// counts updates of every logical page, the counts are halved every aging period
+// so that data which stopped changing cools down again; pages never updated are not kept
+pub struct TemperatureClassifier {
+    updates: HashMap<usize, u8>,
+    writes_since_aging: usize,
+    aging_period: usize,
+}
+
+impl TemperatureClassifier {
+    pub fn new(logical_pages: usize) -> TemperatureClassifier {
+        TemperatureClassifier {
+            updates: HashMap::new(),
+            writes_since_aging: 0,
+            aging_period: logical_pages,
+        }
+    }
+
+    // 0 is the coldest class, pages updated 2^k times land in class k + 1
+    pub fn classify(&self, lpn: usize, classes: usize) -> usize {
+        let updates = self.updates.get(&lpn).copied().unwrap_or(0);
+        let bits_used = (u8::BITS - updates.leading_zeros()) as usize;
+        bits_used.min(classes - 1)
+    }
+
+    pub fn record_write(&mut self, lpn: usize, first_write: bool) {
+        if !first_write {
+            let updates = self.updates.entry(lpn).or_insert(0);
+            *updates = updates.saturating_add(1);
+        }
+        self.writes_since_aging += 1;
+        if self.writes_since_aging >= self.aging_period {
+            self.writes_since_aging = 0;
+            self.updates.retain(|_, updates| {
+                *updates /= 2;
+                *updates > 0
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn classify_should_separate_updated_pages() {
+        let mut target = TemperatureClassifier::new(100);
+
+        target.record_write(1, true);
+        target.record_write(2, true);
+        for _ in 0..4 {
+            target.record_write(2, false);
+        }
+
+        assert_eq!(0, target.classify(1, 2));
+        assert_eq!(1, target.classify(2, 2));
+        assert_eq!(3, target.classify(2, 4));
+        assert_eq!(0, target.classify(2, 1));
+    }
+
+    #[test]
+    fn record_write_should_cool_down_pages_every_aging_period() {
+        let mut target = TemperatureClassifier::new(4);
+
+        target.record_write(0, false);
+        assert_eq!(1, target.classify(0, 2));
+
+        for _ in 0..3 {
+            target.record_write(1, true);
+        }
+
+        assert_eq!(0, target.classify(0, 2));
+    }
+}
@@ -0,0 +1,436 @@
+use crate::config::CELLS_PER_PAGE;
+use crate::config::PAGES_PER_BLOCK;
+use crate::controller::byte_encoder::ByteEncoder;
+use crate::controller::operation_time;
+use crate::controller::page_read_time;
+use crate::controller::CellType;
+use crate::controller::OperationType;
+use crate::controller::ProgramScheme;
+use crate::metric::metric_storage::MetricStorage;
+use crate::metric::MetricType;
+use crate::physic_level::memory::Address;
+use crate::physic_level::memory::Memory;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ZoneState {
+    Empty,
+    Open,
+    Closed,
+    Full,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ZoneDescriptor {
+    // first logical page of the zone
+    pub start: usize,
+    pub capacity: usize,
+    pub write_pointer: usize,
+    pub state: ZoneState,
+}
+
+// host managed placement: logical pages of a zone are written strictly in order and only a
+// whole zone is erased, so no mapping table and no garbage collection are needed
+pub trait ZonedController {
+    fn zone_count(&self) -> usize;
+    // logical pages of every zone, the zone n starts at n * zone_size
+    fn zone_size(&self) -> usize;
+    // pages have to start exactly at the write pointer of their zone
+    fn write(&mut self, lpn: usize, pages: &[Vec<bool>]);
+    // the controller places the pages at the write pointer and returns the first of them
+    fn append(&mut self, zone: usize, pages: &[Vec<bool>]) -> usize;
+    // pages past the write pointer read as zeros
+    fn read(&mut self, lpn: usize) -> Vec<bool>;
+    fn open_zone(&mut self, zone: usize);
+    fn close_zone(&mut self, zone: usize);
+    // moves the write pointer to the zone end, the rest of the zone is never programmed
+    fn finish_zone(&mut self, zone: usize);
+    fn reset_zone(&mut self, zone: usize);
+    fn report_zones(&self, first_zone: usize, count: usize) -> Vec<ZoneDescriptor>;
+}
+
+struct Zone {
+    blocks: Vec<usize>,
+    state: ZoneState,
+    write_pointer: usize,
+    // wordlines on the media, the pages of the next one wait in the buffer until it is whole
+    programmed: usize,
+    buffer: Vec<Vec<bool>>,
+}
+
+pub struct ZonedControllerImpl<const PS: usize> {
+    byte_encoder: Box<dyn ByteEncoder<PS>>,
+    metric_storage: Box<dyn MetricStorage>,
+    memory: Box<dyn Memory>,
+    time: u32,
+    zones: Vec<Zone>,
+    max_open_zones: usize,
+}
+
+impl ZonedControllerImpl<CELLS_PER_PAGE> {
+    // good blocks are grouped into zones in address order, leftover blocks stay unused
+    pub fn new(
+        byte_encoder: Box<dyn ByteEncoder<CELLS_PER_PAGE>>,
+        metric_storage: Box<dyn MetricStorage>,
+        memory: Box<dyn Memory>,
+        blocks_per_zone: usize,
+        max_open_zones: usize,
+    ) -> ZonedControllerImpl<CELLS_PER_PAGE> {
+        if blocks_per_zone == 0 || max_open_zones == 0 {
+            panic!("Zone needs at least one block and one open zone")
+        }
+        let good: Vec<usize> = (0..memory.block_count())
+            .filter(|b| !memory.is_bad(*b))
+            .collect();
+        let zones = good
+            .chunks_exact(blocks_per_zone)
+            .map(|blocks| Zone {
+                blocks: blocks.to_vec(),
+                state: ZoneState::Empty,
+                write_pointer: 0,
+                programmed: 0,
+                buffer: Vec::new(),
+            })
+            .collect();
+        ZonedControllerImpl {
+            byte_encoder,
+            metric_storage,
+            memory,
+            time: 0,
+            zones,
+            max_open_zones,
+        }
+    }
+
+    pub fn time(&self) -> u32 {
+        self.time
+    }
+
+    fn cell_type(&self) -> CellType {
+        self.memory.native_cell_type()
+    }
+
+    fn pages_per_wordline(&self) -> usize {
+        self.cell_type().multiplier() as usize
+    }
+
+    fn zone_mut(&mut self, zone: usize) -> &mut Zone {
+        self.zones
+            .get_mut(zone)
+            .unwrap_or_else(|| panic!("Zone is out of device"))
+    }
+
+    fn wordline_address(&self, zone: usize, wordline: usize) -> Address {
+        Address(
+            self.zones[zone].blocks[wordline / PAGES_PER_BLOCK],
+            wordline % PAGES_PER_BLOCK,
+        )
+    }
+
+    fn open_count(&self) -> usize {
+        self.zones
+            .iter()
+            .filter(|zone| zone.state == ZoneState::Open)
+            .count()
+    }
+
+    fn make_open(&mut self, zone: usize) {
+        match self.zone_mut(zone).state {
+            ZoneState::Open => {}
+            ZoneState::Full => panic!("Zone is full"),
+            ZoneState::Empty | ZoneState::Closed => {
+                if self.open_count() == self.max_open_zones {
+                    panic!("Too many open zones")
+                }
+                self.zone_mut(zone).state = ZoneState::Open;
+            }
+        }
+    }
+
+    fn push_pages(&mut self, zone: usize, pages: &[Vec<bool>]) {
+        self.make_open(zone);
+        if self.zones[zone].write_pointer + pages.len() > self.zone_size() {
+            panic!("Write crosses zone end")
+        }
+        for bits in pages {
+            if bits.len() != CELLS_PER_PAGE {
+                panic!("Logical page has to be one bit per cell")
+            }
+            let per_wordline = self.pages_per_wordline();
+            let state = self.zone_mut(zone);
+            state.buffer.push(bits.clone());
+            state.write_pointer += 1;
+            if state.buffer.len() == per_wordline {
+                self.program_buffer(zone);
+            }
+        }
+        self.metric_storage.put_metric(
+            "zone_write",
+            (pages.len() * CELLS_PER_PAGE) as u32,
+            self.time,
+            MetricType::Write,
+        );
+        if self.zones[zone].write_pointer == self.zone_size() {
+            self.zone_mut(zone).state = ZoneState::Full;
+        }
+    }
+
+    // a partial wordline is padded with zeros, its pages past the write pointer are never read
+    fn program_buffer(&mut self, zone: usize) {
+        let cell_type = self.cell_type();
+        let mut pages = std::mem::take(&mut self.zone_mut(zone).buffer);
+        if pages.is_empty() {
+            return;
+        }
+        pages.resize(self.pages_per_wordline(), vec![false; CELLS_PER_PAGE]);
+        let wordline = self.zones[zone].programmed;
+        let address = self.wordline_address(zone, wordline);
+        if self.memory.block_mode(address.0).is_none() {
+            self.memory.open_block(address.0, cell_type);
+        }
+        let cells =
+            self.byte_encoder
+                .encode_wordline_pass(&pages, cell_type, ProgramScheme::OneShot, 0);
+        self.memory.program(address, cells);
+        self.zone_mut(zone).programmed += 1;
+        self.time += operation_time(cell_type, OperationType::Write);
+    }
+}
+
+impl ZonedController for ZonedControllerImpl<CELLS_PER_PAGE> {
+    fn zone_count(&self) -> usize {
+        self.zones.len()
+    }
+
+    fn zone_size(&self) -> usize {
+        match self.zones.first() {
+            Some(zone) => zone.blocks.len() * PAGES_PER_BLOCK * self.pages_per_wordline(),
+            None => 0,
+        }
+    }
+
+    fn write(&mut self, lpn: usize, pages: &[Vec<bool>]) {
+        let zone = lpn / self.zone_size().max(1);
+        if zone >= self.zones.len() {
+            panic!("Logical page is out of capacity")
+        }
+        if lpn % self.zone_size() != self.zones[zone].write_pointer {
+            panic!("Write is not at the zone write pointer")
+        }
+        self.push_pages(zone, pages)
+    }
+
+    fn append(&mut self, zone: usize, pages: &[Vec<bool>]) -> usize {
+        let lpn = zone * self.zone_size() + self.zone_mut(zone).write_pointer;
+        self.push_pages(zone, pages);
+        lpn
+    }
+
+    fn read(&mut self, lpn: usize) -> Vec<bool> {
+        let zone = lpn / self.zone_size().max(1);
+        if zone >= self.zones.len() {
+            panic!("Logical page is out of capacity")
+        }
+        let offset = lpn % self.zone_size();
+        let per_wordline = self.pages_per_wordline();
+        let (wordline, slot) = (offset / per_wordline, offset % per_wordline);
+        let state = &self.zones[zone];
+        if offset >= state.write_pointer || wordline > state.programmed {
+            return vec![false; CELLS_PER_PAGE];
+        }
+        if wordline == state.programmed {
+            return state
+                .buffer
+                .get(slot)
+                .cloned()
+                .unwrap_or_else(|| vec![false; CELLS_PER_PAGE]);
+        }
+        let cell_type = self.cell_type();
+        let cells = *self.memory.read(self.wordline_address(zone, wordline));
+        self.time += page_read_time(cell_type, slot);
+        self.metric_storage.put_metric(
+            "zone_read",
+            CELLS_PER_PAGE as u32,
+            self.time,
+            MetricType::Read,
+        );
+        self.byte_encoder
+            .decode_wordline_page(cells, cell_type, slot)
+    }
+
+    fn open_zone(&mut self, zone: usize) {
+        self.make_open(zone)
+    }
+
+    // buffered pages stay in the controller, the zone resumes at its write pointer
+    fn close_zone(&mut self, zone: usize) {
+        let state = self.zone_mut(zone);
+        if state.state == ZoneState::Open {
+            state.state = if state.write_pointer == 0 {
+                ZoneState::Empty
+            } else {
+                ZoneState::Closed
+            };
+        }
+    }
+
+    fn finish_zone(&mut self, zone: usize) {
+        self.program_buffer(zone);
+        let zone_size = self.zone_size();
+        let state = self.zone_mut(zone);
+        state.write_pointer = zone_size;
+        state.state = ZoneState::Full;
+    }
+
+    fn reset_zone(&mut self, zone: usize) {
+        let cell_type = self.cell_type();
+        let blocks = self.zone_mut(zone).blocks.clone();
+        for block in blocks {
+            if self.memory.block_mode(block).is_some() {
+                self.memory.reset(block);
+                self.time += operation_time(cell_type, OperationType::Delete);
+            }
+        }
+        let state = self.zone_mut(zone);
+        state.state = ZoneState::Empty;
+        state.write_pointer = 0;
+        state.programmed = 0;
+        state.buffer.clear();
+        self.metric_storage
+            .put_metric("zone_reset", 1, self.time, MetricType::Delete);
+    }
+
+    fn report_zones(&self, first_zone: usize, count: usize) -> Vec<ZoneDescriptor> {
+        let zone_size = self.zone_size();
+        self.zones
+            .iter()
+            .enumerate()
+            .skip(first_zone)
+            .take(count)
+            .map(|(n, zone)| ZoneDescriptor {
+                start: n * zone_size,
+                capacity: zone_size,
+                write_pointer: n * zone_size + zone.write_pointer,
+                state: zone.state,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::controller::byte_encoder::ByteEncoderImpl;
+    use crate::metric::metric_storage::MetricStorageImpl;
+    use crate::physic_level::memory::MemoryImpl;
+    use crate::physic_level::memory_components::ZeroFluctuate;
+
+    #[test]
+    fn read_should_return_sequential_writes_from_media_and_buffer() {
+        let mut target = setup_target();
+        let pages: Vec<Vec<bool>> = (0..5).map(page_bits).collect();
+
+        target.write(0, &pages[..2]);
+        target.write(2, &pages[2..]);
+
+        // pages 3 and 4 wait in the buffer for the rest of their wordline
+        for (lpn, bits) in pages.iter().enumerate() {
+            assert_eq!(*bits, target.read(lpn));
+        }
+        assert_eq!(vec![false; CELLS_PER_PAGE], target.read(5));
+        assert_eq!(5, target.report_zones(0, 1)[0].write_pointer);
+    }
+
+    #[test]
+    #[should_panic(expected = "Write is not at the zone write pointer")]
+    fn write_should_panic_off_write_pointer() {
+        let mut target = setup_target();
+        target.write(0, &[page_bits(0)]);
+
+        target.write(2, &[page_bits(2)]);
+    }
+
+    #[test]
+    fn append_should_place_pages_at_write_pointer() {
+        let mut target = setup_target();
+        let zone_size = target.zone_size();
+        target.append(1, &[page_bits(0), page_bits(1)]);
+
+        let res = target.append(1, &[page_bits(7)]);
+
+        assert_eq!(zone_size + 2, res);
+        assert_eq!(page_bits(7), target.read(res));
+    }
+
+    #[test]
+    fn zone_states_should_follow_zone_lifecycle() {
+        let mut target = setup_target();
+        let zone_size = target.zone_size();
+
+        target.append(0, &[page_bits(0)]);
+        let opened = target.report_zones(0, 1)[0].state;
+        target.close_zone(0);
+        let closed = target.report_zones(0, 1)[0].state;
+        target.finish_zone(0);
+
+        let res = target.report_zones(0, 2);
+        assert_eq!((ZoneState::Open, ZoneState::Closed), (opened, closed));
+        assert_eq!(ZoneState::Full, res[0].state);
+        assert_eq!(zone_size, res[0].write_pointer);
+        assert_eq!(ZoneState::Empty, res[1].state);
+        assert_eq!(4, target.zone_count());
+        assert_eq!(page_bits(0), target.read(0));
+    }
+
+    #[test]
+    fn reset_zone_should_erase_blocks_and_rewind_write_pointer() {
+        let mut target = setup_target();
+        let zone_size = target.zone_size();
+        let pages: Vec<Vec<bool>> = (0..zone_size).map(page_bits).collect();
+        target.write(0, &pages);
+
+        target.reset_zone(0);
+        target.write(0, &[page_bits(9)]);
+
+        assert_eq!(page_bits(9), target.read(0));
+        assert_eq!(vec![false; CELLS_PER_PAGE], target.read(3));
+        assert_eq!(ZoneState::Open, target.report_zones(0, 1)[0].state);
+    }
+
+    #[test]
+    #[should_panic(expected = "Too many open zones")]
+    fn open_zone_should_panic_over_open_limit() {
+        let mut target = setup_target();
+        target.open_zone(0);
+        target.append(1, &[page_bits(0)]);
+
+        target.open_zone(2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Zone is full")]
+    fn append_should_panic_on_full_zone() {
+        let mut target = setup_target();
+        target.finish_zone(0);
+
+        target.append(0, &[page_bits(0)]);
+    }
+
+    fn page_bits(n: usize) -> Vec<bool> {
+        (0..CELLS_PER_PAGE).map(|i| (i + n).is_multiple_of(3)).collect()
+    }
+
+    // zones of two Triple blocks, two of them may be open at once
+    fn setup_target() -> ZonedControllerImpl<CELLS_PER_PAGE> {
+        ZonedControllerImpl::new(
+            Box::new(ByteEncoderImpl::new()),
+            Box::new(MetricStorageImpl::new()),
+            Box::new(MemoryImpl::new(
+                Box::new(ZeroFluctuate),
+                8,
+                CellType::Triple,
+            )),
+            2,
+            2,
+        )
+    }
+}
@@ -0,0 +1,6 @@
+pub mod block_device;
+pub mod device_file;
+pub mod namespace;
+pub mod nbd;
+pub mod nvme;
+pub mod open_channel;
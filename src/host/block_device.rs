@@ -0,0 +1,177 @@
+use crate::config::CELLS_PER_PAGE;
+use crate::controller::byte_encoder::bits_to_bytes;
+use crate::controller::byte_encoder::bytes_to_bits;
+use crate::controller::memory_controller::MemoryController;
+
+// one logical page of the FTL carries a bit per cell
+pub const PAGE_BYTES: usize = CELLS_PER_PAGE / 8;
+pub const SECTOR_SIZES: [usize; 2] = [512, 4096];
+
+// disk-like view of the device: whole sectors of bytes at logical block addresses
+pub trait BlockDevice {
+    fn sector_size(&self) -> usize;
+    fn sector_count(&self) -> u64;
+    fn capacity_bytes(&self) -> u64 {
+        self.sector_size() as u64 * self.sector_count()
+    }
+    // buffers span one or more whole sectors starting at the lba
+    fn read(&mut self, lba: u64, buffer: &mut [u8]);
+    fn write(&mut self, lba: u64, data: &[u8]);
+    fn flush(&mut self);
+    // sectors no longer in use, they read back as the deallocated pattern
+    fn discard(&mut self, lba: u64, sectors: u64);
+}
+
+// every sector is a run of consecutive logical pages of the controller
+pub struct BlockDeviceImpl {
+    controller: Box<dyn MemoryController>,
+    sector_size: usize,
+}
+
+impl BlockDeviceImpl {
+    pub fn new(controller: Box<dyn MemoryController>, sector_size: usize) -> BlockDeviceImpl {
+        if !SECTOR_SIZES.contains(&sector_size) {
+            panic!("Sector size must be 512 or 4096")
+        }
+        BlockDeviceImpl {
+            controller,
+            sector_size,
+        }
+    }
+
+    pub fn controller(&self) -> &dyn MemoryController {
+        &*self.controller
+    }
+
+    pub fn into_controller(self) -> Box<dyn MemoryController> {
+        self.controller
+    }
+
+    fn pages_per_sector(&self) -> usize {
+        self.sector_size / PAGE_BYTES
+    }
+
+    // first logical page of the request, panics when it does not fit the device
+    fn first_page(&self, lba: u64, bytes: usize) -> usize {
+        if !bytes.is_multiple_of(self.sector_size) {
+            panic!("Buffer is not a multiple of sector size")
+        }
+        let sectors = (bytes / self.sector_size) as u64;
+        if lba + sectors > self.sector_count() {
+            panic!("Request is out of device capacity")
+        }
+        lba as usize * self.pages_per_sector()
+    }
+}
+
+impl BlockDevice for BlockDeviceImpl {
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn sector_count(&self) -> u64 {
+        (self.controller.user_capacity() / self.pages_per_sector()) as u64
+    }
+
+    fn read(&mut self, lba: u64, buffer: &mut [u8]) {
+        let first = self.first_page(lba, buffer.len());
+        for (i, chunk) in buffer.chunks_mut(PAGE_BYTES).enumerate() {
+            chunk.copy_from_slice(&bits_to_bytes(&self.controller.read_page(first + i)));
+        }
+    }
+
+    fn write(&mut self, lba: u64, data: &[u8]) {
+        let first = self.first_page(lba, data.len());
+        for (i, chunk) in data.chunks(PAGE_BYTES).enumerate() {
+            self.controller.write_page(first + i, bytes_to_bits(chunk));
+        }
+    }
+
+    fn flush(&mut self) {
+        self.controller.flush()
+    }
+
+    fn discard(&mut self, lba: u64, sectors: u64) {
+        let bytes = sectors as usize * self.sector_size;
+        let first = self.first_page(lba, bytes);
+        let pages = first..first + bytes / PAGE_BYTES;
+        self.controller.deallocate(std::slice::from_ref(&pages));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::controller::memory_controller::test_controller;
+
+    #[test]
+    fn read_should_return_multi_sector_write() {
+        let mut target = setup_target(512);
+        let data: Vec<u8> = (0..3 * 512).map(|i| (i * 7) as u8).collect();
+
+        target.write(2, &data);
+        let mut res = vec![0; 3 * 512];
+        target.read(2, &mut res);
+
+        assert_eq!(data, res);
+    }
+
+    #[test]
+    fn write_should_leave_neighbour_sectors_alone() {
+        let mut target = setup_target(4096);
+        target.write(0, &[0xAA; 2 * 4096]);
+
+        target.write(1, &[0x55; 4096]);
+        let mut res = vec![0; 2 * 4096];
+        target.read(0, &mut res);
+
+        assert!(res[..4096].iter().all(|byte| *byte == 0xAA));
+        assert!(res[4096..].iter().all(|byte| *byte == 0x55));
+    }
+
+    #[test]
+    fn capacity_should_count_whole_sectors_of_user_pages() {
+        let target = setup_target(4096);
+
+        let user_bytes = (target.controller().user_capacity() * PAGE_BYTES) as u64;
+
+        assert!(target.capacity_bytes() <= user_bytes);
+        assert!(target.capacity_bytes() + 4096 > user_bytes);
+        assert_eq!(target.capacity_bytes(), 4096 * target.sector_count());
+    }
+
+    #[test]
+    fn discard_should_return_sectors_to_deallocated_pattern() {
+        let mut target = setup_target(512);
+        target.write(0, &[0xFF; 3 * 512]);
+
+        target.discard(1, 1);
+        let mut res = vec![0; 3 * 512];
+        target.read(0, &mut res);
+
+        assert!(res[..512].iter().all(|byte| *byte == 0xFF));
+        assert!(res[512..1024].iter().all(|byte| *byte == 0));
+        assert!(res[1024..].iter().all(|byte| *byte == 0xFF));
+    }
+
+    #[test]
+    #[should_panic(expected = "Request is out of device capacity")]
+    fn write_should_panic_past_last_sector() {
+        let mut target = setup_target(512);
+        let lba = target.sector_count();
+
+        target.write(lba, &[0; 512]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Buffer is not a multiple of sector size")]
+    fn read_should_panic_on_partial_sector() {
+        let mut target = setup_target(512);
+
+        target.read(0, &mut [0; 100]);
+    }
+
+    fn setup_target(sector_size: usize) -> BlockDeviceImpl {
+        BlockDeviceImpl::new(Box::new(test_controller()), sector_size)
+    }
+}
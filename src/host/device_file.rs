@@ -0,0 +1,227 @@
+use crate::host::block_device::BlockDevice;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+
+// file-like handle over the whole logical address space, partial sectors are read, patched
+// and written back through the block device
+pub struct DeviceFile {
+    device: Box<dyn BlockDevice>,
+    position: u64,
+}
+
+impl DeviceFile {
+    pub fn new(device: Box<dyn BlockDevice>) -> DeviceFile {
+        DeviceFile {
+            device,
+            position: 0,
+        }
+    }
+
+    pub fn into_device(self) -> Box<dyn BlockDevice> {
+        self.device
+    }
+
+    pub fn len(&self) -> u64 {
+        self.device.capacity_bytes()
+    }
+
+    // only sectors lying wholly inside the range are discarded, the position does not move
+    pub fn discard(&mut self, offset: u64, len: u64) {
+        let sector_size = self.device.sector_size() as u64;
+        let first = offset.div_ceil(sector_size);
+        let end = (offset + len).min(self.len()) / sector_size;
+        if end > first {
+            self.device.discard(first, end - first);
+        }
+    }
+
+    // sector under the position, the offset in it and how many bytes of it the access covers
+    fn sector_span(&self, bytes: usize) -> (u64, usize, usize) {
+        let sector_size = self.device.sector_size() as u64;
+        let lba = self.position / sector_size;
+        let offset = (self.position % sector_size) as usize;
+        let rest = self.len().saturating_sub(self.position).min(bytes as u64) as usize;
+        (lba, offset, rest.min(sector_size as usize - offset))
+    }
+
+    // whole sectors go straight to the device, a partial one is read first
+    fn write_sectors(&mut self, data: &[u8]) -> usize {
+        let sector_size = self.device.sector_size();
+        let (lba, offset, len) = self.sector_span(data.len());
+        if offset == 0 && len == sector_size {
+            let whole = (data.len() / sector_size)
+                .min(((self.len() - self.position) / sector_size as u64) as usize);
+            self.device.write(lba, &data[..whole * sector_size]);
+            return whole * sector_size;
+        }
+        let mut sector = vec![0; sector_size];
+        self.device.read(lba, &mut sector);
+        sector[offset..offset + len].copy_from_slice(&data[..len]);
+        self.device.write(lba, &sector);
+        len
+    }
+}
+
+impl Read for DeviceFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let sector_size = self.device.sector_size();
+        let (lba, offset, len) = self.sector_span(buf.len());
+        if len == 0 {
+            return Ok(0);
+        }
+        let mut sector = vec![0; sector_size];
+        self.device.read(lba, &mut sector);
+        buf[..len].copy_from_slice(&sector[offset..offset + len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for DeviceFile {
+    // a write at the end of the device writes nothing, write_all turns it into WriteZero
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.position >= self.len() {
+            return Ok(0);
+        }
+        let written = self.write_sectors(buf);
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.device.flush();
+        Ok(())
+    }
+}
+
+impl Seek for DeviceFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => {
+                self.position = offset;
+                return Ok(offset);
+            }
+            SeekFrom::End(delta) => (self.len(), delta),
+            SeekFrom::Current(delta) => (self.position, delta),
+        };
+        match base.checked_add_signed(delta) {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before start of device",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::controller::memory_controller::test_controller;
+    use crate::host::block_device::BlockDeviceImpl;
+
+    #[test]
+    fn read_should_return_unaligned_write_across_sectors() {
+        let mut target = setup_target();
+        let data: Vec<u8> = (0..1500).map(|i| (i % 251) as u8).collect();
+        target.seek(SeekFrom::Start(300)).unwrap();
+
+        target.write_all(&data).unwrap();
+        target.seek(SeekFrom::Start(300)).unwrap();
+        let mut res = vec![0; 1500];
+        target.read_exact(&mut res).unwrap();
+
+        assert_eq!(data, res);
+    }
+
+    #[test]
+    fn write_should_keep_rest_of_partial_sector() {
+        let mut target = setup_target();
+        target.write_all(&[0x11; 512]).unwrap();
+
+        target.seek(SeekFrom::Start(10)).unwrap();
+        target.write_all(b"boot").unwrap();
+        target.rewind().unwrap();
+        let mut res = vec![0; 512];
+        target.read_exact(&mut res).unwrap();
+
+        assert_eq!(b"boot", &res[10..14]);
+        assert!(res[..10].iter().chain(&res[14..]).all(|byte| *byte == 0x11));
+    }
+
+    #[test]
+    fn read_should_stop_at_end_of_device() {
+        let mut target = setup_target();
+        let len = target.len();
+        target.seek(SeekFrom::End(-3)).unwrap();
+
+        let mut res = Vec::new();
+        target.read_to_end(&mut res).unwrap();
+
+        assert_eq!(3, res.len());
+        assert_eq!(len, target.stream_position().unwrap());
+        target.seek(SeekFrom::End(10)).unwrap();
+        assert_eq!(0, target.read(&mut [0; 4]).unwrap());
+    }
+
+    #[test]
+    fn write_all_should_fail_past_end_of_device() {
+        let mut target = setup_target();
+        target.seek(SeekFrom::End(-2)).unwrap();
+
+        let res = target.write_all(&[1, 2, 3, 4]);
+
+        assert_eq!(io::ErrorKind::WriteZero, res.unwrap_err().kind());
+    }
+
+    #[test]
+    fn io_copy_should_populate_image_through_controller() {
+        let mut target = setup_target();
+        let image: Vec<u8> = (0..target.len()).map(|i| (i * 13 % 256) as u8).collect();
+
+        io::copy(&mut &image[..], &mut target).unwrap();
+        target.flush().unwrap();
+        target.rewind().unwrap();
+        let mut res = Vec::new();
+        target.read_to_end(&mut res).unwrap();
+
+        assert_eq!(image, res);
+    }
+
+    #[test]
+    fn discard_should_keep_partially_covered_sectors() {
+        let mut target = setup_target();
+        target.write_all(&[0x77; 3 * 512]).unwrap();
+
+        target.discard(100, 2 * 512);
+        target.rewind().unwrap();
+        let mut res = vec![0; 3 * 512];
+        target.read_exact(&mut res).unwrap();
+
+        assert!(res[..512].iter().all(|byte| *byte == 0x77));
+        assert!(res[512..1024].iter().all(|byte| *byte == 0));
+        assert!(res[1024..].iter().all(|byte| *byte == 0x77));
+    }
+
+    #[test]
+    fn seek_should_reject_position_before_start() {
+        let mut target = setup_target();
+
+        let res = target.seek(SeekFrom::Current(-1));
+
+        assert_eq!(io::ErrorKind::InvalidInput, res.unwrap_err().kind());
+    }
+
+    fn setup_target() -> DeviceFile {
+        DeviceFile::new(Box::new(BlockDeviceImpl::new(
+            Box::new(test_controller()),
+            512,
+        )))
+    }
+}
@@ -0,0 +1,392 @@
+use crate::config::CELLS_PER_PAGE;
+use crate::controller::byte_encoder::bits_to_bytes;
+use crate::controller::byte_encoder::bytes_to_bits;
+use crate::controller::memory_controller::MemoryController;
+use crate::controller::memory_controller::MemoryControllerImpl;
+use crate::controller::CellType;
+use crate::host::block_device::PAGE_BYTES;
+use crate::host::block_device::SECTOR_SIZES;
+use crate::metric::metric_storage::MetricStorage;
+use crate::metric::MetricType;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NamespaceInfo {
+    pub sectors: u64,
+    pub sector_size: usize,
+    // Single keeps writes in the SLC cache by folding it when full, the native type bypasses
+    // it, None leaves it to the FTL
+    pub cell_type: Option<CellType>,
+    pub attached: bool,
+}
+
+struct Namespace {
+    info: NamespaceInfo,
+    // the namespace owns a contiguous range of logical pages of the controller
+    first_page: usize,
+}
+
+impl Namespace {
+    fn pages(&self) -> usize {
+        self.info.sectors as usize * self.info.sector_size / PAGE_BYTES
+    }
+}
+
+// namespaces split the logical pages of one FTL, so the mapping of every namespace is its own
+// while free blocks, GC and the SLC cache are shared by all tenants
+pub struct NamespaceManager {
+    controller: MemoryControllerImpl<CELLS_PER_PAGE>,
+    metric_storage: Box<dyn MetricStorage>,
+    namespaces: BTreeMap<u32, Namespace>,
+}
+
+impl NamespaceManager {
+    pub fn new(
+        controller: MemoryControllerImpl<CELLS_PER_PAGE>,
+        metric_storage: Box<dyn MetricStorage>,
+    ) -> NamespaceManager {
+        NamespaceManager {
+            controller,
+            metric_storage,
+            namespaces: BTreeMap::new(),
+        }
+    }
+
+    pub fn controller(&self) -> &MemoryControllerImpl<CELLS_PER_PAGE> {
+        &self.controller
+    }
+
+    // series of a namespace are prefixed with its id, e.g. "ns1_write"
+    pub fn metric_storage(&self) -> &dyn MetricStorage {
+        &*self.metric_storage
+    }
+
+    // returns the lowest free namespace id, the namespace starts detached
+    pub fn create(&mut self, sectors: u64, sector_size: usize, cell_type: Option<CellType>) -> u32 {
+        if !SECTOR_SIZES.contains(&sector_size) {
+            panic!("Sector size must be 512 or 4096")
+        }
+        let native = self.controller.native_cell_type();
+        if cell_type.is_some_and(|t| t != CellType::Single && t != native) {
+            panic!("Cell type preference has to be Single or the native type")
+        }
+        let first_page = usize::try_from(sectors)
+            .ok()
+            .and_then(|sectors| sectors.checked_mul(sector_size))
+            .and_then(|bytes| self.free_extent(bytes / PAGE_BYTES))
+            .unwrap_or_else(|| panic!("Not enough unallocated capacity"));
+        let nsid = (1..).find(|id| !self.namespaces.contains_key(id)).unwrap();
+        self.namespaces.insert(
+            nsid,
+            Namespace {
+                info: NamespaceInfo {
+                    sectors,
+                    sector_size,
+                    cell_type,
+                    attached: false,
+                },
+                first_page,
+            },
+        );
+        nsid
+    }
+
+    // the pages of the namespace are deallocated, so GC reclaims them
+    pub fn delete(&mut self, nsid: u32) {
+        let namespace = self
+            .namespaces
+            .remove(&nsid)
+            .unwrap_or_else(|| panic!("Namespace does not exist"));
+        let pages = namespace.first_page..namespace.first_page + namespace.pages();
+        self.controller.deallocate(std::slice::from_ref(&pages));
+        self.metric_storage.put_metric(
+            &format!("ns{}_delete", nsid),
+            1,
+            self.controller.time(),
+            MetricType::Delete,
+        );
+    }
+
+    pub fn attach(&mut self, nsid: u32) {
+        self.namespace_mut(nsid).info.attached = true;
+    }
+
+    pub fn detach(&mut self, nsid: u32) {
+        self.namespace_mut(nsid).info.attached = false;
+    }
+
+    pub fn namespace(&self, nsid: u32) -> Option<NamespaceInfo> {
+        self.namespaces.get(&nsid).map(|namespace| namespace.info)
+    }
+
+    pub fn namespace_ids(&self) -> Vec<u32> {
+        self.namespaces.keys().copied().collect()
+    }
+
+    // logical pages of the controller not taken by any namespace
+    pub fn unallocated_pages(&self) -> usize {
+        let allocated: usize = self.namespaces.values().map(|n| n.pages()).sum();
+        self.controller.user_capacity() - allocated
+    }
+
+    pub fn read(&mut self, nsid: u32, lba: u64, buffer: &mut [u8]) {
+        let first = self.first_page(nsid, lba, buffer.len());
+        let start = self.controller.time();
+        for (i, chunk) in buffer.chunks_mut(PAGE_BYTES).enumerate() {
+            chunk.copy_from_slice(&bits_to_bytes(&self.controller.read_page(first + i)));
+        }
+        self.put_io_metrics(nsid, "read", buffer.len(), start, MetricType::Read);
+    }
+
+    pub fn write(&mut self, nsid: u32, lba: u64, data: &[u8]) {
+        let first = self.first_page(nsid, lba, data.len());
+        let cell_type = self.namespaces[&nsid].info.cell_type;
+        let native = cell_type == Some(self.controller.native_cell_type());
+        let start = self.controller.time();
+        for (i, chunk) in data.chunks(PAGE_BYTES).enumerate() {
+            let bits = bytes_to_bits(chunk);
+            if native {
+                self.controller.write_native_page(first + i, bits);
+            } else if cell_type == Some(CellType::Single) {
+                self.controller.write_cached_page(first + i, bits);
+            } else {
+                self.controller.write_page(first + i, bits);
+            }
+        }
+        self.put_io_metrics(nsid, "write", data.len(), start, MetricType::Write);
+    }
+
+    pub fn discard(&mut self, nsid: u32, lba: u64, sectors: u64) {
+        let bytes = usize::try_from(sectors)
+            .ok()
+            .and_then(|sectors| sectors.checked_mul(self.namespace_mut(nsid).info.sector_size))
+            .unwrap_or_else(|| panic!("Request is out of namespace capacity"));
+        let first = self.first_page(nsid, lba, bytes);
+        let pages = first..first + bytes / PAGE_BYTES;
+        self.controller.deallocate(std::slice::from_ref(&pages));
+        self.metric_storage.put_metric(
+            &format!("ns{}_discard", nsid),
+            (bytes * 8) as u32,
+            self.controller.time(),
+            MetricType::Delete,
+        );
+    }
+
+    pub fn flush(&mut self) {
+        self.controller.flush()
+    }
+
+    fn namespace_mut(&mut self, nsid: u32) -> &mut Namespace {
+        self.namespaces
+            .get_mut(&nsid)
+            .unwrap_or_else(|| panic!("Namespace does not exist"))
+    }
+
+    // first controller page of the request, panics when the namespace cannot take it
+    fn first_page(&mut self, nsid: u32, lba: u64, bytes: usize) -> usize {
+        let namespace = self.namespace_mut(nsid);
+        if !namespace.info.attached {
+            panic!("Namespace is not attached")
+        }
+        let sector_size = namespace.info.sector_size;
+        if !bytes.is_multiple_of(sector_size) {
+            panic!("Buffer is not a multiple of sector size")
+        }
+        if lba
+            .checked_add((bytes / sector_size) as u64)
+            .is_none_or(|end| end > namespace.info.sectors)
+        {
+            panic!("Request is out of namespace capacity")
+        }
+        namespace.first_page + lba as usize * sector_size / PAGE_BYTES
+    }
+
+    // first fit among the gaps between namespaces in logical page order
+    fn free_extent(&self, pages: usize) -> Option<usize> {
+        let mut taken: Vec<(usize, usize)> = self
+            .namespaces
+            .values()
+            .map(|n| (n.first_page, n.first_page + n.pages()))
+            .collect();
+        taken.sort();
+        let mut start = 0;
+        for (first, end) in taken {
+            if first - start >= pages {
+                return Some(start);
+            }
+            start = end;
+        }
+        (self.controller.user_capacity() - start >= pages).then_some(start)
+    }
+
+    // bits moved and time the controller spent on the request, GC caused by other tenants included
+    fn put_io_metrics(
+        &mut self,
+        nsid: u32,
+        operation: &str,
+        bytes: usize,
+        start: u32,
+        metric_type: MetricType,
+    ) {
+        let time = self.controller.time();
+        self.metric_storage.put_metric(
+            &format!("ns{}_{}", nsid, operation),
+            (bytes * 8) as u32,
+            time,
+            metric_type,
+        );
+        self.metric_storage.put_metric(
+            &format!("ns{}_{}_latency", nsid, operation),
+            time - start,
+            time,
+            metric_type,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::controller::memory_controller::test_controller;
+    use crate::metric::metric_storage::MetricStorageImpl;
+
+    #[test]
+    fn namespaces_should_keep_same_lba_apart() {
+        let mut target = setup_target();
+        let first = attached(&mut target, 4, 512, None);
+        let second = attached(&mut target, 1, 4096, None);
+
+        target.write(first, 0, &[0x11; 512]);
+        target.write(second, 0, &[0x22; 4096]);
+        let mut res_first = vec![0; 512];
+        let mut res_second = vec![0; 4096];
+        target.read(first, 0, &mut res_first);
+        target.read(second, 0, &mut res_second);
+
+        assert!(res_first.iter().all(|byte| *byte == 0x11));
+        assert!(res_second.iter().all(|byte| *byte == 0x22));
+        assert_eq!(vec![1, 2], target.namespace_ids());
+    }
+
+    #[test]
+    fn delete_should_release_capacity_and_data() {
+        let mut target = setup_target();
+        let sectors = (target.unallocated_pages() * PAGE_BYTES / 512) as u64;
+        let nsid = attached(&mut target, sectors, 512, None);
+        target.write(nsid, 0, &[0x33; 512]);
+
+        target.delete(nsid);
+        let nsid = attached(&mut target, 1, 512, None);
+        let mut res = vec![0xFF; 512];
+        target.read(nsid, 0, &mut res);
+
+        assert_eq!(1, nsid);
+        assert!(res.iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough unallocated capacity")]
+    fn create_should_panic_when_capacity_is_taken() {
+        let mut target = setup_target();
+        let sectors = (target.unallocated_pages() * PAGE_BYTES / 512) as u64;
+        target.create(sectors, 512, None);
+
+        target.create(1, 512, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough unallocated capacity")]
+    fn create_should_panic_when_size_overflows() {
+        let mut target = setup_target();
+
+        target.create(u64::MAX / 512 + 1, 4096, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Namespace is not attached")]
+    fn write_should_panic_on_detached_namespace() {
+        let mut target = setup_target();
+        let nsid = attached(&mut target, 4, 512, None);
+        target.detach(nsid);
+
+        target.write(nsid, 0, &[0; 512]);
+    }
+
+    #[test]
+    fn native_preference_should_bypass_slc_cache() {
+        let mut target = setup_target();
+        let cached = attached(&mut target, 4, 512, Some(CellType::Single));
+        let native = attached(&mut target, 4, 512, Some(CellType::Triple));
+
+        target.write(native, 0, &[0x44; 2 * 512]);
+        let before = metric_sum(target.controller().metric_storage(), "slc_cache_write");
+        target.write(cached, 0, &[0x55; 2 * 512]);
+        let after = metric_sum(target.controller().metric_storage(), "slc_cache_write");
+
+        assert_eq!(0, before);
+        assert!(after > 0);
+    }
+
+    #[test]
+    fn single_preference_should_keep_writes_in_slc_cache() {
+        let mut direct = Vec::new();
+        for cell_type in [None, Some(CellType::Single)] {
+            let mut target = setup_target();
+            let nsid = attached(&mut target, 8, 512, cell_type);
+
+            target.write(nsid, 0, &[0x66; 8 * 512]);
+
+            let mut res = vec![0; 8 * 512];
+            target.read(nsid, 0, &mut res);
+            assert!(res.iter().all(|byte| *byte == 0x66));
+            direct.push(metric_sum(
+                target.controller().metric_storage(),
+                "direct_write",
+            ));
+        }
+
+        // the cache is full long before the end, only the Single namespace folds to make room
+        assert!(direct[0] > 0);
+        assert_eq!(0, direct[1]);
+    }
+
+    #[test]
+    fn metrics_should_be_kept_per_namespace() {
+        let mut target = setup_target();
+        let first = attached(&mut target, 4, 512, None);
+        let second = attached(&mut target, 4, 512, None);
+
+        target.write(first, 0, &[0; 3 * 512]);
+        target.read(second, 1, &mut [0; 512]);
+
+        let metrics = target.metric_storage();
+        assert_eq!(3 * 512 * 8, metric_sum(metrics, "ns1_write"));
+        assert_eq!(0, metric_sum(metrics, "ns2_write"));
+        assert_eq!(512 * 8, metric_sum(metrics, "ns2_read"));
+        assert!(metric_sum(metrics, "ns1_write_latency") > 0);
+    }
+
+    fn attached(
+        target: &mut NamespaceManager,
+        sectors: u64,
+        sector_size: usize,
+        cell_type: Option<CellType>,
+    ) -> u32 {
+        let nsid = target.create(sectors, sector_size, cell_type);
+        target.attach(nsid);
+        nsid
+    }
+
+    fn metric_sum(metrics: &dyn MetricStorage, name: &str) -> u32 {
+        metrics
+            .get_metric(name)
+            .into_iter()
+            .map(|(_, value)| value)
+            .sum()
+    }
+
+    fn setup_target() -> NamespaceManager {
+        NamespaceManager::new(test_controller(), Box::new(MetricStorageImpl::new()))
+    }
+}
@@ -0,0 +1,611 @@
+use crate::host::block_device::BlockDevice;
+use crate::host::device_file::DeviceFile;
+use std::convert::TryInto;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+
+// fixed newstyle handshake of the network block device protocol, all fields are big endian
+const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943;
+const OPTION_MAGIC: u64 = 0x4948_4156_454f_5054;
+const OPTION_REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+const REQUEST_MAGIC: u32 = 0x2560_9513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+const FLAG_FIXED_NEWSTYLE: u16 = 1;
+const FLAG_NO_ZEROES: u16 = 2;
+const CLIENT_FLAG_FIXED_NEWSTYLE: u32 = 1;
+const CLIENT_FLAG_NO_ZEROES: u32 = 2;
+
+const OPT_EXPORT_NAME: u32 = 1;
+const OPT_ABORT: u32 = 2;
+const OPT_INFO: u32 = 6;
+const OPT_GO: u32 = 7;
+const REP_ACK: u32 = 1;
+const REP_INFO: u32 = 3;
+const REP_ERR_UNSUP: u32 = 0x8000_0001;
+const REP_ERR_UNKNOWN: u32 = 0x8000_0006;
+const REP_ERR_TOO_BIG: u32 = 0x8000_0009;
+const INFO_EXPORT: u16 = 0;
+
+const TRANSMISSION_FLAGS: u16 = FLAG_HAS_FLAGS | FLAG_SEND_FLUSH | FLAG_SEND_FUA | FLAG_SEND_TRIM;
+const FLAG_HAS_FLAGS: u16 = 1;
+const FLAG_SEND_FLUSH: u16 = 4;
+const FLAG_SEND_FUA: u16 = 8;
+const FLAG_SEND_TRIM: u16 = 0x20;
+const COMMAND_FLAG_FUA: u16 = 1;
+
+pub const CMD_READ: u16 = 0;
+pub const CMD_WRITE: u16 = 1;
+pub const CMD_DISC: u16 = 2;
+pub const CMD_FLUSH: u16 = 3;
+pub const CMD_TRIM: u16 = 4;
+
+const EINVAL: u32 = 22;
+const ENOSPC: u32 = 28;
+const EOVERFLOW: u32 = 75;
+
+// lengths come from the peer, larger ones are refused instead of allocated
+const MAX_OPTION_LENGTH: u32 = 4096;
+const MAX_REQUEST_LENGTH: u32 = 32 << 20;
+
+// serves the logical address space of one device to clients connecting one after another
+pub struct NbdServer {
+    file: DeviceFile,
+    export_name: String,
+}
+
+impl NbdServer {
+    pub fn new(device: Box<dyn BlockDevice>, export_name: &str) -> NbdServer {
+        NbdServer {
+            file: DeviceFile::new(device),
+            export_name: export_name.to_string(),
+        }
+    }
+
+    pub fn into_device(self) -> Box<dyn BlockDevice> {
+        self.file.into_device()
+    }
+
+    pub fn serve_tcp(&mut self, listener: &TcpListener) -> io::Result<()> {
+        self.serve_all(listener.incoming())
+    }
+
+    #[cfg(unix)]
+    pub fn serve_unix(&mut self, listener: &UnixListener) -> io::Result<()> {
+        self.serve_all(listener.incoming())
+    }
+
+    // only a failing listener ends the loop, a client breaking the protocol or its
+    // connection loses just that connection
+    fn serve_all<S: Read + Write>(
+        &mut self,
+        streams: impl IntoIterator<Item = io::Result<S>>,
+    ) -> io::Result<()> {
+        for stream in streams {
+            let _ = self.serve(&mut stream?);
+        }
+        Ok(())
+    }
+
+    // one connection from handshake to disconnect
+    pub fn serve<S: Read + Write>(&mut self, stream: &mut S) -> io::Result<()> {
+        if self.negotiate(stream)? {
+            self.transmit(stream)?;
+        }
+        Ok(())
+    }
+
+    // returns false when the client gave up before choosing the export
+    fn negotiate<S: Read + Write>(&mut self, stream: &mut S) -> io::Result<bool> {
+        write_u64(stream, NBD_MAGIC)?;
+        write_u64(stream, OPTION_MAGIC)?;
+        write_u16(stream, FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES)?;
+        let client_flags = read_u32(stream)?;
+        // an old style client could not follow the option haggling
+        if client_flags & CLIENT_FLAG_FIXED_NEWSTYLE == 0 {
+            return Ok(false);
+        }
+        loop {
+            match read_u64(stream) {
+                Ok(OPTION_MAGIC) => {}
+                Ok(_) => return Err(invalid("bad option magic")),
+                // client went away after a refused option
+                Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+                Err(error) => return Err(error),
+            }
+            let option = read_u32(stream)?;
+            let length = read_u32(stream)?;
+            if length > MAX_OPTION_LENGTH {
+                if option == OPT_EXPORT_NAME {
+                    return Ok(false);
+                }
+                io::copy(
+                    &mut Read::by_ref(stream).take(length as u64),
+                    &mut io::sink(),
+                )?;
+                write_option_reply(stream, option, REP_ERR_TOO_BIG, &[])?;
+                continue;
+            }
+            let mut data = vec![0; length as usize];
+            stream.read_exact(&mut data)?;
+            match option {
+                OPT_EXPORT_NAME => {
+                    if data != self.export_name.as_bytes() {
+                        return Ok(false);
+                    }
+                    write_u64(stream, self.file.len())?;
+                    write_u16(stream, TRANSMISSION_FLAGS)?;
+                    if client_flags & CLIENT_FLAG_NO_ZEROES == 0 {
+                        stream.write_all(&[0; 124])?;
+                    }
+                    return Ok(true);
+                }
+                OPT_INFO | OPT_GO => {
+                    if export_name_of(&data) != Some(self.export_name.as_bytes()) {
+                        write_option_reply(stream, option, REP_ERR_UNKNOWN, &[])?;
+                        continue;
+                    }
+                    let mut info = INFO_EXPORT.to_be_bytes().to_vec();
+                    info.extend(self.file.len().to_be_bytes());
+                    info.extend(TRANSMISSION_FLAGS.to_be_bytes());
+                    write_option_reply(stream, option, REP_INFO, &info)?;
+                    write_option_reply(stream, option, REP_ACK, &[])?;
+                    if option == OPT_GO {
+                        return Ok(true);
+                    }
+                }
+                OPT_ABORT => {
+                    write_option_reply(stream, option, REP_ACK, &[])?;
+                    return Ok(false);
+                }
+                _ => write_option_reply(stream, option, REP_ERR_UNSUP, &[])?,
+            }
+        }
+    }
+
+    fn transmit<S: Read + Write>(&mut self, stream: &mut S) -> io::Result<()> {
+        loop {
+            let magic = match read_u32(stream) {
+                Ok(magic) => magic,
+                // client went away without a disconnect request
+                Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(error) => return Err(error),
+            };
+            if magic != REQUEST_MAGIC {
+                return Err(invalid("bad request magic"));
+            }
+            let flags = read_u16(stream)?;
+            let command = read_u16(stream)?;
+            let handle = read_u64(stream)?;
+            let offset = read_u64(stream)?;
+            let length = read_u32(stream)?;
+            let in_range = offset
+                .checked_add(length as u64)
+                .is_some_and(|end| end <= self.file.len());
+            match command {
+                CMD_READ if length > MAX_REQUEST_LENGTH => {
+                    write_simple_reply(stream, EOVERFLOW, handle)?
+                }
+                // the payload is not read, the stream cannot get back in step
+                CMD_WRITE if length > MAX_REQUEST_LENGTH => {
+                    return write_simple_reply(stream, EOVERFLOW, handle);
+                }
+                CMD_READ if in_range => {
+                    let mut data = vec![0; length as usize];
+                    self.file.seek(SeekFrom::Start(offset))?;
+                    self.file.read_exact(&mut data)?;
+                    write_simple_reply(stream, 0, handle)?;
+                    stream.write_all(&data)?;
+                }
+                CMD_WRITE => {
+                    let mut data = vec![0; length as usize];
+                    stream.read_exact(&mut data)?;
+                    if !in_range {
+                        write_simple_reply(stream, ENOSPC, handle)?;
+                        continue;
+                    }
+                    self.file.seek(SeekFrom::Start(offset))?;
+                    self.file.write_all(&data)?;
+                    if flags & COMMAND_FLAG_FUA != 0 {
+                        self.file.flush()?;
+                    }
+                    write_simple_reply(stream, 0, handle)?;
+                }
+                CMD_FLUSH => {
+                    self.file.flush()?;
+                    write_simple_reply(stream, 0, handle)?;
+                }
+                CMD_TRIM if in_range => {
+                    self.file.discard(offset, length as u64);
+                    write_simple_reply(stream, 0, handle)?;
+                }
+                CMD_DISC => return Ok(()),
+                _ => write_simple_reply(stream, EINVAL, handle)?,
+            }
+        }
+    }
+}
+
+// minimal fixed newstyle client, enough to drive the server from Rust code and tests
+pub struct NbdClient<S: Read + Write> {
+    stream: S,
+    size: u64,
+    next_handle: u64,
+}
+
+impl<S: Read + Write> NbdClient<S> {
+    pub fn connect(mut stream: S, export_name: &str) -> io::Result<NbdClient<S>> {
+        if read_u64(&mut stream)? != NBD_MAGIC || read_u64(&mut stream)? != OPTION_MAGIC {
+            return Err(invalid("not a newstyle NBD server"));
+        }
+        if read_u16(&mut stream)? & FLAG_FIXED_NEWSTYLE == 0 {
+            return Err(invalid("server is not fixed newstyle"));
+        }
+        write_u32(
+            &mut stream,
+            CLIENT_FLAG_FIXED_NEWSTYLE | CLIENT_FLAG_NO_ZEROES,
+        )?;
+        let mut data = (export_name.len() as u32).to_be_bytes().to_vec();
+        data.extend(export_name.as_bytes());
+        data.extend(0u16.to_be_bytes());
+        write_u64(&mut stream, OPTION_MAGIC)?;
+        write_u32(&mut stream, OPT_GO)?;
+        write_u32(&mut stream, data.len() as u32)?;
+        stream.write_all(&data)?;
+
+        let mut size = None;
+        loop {
+            if read_u64(&mut stream)? != OPTION_REPLY_MAGIC || read_u32(&mut stream)? != OPT_GO {
+                return Err(invalid("bad option reply"));
+            }
+            let reply = read_u32(&mut stream)?;
+            let length = read_u32(&mut stream)?;
+            if length > MAX_OPTION_LENGTH {
+                return Err(invalid("option reply too long"));
+            }
+            let mut data = vec![0; length as usize];
+            stream.read_exact(&mut data)?;
+            match reply {
+                REP_INFO if data.len() >= 10 && data[..2] == INFO_EXPORT.to_be_bytes() => {
+                    size = Some(u64::from_be_bytes(data[2..10].try_into().unwrap()))
+                }
+                REP_INFO => {}
+                REP_ACK => break,
+                _ => return Err(io::Error::other(format!("export refused: {:x}", reply))),
+            }
+        }
+        Ok(NbdClient {
+            stream,
+            size: size.ok_or_else(|| invalid("export size was not sent"))?,
+            next_handle: 0,
+        })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn read(&mut self, offset: u64, length: u32) -> io::Result<Vec<u8>> {
+        self.request(CMD_READ, 0, offset, length, &[])?;
+        let mut data = vec![0; length as usize];
+        self.stream.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    pub fn write(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.request(CMD_WRITE, 0, offset, data.len() as u32, data)
+    }
+
+    // write that is on the media when the reply arrives
+    pub fn write_fua(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.request(CMD_WRITE, COMMAND_FLAG_FUA, offset, data.len() as u32, data)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.request(CMD_FLUSH, 0, 0, 0, &[])
+    }
+
+    pub fn trim(&mut self, offset: u64, length: u32) -> io::Result<()> {
+        self.request(CMD_TRIM, 0, offset, length, &[])
+    }
+
+    pub fn disconnect(mut self) -> io::Result<()> {
+        self.send(CMD_DISC, 0, 0, 0, &[]).map(|_| ())
+    }
+
+    fn send(
+        &mut self,
+        command: u16,
+        flags: u16,
+        offset: u64,
+        length: u32,
+        data: &[u8],
+    ) -> io::Result<u64> {
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        write_u32(&mut self.stream, REQUEST_MAGIC)?;
+        write_u16(&mut self.stream, flags)?;
+        write_u16(&mut self.stream, command)?;
+        write_u64(&mut self.stream, handle)?;
+        write_u64(&mut self.stream, offset)?;
+        write_u32(&mut self.stream, length)?;
+        self.stream.write_all(data)?;
+        Ok(handle)
+    }
+
+    // errors of the server come back as the errno they carry
+    fn request(
+        &mut self,
+        command: u16,
+        flags: u16,
+        offset: u64,
+        length: u32,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let handle = self.send(command, flags, offset, length, data)?;
+        if read_u32(&mut self.stream)? != SIMPLE_REPLY_MAGIC {
+            return Err(invalid("bad reply magic"));
+        }
+        let error = read_u32(&mut self.stream)?;
+        if read_u64(&mut self.stream)? != handle {
+            return Err(invalid("reply for unknown handle"));
+        }
+        match error {
+            0 => Ok(()),
+            errno => Err(io::Error::from_raw_os_error(errno as i32)),
+        }
+    }
+}
+
+// OPT_INFO and OPT_GO carry the name length, the name and the requested info types
+fn export_name_of(data: &[u8]) -> Option<&[u8]> {
+    let len = u32::from_be_bytes(data.get(..4)?.try_into().unwrap()) as usize;
+    data.get(4..4 + len)
+}
+
+fn write_option_reply(
+    writer: &mut dyn Write,
+    option: u32,
+    reply: u32,
+    data: &[u8],
+) -> io::Result<()> {
+    write_u64(writer, OPTION_REPLY_MAGIC)?;
+    write_u32(writer, option)?;
+    write_u32(writer, reply)?;
+    write_u32(writer, data.len() as u32)?;
+    writer.write_all(data)
+}
+
+fn write_simple_reply(writer: &mut dyn Write, error: u32, handle: u64) -> io::Result<()> {
+    write_u32(writer, SIMPLE_REPLY_MAGIC)?;
+    write_u32(writer, error)?;
+    write_u64(writer, handle)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn write_u16(writer: &mut dyn Write, value: u16) -> io::Result<()> {
+    writer.write_all(&value.to_be_bytes())
+}
+
+fn write_u32(writer: &mut dyn Write, value: u32) -> io::Result<()> {
+    writer.write_all(&value.to_be_bytes())
+}
+
+fn write_u64(writer: &mut dyn Write, value: u64) -> io::Result<()> {
+    writer.write_all(&value.to_be_bytes())
+}
+
+fn read_u16(reader: &mut dyn Read) -> io::Result<u16> {
+    let mut bytes = [0; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_be_bytes(bytes))
+}
+
+fn read_u32(reader: &mut dyn Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn read_u64(reader: &mut dyn Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::controller::memory_controller::test_controller;
+    use crate::host::block_device::BlockDeviceImpl;
+    use std::net::TcpStream;
+    use std::thread;
+
+    #[test]
+    fn client_should_read_back_writes_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let stream = TcpStream::connect(address).unwrap();
+            let mut client = NbdClient::connect(stream, "sdd").unwrap();
+            client.write(1000, b"hello flash").unwrap();
+            client.write_fua(0, &[0xEE; 512]).unwrap();
+            client.flush().unwrap();
+            let res = (client.read(1000, 11).unwrap(), client.read(510, 4).unwrap());
+            client.disconnect().unwrap();
+            res
+        });
+
+        let mut target = setup_target();
+        let (stream, _) = listener.accept().unwrap();
+        target.serve(&mut { stream }).unwrap();
+
+        let (greeting, edge) = client.join().unwrap();
+        assert_eq!(b"hello flash".to_vec(), greeting);
+        assert_eq!(vec![0xEE, 0xEE, 0, 0], edge);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn overwriting_export_should_run_through_gc() {
+        let (server_end, client_end) = std::os::unix::net::UnixStream::pair().unwrap();
+        let client = thread::spawn(move || {
+            let mut client = NbdClient::connect(client_end, "sdd").unwrap();
+            let size = client.size();
+            // the second pass over the first half only fits once GC reclaims the first one
+            for (round, end) in [(1, size), (2, size / 2)] {
+                for offset in (0..end).step_by(4096) {
+                    let len = (end - offset).min(4096) as usize;
+                    client.write(offset, &vec![round; len]).unwrap();
+                }
+            }
+            let data = client.read(0, size as u32).unwrap();
+            client.disconnect().unwrap();
+            data
+        });
+
+        let mut target = setup_target();
+        target.serve(&mut { server_end }).unwrap();
+
+        let data = client.join().unwrap();
+        let half = data.len() / 2;
+        assert!(data[..half].iter().all(|byte| *byte == 2));
+        assert!(data[half..].iter().all(|byte| *byte == 1));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn trim_should_zero_range_and_out_of_range_write_should_fail() {
+        let (server_end, client_end) = std::os::unix::net::UnixStream::pair().unwrap();
+        let client = thread::spawn(move || {
+            let mut client = NbdClient::connect(client_end, "sdd").unwrap();
+            client.write(0, &[0x42; 1024]).unwrap();
+            client.trim(512, 512).unwrap();
+            let trimmed = client.read(0, 1024).unwrap();
+            let size = client.size();
+            let error = client.write(size - 2, &[1, 2, 3, 4]).unwrap_err();
+            client.disconnect().unwrap();
+            (trimmed, error.raw_os_error())
+        });
+
+        let mut target = setup_target();
+        target.serve(&mut { server_end }).unwrap();
+
+        let (trimmed, error) = client.join().unwrap();
+        assert!(trimmed[..512].iter().all(|byte| *byte == 0x42));
+        assert!(trimmed[512..].iter().all(|byte| *byte == 0));
+        assert_eq!(Some(ENOSPC as i32), error);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn connect_should_fail_for_unknown_export() {
+        let (server_end, client_end) = std::os::unix::net::UnixStream::pair().unwrap();
+        let client = thread::spawn(move || NbdClient::connect(client_end, "other").err());
+
+        let mut target = setup_target();
+        let res = target.serve(&mut { server_end });
+
+        assert!(client.join().unwrap().is_some());
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn serve_all_should_outlive_broken_clients() {
+        let (gone, client_end) = std::os::unix::net::UnixStream::pair().unwrap();
+        drop(client_end);
+        let (old_style, mut old_client) = std::os::unix::net::UnixStream::pair().unwrap();
+        let old = thread::spawn(move || {
+            old_client.read_exact(&mut [0; 18]).unwrap();
+            write_u32(&mut old_client, 0).unwrap();
+            old_client.read(&mut [0; 8]).unwrap()
+        });
+        let (server_end, client_end) = std::os::unix::net::UnixStream::pair().unwrap();
+        let client = thread::spawn(move || {
+            let mut client = NbdClient::connect(client_end, "sdd").unwrap();
+            client.write(0, b"still up").unwrap();
+            let res = client.read(0, 8).unwrap();
+            client.disconnect().unwrap();
+            res
+        });
+
+        let mut target = setup_target();
+        let res = target.serve_all(vec![Ok(gone), Ok(old_style), Ok(server_end)]);
+
+        assert!(res.is_ok());
+        assert_eq!(0, old.join().unwrap());
+        assert_eq!(b"still up".to_vec(), client.join().unwrap());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn oversized_option_should_be_refused_and_skipped() {
+        let (server_end, mut client_end) = std::os::unix::net::UnixStream::pair().unwrap();
+        let client = thread::spawn(move || {
+            client_end.read_exact(&mut [0; 18]).unwrap();
+            write_u32(&mut client_end, CLIENT_FLAG_FIXED_NEWSTYLE).unwrap();
+            write_u64(&mut client_end, OPTION_MAGIC).unwrap();
+            write_u32(&mut client_end, OPT_INFO).unwrap();
+            write_u32(&mut client_end, MAX_OPTION_LENGTH + 1).unwrap();
+            client_end
+                .write_all(&[0; MAX_OPTION_LENGTH as usize + 1])
+                .unwrap();
+            read_u64(&mut client_end).unwrap();
+            read_u32(&mut client_end).unwrap();
+            let reply = read_u32(&mut client_end).unwrap();
+            read_u32(&mut client_end).unwrap();
+            // still in step with the server, which acknowledges the abort
+            write_u64(&mut client_end, OPTION_MAGIC).unwrap();
+            write_u32(&mut client_end, OPT_ABORT).unwrap();
+            write_u32(&mut client_end, 0).unwrap();
+            read_u64(&mut client_end).unwrap();
+            read_u32(&mut client_end).unwrap();
+            let ack = read_u32(&mut client_end).unwrap();
+            read_u32(&mut client_end).unwrap();
+            (reply, ack)
+        });
+
+        let mut target = setup_target();
+        target.serve(&mut { server_end }).unwrap();
+
+        assert_eq!((REP_ERR_TOO_BIG, REP_ACK), client.join().unwrap());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn oversized_requests_should_fail_and_oversized_write_should_close_connection() {
+        let (server_end, client_end) = std::os::unix::net::UnixStream::pair().unwrap();
+        let client = thread::spawn(move || {
+            let mut client = NbdClient::connect(client_end, "sdd").unwrap();
+            let read = client.request(CMD_READ, 0, 0, MAX_REQUEST_LENGTH + 1, &[]);
+            let write = client.request(CMD_WRITE, 0, 0, MAX_REQUEST_LENGTH + 1, &[]);
+            let flush = client.flush();
+            (read.unwrap_err(), write.unwrap_err(), flush.is_err())
+        });
+
+        let mut target = setup_target();
+        target.serve(&mut { server_end }).unwrap();
+
+        let (read, write, closed) = client.join().unwrap();
+        assert_eq!(Some(EOVERFLOW as i32), read.raw_os_error());
+        assert_eq!(Some(EOVERFLOW as i32), write.raw_os_error());
+        assert!(closed);
+    }
+
+    fn setup_target() -> NbdServer {
+        NbdServer::new(
+            Box::new(BlockDeviceImpl::new(Box::new(test_controller()), 512)),
+            "sdd",
+        )
+    }
+}
@@ -0,0 +1,785 @@
+use crate::host::block_device::BlockDevice;
+use crate::host::block_device::BlockDeviceImpl;
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+pub const ADMIN_QUEUE: u16 = 0;
+pub const NAMESPACE_ID: u32 = 1;
+const ALL_NAMESPACES: u32 = 0xFFFF_FFFF;
+
+pub const ADMIN_DELETE_SQ: u8 = 0x00;
+pub const ADMIN_CREATE_SQ: u8 = 0x01;
+pub const ADMIN_GET_LOG_PAGE: u8 = 0x02;
+pub const ADMIN_DELETE_CQ: u8 = 0x04;
+pub const ADMIN_CREATE_CQ: u8 = 0x05;
+pub const ADMIN_IDENTIFY: u8 = 0x06;
+
+pub const IO_FLUSH: u8 = 0x00;
+pub const IO_WRITE: u8 = 0x01;
+pub const IO_READ: u8 = 0x02;
+pub const IO_WRITE_ZEROES: u8 = 0x08;
+pub const IO_DATASET_MANAGEMENT: u8 = 0x09;
+
+pub const CNS_NAMESPACE: u32 = 0x00;
+pub const CNS_CONTROLLER: u32 = 0x01;
+pub const LOG_SMART: u32 = 0x02;
+pub const DSM_DEALLOCATE: u32 = 1 << 2;
+
+// status code type in the high byte, status code in the low byte
+pub const STATUS_SUCCESS: u16 = 0x000;
+pub const STATUS_INVALID_OPCODE: u16 = 0x001;
+pub const STATUS_INVALID_FIELD: u16 = 0x002;
+pub const STATUS_DATA_TRANSFER_ERROR: u16 = 0x004;
+pub const STATUS_INVALID_NAMESPACE: u16 = 0x00B;
+pub const STATUS_LBA_OUT_OF_RANGE: u16 = 0x080;
+pub const STATUS_COMPLETION_QUEUE_INVALID: u16 = 0x100;
+pub const STATUS_INVALID_QUEUE_ID: u16 = 0x101;
+pub const STATUS_INVALID_QUEUE_SIZE: u16 = 0x102;
+pub const STATUS_INVALID_LOG_PAGE: u16 = 0x109;
+pub const STATUS_INVALID_QUEUE_DELETION: u16 = 0x10C;
+
+pub const IDENTIFY_BYTES: usize = 4096;
+pub const SMART_LOG_BYTES: usize = 512;
+pub const DSM_RANGE_BYTES: usize = 16;
+// SMART data units count thousands of 512 byte units
+const DATA_UNIT_BYTES: u64 = 512 * 1000;
+
+// submission queue entry, the data pointer is an offset of a contiguous buffer in host memory
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Command {
+    pub opcode: u8,
+    pub command_id: u16,
+    pub nsid: u32,
+    pub prp1: u64,
+    pub cdw10: u32,
+    pub cdw11: u32,
+    pub cdw12: u32,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Completion {
+    pub result: u32,
+    pub sq_head: u16,
+    pub sq_id: u16,
+    pub command_id: u16,
+    pub phase: bool,
+    pub status: u16,
+}
+
+struct SubmissionQueue {
+    entries: Vec<Command>,
+    head: u16,
+    tail: u16,
+    cq_id: u16,
+}
+
+// the phase tag flips on every wrap, so the host tells new entries from old ones
+struct CompletionQueue {
+    entries: Vec<Completion>,
+    head: u16,
+    tail: u16,
+    phase: bool,
+}
+
+impl SubmissionQueue {
+    fn new(size: u16, cq_id: u16) -> SubmissionQueue {
+        SubmissionQueue {
+            entries: vec![Command::default(); size as usize],
+            head: 0,
+            tail: 0,
+            cq_id,
+        }
+    }
+}
+
+impl CompletionQueue {
+    fn new(size: u16) -> CompletionQueue {
+        CompletionQueue {
+            entries: vec![Completion::default(); size as usize],
+            head: 0,
+            tail: 0,
+            phase: true,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        (self.tail as usize + 1) % self.entries.len() == self.head as usize
+    }
+
+    fn post(&mut self, completion: Completion) {
+        self.entries[self.tail as usize] = Completion {
+            phase: self.phase,
+            ..completion
+        };
+        self.tail += 1;
+        if self.tail as usize == self.entries.len() {
+            self.tail = 0;
+            self.phase = !self.phase;
+        }
+    }
+}
+
+// single namespace controller, commands run to completion when their doorbell is rung
+pub struct NvmeController {
+    device: BlockDeviceImpl,
+    host_memory: Vec<u8>,
+    submission_queues: HashMap<u16, SubmissionQueue>,
+    completion_queues: HashMap<u16, CompletionQueue>,
+    bytes_read: u64,
+    bytes_written: u64,
+    read_commands: u64,
+    write_commands: u64,
+}
+
+impl NvmeController {
+    pub fn new(
+        device: BlockDeviceImpl,
+        admin_queue_size: u16,
+        host_memory_bytes: usize,
+    ) -> NvmeController {
+        // a ring of one entry is always full, as for the I/O queues the admin command creates
+        if admin_queue_size < 2 {
+            panic!("Admin queue needs at least 2 entries")
+        }
+        let mut submission_queues = HashMap::new();
+        let mut completion_queues = HashMap::new();
+        submission_queues.insert(
+            ADMIN_QUEUE,
+            SubmissionQueue::new(admin_queue_size, ADMIN_QUEUE),
+        );
+        completion_queues.insert(ADMIN_QUEUE, CompletionQueue::new(admin_queue_size));
+        NvmeController {
+            device,
+            host_memory: vec![0; host_memory_bytes],
+            submission_queues,
+            completion_queues,
+            bytes_read: 0,
+            bytes_written: 0,
+            read_commands: 0,
+            write_commands: 0,
+        }
+    }
+
+    pub fn into_device(self) -> BlockDeviceImpl {
+        self.device
+    }
+
+    // buffers commands transfer data from and to
+    pub fn host_memory(&mut self) -> &mut [u8] {
+        &mut self.host_memory
+    }
+
+    pub fn write_submission_entry(&mut self, sq_id: u16, index: u16, command: Command) {
+        let queue = self
+            .submission_queues
+            .get_mut(&sq_id)
+            .unwrap_or_else(|| panic!("Submission queue does not exist"));
+        queue.entries[index as usize] = command;
+    }
+
+    pub fn read_completion_entry(&self, cq_id: u16, index: u16) -> Completion {
+        let queue = self
+            .completion_queues
+            .get(&cq_id)
+            .unwrap_or_else(|| panic!("Completion queue does not exist"));
+        queue.entries[index as usize]
+    }
+
+    pub fn ring_submission_doorbell(&mut self, sq_id: u16, tail: u16) {
+        let queue = self
+            .submission_queues
+            .get_mut(&sq_id)
+            .unwrap_or_else(|| panic!("Submission queue does not exist"));
+        if tail as usize >= queue.entries.len() {
+            panic!("Doorbell is past queue end")
+        }
+        queue.tail = tail;
+        self.process(sq_id);
+    }
+
+    // freed completion slots let submission queues stalled on a full queue go on
+    pub fn ring_completion_doorbell(&mut self, cq_id: u16, head: u16) {
+        let queue = self
+            .completion_queues
+            .get_mut(&cq_id)
+            .unwrap_or_else(|| panic!("Completion queue does not exist"));
+        if head as usize >= queue.entries.len() {
+            panic!("Doorbell is past queue end")
+        }
+        queue.head = head;
+        let mut stalled: Vec<u16> = self
+            .submission_queues
+            .iter()
+            .filter(|(_, queue)| queue.cq_id == cq_id)
+            .map(|(sq_id, _)| *sq_id)
+            .collect();
+        stalled.sort();
+        for sq_id in stalled {
+            self.process(sq_id);
+        }
+    }
+
+    fn process(&mut self, sq_id: u16) {
+        loop {
+            let queue = match self.submission_queues.get_mut(&sq_id) {
+                Some(queue) if queue.head != queue.tail => queue,
+                _ => return,
+            };
+            let cq_id = queue.cq_id;
+            if self.completion_queues[&cq_id].is_full() {
+                return;
+            }
+            let command = queue.entries[queue.head as usize];
+            queue.head = ((queue.head as usize + 1) % queue.entries.len()) as u16;
+            let sq_head = queue.head;
+            let (status, result) = if sq_id == ADMIN_QUEUE {
+                self.execute_admin(command)
+            } else {
+                self.execute_io(command)
+            };
+            // the queue pair may be gone after its own delete command
+            if let Some(completions) = self.completion_queues.get_mut(&cq_id) {
+                completions.post(Completion {
+                    result,
+                    sq_head,
+                    sq_id,
+                    command_id: command.command_id,
+                    phase: false,
+                    status,
+                });
+            }
+        }
+    }
+
+    fn execute_admin(&mut self, command: Command) -> (u16, u32) {
+        let qid = (command.cdw10 & 0xFFFF) as u16;
+        let size = (command.cdw10 >> 16) + 1;
+        let status = match command.opcode {
+            ADMIN_IDENTIFY => {
+                let data = match command.cdw10 & 0xFF {
+                    CNS_CONTROLLER => self.identify_controller(),
+                    CNS_NAMESPACE if command.nsid == NAMESPACE_ID => self.identify_namespace(),
+                    CNS_NAMESPACE => return (STATUS_INVALID_NAMESPACE, 0),
+                    _ => return (STATUS_INVALID_FIELD, 0),
+                };
+                self.copy_to_host(command.prp1, &data)
+            }
+            ADMIN_GET_LOG_PAGE => {
+                let dwords = ((command.cdw10 >> 16) + 1) as usize;
+                let data = match command.cdw10 & 0xFF {
+                    LOG_SMART => self.smart_log(),
+                    _ => return (STATUS_INVALID_LOG_PAGE, 0),
+                };
+                self.copy_to_host(command.prp1, &data[..(dwords * 4).min(data.len())])
+            }
+            ADMIN_CREATE_CQ => {
+                if qid == ADMIN_QUEUE || self.completion_queues.contains_key(&qid) {
+                    STATUS_INVALID_QUEUE_ID
+                } else if !(2..=u16::MAX as u32).contains(&size) {
+                    STATUS_INVALID_QUEUE_SIZE
+                } else {
+                    self.completion_queues
+                        .insert(qid, CompletionQueue::new(size as u16));
+                    STATUS_SUCCESS
+                }
+            }
+            ADMIN_CREATE_SQ => {
+                let cq_id = (command.cdw11 >> 16) as u16;
+                if qid == ADMIN_QUEUE || self.submission_queues.contains_key(&qid) {
+                    STATUS_INVALID_QUEUE_ID
+                } else if !(2..=u16::MAX as u32).contains(&size) {
+                    STATUS_INVALID_QUEUE_SIZE
+                } else if cq_id == ADMIN_QUEUE || !self.completion_queues.contains_key(&cq_id) {
+                    STATUS_COMPLETION_QUEUE_INVALID
+                } else {
+                    self.submission_queues
+                        .insert(qid, SubmissionQueue::new(size as u16, cq_id));
+                    STATUS_SUCCESS
+                }
+            }
+            ADMIN_DELETE_SQ => {
+                if qid == ADMIN_QUEUE || self.submission_queues.remove(&qid).is_none() {
+                    STATUS_INVALID_QUEUE_ID
+                } else {
+                    STATUS_SUCCESS
+                }
+            }
+            ADMIN_DELETE_CQ => {
+                if qid == ADMIN_QUEUE || !self.completion_queues.contains_key(&qid) {
+                    STATUS_INVALID_QUEUE_ID
+                } else if self.submission_queues.values().any(|sq| sq.cq_id == qid) {
+                    STATUS_INVALID_QUEUE_DELETION
+                } else {
+                    self.completion_queues.remove(&qid);
+                    STATUS_SUCCESS
+                }
+            }
+            _ => STATUS_INVALID_OPCODE,
+        };
+        (status, 0)
+    }
+
+    fn execute_io(&mut self, command: Command) -> (u16, u32) {
+        if command.nsid != NAMESPACE_ID
+            && !(command.opcode == IO_FLUSH && command.nsid == ALL_NAMESPACES)
+        {
+            return (STATUS_INVALID_NAMESPACE, 0);
+        }
+        let lba = (command.cdw11 as u64) << 32 | command.cdw10 as u64;
+        let blocks = (command.cdw12 & 0xFFFF) as u64 + 1;
+        let bytes = blocks as usize * self.device.sector_size();
+        let in_range = lba
+            .checked_add(blocks)
+            .is_some_and(|end| end <= self.device.sector_count());
+        let status = match command.opcode {
+            IO_READ | IO_WRITE | IO_WRITE_ZEROES if !in_range => STATUS_LBA_OUT_OF_RANGE,
+            IO_READ => {
+                if !self.host_range_fits(command.prp1, bytes) {
+                    return (STATUS_DATA_TRANSFER_ERROR, 0);
+                }
+                let mut data = vec![0; bytes];
+                self.device.read(lba, &mut data);
+                self.bytes_read += bytes as u64;
+                self.read_commands += 1;
+                self.copy_to_host(command.prp1, &data)
+            }
+            IO_WRITE => {
+                let data = match self.copy_from_host(command.prp1, bytes) {
+                    Some(data) => data,
+                    None => return (STATUS_DATA_TRANSFER_ERROR, 0),
+                };
+                self.device.write(lba, &data);
+                self.bytes_written += bytes as u64;
+                self.write_commands += 1;
+                STATUS_SUCCESS
+            }
+            IO_WRITE_ZEROES => {
+                self.device.write(lba, &vec![0; bytes]);
+                STATUS_SUCCESS
+            }
+            IO_FLUSH => {
+                self.device.flush();
+                STATUS_SUCCESS
+            }
+            IO_DATASET_MANAGEMENT => self.dataset_management(command),
+            _ => STATUS_INVALID_OPCODE,
+        };
+        (status, 0)
+    }
+
+    // ranges are checked all together, so a bad one leaves the namespace untouched
+    fn dataset_management(&mut self, command: Command) -> u16 {
+        if command.cdw11 & DSM_DEALLOCATE == 0 {
+            return STATUS_SUCCESS;
+        }
+        let count = (command.cdw10 & 0xFF) as usize + 1;
+        let data = match self.copy_from_host(command.prp1, count * DSM_RANGE_BYTES) {
+            Some(data) => data,
+            None => return STATUS_DATA_TRANSFER_ERROR,
+        };
+        let ranges: Vec<(u64, u64)> = data
+            .chunks(DSM_RANGE_BYTES)
+            .map(|range| {
+                let blocks = u32::from_le_bytes(range[4..8].try_into().unwrap()) as u64;
+                let lba = u64::from_le_bytes(range[8..16].try_into().unwrap());
+                (lba, blocks)
+            })
+            .collect();
+        let sector_count = self.device.sector_count();
+        if ranges.iter().any(|(lba, blocks)| {
+            lba.checked_add(*blocks)
+                .is_none_or(|end| end > sector_count)
+        }) {
+            return STATUS_LBA_OUT_OF_RANGE;
+        }
+        for (lba, blocks) in ranges {
+            if blocks > 0 {
+                self.device.discard(lba, blocks);
+            }
+        }
+        STATUS_SUCCESS
+    }
+
+    fn host_range_fits(&self, offset: u64, bytes: usize) -> bool {
+        (offset as usize)
+            .checked_add(bytes)
+            .is_some_and(|end| end <= self.host_memory.len())
+    }
+
+    fn copy_to_host(&mut self, offset: u64, data: &[u8]) -> u16 {
+        if !self.host_range_fits(offset, data.len()) {
+            return STATUS_DATA_TRANSFER_ERROR;
+        }
+        let offset = offset as usize;
+        self.host_memory[offset..offset + data.len()].copy_from_slice(data);
+        STATUS_SUCCESS
+    }
+
+    fn copy_from_host(&self, offset: u64, bytes: usize) -> Option<Vec<u8>> {
+        if !self.host_range_fits(offset, bytes) {
+            return None;
+        }
+        let offset = offset as usize;
+        Some(self.host_memory[offset..offset + bytes].to_vec())
+    }
+
+    fn identify_controller(&self) -> Vec<u8> {
+        let mut data = vec![0; IDENTIFY_BYTES];
+        data[4..24].copy_from_slice(b"SDD0000000000000001 ");
+        data[24..64].copy_from_slice(b"SDD SIMULATED SSD                       ");
+        data[64..72].copy_from_slice(b"1.0     ");
+        data[78..80].copy_from_slice(&1u16.to_le_bytes());
+        // version 1.4
+        data[80..84].copy_from_slice(&0x0001_0400u32.to_le_bytes());
+        // 64 byte submission and 16 byte completion queue entries
+        data[512] = 0x66;
+        data[513] = 0x44;
+        data[516..520].copy_from_slice(&1u32.to_le_bytes());
+        // dataset management and write zeroes
+        data[520..522].copy_from_slice(&0x000Cu16.to_le_bytes());
+        // volatile write cache, the die pipelines hold programs until flush
+        data[525] = 1;
+        data
+    }
+
+    fn identify_namespace(&self) -> Vec<u8> {
+        let mut data = vec![0; IDENTIFY_BYTES];
+        let sectors = self.device.sector_count().to_le_bytes();
+        data[0..8].copy_from_slice(&sectors);
+        data[8..16].copy_from_slice(&sectors);
+        data[16..24].copy_from_slice(&sectors);
+        // one LBA format, the block size is a power of two
+        data[130] = self.device.sector_size().trailing_zeros() as u8;
+        data
+    }
+
+    fn smart_log(&self) -> Vec<u8> {
+        let mut data = vec![0; SMART_LOG_BYTES];
+        // 40 degrees Celsius in Kelvin
+        data[1..3].copy_from_slice(&313u16.to_le_bytes());
+        data[3] = 100;
+        data[4] = 10;
+        let used = (self.device.controller().life_used() * 100.0).ceil();
+        data[5] = used.min(255.0) as u8;
+        let units_read = self.bytes_read.div_ceil(DATA_UNIT_BYTES) as u128;
+        let units_written = self.bytes_written.div_ceil(DATA_UNIT_BYTES) as u128;
+        data[32..48].copy_from_slice(&units_read.to_le_bytes());
+        data[48..64].copy_from_slice(&units_written.to_le_bytes());
+        data[64..80].copy_from_slice(&(self.read_commands as u128).to_le_bytes());
+        data[80..96].copy_from_slice(&(self.write_commands as u128).to_le_bytes());
+        data
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::controller::memory_controller::test_controller;
+
+    #[test]
+    fn identify_should_describe_controller_and_namespace() {
+        let mut target = setup_target();
+        let mut admin = QueuePair::admin();
+
+        let res = admin.run(&mut target, identify(CNS_CONTROLLER, 0, 0));
+        assert_eq!(STATUS_SUCCESS, res.status);
+        assert_eq!(b"SDD SIMULATED SSD", &target.host_memory()[24..41]);
+        assert_eq!(0x0C, target.host_memory()[520]);
+
+        let res = admin.run(&mut target, identify(CNS_NAMESPACE, NAMESPACE_ID, 0));
+        assert_eq!(STATUS_SUCCESS, res.status);
+        let sectors = u64::from_le_bytes(target.host_memory()[0..8].try_into().unwrap());
+        assert_eq!(target.device.sector_count(), sectors);
+        assert_eq!(9, target.host_memory()[130]);
+
+        let res = admin.run(&mut target, identify(CNS_NAMESPACE, 2, 0));
+        assert_eq!(STATUS_INVALID_NAMESPACE, res.status);
+    }
+
+    #[test]
+    fn io_queue_pair_should_write_and_read_back() {
+        let mut target = setup_target();
+        let mut admin = QueuePair::admin();
+        let mut io = create_io_queue_pair(&mut target, &mut admin, 1, 8);
+        let data: Vec<u8> = (0..3 * 512).map(|i| (i * 11) as u8).collect();
+        target.host_memory()[..data.len()].copy_from_slice(&data);
+
+        let written = io.run(&mut target, rw(IO_WRITE, 5, 3, 0));
+        let read = io.run(&mut target, rw(IO_READ, 5, 3, 8192));
+
+        assert_eq!(
+            (STATUS_SUCCESS, STATUS_SUCCESS),
+            (written.status, read.status)
+        );
+        assert_eq!((1, 1), (written.sq_id, written.sq_head));
+        assert_eq!(data, target.host_memory()[8192..8192 + data.len()]);
+    }
+
+    #[test]
+    fn read_should_fail_past_namespace_end() {
+        let mut target = setup_target();
+        let mut admin = QueuePair::admin();
+        let mut io = create_io_queue_pair(&mut target, &mut admin, 1, 4);
+        let last = target.device.sector_count() - 1;
+
+        let res = io.run(&mut target, rw(IO_READ, last, 2, 0));
+
+        assert_eq!(STATUS_LBA_OUT_OF_RANGE, res.status);
+    }
+
+    #[test]
+    fn write_should_fail_when_range_end_overflows() {
+        let mut target = setup_target();
+        let mut admin = QueuePair::admin();
+        let mut io = create_io_queue_pair(&mut target, &mut admin, 1, 4);
+
+        let res = io.run(&mut target, rw(IO_WRITE, u64::MAX, 2, 0));
+
+        assert_eq!(STATUS_LBA_OUT_OF_RANGE, res.status);
+    }
+
+    #[test]
+    fn write_zeroes_and_deallocate_should_clear_ranges() {
+        let mut target = setup_target();
+        let mut admin = QueuePair::admin();
+        let mut io = create_io_queue_pair(&mut target, &mut admin, 1, 4);
+        target.host_memory()[..4 * 512].fill(0xAB);
+        io.run(&mut target, rw(IO_WRITE, 0, 4, 0));
+
+        let zeroed = io.run(&mut target, rw(IO_WRITE_ZEROES, 0, 1, 0));
+        target.host_memory()[..DSM_RANGE_BYTES].copy_from_slice(&dsm_range(2, 1));
+        let deallocated = io.run(
+            &mut target,
+            Command {
+                opcode: IO_DATASET_MANAGEMENT,
+                nsid: NAMESPACE_ID,
+                cdw11: DSM_DEALLOCATE,
+                ..Command::default()
+            },
+        );
+        io.run(&mut target, rw(IO_READ, 0, 4, 4096));
+
+        assert_eq!(
+            (STATUS_SUCCESS, STATUS_SUCCESS),
+            (zeroed.status, deallocated.status)
+        );
+        let res = &target.host_memory()[4096..4096 + 4 * 512];
+        assert!(res[..512].iter().all(|byte| *byte == 0));
+        assert!(res[512..1024].iter().all(|byte| *byte == 0xAB));
+        assert!(res[1024..1536].iter().all(|byte| *byte == 0));
+        assert!(res[1536..].iter().all(|byte| *byte == 0xAB));
+    }
+
+    #[test]
+    fn smart_log_should_count_host_commands() {
+        let mut target = setup_target();
+        let mut admin = QueuePair::admin();
+        let mut io = create_io_queue_pair(&mut target, &mut admin, 1, 4);
+        io.run(&mut target, rw(IO_WRITE, 0, 2, 0));
+        io.run(&mut target, rw(IO_READ, 0, 1, 0));
+        io.run(&mut target, rw(IO_READ, 1, 1, 0));
+
+        let res = admin.run(
+            &mut target,
+            Command {
+                opcode: ADMIN_GET_LOG_PAGE,
+                nsid: ALL_NAMESPACES,
+                cdw10: LOG_SMART | ((SMART_LOG_BYTES as u32 / 4 - 1) << 16),
+                ..Command::default()
+            },
+        );
+
+        assert_eq!(STATUS_SUCCESS, res.status);
+        let log = &target.host_memory()[..SMART_LOG_BYTES];
+        assert_eq!(1, u128::from_le_bytes(log[32..48].try_into().unwrap()));
+        assert_eq!(2, u128::from_le_bytes(log[64..80].try_into().unwrap()));
+        assert_eq!(1, u128::from_le_bytes(log[80..96].try_into().unwrap()));
+    }
+
+    #[test]
+    fn full_completion_queue_should_stall_submissions_until_doorbell() {
+        let mut target = setup_target();
+        let mut admin = QueuePair::admin();
+        // a queue of two entries holds a single completion
+        let mut io = create_io_queue_pair(&mut target, &mut admin, 1, 2);
+        let flush = Command {
+            opcode: IO_FLUSH,
+            nsid: NAMESPACE_ID,
+            ..Command::default()
+        };
+
+        io.submit(
+            &mut target,
+            Command {
+                command_id: 1,
+                ..flush
+            },
+        );
+        io.submit(
+            &mut target,
+            Command {
+                command_id: 2,
+                ..flush
+            },
+        );
+
+        assert_eq!(1, io.reap(&mut target).unwrap().command_id);
+        assert_eq!(2, io.reap(&mut target).unwrap().command_id);
+        assert_eq!(None, io.reap(&mut target));
+    }
+
+    #[test]
+    fn delete_completion_queue_should_fail_while_submission_queue_uses_it() {
+        let mut target = setup_target();
+        let mut admin = QueuePair::admin();
+        create_io_queue_pair(&mut target, &mut admin, 1, 4);
+
+        let refused = admin.run(&mut target, delete(ADMIN_DELETE_CQ, 1));
+        let sq = admin.run(&mut target, delete(ADMIN_DELETE_SQ, 1));
+        let cq = admin.run(&mut target, delete(ADMIN_DELETE_CQ, 1));
+
+        assert_eq!(STATUS_INVALID_QUEUE_DELETION, refused.status);
+        assert_eq!((STATUS_SUCCESS, STATUS_SUCCESS), (sq.status, cq.status));
+    }
+
+    // host side bookkeeping of a queue pair: its tail, head and expected phase
+    struct QueuePair {
+        id: u16,
+        size: u16,
+        tail: u16,
+        head: u16,
+        phase: bool,
+        next_command_id: u16,
+    }
+
+    impl QueuePair {
+        fn admin() -> QueuePair {
+            QueuePair::new(ADMIN_QUEUE, ADMIN_QUEUE_SIZE)
+        }
+
+        fn new(id: u16, size: u16) -> QueuePair {
+            QueuePair {
+                id,
+                size,
+                tail: 0,
+                head: 0,
+                phase: true,
+                next_command_id: 100,
+            }
+        }
+
+        fn submit(&mut self, target: &mut NvmeController, command: Command) {
+            target.write_submission_entry(self.id, self.tail, command);
+            self.tail = (self.tail + 1) % self.size;
+            target.ring_submission_doorbell(self.id, self.tail);
+        }
+
+        fn reap(&mut self, target: &mut NvmeController) -> Option<Completion> {
+            let completion = target.read_completion_entry(self.id, self.head);
+            if completion.phase != self.phase {
+                return None;
+            }
+            self.head = (self.head + 1) % self.size;
+            if self.head == 0 {
+                self.phase = !self.phase;
+            }
+            target.ring_completion_doorbell(self.id, self.head);
+            Some(completion)
+        }
+
+        fn run(&mut self, target: &mut NvmeController, command: Command) -> Completion {
+            self.next_command_id += 1;
+            let command_id = self.next_command_id;
+            self.submit(
+                target,
+                Command {
+                    command_id,
+                    ..command
+                },
+            );
+            let completion = self.reap(target).unwrap();
+            assert_eq!(command_id, completion.command_id);
+            completion
+        }
+    }
+
+    fn create_io_queue_pair(
+        target: &mut NvmeController,
+        admin: &mut QueuePair,
+        id: u16,
+        size: u16,
+    ) -> QueuePair {
+        let cdw10 = (size as u32 - 1) << 16 | id as u32;
+        let cq = admin.run(
+            target,
+            Command {
+                opcode: ADMIN_CREATE_CQ,
+                cdw10,
+                ..Command::default()
+            },
+        );
+        let sq = admin.run(
+            target,
+            Command {
+                opcode: ADMIN_CREATE_SQ,
+                cdw10,
+                cdw11: (id as u32) << 16,
+                ..Command::default()
+            },
+        );
+        assert_eq!((STATUS_SUCCESS, STATUS_SUCCESS), (cq.status, sq.status));
+        QueuePair::new(id, size)
+    }
+
+    fn identify(cns: u32, nsid: u32, prp1: u64) -> Command {
+        Command {
+            opcode: ADMIN_IDENTIFY,
+            nsid,
+            prp1,
+            cdw10: cns,
+            ..Command::default()
+        }
+    }
+
+    fn delete(opcode: u8, qid: u16) -> Command {
+        Command {
+            opcode,
+            cdw10: qid as u32,
+            ..Command::default()
+        }
+    }
+
+    fn rw(opcode: u8, lba: u64, blocks: u32, prp1: u64) -> Command {
+        Command {
+            opcode,
+            nsid: NAMESPACE_ID,
+            prp1,
+            cdw10: lba as u32,
+            cdw11: (lba >> 32) as u32,
+            cdw12: blocks - 1,
+            ..Command::default()
+        }
+    }
+
+    fn dsm_range(lba: u64, blocks: u32) -> [u8; DSM_RANGE_BYTES] {
+        let mut range = [0; DSM_RANGE_BYTES];
+        range[4..8].copy_from_slice(&blocks.to_le_bytes());
+        range[8..16].copy_from_slice(&lba.to_le_bytes());
+        range
+    }
+
+    #[test]
+    #[should_panic(expected = "Admin queue needs at least 2 entries")]
+    fn new_should_panic_when_admin_queue_has_one_entry() {
+        let device = BlockDeviceImpl::new(Box::new(test_controller()), 512);
+
+        NvmeController::new(device, 1, 16 * 1024);
+    }
+
+    const ADMIN_QUEUE_SIZE: u16 = 4;
+
+    fn setup_target() -> NvmeController {
+        let device = BlockDeviceImpl::new(Box::new(test_controller()), 512);
+        NvmeController::new(device, ADMIN_QUEUE_SIZE, 16 * 1024)
+    }
+}
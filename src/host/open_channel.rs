@@ -0,0 +1,451 @@
+use crate::config::CELLS_PER_PAGE;
+use crate::config::CHANNELS;
+use crate::config::DIES;
+use crate::config::PAGES_PER_BLOCK;
+use crate::config::PLANES_PER_DIE;
+use crate::config::SPARE_BYTES_PER_PAGE;
+use crate::controller::byte_encoder::ByteEncoder;
+use crate::controller::CellType;
+use crate::physic_level::memory::Address;
+use crate::physic_level::memory::Memory;
+
+// what the host needs to place data itself, blocks of a die are numbered from zero
+#[derive(Clone, Debug, PartialEq)]
+pub struct Geometry {
+    pub channels: usize,
+    pub dies_per_channel: usize,
+    pub planes_per_die: usize,
+    pub blocks_per_die: usize,
+    pub pages_per_block: usize,
+    pub cells_per_page: usize,
+    pub spare_bytes_per_page: usize,
+    pub native_cell_type: CellType,
+    // densities a block may be opened in, from the densest one
+    pub cell_types: Vec<CellType>,
+}
+
+// page is a wordline, it carries one bit per cell for every page of the block mode
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PhysicalAddress {
+    pub channel: usize,
+    pub die: usize,
+    pub block: usize,
+    pub page: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MediaError {
+    InvalidAddress,
+    BadBlock,
+    // pages of a block are programmed from the first one without gaps
+    OutOfOrderProgram,
+    // the block is already programmed in another density
+    ModeMismatch,
+    DenserThanNative,
+    InvalidLength,
+    EmptyPage,
+    // the erase spent the last cycle of the block, it is retired
+    WornOut,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockState {
+    Free,
+    Open,
+    Closed,
+    Bad,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BlockInfo {
+    pub address: PhysicalAddress,
+    pub state: BlockState,
+    pub mode: Option<CellType>,
+    // next page to program
+    pub write_pointer: usize,
+    pub life_used: f64,
+}
+
+// the device marks every wordline it programs in the spare area, cells alone cannot tell an
+// erased page from one whose data sits at the lowest charge
+const WRITTEN_MARKER: u8 = 0x5A;
+
+// host managed device: the host picks every physical page and the device only enforces the
+// media rules, entries of a vectored command succeed or fail one by one
+pub struct OpenChannelDevice<const PS: usize> {
+    byte_encoder: Box<dyn ByteEncoder<PS>>,
+    memory: Box<dyn Memory>,
+    write_pointers: Vec<usize>,
+}
+
+impl OpenChannelDevice<CELLS_PER_PAGE> {
+    // write pointers of opened blocks are found from the written markers, pages of a block are
+    // programmed in order so a binary search over them is enough
+    pub fn new(
+        byte_encoder: Box<dyn ByteEncoder<CELLS_PER_PAGE>>,
+        memory: Box<dyn Memory>,
+    ) -> OpenChannelDevice<CELLS_PER_PAGE> {
+        if !memory.block_count().is_multiple_of(DIES) {
+            panic!("Block count has to split evenly into dies")
+        }
+        let pages: Vec<usize> = (0..PAGES_PER_BLOCK).collect();
+        let write_pointers = (0..memory.block_count())
+            .map(|block| match memory.block_mode(block) {
+                Some(_) => pages.partition_point(|page| {
+                    memory.read_spare(Address(block, *page))[0] == WRITTEN_MARKER
+                }),
+                None => 0,
+            })
+            .collect();
+        OpenChannelDevice {
+            byte_encoder,
+            memory,
+            write_pointers,
+        }
+    }
+
+    pub fn into_memory(self) -> Box<dyn Memory> {
+        self.memory
+    }
+
+    pub fn geometry(&self) -> Geometry {
+        let native = self.memory.native_cell_type();
+        let mut cell_types = vec![native];
+        while let Some(lower) = cell_types.last().unwrap().lower() {
+            cell_types.push(lower);
+        }
+        Geometry {
+            channels: CHANNELS,
+            dies_per_channel: DIES / CHANNELS,
+            planes_per_die: PLANES_PER_DIE,
+            blocks_per_die: self.memory.block_count() / DIES,
+            pages_per_block: PAGES_PER_BLOCK,
+            cells_per_page: CELLS_PER_PAGE,
+            spare_bytes_per_page: SPARE_BYTES_PER_PAGE,
+            native_cell_type: native,
+            cell_types,
+        }
+    }
+
+    // every block of the die in block order
+    pub fn block_report(&self, channel: usize, die: usize) -> Vec<BlockInfo> {
+        let blocks_per_die = self.memory.block_count() / DIES;
+        (0..blocks_per_die)
+            .map(|block| PhysicalAddress {
+                channel,
+                die,
+                block,
+                page: 0,
+            })
+            .filter_map(|address| {
+                let block_id = self.block_id(address).ok()?;
+                let write_pointer = self.write_pointers[block_id];
+                let state = if self.memory.is_bad(block_id) {
+                    BlockState::Bad
+                } else if self.memory.block_mode(block_id).is_none() {
+                    BlockState::Free
+                } else if write_pointer == PAGES_PER_BLOCK {
+                    BlockState::Closed
+                } else {
+                    BlockState::Open
+                };
+                Some(BlockInfo {
+                    address,
+                    state,
+                    mode: self.memory.block_mode(block_id),
+                    write_pointer,
+                    life_used: self.memory.block_life_used(block_id),
+                })
+            })
+            .collect()
+    }
+
+    // the first program of a free block opens it in the given mode
+    pub fn program(
+        &mut self,
+        mode: CellType,
+        pages: &[(PhysicalAddress, Vec<bool>)],
+    ) -> Vec<Result<(), MediaError>> {
+        pages
+            .iter()
+            .map(|(address, bits)| self.program_page(mode, *address, bits))
+            .collect()
+    }
+
+    pub fn read(&mut self, addresses: &[PhysicalAddress]) -> Vec<Result<Vec<bool>, MediaError>> {
+        addresses
+            .iter()
+            .map(|address| self.read_page(*address))
+            .collect()
+    }
+
+    // the page of an address is ignored, the whole block is erased
+    pub fn erase(&mut self, blocks: &[PhysicalAddress]) -> Vec<Result<(), MediaError>> {
+        blocks
+            .iter()
+            .map(|address| self.erase_block(*address))
+            .collect()
+    }
+
+    fn block_id(&self, address: PhysicalAddress) -> Result<usize, MediaError> {
+        let blocks_per_die = self.memory.block_count() / DIES;
+        if address.channel >= CHANNELS
+            || address.die >= DIES / CHANNELS
+            || address.block >= blocks_per_die
+            || address.page >= PAGES_PER_BLOCK
+        {
+            return Err(MediaError::InvalidAddress);
+        }
+        let die = address.channel * (DIES / CHANNELS) + address.die;
+        Ok(die * blocks_per_die + address.block)
+    }
+
+    fn good_block(&self, address: PhysicalAddress) -> Result<usize, MediaError> {
+        let block_id = self.block_id(address)?;
+        if self.memory.is_bad(block_id) {
+            return Err(MediaError::BadBlock);
+        }
+        Ok(block_id)
+    }
+
+    fn program_page(
+        &mut self,
+        mode: CellType,
+        address: PhysicalAddress,
+        bits: &[bool],
+    ) -> Result<(), MediaError> {
+        let block_id = self.good_block(address)?;
+        if mode.multiplier() > self.memory.native_cell_type().multiplier() {
+            return Err(MediaError::DenserThanNative);
+        }
+        if self.memory.block_mode(block_id).is_some_and(|m| m != mode) {
+            return Err(MediaError::ModeMismatch);
+        }
+        if bits.len() != CELLS_PER_PAGE * mode.multiplier() as usize {
+            return Err(MediaError::InvalidLength);
+        }
+        if address.page != self.write_pointers[block_id] {
+            return Err(MediaError::OutOfOrderProgram);
+        }
+        self.memory.open_block(block_id, mode);
+        let cells = self.byte_encoder.encode_bytes_to_page(bits.to_vec(), mode);
+        // the marker goes first so a torn program still moves the write pointer past the page
+        let mut spare = [0; SPARE_BYTES_PER_PAGE];
+        spare[0] = WRITTEN_MARKER;
+        self.memory
+            .program_spare(Address(block_id, address.page), spare);
+        self.memory.program(Address(block_id, address.page), cells);
+        self.write_pointers[block_id] += 1;
+        Ok(())
+    }
+
+    fn read_page(&mut self, address: PhysicalAddress) -> Result<Vec<bool>, MediaError> {
+        let block_id = self.good_block(address)?;
+        let mode = match self.memory.block_mode(block_id) {
+            Some(mode) if address.page < self.write_pointers[block_id] => mode,
+            _ => return Err(MediaError::EmptyPage),
+        };
+        let cells = *self.memory.read(Address(block_id, address.page));
+        Ok(self.byte_encoder.decode_page_to_bytes(cells, mode))
+    }
+
+    fn erase_block(&mut self, address: PhysicalAddress) -> Result<(), MediaError> {
+        let block_id = self.good_block(address)?;
+        self.memory.reset(block_id);
+        self.write_pointers[block_id] = 0;
+        if self.memory.block_life_used(block_id) >= 1.0 {
+            self.memory.mark_bad(block_id);
+            return Err(MediaError::WornOut);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::controller::byte_encoder::ByteEncoderImpl;
+    use crate::controller::ProgramScheme;
+    use crate::physic_level::memory::MemoryImpl;
+    use crate::physic_level::memory_components::ZeroFluctuate;
+
+    #[test]
+    fn geometry_should_describe_channels_dies_and_modes() {
+        let target = setup_target();
+
+        let res = target.geometry();
+
+        assert_eq!(CHANNELS * res.dies_per_channel, DIES);
+        assert_eq!(2, res.blocks_per_die);
+        assert_eq!(
+            vec![CellType::Triple, CellType::Double, CellType::Single],
+            res.cell_types
+        );
+    }
+
+    #[test]
+    fn read_should_return_vectored_program_on_other_dies() {
+        let mut target = setup_target();
+        let pages = vec![
+            (address(0, 0, 1, 0), wordline_bits(CellType::Double, 1)),
+            (address(1, 1, 0, 0), wordline_bits(CellType::Double, 2)),
+            (address(1, 1, 0, 1), wordline_bits(CellType::Double, 3)),
+        ];
+
+        let programmed = target.program(CellType::Double, &pages);
+        let addresses: Vec<PhysicalAddress> = pages.iter().map(|(a, _)| *a).collect();
+        let res = target.read(&addresses);
+
+        assert!(programmed.iter().all(|r| r.is_ok()));
+        for ((_, bits), read) in pages.iter().zip(res) {
+            assert_eq!(Ok(bits.clone()), read);
+        }
+    }
+
+    #[test]
+    fn program_should_enforce_media_rules_per_entry() {
+        let mut target = setup_target();
+        target.program(
+            CellType::Single,
+            &[(address(0, 0, 0, 0), wordline_bits(CellType::Single, 0))],
+        );
+
+        let res = target.program(
+            CellType::Single,
+            &[
+                (address(0, 0, 0, 2), wordline_bits(CellType::Single, 1)),
+                (address(0, 0, 0, 1), wordline_bits(CellType::Double, 1)),
+                (address(0, 0, 0, 1), wordline_bits(CellType::Single, 1)),
+                (address(0, 2, 0, 0), wordline_bits(CellType::Single, 1)),
+            ],
+        );
+        let mismatch = target.program(
+            CellType::Double,
+            &[(address(0, 0, 0, 2), wordline_bits(CellType::Double, 1))],
+        );
+
+        assert_eq!(
+            vec![
+                Err(MediaError::OutOfOrderProgram),
+                Err(MediaError::InvalidLength),
+                Ok(()),
+                Err(MediaError::InvalidAddress),
+            ],
+            res
+        );
+        assert_eq!(vec![Err(MediaError::ModeMismatch)], mismatch);
+    }
+
+    #[test]
+    fn erase_should_rewind_write_pointer_and_free_block() {
+        let mut target = setup_target();
+        let block = address(1, 0, 1, 0);
+        target.program(
+            CellType::Triple,
+            &[(block, wordline_bits(CellType::Triple, 0))],
+        );
+        let open = target.block_report(1, 0)[1];
+
+        let res = target.erase(&[block]);
+
+        let erased = target.block_report(1, 0)[1];
+        assert_eq!(vec![Ok(())], res);
+        assert_eq!((BlockState::Open, 1), (open.state, open.write_pointer));
+        assert_eq!((BlockState::Free, 0), (erased.state, erased.write_pointer));
+        assert!(erased.life_used > 0.0);
+        assert_eq!(vec![Err(MediaError::EmptyPage)], target.read(&[block]));
+    }
+
+    #[test]
+    fn new_should_find_write_pointers_of_programmed_media() {
+        let mut target = setup_target();
+        let pages: Vec<(PhysicalAddress, Vec<bool>)> = (0..3)
+            .map(|page| (address(0, 1, 1, page), vec![true; CELLS_PER_PAGE]))
+            .collect();
+        target.program(CellType::Single, &pages);
+
+        let target = OpenChannelDevice::new(Box::new(ByteEncoderImpl::new()), target.into_memory());
+
+        assert_eq!(3, target.block_report(0, 1)[1].write_pointer);
+    }
+
+    #[test]
+    fn new_should_keep_pages_programmed_at_lowest_charge() {
+        let mut target = OpenChannelDevice::new(Box::new(LowestChargeEncoder), setup_memory());
+        let bits = vec![false; CELLS_PER_PAGE];
+        target.program(CellType::Single, &[(address(0, 0, 0, 0), bits.clone())]);
+
+        let mut target =
+            OpenChannelDevice::new(Box::new(LowestChargeEncoder), target.into_memory());
+
+        let block = target.block_report(0, 0)[0];
+        assert_eq!((BlockState::Open, 1), (block.state, block.write_pointer));
+        assert_eq!(vec![Ok(bits)], target.read(&[address(0, 0, 0, 0)]));
+        assert_eq!(
+            vec![Err(MediaError::OutOfOrderProgram)],
+            target.program(
+                CellType::Single,
+                &[(address(0, 0, 0, 0), vec![false; CELLS_PER_PAGE])]
+            )
+        );
+    }
+
+    // every page sits at zero charge, as an erased one does
+    struct LowestChargeEncoder;
+
+    impl ByteEncoder<CELLS_PER_PAGE> for LowestChargeEncoder {
+        fn encode_bytes_to_page(&self, _: Vec<bool>, _: CellType) -> [u8; CELLS_PER_PAGE] {
+            [0; CELLS_PER_PAGE]
+        }
+
+        fn decode_page_to_bytes(&self, cells: [u8; CELLS_PER_PAGE], _: CellType) -> Vec<bool> {
+            cells.iter().map(|c| *c != 0).collect()
+        }
+
+        fn encode_wordline_pass(
+            &self,
+            _: &[Vec<bool>],
+            _: CellType,
+            _: ProgramScheme,
+            _: usize,
+        ) -> [u8; CELLS_PER_PAGE] {
+            unimplemented!()
+        }
+
+        fn decode_wordline_page(
+            &self,
+            _: [u8; CELLS_PER_PAGE],
+            _: CellType,
+            _: usize,
+        ) -> Vec<bool> {
+            unimplemented!()
+        }
+    }
+
+    fn address(channel: usize, die: usize, block: usize, page: usize) -> PhysicalAddress {
+        PhysicalAddress {
+            channel,
+            die,
+            block,
+            page,
+        }
+    }
+
+    fn wordline_bits(mode: CellType, seed: usize) -> Vec<bool> {
+        (0..CELLS_PER_PAGE * mode.multiplier() as usize)
+            .map(|i| (i + seed).is_multiple_of(3))
+            .collect()
+    }
+
+    fn setup_target() -> OpenChannelDevice<CELLS_PER_PAGE> {
+        OpenChannelDevice::new(Box::new(ByteEncoderImpl::new()), setup_memory())
+    }
+
+    fn setup_memory() -> Box<dyn Memory> {
+        Box::new(MemoryImpl::new(
+            Box::new(ZeroFluctuate),
+            8,
+            CellType::Triple,
+        ))
+    }
+}
@@ -1,7 +1,9 @@
 pub mod config;
 mod physic_level;
 mod controller;
+mod host;
 mod metric;
+mod workload;
 fn main() {
     
 }
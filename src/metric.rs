@@ -3,10 +3,13 @@ pub mod metric_storage;
 struct TimeSeries {
     time: u32,
     value: u32,
-    metric_type: MetricType
+    metric_type: MetricType,
 }
 
+#[derive(Clone, Copy)]
 pub enum MetricType {
     Write,
-    Read
-}
\ No newline at end of file
+    Read,
+    Delete,
+    Capacity,
+}
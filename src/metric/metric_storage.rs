@@ -1,7 +1,89 @@
 use super::MetricType;
+use super::TimeSeries;
+use std::collections::HashMap;
 
 pub trait MetricStorage {
-     fn list_metric(&self) -> Vec<String>;
+    fn list_metric(&self) -> Vec<String>;
 
-     fn put_metric(&mut self, series_name: &str, bit_amount: u32, timestamp: u32, metric_type: MetricType);
+    fn put_metric(
+        &mut self,
+        series_name: &str,
+        bit_amount: u32,
+        timestamp: u32,
+        metric_type: MetricType,
+    );
+
+    // (timestamp, value) points of the series in the order they were put
+    fn get_metric(&self, series_name: &str) -> Vec<(u32, u32)>;
+}
+
+pub struct MetricStorageImpl {
+    series: HashMap<String, Vec<TimeSeries>>,
+}
+
+impl MetricStorageImpl {
+    pub fn new() -> MetricStorageImpl {
+        MetricStorageImpl {
+            series: HashMap::new(),
+        }
+    }
+}
+
+impl MetricStorage for MetricStorageImpl {
+    fn list_metric(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.series.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    fn put_metric(
+        &mut self,
+        series_name: &str,
+        bit_amount: u32,
+        timestamp: u32,
+        metric_type: MetricType,
+    ) {
+        self.series
+            .entry(series_name.to_string())
+            .or_default()
+            .push(TimeSeries {
+                time: timestamp,
+                value: bit_amount,
+                metric_type,
+            })
+    }
+
+    fn get_metric(&self, series_name: &str) -> Vec<(u32, u32)> {
+        self.series
+            .get(series_name)
+            .map(|points| points.iter().map(|p| (p.time, p.value)).collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn put_metric_should_append_points_to_series() {
+        let mut target = MetricStorageImpl::new();
+
+        target.put_metric("write", 16, 20, MetricType::Write);
+        target.put_metric("write", 32, 40, MetricType::Write);
+        target.put_metric("read", 16, 43, MetricType::Read);
+
+        assert_eq!(
+            vec!["read".to_string(), "write".to_string()],
+            target.list_metric()
+        );
+        assert_eq!(vec![(20, 16), (40, 32)], target.get_metric("write"));
+    }
+
+    #[test]
+    fn get_metric_should_return_empty_for_unknown_series() {
+        let target = MetricStorageImpl::new();
+
+        assert!(target.get_metric("erase").is_empty());
+    }
 }
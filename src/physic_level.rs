@@ -1,2 +1,5 @@
+pub mod image;
 pub mod memory;
-mod memory_components;
+pub mod memory_components;
+pub mod onfi;
+pub mod sparse_memory;
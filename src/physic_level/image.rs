@@ -0,0 +1,154 @@
+use crate::config::CELLS_PER_PAGE;
+use crate::config::PAGES_PER_BLOCK;
+use crate::config::SPARE_BYTES_PER_PAGE;
+use crate::controller::CellType;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+// media image: header, then every block in order, all numbers little endian, so it can be
+// written and read block by block
+const MAGIC: &[u8; 4] = b"SDDI";
+const VERSION: u32 = 2;
+
+pub struct Header {
+    pub native_cell_type: CellType,
+    pub block_count: usize,
+}
+
+impl Header {
+    pub fn write(&self, writer: &mut dyn Write) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        write_u32(writer, VERSION)?;
+        write_u8(writer, encode_mode(Some(self.native_cell_type)))?;
+        for value in [
+            self.block_count,
+            PAGES_PER_BLOCK,
+            CELLS_PER_PAGE,
+            SPARE_BYTES_PER_PAGE,
+        ] {
+            write_u32(writer, value as u32)?;
+        }
+        Ok(())
+    }
+
+    pub fn read(reader: &mut dyn Read) -> io::Result<Header> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a media image"));
+        }
+        if read_u32(reader)? != VERSION {
+            return Err(invalid("unsupported media image version"));
+        }
+        let native_cell_type = decode_mode(read_u8(reader)?)?
+            .ok_or_else(|| invalid("image has no native cell type"))?;
+        let block_count = read_u32(reader)? as usize;
+        for expected in [PAGES_PER_BLOCK, CELLS_PER_PAGE, SPARE_BYTES_PER_PAGE] {
+            if read_u32(reader)? as usize != expected {
+                return Err(invalid("image geometry differs from the build"));
+            }
+        }
+        Ok(Header {
+            native_cell_type,
+            block_count,
+        })
+    }
+}
+
+pub fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// zero for an erased block, bits per cell otherwise
+pub fn encode_mode(mode: Option<CellType>) -> u8 {
+    mode.map_or(0, |mode| mode.multiplier())
+}
+
+pub fn decode_mode(code: u8) -> io::Result<Option<CellType>> {
+    match code {
+        0 => Ok(None),
+        1 => Ok(Some(CellType::Single)),
+        2 => Ok(Some(CellType::Double)),
+        3 => Ok(Some(CellType::Triple)),
+        4 => Ok(Some(CellType::Quadro)),
+        5 => Ok(Some(CellType::Penta)),
+        _ => Err(invalid("unknown cell type in image")),
+    }
+}
+
+// mode of a stored block, which cannot be denser than the cells of the device
+pub fn decode_block_mode(code: u8, native_cell_type: CellType) -> io::Result<Option<CellType>> {
+    let mode = decode_mode(code)?;
+    if mode.is_some_and(|mode| mode.multiplier() > native_cell_type.multiplier()) {
+        return Err(invalid("block mode is denser than the native cell type"));
+    }
+    Ok(mode)
+}
+
+pub fn write_u8(writer: &mut dyn Write, value: u8) -> io::Result<()> {
+    writer.write_all(&[value])
+}
+
+pub fn write_u32(writer: &mut dyn Write, value: u32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+pub fn write_f64(writer: &mut dyn Write, value: f64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+pub fn read_u8(reader: &mut dyn Read) -> io::Result<u8> {
+    let mut bytes = [0; 1];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+pub fn read_u32(reader: &mut dyn Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+pub fn read_f64(reader: &mut dyn Read) -> io::Result<f64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(f64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn header_should_be_read_back() {
+        let mut bytes = Vec::new();
+        Header {
+            native_cell_type: CellType::Triple,
+            block_count: 1024,
+        }
+        .write(&mut bytes)
+        .unwrap();
+
+        let res = Header::read(&mut &bytes[..]).unwrap();
+
+        assert_eq!(CellType::Triple, res.native_cell_type);
+        assert_eq!(1024, res.block_count);
+    }
+
+    #[test]
+    fn header_should_reject_other_version() {
+        let mut bytes = Vec::new();
+        Header {
+            native_cell_type: CellType::Quadro,
+            block_count: 8,
+        }
+        .write(&mut bytes)
+        .unwrap();
+        bytes[4] = VERSION as u8 + 1;
+
+        let res = Header::read(&mut &bytes[..]);
+
+        assert_eq!(io::ErrorKind::InvalidData, res.err().unwrap().kind());
+    }
+}
@@ -1,31 +1,158 @@
 use crate::config::CELLS_PER_PAGE;
+use crate::config::DIES;
 use crate::config::PAGES_PER_BLOCK;
+use crate::config::PLANES_PER_DIE;
+use crate::config::SPARE_BYTES_PER_PAGE;
+use crate::controller::CellType;
+use crate::physic_level::image;
+use crate::physic_level::image::Header;
 use crate::physic_level::memory_components::*;
 use rand::Rng;
+use std::io;
+use std::io::Read;
+use std::io::Write;
 
 pub trait Memory {
     fn read(&self, address: Address) -> &[u8; CELLS_PER_PAGE];
     fn program(&mut self, address: Address, data: [u8; CELLS_PER_PAGE]) -> ();
+    // one pass of a multi-pass (or foggy-fine) wordline program
+    fn program_pass(&mut self, address: Address, data: [u8; CELLS_PER_PAGE]);
     fn reset(&mut self, lock_id: usize) -> ();
+    // out-of-band area of the page, erased together with the block
+    fn read_spare(&self, address: Address) -> &[u8; SPARE_BYTES_PER_PAGE];
+    fn program_spare(&mut self, address: Address, data: [u8; SPARE_BYTES_PER_PAGE]);
+    fn block_count(&self) -> usize;
+    fn native_cell_type(&self) -> CellType;
+    // assigns the density an erased block is going to be programmed with (pSLC, pMLC, ...)
+    fn open_block(&mut self, block_id: usize, mode: CellType);
+    fn block_mode(&self, block_id: usize) -> Option<CellType>;
+    fn block_life_used(&self, block_id: usize) -> f64;
+    fn is_bad(&self, block_id: usize) -> bool;
+    fn mark_bad(&mut self, block_id: usize);
+    // power goes down during the given flash operation (program, pass or erase) counted from
+    // zero, that operation is left torn and nothing after it survives the next power on
+    fn cut_power_after(&mut self, operations: usize);
+    fn is_powered(&self) -> bool;
+    // media comes back exactly as it was when the power was cut
+    fn power_on(&mut self);
+    // streams the media image (what would survive a power cycle) block by block
+    fn save(&self, writer: &mut dyn Write) -> io::Result<()>;
+    // replaces the media with an image of the same geometry
+    fn load(&mut self, reader: &mut dyn Read) -> io::Result<()>;
+
+    // moves a wordline through the page buffer without leaving the die: sensed charges,
+    // drift included, are programmed again without any correction
+    fn copyback(&mut self, from: Address, to: Address) {
+        let (Address(from_block, _), Address(to_block, _)) = (from, to);
+        let block_count = self.block_count();
+        if plane_of(from_block, block_count) != plane_of(to_block, block_count) {
+            panic!("Copyback must stay within one plane")
+        }
+        if self.block_mode(from_block) != self.block_mode(to_block) {
+            panic!("Copyback needs blocks of the same mode")
+        }
+        let cells = *self.read(from);
+        let spare = *self.read_spare(from);
+        self.program(to, cells);
+        self.program_spare(to, spare);
+    }
+}
+
+// every die owns a contiguous range of blocks
+pub fn die_of(block_id: usize, block_count: usize) -> usize {
+    block_id * DIES / block_count
+}
+
+// blocks of a die alternate between its planes
+pub fn plane_of(block_id: usize, block_count: usize) -> usize {
+    die_of(block_id, block_count) * PLANES_PER_DIE + block_id % PLANES_PER_DIE
 }
 
-struct MemoryImpl {
+type Blocks = Vec<block::Block<CELLS_PER_PAGE, PAGES_PER_BLOCK>>;
+
+pub struct MemoryImpl {
     fluctuator: Box<dyn FluctuareT>,
-    blocks: Vec<block::Block<CELLS_PER_PAGE, PAGES_PER_BLOCK>>,
+    blocks: Blocks,
+    native_cell_type: CellType,
+    operations_before_cut: Option<usize>,
+    // media as it was at the power cut, the live copy keeps running so that the controller
+    // does not notice the cut until it is restarted
+    frozen: Option<Blocks>,
 }
 
 impl MemoryImpl {
-    fn new(fluctuator: Box<dyn FluctuareT>, blocks_amount: usize) -> MemoryImpl {
+    pub fn new(
+        fluctuator: Box<dyn FluctuareT>,
+        blocks_amount: usize,
+        native_cell_type: CellType,
+    ) -> MemoryImpl {
         let mut blocks = Vec::new();
 
-        for _ in 1..blocks_amount {
+        for _ in 0..blocks_amount {
             blocks.push(block::Block::new())
         }
 
         MemoryImpl {
             fluctuator: fluctuator,
             blocks: blocks,
+            native_cell_type,
+            operations_before_cut: None,
+            frozen: None,
+        }
+    }
+
+    // device with the geometry stored in the image
+    pub fn from_image(
+        fluctuator: Box<dyn FluctuareT>,
+        reader: &mut dyn Read,
+    ) -> io::Result<MemoryImpl> {
+        let header = Header::read(reader)?;
+        let mut memory = MemoryImpl::new(fluctuator, 0, header.native_cell_type);
+        memory.load_body(&header, reader)?;
+        Ok(memory)
+    }
+
+    fn load_body(&mut self, header: &Header, reader: &mut dyn Read) -> io::Result<()> {
+        // grows with the blocks actually read, the header alone is no proof of the image size
+        let mut blocks = Vec::new();
+        for _ in 0..header.block_count {
+            blocks.push(block::Block::load(reader, header.native_cell_type)?);
+        }
+        self.blocks = blocks;
+        self.operations_before_cut = None;
+        self.frozen = None;
+        Ok(())
+    }
+
+    // true when the power goes down during the current operation
+    fn cuts_power(&mut self) -> bool {
+        match self.operations_before_cut {
+            Some(0) => {
+                self.operations_before_cut = None;
+                true
+            }
+            Some(left) => {
+                self.operations_before_cut = Some(left - 1);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn freeze(&mut self, torn: Blocks) {
+        self.frozen = Some(torn);
+    }
+
+    // only the first half of the cells got their charge before the power went down
+    fn torn_program(&mut self, address: Address, data: [u8; CELLS_PER_PAGE]) {
+        let Address(block_id, page_id) = address;
+        let mut torn = self.blocks.clone();
+        let mut half = data;
+        for cell in half.iter_mut().skip(CELLS_PER_PAGE / 2) {
+            *cell = 0;
         }
+        torn[block_id].program_pass(page_id, half, &*self.fluctuator);
+        self.freeze(torn);
     }
 }
 
@@ -35,16 +162,112 @@ impl Memory for MemoryImpl {
         self.blocks[block_id].read(page_id)
     }
     fn program(&mut self, address: Address, data: [u8; CELLS_PER_PAGE]) -> () {
+        if self.cuts_power() {
+            self.torn_program(address, data);
+        }
         let Address(block_id, page_id) = address;
         self.blocks[block_id].program(page_id, data, &mut *self.fluctuator)
     }
+    fn program_pass(&mut self, address: Address, data: [u8; CELLS_PER_PAGE]) {
+        if self.cuts_power() {
+            self.torn_program(address, data);
+        }
+        let Address(block_id, page_id) = address;
+        self.blocks[block_id].program_pass(page_id, data, &*self.fluctuator)
+    }
 
     fn reset(&mut self, block_id: usize) -> () {
+        if self.cuts_power() {
+            let mut torn = self.blocks.clone();
+            torn[block_id].interrupt_reset();
+            self.freeze(torn);
+        }
         self.blocks[block_id].reset()
     }
+
+    fn read_spare(&self, address: Address) -> &[u8; SPARE_BYTES_PER_PAGE] {
+        let Address(block_id, page_id) = address;
+        self.blocks[block_id].read_spare(page_id)
+    }
+
+    fn program_spare(&mut self, address: Address, data: [u8; SPARE_BYTES_PER_PAGE]) {
+        let Address(block_id, page_id) = address;
+        self.blocks[block_id].program_spare(page_id, data)
+    }
+
+    fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    fn native_cell_type(&self) -> CellType {
+        self.native_cell_type
+    }
+
+    fn open_block(&mut self, block_id: usize, mode: CellType) {
+        if mode.multiplier() > self.native_cell_type.multiplier() {
+            panic!("Block cannot be denser than native cell type")
+        }
+        self.blocks[block_id].open(mode)
+    }
+
+    fn block_mode(&self, block_id: usize) -> Option<CellType> {
+        self.blocks[block_id].mode()
+    }
+
+    fn block_life_used(&self, block_id: usize) -> f64 {
+        self.blocks[block_id].life_used()
+    }
+
+    fn is_bad(&self, block_id: usize) -> bool {
+        self.blocks[block_id].is_bad()
+    }
+
+    fn mark_bad(&mut self, block_id: usize) {
+        self.blocks[block_id].mark_bad()
+    }
+
+    fn cut_power_after(&mut self, operations: usize) {
+        if self.frozen.is_none() {
+            self.operations_before_cut = Some(operations);
+        }
+    }
+
+    fn is_powered(&self) -> bool {
+        self.frozen.is_none()
+    }
+
+    fn power_on(&mut self) {
+        self.operations_before_cut = None;
+        if let Some(blocks) = self.frozen.take() {
+            self.blocks = blocks;
+        }
+    }
+
+    fn save(&self, writer: &mut dyn Write) -> io::Result<()> {
+        let blocks = self.frozen.as_ref().unwrap_or(&self.blocks);
+        Header {
+            native_cell_type: self.native_cell_type,
+            block_count: blocks.len(),
+        }
+        .write(writer)?;
+        for block in blocks.iter() {
+            block.save(writer)?;
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, reader: &mut dyn Read) -> io::Result<()> {
+        let header = Header::read(reader)?;
+        if header.block_count != self.blocks.len()
+            || header.native_cell_type != self.native_cell_type
+        {
+            return Err(image::invalid("image geometry differs from the device"));
+        }
+        self.load_body(&header, reader)
+    }
 }
 
-struct ProdFluctuate {}
+pub struct ProdFluctuate {}
 impl FluctuareT for ProdFluctuate {
     fn fluctuate(&self, count: u32, value: u8) -> u8 {
         let fluctuation_size = 1000.0 / (1100.0 - count as f64);
@@ -99,7 +322,7 @@ mod test {
 
     #[test]
     fn program_should_save_value() -> () {
-        let mut target = MemoryImpl::new(Box::new(ZERO_FLU), 8);
+        let mut target = MemoryImpl::new(Box::new(ZERO_FLU), 8, CellType::Quadro);
         let mut cells = Vec::new();
         for i in 0..CELLS_PER_PAGE {
             cells.push(i as u8);
@@ -113,7 +336,7 @@ mod test {
     }
     #[test]
     fn reset_should_delete_values_in_block() -> () {
-        let mut target = MemoryImpl::new(Box::new(ZERO_FLU), 8);
+        let mut target = MemoryImpl::new(Box::new(ZERO_FLU), 8, CellType::Quadro);
         let mut cells = Vec::new();
         for i in 0..CELLS_PER_PAGE {
             cells.push(i as u8);
@@ -130,6 +353,227 @@ mod test {
         }
     }
 
+    #[test]
+    fn program_pass_should_accumulate_passes() {
+        let mut target = MemoryImpl::new(Box::new(ZERO_FLU), 8, CellType::Quadro);
+        let address = Address(3, 7);
+
+        target.program_pass(address, [15; CELLS_PER_PAGE]);
+        target.program_pass(address, [47; CELLS_PER_PAGE]);
+
+        assert_eq!([47; CELLS_PER_PAGE], *target.read(address))
+    }
+
+    #[test]
+    fn new_should_create_all_blocks() {
+        let target = MemoryImpl::new(Box::new(ZERO_FLU), 8, CellType::Quadro);
+
+        assert_eq!(8, target.block_count());
+    }
+
+    #[test]
+    fn open_block_should_track_pseudo_slc_mode_until_reset() {
+        let mut target = MemoryImpl::new(Box::new(ZERO_FLU), 8, CellType::Quadro);
+
+        target.open_block(5, CellType::Single);
+        assert_eq!(Some(CellType::Single), target.block_mode(5));
+        assert_eq!(None, target.block_mode(4));
+
+        target.reset(5);
+        assert_eq!(None, target.block_mode(5));
+        assert!(target.block_life_used(5) > 0.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Block cannot be denser than native cell type")]
+    fn open_block_should_panic_when_denser_than_native() {
+        let mut target = MemoryImpl::new(Box::new(ZERO_FLU), 8, CellType::Triple);
+
+        target.open_block(1, CellType::Quadro);
+    }
+
+    #[test]
+    fn program_spare_should_be_erased_with_block() {
+        let mut target = MemoryImpl::new(Box::new(ZERO_FLU), 8, CellType::Quadro);
+        let address = Address(2, 3);
+        let mut spare = [0; SPARE_BYTES_PER_PAGE];
+        spare[0] = 42;
+
+        target.program_spare(address, spare);
+        assert_eq!(spare, *target.read_spare(address));
+
+        target.reset(2);
+        assert_eq!([0; SPARE_BYTES_PER_PAGE], *target.read_spare(address));
+    }
+
+    #[test]
+    fn power_on_should_restore_media_as_it_was_at_cut() {
+        let mut target = MemoryImpl::new(Box::new(ZERO_FLU), 8, CellType::Quadro);
+        target.program(Address(1, 0), [64; CELLS_PER_PAGE]);
+
+        target.cut_power_after(1);
+        target.program(Address(1, 1), [64; CELLS_PER_PAGE]);
+        assert!(target.is_powered());
+        target.program(Address(1, 2), [64; CELLS_PER_PAGE]);
+        assert!(!target.is_powered());
+        target.program(Address(1, 3), [64; CELLS_PER_PAGE]);
+        assert_eq!([64; CELLS_PER_PAGE], *target.read(Address(1, 3)));
+
+        target.power_on();
+
+        assert!(target.is_powered());
+        assert_eq!([64; CELLS_PER_PAGE], *target.read(Address(1, 1)));
+        let torn = target.read(Address(1, 2));
+        assert_eq!([64; CELLS_PER_PAGE / 2], torn[..CELLS_PER_PAGE / 2]);
+        assert_eq!([0; CELLS_PER_PAGE / 2], torn[CELLS_PER_PAGE / 2..]);
+        assert_eq!([0; CELLS_PER_PAGE], *target.read(Address(1, 3)));
+    }
+
+    #[test]
+    fn power_cut_during_reset_should_leave_block_partially_erased() {
+        let mut target = MemoryImpl::new(Box::new(ZERO_FLU), 8, CellType::Quadro);
+        target.open_block(4, CellType::Quadro);
+        for i in 0..PAGES_PER_BLOCK {
+            target.program(Address(4, i), [64; CELLS_PER_PAGE]);
+        }
+
+        target.cut_power_after(0);
+        target.reset(4);
+        target.power_on();
+
+        assert_eq!(Some(CellType::Quadro), target.block_mode(4));
+        assert_eq!([0; CELLS_PER_PAGE], *target.read(Address(4, 0)));
+        assert_eq!(
+            [64; CELLS_PER_PAGE],
+            *target.read(Address(4, PAGES_PER_BLOCK - 1))
+        );
+    }
+
+    #[test]
+    fn from_image_should_restore_saved_media() {
+        let mut target = MemoryImpl::new(Box::new(ZERO_FLU), 8, CellType::Quadro);
+        target.open_block(2, CellType::Single);
+        target.program(Address(2, 5), [64; CELLS_PER_PAGE]);
+        let mut spare = [0; SPARE_BYTES_PER_PAGE];
+        spare[3] = 9;
+        target.program_spare(Address(2, 5), spare);
+        target.reset(1);
+        target.mark_bad(7);
+        let mut bytes = Vec::new();
+        target.save(&mut bytes).unwrap();
+
+        let res = MemoryImpl::from_image(Box::new(ZERO_FLU), &mut &bytes[..]).unwrap();
+
+        assert_eq!(8, res.block_count());
+        assert_eq!(CellType::Quadro, res.native_cell_type());
+        assert_eq!(Some(CellType::Single), res.block_mode(2));
+        assert_eq!([64; CELLS_PER_PAGE], *res.read(Address(2, 5)));
+        assert_eq!(spare, *res.read_spare(Address(2, 5)));
+        assert_eq!(target.block_life_used(1), res.block_life_used(1));
+        assert!(res.is_bad(7));
+    }
+
+    #[test]
+    fn load_should_reject_image_of_other_geometry() {
+        let mut bytes = Vec::new();
+        MemoryImpl::new(Box::new(ZERO_FLU), 8, CellType::Quadro)
+            .save(&mut bytes)
+            .unwrap();
+        let mut target = MemoryImpl::new(Box::new(ZERO_FLU), 4, CellType::Quadro);
+
+        let res = target.load(&mut &bytes[..]);
+
+        assert_eq!(io::ErrorKind::InvalidData, res.err().unwrap().kind());
+    }
+
+    #[test]
+    fn from_image_should_reject_block_denser_than_native_cell_type() {
+        let mut bytes = Vec::new();
+        MemoryImpl::new(Box::new(ZERO_FLU), 8, CellType::Quadro)
+            .save(&mut bytes)
+            .unwrap();
+        // mode of the first block, right after the header
+        bytes[25] = image::encode_mode(Some(CellType::Penta));
+
+        let res = MemoryImpl::from_image(Box::new(ZERO_FLU), &mut &bytes[..]);
+
+        assert_eq!(io::ErrorKind::InvalidData, res.err().unwrap().kind());
+    }
+
+    #[test]
+    fn from_image_should_fail_on_short_image_claiming_huge_block_count() {
+        let mut bytes = Vec::new();
+        Header {
+            native_cell_type: CellType::Quadro,
+            block_count: u32::MAX as usize,
+        }
+        .write(&mut bytes)
+        .unwrap();
+
+        let res = MemoryImpl::from_image(Box::new(ZERO_FLU), &mut &bytes[..]);
+
+        assert_eq!(io::ErrorKind::UnexpectedEof, res.err().unwrap().kind());
+    }
+
+    #[test]
+    fn save_should_write_media_as_it_was_at_power_cut() {
+        let mut target = MemoryImpl::new(Box::new(ZERO_FLU), 8, CellType::Quadro);
+        target.cut_power_after(0);
+        target.program(Address(0, 0), [64; CELLS_PER_PAGE]);
+        let mut bytes = Vec::new();
+        target.save(&mut bytes).unwrap();
+
+        let res = MemoryImpl::from_image(Box::new(ZERO_FLU), &mut &bytes[..]).unwrap();
+
+        assert_eq!(0, res.read(Address(0, 0))[CELLS_PER_PAGE - 1]);
+    }
+
+    #[test]
+    fn copyback_should_move_page_with_its_drift() {
+        let mut target = MemoryImpl::new(Box::new(DriftFluctuate), 16, CellType::Quadro);
+        target.open_block(0, CellType::Double);
+        target.open_block(2, CellType::Double);
+        target.program(Address(0, 3), [40; CELLS_PER_PAGE]);
+        let mut spare = [0; SPARE_BYTES_PER_PAGE];
+        spare[0] = 1;
+        target.program_spare(Address(0, 3), spare);
+
+        target.copyback(Address(0, 3), Address(2, 0));
+
+        assert_eq!([42; CELLS_PER_PAGE], *target.read(Address(0, 3)));
+        assert_eq!([44; CELLS_PER_PAGE], *target.read(Address(2, 0)));
+        assert_eq!(spare, *target.read_spare(Address(2, 0)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Copyback must stay within one plane")]
+    fn copyback_should_panic_across_planes() {
+        let mut target = MemoryImpl::new(Box::new(ZERO_FLU), 16, CellType::Quadro);
+
+        target.copyback(Address(0, 0), Address(1, 0));
+    }
+
+    #[test]
+    fn plane_of_should_split_dies_into_planes() {
+        assert_eq!(0, die_of(3, 16));
+        assert_eq!(1, die_of(4, 16));
+        assert_eq!(
+            vec![0, 1, 0, 1, 2, 3],
+            (0..6).map(|b| plane_of(b, 16)).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn mark_bad_should_survive_reset() {
+        let mut target = MemoryImpl::new(Box::new(ZERO_FLU), 8, CellType::Quadro);
+
+        target.mark_bad(6);
+        target.reset(6);
+
+        assert!(target.is_bad(6));
+        assert!(!target.is_bad(5));
+    }
+
     struct ZeroFluctuate;
     impl FluctuareT for ZeroFluctuate {
         fn fluctuate(&self, _: u32, value: u8) -> u8 {
@@ -138,8 +582,15 @@ mod test {
     }
 
     const ZERO_FLU: ZeroFluctuate = ZeroFluctuate {};
+
+    struct DriftFluctuate;
+    impl FluctuareT for DriftFluctuate {
+        fn fluctuate(&self, _: u32, value: u8) -> u8 {
+            value.saturating_add(2)
+        }
+    }
 }
 
 // Address(block page)
-#[derive(Clone, Copy)]
-pub struct Address(usize, usize);
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Address(pub usize, pub usize);
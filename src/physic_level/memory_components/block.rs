@@ -1,9 +1,20 @@
+use crate::config::SPARE_BYTES_PER_PAGE;
+use crate::controller::CellType;
+use crate::physic_level::image;
 use crate::physic_level::memory_components;
 use crate::physic_level::memory_components::page::Page;
 use std::convert::TryInto;
+use std::io;
+use std::io::Read;
+use std::io::Write;
 
+#[derive(Clone)]
 pub struct Block<const PS: usize, const BS: usize> {
     pages: [Page<PS>; BS],
+    mode: Option<CellType>,
+    // share of endurance already spent, every erase costs 1 / endurance of the mode it ran in
+    life_used: f64,
+    bad: bool,
 }
 
 impl<const PS: usize, const BS: usize> Block<PS, BS> {
@@ -17,9 +28,41 @@ impl<const PS: usize, const BS: usize> Block<PS, BS> {
             pages: pages_v.try_into().unwrap_or_else(|v: Vec<Page<PS>>| {
                 panic!("Expected a Vec of length {} but it was {}", PS, v.len())
             }),
+            mode: None,
+            life_used: 0.0,
+            bad: false,
         }
     }
 
+    pub fn open(&mut self, mode: CellType) {
+        match self.mode {
+            Some(current) if current != mode => {
+                panic!("Cannot change mode of programmed block")
+            }
+            _ => self.mode = Some(mode),
+        }
+    }
+
+    pub fn mode(&self) -> Option<CellType> {
+        self.mode
+    }
+
+    pub fn life_used(&self) -> f64 {
+        self.life_used
+    }
+
+    pub fn is_bad(&self) -> bool {
+        self.bad
+    }
+
+    pub fn mark_bad(&mut self) {
+        self.bad = true;
+    }
+
+    pub fn erase_count(&self) -> u32 {
+        self.pages[0].write_count
+    }
+
     pub fn read(&self, page_id: usize) -> &[u8; PS] {
         self.pages[page_id].read()
     }
@@ -33,10 +76,66 @@ impl<const PS: usize, const BS: usize> Block<PS, BS> {
         self.pages[page_id].program(data, f)
     }
 
+    pub fn program_pass(
+        &mut self,
+        page_id: usize,
+        data: [u8; PS],
+        f: &dyn memory_components::FluctuareT,
+    ) {
+        self.pages[page_id].program_pass(data, f)
+    }
+
+    pub fn program_spare(&mut self, page_id: usize, data: [u8; SPARE_BYTES_PER_PAGE]) {
+        self.pages[page_id].program_spare(data)
+    }
+
+    pub fn read_spare(&self, page_id: usize) -> &[u8; SPARE_BYTES_PER_PAGE] {
+        self.pages[page_id].read_spare()
+    }
+
     pub fn reset(&mut self) -> () {
         for i in 0..self.pages.len() {
             self.pages[i].reset();
         }
+        if let Some(mode) = self.mode.take() {
+            self.life_used += 1.0 / mode.endurance() as f64;
+        }
+    }
+
+    pub fn save(&self, writer: &mut dyn Write) -> io::Result<()> {
+        image::write_u8(writer, image::encode_mode(self.mode))?;
+        image::write_f64(writer, self.life_used)?;
+        image::write_u8(writer, self.bad as u8)?;
+        for page in self.pages.iter() {
+            page.save(writer)?;
+        }
+        Ok(())
+    }
+
+    pub fn load(reader: &mut dyn Read, native_cell_type: CellType) -> io::Result<Block<PS, BS>> {
+        let mode = image::decode_block_mode(image::read_u8(reader)?, native_cell_type)?;
+        let life_used = image::read_f64(reader)?;
+        let bad = image::read_u8(reader)? != 0;
+        let mut pages = Vec::new();
+        for _ in 0..BS {
+            pages.push(Page::load(reader)?);
+        }
+        Ok(Block {
+            pages: pages
+                .try_into()
+                .unwrap_or_else(|_: Vec<Page<PS>>| unreachable!()),
+            mode,
+            life_used,
+            bad,
+        })
+    }
+
+    // erase stopped halfway: the first half of the pages is erased, the block keeps its mode
+    // and has to be erased again before programming
+    pub fn interrupt_reset(&mut self) {
+        for i in 0..self.pages.len() / 2 {
+            self.pages[i].reset();
+        }
     }
 }
 
@@ -74,6 +173,81 @@ mod test {
         }
     }
 
+    #[test]
+    fn program_pass_should_refine_only_given_wordline() {
+        let mut target = setup_target();
+
+        target.program_pass(2, [16, 16, 0, 0], &ZERO_FLU);
+        target.program_pass(2, [16, 48, 80, 0], &ZERO_FLU);
+
+        assert_eq!([16, 48, 80, 0], *target.read(2));
+        assert_eq!([0; PAGE_SIZE], *target.read(1));
+    }
+
+    #[test]
+    fn reset_should_clear_mode_and_spend_life_of_mode() {
+        let mut slc = setup_target();
+        let mut qlc = setup_target();
+
+        slc.open(CellType::Single);
+        qlc.open(CellType::Quadro);
+        assert_eq!(Some(CellType::Single), slc.mode());
+
+        slc.reset();
+        qlc.reset();
+
+        assert_eq!(None, slc.mode());
+        assert_eq!(1, slc.erase_count());
+        assert!(slc.life_used() < qlc.life_used());
+    }
+
+    #[test]
+    fn interrupt_reset_should_keep_mode_and_second_half_of_pages() {
+        let mut target = setup_target();
+        target.open(CellType::Double);
+        for i in 0..BLOCK_SIZE {
+            target.program(i, [64, 64, 0, 0], &ZERO_FLU);
+        }
+
+        target.interrupt_reset();
+
+        assert_eq!(Some(CellType::Double), target.mode());
+        assert_eq!([0; PAGE_SIZE], *target.read(1));
+        assert_eq!([64, 64, 0, 0], *target.read(2));
+    }
+
+    #[test]
+    fn load_should_read_back_saved_block() {
+        let mut target = setup_target();
+        target.open(CellType::Double);
+        target.program(1, [64, 32, 0, 0], &ZERO_FLU);
+        target.reset();
+        target.open(CellType::Single);
+        target.program(3, [96, 0, 0, 96], &ZERO_FLU);
+        target.mark_bad();
+        let mut bytes = Vec::new();
+        target.save(&mut bytes).unwrap();
+
+        let res: Block<PAGE_SIZE, BLOCK_SIZE> =
+            Block::load(&mut &bytes[..], CellType::Quadro).unwrap();
+
+        assert_eq!(Some(CellType::Single), res.mode());
+        assert_eq!(target.life_used(), res.life_used());
+        assert!(res.is_bad());
+        assert_eq!(1, res.erase_count());
+        assert_eq!([96, 0, 0, 96], *res.read(3));
+        assert_eq!([0; PAGE_SIZE], *res.read(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot change mode of programmed block")]
+    fn open_should_panic_when_mode_differs() {
+        let mut target = setup_target();
+
+        target.open(CellType::Triple);
+        target.open(CellType::Single);
+    }
+
     fn setup_target() -> Block<PAGE_SIZE, BLOCK_SIZE> {
         Block::new()
     }
@@ -1,16 +1,54 @@
+use crate::config::SPARE_BYTES_PER_PAGE;
+use crate::physic_level::image;
 use crate::physic_level::memory_components;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+#[derive(Clone)]
 pub struct Page<const PS: usize> {
     cells: [u8; PS],
+    spare: [u8; SPARE_BYTES_PER_PAGE],
     pub write_count: u32,
 }
 impl<const PS: usize> Page<PS> {
     pub fn new() -> Page<PS> {
         Page {
             cells: [0; PS],
+            spare: [0; SPARE_BYTES_PER_PAGE],
             write_count: 0,
         }
     }
 
+    pub fn from_parts(
+        cells: [u8; PS],
+        spare: [u8; SPARE_BYTES_PER_PAGE],
+        write_count: u32,
+    ) -> Page<PS> {
+        Page {
+            cells,
+            spare,
+            write_count,
+        }
+    }
+
+    // zero bytes are skipped, so metadata of every wordline pass can be added separately
+    pub fn program_spare(&mut self, data: [u8; SPARE_BYTES_PER_PAGE]) {
+        for (i, e) in data.iter().enumerate() {
+            if *e == 0 || self.spare[i] == *e {
+                continue;
+            }
+            if self.spare[i] == 0 {
+                self.spare[i] = *e;
+            } else {
+                panic!("Cannot program non-empty spare byte")
+            }
+        }
+    }
+
+    pub fn read_spare(&self) -> &[u8; SPARE_BYTES_PER_PAGE] {
+        &self.spare
+    }
+
     pub fn program(&mut self, data: [u8; PS], f: &dyn memory_components::FluctuareT) -> () {
         for (i, e) in data.iter().enumerate() {
             if self.cells[i] == 0 {
@@ -21,14 +59,43 @@ impl<const PS: usize> Page<PS> {
         }
     }
 
+    // charge can only be added to a cell, so a pass never lowers an already programmed value
+    pub fn program_pass(&mut self, data: [u8; PS], f: &dyn memory_components::FluctuareT) {
+        for (i, e) in data.iter().enumerate() {
+            if *e > self.cells[i] {
+                self.cells[i] = self.cells[i].max(f.fluctuate(self.write_count, *e));
+            }
+        }
+    }
+
     pub fn read(&self) -> &[u8; PS] {
         &self.cells
     }
 
+    pub fn save(&self, writer: &mut dyn Write) -> io::Result<()> {
+        image::write_u32(writer, self.write_count)?;
+        writer.write_all(&self.cells)?;
+        writer.write_all(&self.spare)
+    }
+
+    pub fn load(reader: &mut dyn Read) -> io::Result<Page<PS>> {
+        let write_count = image::read_u32(reader)?;
+        let mut cells = [0; PS];
+        reader.read_exact(&mut cells)?;
+        let mut spare = [0; SPARE_BYTES_PER_PAGE];
+        reader.read_exact(&mut spare)?;
+        Ok(Page {
+            cells,
+            spare,
+            write_count,
+        })
+    }
+
     pub fn reset(&mut self) -> () {
         for i in 0..self.cells.len() {
             self.cells[i] = 0;
         }
+        self.spare = [0; SPARE_BYTES_PER_PAGE];
         self.write_count = self.write_count + 1;
     }
 }
@@ -63,6 +130,56 @@ mod test {
         assert_eq!(1, count_size_after_reset - count_size_before_reset)
     }
 
+    #[test]
+    fn program_pass_should_add_charge_to_programmed_page() {
+        let mut target: Page<PAGE_SIZE> = Page::new();
+
+        target.program_pass([16, 0, 16, 0], &ZERO_FLU);
+        target.program_pass([48, 48, 16, 0], &ZERO_FLU);
+
+        assert_eq!([48, 48, 16, 0], *target.read());
+    }
+
+    #[test]
+    fn program_pass_should_not_lower_charge() {
+        let mut target: Page<PAGE_SIZE> = Page::new();
+
+        target.program_pass([64, 64, 64, 64], &ZERO_FLU);
+        target.program_pass([32, 64, 96, 0], &ZERO_FLU);
+
+        assert_eq!([64, 64, 96, 64], *target.read());
+    }
+
+    #[test]
+    fn program_spare_should_merge_bytes_until_reset() {
+        let mut target: Page<PAGE_SIZE> = Page::new();
+        let mut first = [0; SPARE_BYTES_PER_PAGE];
+        first[0] = 7;
+        let mut second = [0; SPARE_BYTES_PER_PAGE];
+        second[4] = 9;
+
+        target.program_spare(first);
+        target.program_spare(second);
+        assert_eq!(7, target.read_spare()[0]);
+        assert_eq!(9, target.read_spare()[4]);
+
+        target.reset();
+        assert_eq!([0; SPARE_BYTES_PER_PAGE], *target.read_spare());
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot program non-empty spare byte")]
+    fn program_spare_should_panic_when_byte_already_set() {
+        let mut target: Page<PAGE_SIZE> = Page::new();
+        let mut first = [0; SPARE_BYTES_PER_PAGE];
+        first[1] = 7;
+        let mut second = [0; SPARE_BYTES_PER_PAGE];
+        second[1] = 8;
+
+        target.program_spare(first);
+        target.program_spare(second);
+    }
+
     const PAGE_SIZE: usize = 4;
 
     struct ZeroFluctuate;
@@ -0,0 +1,460 @@
+use crate::config::CELLS_PER_PAGE;
+use crate::config::PAGES_PER_BLOCK;
+use crate::config::SPARE_BYTES_PER_PAGE;
+use crate::physic_level::memory::Address;
+use crate::physic_level::memory::Memory;
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+// command set of an ONFI target, the page register holds the cells of the page followed by
+// its spare area, so a column addresses a single cell charge or spare byte
+pub const READ: u8 = 0x00;
+pub const READ_CONFIRM: u8 = 0x30;
+pub const PROGRAM: u8 = 0x80;
+pub const PROGRAM_CONFIRM: u8 = 0x10;
+pub const ERASE: u8 = 0x60;
+pub const ERASE_CONFIRM: u8 = 0xD0;
+pub const READ_STATUS: u8 = 0x70;
+pub const GET_FEATURES: u8 = 0xEE;
+pub const SET_FEATURES: u8 = 0xEF;
+pub const READ_PARAMETER_PAGE: u8 = 0xEC;
+pub const RESET: u8 = 0xFF;
+
+pub const STATUS_FAIL: u8 = 0x01;
+pub const STATUS_ARDY: u8 = 0x20;
+pub const STATUS_RDY: u8 = 0x40;
+// write protect is active low, the simulator is never protected
+pub const STATUS_WP_N: u8 = 0x80;
+
+pub const PAGE_REGISTER_BYTES: usize = CELLS_PER_PAGE + SPARE_BYTES_PER_PAGE;
+pub const COLUMN_CYCLES: usize = 2;
+pub const ROW_CYCLES: usize = 3;
+pub const FEATURE_BYTES: usize = 4;
+pub const PARAMETER_PAGE_BYTES: usize = 256;
+// the parameter page is repeated, so the host can pick a copy with a valid CRC
+const PARAMETER_PAGE_COPIES: usize = 3;
+const READY: u8 = STATUS_WP_N | STATUS_RDY | STATUS_ARDY;
+
+enum Output {
+    Nothing,
+    Register,
+    Status,
+    Bytes(Vec<u8>, usize),
+}
+
+pub struct OnfiDevice {
+    memory: Box<dyn Memory>,
+    // first cycle of the command being assembled
+    command: Option<u8>,
+    addresses: Vec<u8>,
+    register: [u8; PAGE_REGISTER_BYTES],
+    column: usize,
+    output: Output,
+    feature_data: Vec<u8>,
+    features: HashMap<u8, [u8; FEATURE_BYTES]>,
+    status: u8,
+}
+
+impl OnfiDevice {
+    pub fn new(memory: Box<dyn Memory>) -> OnfiDevice {
+        OnfiDevice {
+            memory,
+            command: None,
+            addresses: Vec::new(),
+            register: [0; PAGE_REGISTER_BYTES],
+            column: 0,
+            output: Output::Nothing,
+            feature_data: Vec::new(),
+            features: HashMap::new(),
+            status: READY,
+        }
+    }
+
+    pub fn into_memory(self) -> Box<dyn Memory> {
+        self.memory
+    }
+
+    pub fn command(&mut self, opcode: u8) {
+        match opcode {
+            READ | PROGRAM | ERASE | GET_FEATURES | SET_FEATURES | READ_PARAMETER_PAGE => {
+                self.command = Some(opcode);
+                self.addresses.clear();
+                self.feature_data.clear();
+                self.output = Output::Nothing;
+                if opcode == PROGRAM {
+                    self.register = [0; PAGE_REGISTER_BYTES];
+                }
+            }
+            READ_CONFIRM => {
+                self.expect(READ, COLUMN_CYCLES + ROW_CYCLES, opcode);
+                self.read_page();
+            }
+            PROGRAM_CONFIRM => {
+                self.expect(PROGRAM, COLUMN_CYCLES + ROW_CYCLES, opcode);
+                self.program_page();
+            }
+            ERASE_CONFIRM => {
+                self.expect(ERASE, ROW_CYCLES, opcode);
+                self.erase_block();
+            }
+            READ_STATUS => self.output = Output::Status,
+            RESET => {
+                self.command = None;
+                self.addresses.clear();
+                self.output = Output::Nothing;
+                self.status = READY;
+            }
+            _ => panic!("Unsupported command {:02X}h", opcode),
+        }
+    }
+
+    pub fn address(&mut self, cycle: u8) {
+        let command = match self.command {
+            Some(command) => command,
+            None => panic!("Address cycle without command"),
+        };
+        let expected = match command {
+            READ | PROGRAM => COLUMN_CYCLES + ROW_CYCLES,
+            ERASE => ROW_CYCLES,
+            _ => 1,
+        };
+        if self.addresses.len() == expected {
+            panic!("Too many address cycles for {:02X}h", command)
+        }
+        self.addresses.push(cycle);
+        match command {
+            PROGRAM if self.addresses.len() == expected => self.column = self.column_address(),
+            GET_FEATURES => {
+                let data = self.features.get(&cycle).copied().unwrap_or_default();
+                self.output = Output::Bytes(data.to_vec(), 0);
+                self.command = None;
+            }
+            READ_PARAMETER_PAGE => {
+                self.output = Output::Bytes(self.parameter_page(), 0);
+                self.command = None;
+            }
+            _ => {}
+        }
+    }
+
+    pub fn write_data(&mut self, byte: u8) {
+        match self.command {
+            Some(PROGRAM) if self.addresses.len() == COLUMN_CYCLES + ROW_CYCLES => {
+                if self.column >= PAGE_REGISTER_BYTES {
+                    panic!("Column is out of page register")
+                }
+                self.register[self.column] = byte;
+                self.column += 1;
+            }
+            Some(SET_FEATURES) if self.addresses.len() == 1 => {
+                self.feature_data.push(byte);
+                if self.feature_data.len() == FEATURE_BYTES {
+                    let data = self.feature_data.as_slice().try_into().unwrap();
+                    self.features.insert(self.addresses[0], data);
+                    self.command = None;
+                }
+            }
+            _ => panic!("Data input is not expected"),
+        }
+    }
+
+    pub fn read_data(&mut self) -> u8 {
+        match &mut self.output {
+            Output::Nothing => panic!("Data output is not expected"),
+            Output::Status => self.status,
+            Output::Register => {
+                if self.column >= PAGE_REGISTER_BYTES {
+                    panic!("Column is out of page register")
+                }
+                self.column += 1;
+                self.register[self.column - 1]
+            }
+            Output::Bytes(bytes, position) => {
+                if *position >= bytes.len() {
+                    panic!("Data output is over")
+                }
+                *position += 1;
+                bytes[*position - 1]
+            }
+        }
+    }
+
+    fn expect(&self, first: u8, cycles: usize, opcode: u8) {
+        if self.command != Some(first) {
+            panic!("Command {:02X}h has to follow {:02X}h", opcode, first)
+        }
+        if self.addresses.len() != cycles {
+            panic!("Command {:02X}h needs {} address cycles", opcode, cycles)
+        }
+    }
+
+    fn column_address(&self) -> usize {
+        self.addresses[0] as usize | (self.addresses[1] as usize) << 8
+    }
+
+    // row cycles follow column cycles, the row is the page number counted over all blocks
+    fn row_address(&self) -> Option<Address> {
+        let row = self.addresses[self.addresses.len() - ROW_CYCLES..]
+            .iter()
+            .rev()
+            .fold(0, |row, cycle| row << 8 | *cycle as usize);
+        let block = row / PAGES_PER_BLOCK;
+        if block >= self.memory.block_count() {
+            return None;
+        }
+        Some(Address(block, row % PAGES_PER_BLOCK))
+    }
+
+    fn read_page(&mut self) {
+        self.command = None;
+        self.status = READY;
+        self.column = self.column_address();
+        self.output = Output::Register;
+        self.register = [0; PAGE_REGISTER_BYTES];
+        match self.row_address() {
+            Some(address) => {
+                self.register[..CELLS_PER_PAGE].copy_from_slice(self.memory.read(address));
+                self.register[CELLS_PER_PAGE..].copy_from_slice(self.memory.read_spare(address));
+            }
+            None => self.status |= STATUS_FAIL,
+        }
+    }
+
+    // a page can be programmed once after erase, overwriting charge reports a failure
+    fn program_page(&mut self) {
+        self.command = None;
+        self.status = READY;
+        let address = match self.row_address() {
+            Some(address) if !self.memory.is_bad(address.0) => address,
+            _ => {
+                self.status |= STATUS_FAIL;
+                return;
+            }
+        };
+        let cells: [u8; CELLS_PER_PAGE] = self.register[..CELLS_PER_PAGE].try_into().unwrap();
+        let spare: [u8; SPARE_BYTES_PER_PAGE] = self.register[CELLS_PER_PAGE..].try_into().unwrap();
+        let programmed = self.memory.read(address).iter().any(|cell| *cell != 0);
+        let spare_conflict = self
+            .memory
+            .read_spare(address)
+            .iter()
+            .zip(spare.iter())
+            .any(|(old, new)| *old != 0 && *new != 0 && old != new);
+        if programmed || spare_conflict {
+            self.status |= STATUS_FAIL;
+            return;
+        }
+        if self.memory.block_mode(address.0).is_none() {
+            let native = self.memory.native_cell_type();
+            self.memory.open_block(address.0, native);
+        }
+        self.memory.program(address, cells);
+        self.memory.program_spare(address, spare);
+    }
+
+    fn erase_block(&mut self) {
+        self.command = None;
+        self.status = READY;
+        match self.row_address() {
+            Some(Address(block, _)) if !self.memory.is_bad(block) => self.memory.reset(block),
+            _ => self.status |= STATUS_FAIL,
+        }
+    }
+
+    fn parameter_page(&self) -> Vec<u8> {
+        let mut page = [0; PARAMETER_PAGE_BYTES];
+        page[0..4].copy_from_slice(b"ONFI");
+        // revision 1.0
+        page[4] = 0x02;
+        page[32..44].copy_from_slice(b"SDD         ");
+        page[44..64].copy_from_slice(b"SIMULATED NAND      ");
+        page[80..84].copy_from_slice(&(CELLS_PER_PAGE as u32).to_le_bytes());
+        page[84..86].copy_from_slice(&(SPARE_BYTES_PER_PAGE as u16).to_le_bytes());
+        page[92..96].copy_from_slice(&(PAGES_PER_BLOCK as u32).to_le_bytes());
+        page[96..100].copy_from_slice(&(self.memory.block_count() as u32).to_le_bytes());
+        page[100] = 1;
+        page[101] = (COLUMN_CYCLES << 4 | ROW_CYCLES) as u8;
+        page[102] = self.memory.native_cell_type().multiplier();
+        let crc = parameter_page_crc(&page[..PARAMETER_PAGE_BYTES - 2]);
+        page[PARAMETER_PAGE_BYTES - 2..].copy_from_slice(&crc.to_le_bytes());
+        page.repeat(PARAMETER_PAGE_COPIES)
+    }
+}
+
+// CRC-16 of the ONFI specification: polynomial 8005h, initial value 4F4Eh, no reflection
+pub fn parameter_page_crc(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0x4F4E;
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::controller::CellType;
+    use crate::physic_level::memory::MemoryImpl;
+    use crate::physic_level::memory_components::ZeroFluctuate;
+
+    #[test]
+    fn read_should_return_programmed_page_with_spare() {
+        let mut target = setup_target();
+        let cells: Vec<u8> = (1..=CELLS_PER_PAGE as u8).collect();
+        let mut data = cells.clone();
+        data.extend([0, 0, 0, 0, 0, 0, 0, 0xAB]);
+
+        program(&mut target, 2, 5, 0, &data);
+
+        assert_eq!(READY, read_status(&mut target));
+        let res = read(&mut target, 2, 5, 0, PAGE_REGISTER_BYTES);
+        assert_eq!(cells, res[..CELLS_PER_PAGE]);
+        assert_eq!(0xAB, res[CELLS_PER_PAGE + 7]);
+        let memory = target.into_memory();
+        assert_eq!(cells, memory.read(Address(2, 5)));
+        assert_eq!(Some(CellType::Single), memory.block_mode(2));
+    }
+
+    #[test]
+    fn read_should_start_output_at_column() {
+        let mut target = setup_target();
+        program(&mut target, 0, 1, 3, &[9, 8]);
+
+        let res = read(&mut target, 0, 1, 2, 3);
+
+        assert_eq!(vec![0, 9, 8], res);
+    }
+
+    #[test]
+    fn program_should_fail_on_programmed_page_until_erase() {
+        let mut target = setup_target();
+        program(&mut target, 1, 0, 0, &[100]);
+
+        program(&mut target, 1, 0, 0, &[50]);
+        assert_eq!(READY | STATUS_FAIL, read_status(&mut target));
+
+        erase(&mut target, 1);
+        assert_eq!(READY, read_status(&mut target));
+        program(&mut target, 1, 0, 0, &[50]);
+        assert_eq!(READY, read_status(&mut target));
+        assert_eq!(vec![50], read(&mut target, 1, 0, 0, 1));
+    }
+
+    #[test]
+    fn erase_should_fail_on_bad_and_missing_block() {
+        let mut target = setup_target();
+
+        erase(&mut target, 8);
+        assert_eq!(READY | STATUS_FAIL, read_status(&mut target));
+
+        let mut memory = target.into_memory();
+        memory.mark_bad(3);
+        let mut target = OnfiDevice::new(memory);
+        erase(&mut target, 3);
+        assert_eq!(READY | STATUS_FAIL, read_status(&mut target));
+    }
+
+    #[test]
+    fn get_features_should_return_what_set_features_stored() {
+        let mut target = setup_target();
+        target.command(SET_FEATURES);
+        target.address(0x01);
+        for byte in [4, 0, 0, 0] {
+            target.write_data(byte);
+        }
+
+        target.command(GET_FEATURES);
+        target.address(0x01);
+        let res: Vec<u8> = (0..FEATURE_BYTES).map(|_| target.read_data()).collect();
+
+        assert_eq!(vec![4, 0, 0, 0], res);
+    }
+
+    #[test]
+    fn parameter_page_should_describe_geometry_with_valid_crc() {
+        let mut target = setup_target();
+        target.command(READ_PARAMETER_PAGE);
+        target.address(0x00);
+
+        let res: Vec<u8> = (0..PARAMETER_PAGE_BYTES * 2)
+            .map(|_| target.read_data())
+            .collect();
+
+        assert_eq!(b"ONFI", &res[0..4]);
+        assert_eq!(
+            CELLS_PER_PAGE as u32,
+            u32::from_le_bytes(res[80..84].try_into().unwrap())
+        );
+        assert_eq!(8, u32::from_le_bytes(res[96..100].try_into().unwrap()));
+        let crc = u16::from_le_bytes(res[254..256].try_into().unwrap());
+        assert_eq!(parameter_page_crc(&res[..254]), crc);
+        assert_eq!(res[..PARAMETER_PAGE_BYTES], res[PARAMETER_PAGE_BYTES..]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Command 30h has to follow 00h")]
+    fn read_confirm_should_panic_without_read_setup() {
+        let mut target = setup_target();
+
+        target.command(READ_CONFIRM);
+    }
+
+    fn setup_target() -> OnfiDevice {
+        OnfiDevice::new(Box::new(MemoryImpl::new(
+            Box::new(ZeroFluctuate),
+            8,
+            CellType::Single,
+        )))
+    }
+
+    fn send_address(target: &mut OnfiDevice, block: usize, page: usize, column: Option<usize>) {
+        if let Some(column) = column {
+            target.address(column as u8);
+            target.address((column >> 8) as u8);
+        }
+        let row = block * PAGES_PER_BLOCK + page;
+        for i in 0..ROW_CYCLES {
+            target.address((row >> (8 * i)) as u8);
+        }
+    }
+
+    fn program(target: &mut OnfiDevice, block: usize, page: usize, column: usize, data: &[u8]) {
+        target.command(PROGRAM);
+        send_address(target, block, page, Some(column));
+        for byte in data {
+            target.write_data(*byte);
+        }
+        target.command(PROGRAM_CONFIRM);
+    }
+
+    fn read(
+        target: &mut OnfiDevice,
+        block: usize,
+        page: usize,
+        column: usize,
+        len: usize,
+    ) -> Vec<u8> {
+        target.command(READ);
+        send_address(target, block, page, Some(column));
+        target.command(READ_CONFIRM);
+        (0..len).map(|_| target.read_data()).collect()
+    }
+
+    fn erase(target: &mut OnfiDevice, block: usize) {
+        target.command(ERASE);
+        send_address(target, block, 0, None);
+        target.command(ERASE_CONFIRM);
+    }
+
+    fn read_status(target: &mut OnfiDevice) -> u8 {
+        target.command(READ_STATUS);
+        target.read_data()
+    }
+}
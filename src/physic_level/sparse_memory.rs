@@ -0,0 +1,435 @@
+#[cfg(feature = "mmap")]
+pub mod mmap_store;
+pub mod page_store;
+
+use crate::config::CELLS_PER_PAGE;
+use crate::config::PAGES_PER_BLOCK;
+use crate::config::SPARE_BYTES_PER_PAGE;
+use crate::controller::CellType;
+use crate::physic_level::image;
+use crate::physic_level::image::Header;
+use crate::physic_level::memory::Address;
+use crate::physic_level::memory::Memory;
+use crate::physic_level::memory_components::page::Page;
+use crate::physic_level::memory_components::FluctuareT;
+use page_store::BlockMeta;
+use page_store::HashMapStore;
+use page_store::PageRecord;
+use page_store::PageStore;
+use page_store::PAGE_RECORD_BYTES;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+static ERASED: PageRecord = [0; PAGE_RECORD_BYTES];
+
+// changes made after a power cut, the store keeps the media as it was at the cut
+struct Overlay {
+    pages: HashMap<(usize, usize), Option<PageRecord>>,
+    blocks: HashMap<usize, BlockMeta>,
+}
+
+// memory backend allocating a page only when it is first programmed,
+// so the block count is limited by what gets written rather than by RAM
+pub struct SparseMemoryImpl {
+    fluctuator: Box<dyn FluctuareT>,
+    block_count: usize,
+    native_cell_type: CellType,
+    store: Box<dyn PageStore>,
+    operations_before_cut: Option<usize>,
+    overlay: Option<Overlay>,
+}
+
+impl SparseMemoryImpl {
+    pub fn new(
+        fluctuator: Box<dyn FluctuareT>,
+        blocks_amount: usize,
+        native_cell_type: CellType,
+    ) -> SparseMemoryImpl {
+        SparseMemoryImpl::with_store(
+            fluctuator,
+            blocks_amount,
+            native_cell_type,
+            Box::new(HashMapStore::new()),
+        )
+    }
+
+    pub fn with_store(
+        fluctuator: Box<dyn FluctuareT>,
+        blocks_amount: usize,
+        native_cell_type: CellType,
+        store: Box<dyn PageStore>,
+    ) -> SparseMemoryImpl {
+        SparseMemoryImpl {
+            fluctuator,
+            block_count: blocks_amount,
+            native_cell_type,
+            store,
+            operations_before_cut: None,
+            overlay: None,
+        }
+    }
+
+    pub fn from_image(
+        fluctuator: Box<dyn FluctuareT>,
+        reader: &mut dyn Read,
+    ) -> io::Result<SparseMemoryImpl> {
+        let header = Header::read(reader)?;
+        let mut memory =
+            SparseMemoryImpl::new(fluctuator, header.block_count, header.native_cell_type);
+        memory.load_body(reader)?;
+        Ok(memory)
+    }
+
+    fn load_body(&mut self, reader: &mut dyn Read) -> io::Result<()> {
+        self.overlay = None;
+        self.operations_before_cut = None;
+        for block in 0..self.block_count {
+            let mode = image::decode_block_mode(image::read_u8(reader)?, self.native_cell_type)?;
+            let life_used = image::read_f64(reader)?;
+            let bad = image::read_u8(reader)? != 0;
+            let mut erase_count = 0;
+            self.store.erase_pages(block, 0..PAGES_PER_BLOCK);
+            for page in 0..PAGES_PER_BLOCK {
+                let write_count = image::read_u32(reader)?;
+                if page == 0 {
+                    erase_count = write_count;
+                }
+                let mut record = [0; PAGE_RECORD_BYTES];
+                reader.read_exact(&mut record)?;
+                if record.iter().any(|byte| *byte != 0) {
+                    self.store.set_page(block, page, record);
+                }
+            }
+            self.store.set_block(
+                block,
+                BlockMeta {
+                    mode,
+                    life_used,
+                    bad,
+                    erase_count,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn record(&self, block: usize, page: usize) -> &PageRecord {
+        if let Some(overlay) = &self.overlay {
+            if let Some(record) = overlay.pages.get(&(block, page)) {
+                return record.as_ref().unwrap_or(&ERASED);
+            }
+        }
+        self.store.page(block, page).unwrap_or(&ERASED)
+    }
+
+    fn set_record(&mut self, block: usize, page: usize, record: PageRecord) {
+        match self.overlay.as_mut() {
+            Some(overlay) => {
+                overlay.pages.insert((block, page), Some(record));
+            }
+            None => self.store.set_page(block, page, record),
+        }
+    }
+
+    fn meta(&self, block: usize) -> BlockMeta {
+        self.overlay
+            .as_ref()
+            .and_then(|overlay| overlay.blocks.get(&block).copied())
+            .unwrap_or_else(|| self.store.block(block))
+    }
+
+    fn set_meta(&mut self, block: usize, meta: BlockMeta) {
+        match self.overlay.as_mut() {
+            Some(overlay) => {
+                overlay.blocks.insert(block, meta);
+            }
+            None => self.store.set_block(block, meta),
+        }
+    }
+
+    fn page(record: &PageRecord, write_count: u32) -> Page<CELLS_PER_PAGE> {
+        Page::from_parts(
+            record[..CELLS_PER_PAGE].try_into().unwrap(),
+            record[CELLS_PER_PAGE..].try_into().unwrap(),
+            write_count,
+        )
+    }
+
+    fn to_record(page: &Page<CELLS_PER_PAGE>) -> PageRecord {
+        let mut record = [0; PAGE_RECORD_BYTES];
+        record[..CELLS_PER_PAGE].copy_from_slice(page.read());
+        record[CELLS_PER_PAGE..].copy_from_slice(page.read_spare());
+        record
+    }
+
+    // true when the power goes down during the current operation
+    fn cuts_power(&mut self) -> bool {
+        match self.operations_before_cut {
+            Some(0) => {
+                self.operations_before_cut = None;
+                true
+            }
+            Some(left) => {
+                self.operations_before_cut = Some(left - 1);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn freeze(&mut self) {
+        self.overlay = Some(Overlay {
+            pages: HashMap::new(),
+            blocks: HashMap::new(),
+        });
+    }
+
+    fn program_page(&mut self, address: Address, data: [u8; CELLS_PER_PAGE], pass: bool) {
+        let Address(block_id, page_id) = address;
+        let write_count = self.meta(block_id).erase_count;
+        let mut page = SparseMemoryImpl::page(self.record(block_id, page_id), write_count);
+        if self.cuts_power() {
+            // only the first half of the cells got their charge before the power went down
+            let mut torn = SparseMemoryImpl::page(self.record(block_id, page_id), write_count);
+            let mut half = data;
+            for cell in half.iter_mut().skip(CELLS_PER_PAGE / 2) {
+                *cell = 0;
+            }
+            torn.program_pass(half, &*self.fluctuator);
+            self.store
+                .set_page(block_id, page_id, SparseMemoryImpl::to_record(&torn));
+            self.freeze();
+        }
+        if pass {
+            page.program_pass(data, &*self.fluctuator);
+        } else {
+            page.program(data, &*self.fluctuator);
+        }
+        self.set_record(block_id, page_id, SparseMemoryImpl::to_record(&page));
+    }
+}
+
+impl Memory for SparseMemoryImpl {
+    fn read(&self, address: Address) -> &[u8; CELLS_PER_PAGE] {
+        let Address(block_id, page_id) = address;
+        self.record(block_id, page_id)[..CELLS_PER_PAGE]
+            .try_into()
+            .unwrap()
+    }
+
+    fn program(&mut self, address: Address, data: [u8; CELLS_PER_PAGE]) {
+        self.program_page(address, data, false)
+    }
+
+    fn program_pass(&mut self, address: Address, data: [u8; CELLS_PER_PAGE]) {
+        self.program_page(address, data, true)
+    }
+
+    fn reset(&mut self, block_id: usize) {
+        if self.cuts_power() {
+            // erase stopped halfway, the block keeps its mode
+            self.store.erase_pages(block_id, 0..PAGES_PER_BLOCK / 2);
+            self.freeze();
+        }
+        match self.overlay.as_mut() {
+            Some(overlay) => {
+                for page in 0..PAGES_PER_BLOCK {
+                    overlay.pages.insert((block_id, page), None);
+                }
+            }
+            None => self.store.erase_pages(block_id, 0..PAGES_PER_BLOCK),
+        }
+        let mut meta = self.meta(block_id);
+        if let Some(mode) = meta.mode.take() {
+            meta.life_used += 1.0 / mode.endurance() as f64;
+        }
+        meta.erase_count += 1;
+        self.set_meta(block_id, meta);
+    }
+
+    fn read_spare(&self, address: Address) -> &[u8; SPARE_BYTES_PER_PAGE] {
+        let Address(block_id, page_id) = address;
+        self.record(block_id, page_id)[CELLS_PER_PAGE..]
+            .try_into()
+            .unwrap()
+    }
+
+    fn program_spare(&mut self, address: Address, data: [u8; SPARE_BYTES_PER_PAGE]) {
+        let Address(block_id, page_id) = address;
+        let write_count = self.meta(block_id).erase_count;
+        let mut page = SparseMemoryImpl::page(self.record(block_id, page_id), write_count);
+        page.program_spare(data);
+        self.set_record(block_id, page_id, SparseMemoryImpl::to_record(&page));
+    }
+
+    fn block_count(&self) -> usize {
+        self.block_count
+    }
+
+    fn native_cell_type(&self) -> CellType {
+        self.native_cell_type
+    }
+
+    fn open_block(&mut self, block_id: usize, mode: CellType) {
+        if mode.multiplier() > self.native_cell_type.multiplier() {
+            panic!("Block cannot be denser than native cell type")
+        }
+        let mut meta = self.meta(block_id);
+        match meta.mode {
+            Some(current) if current != mode => {
+                panic!("Cannot change mode of programmed block")
+            }
+            _ => meta.mode = Some(mode),
+        }
+        self.set_meta(block_id, meta);
+    }
+
+    fn block_mode(&self, block_id: usize) -> Option<CellType> {
+        self.meta(block_id).mode
+    }
+
+    fn block_life_used(&self, block_id: usize) -> f64 {
+        self.meta(block_id).life_used
+    }
+
+    fn is_bad(&self, block_id: usize) -> bool {
+        self.meta(block_id).bad
+    }
+
+    fn mark_bad(&mut self, block_id: usize) {
+        let mut meta = self.meta(block_id);
+        meta.bad = true;
+        self.set_meta(block_id, meta);
+    }
+
+    fn cut_power_after(&mut self, operations: usize) {
+        if self.overlay.is_none() {
+            self.operations_before_cut = Some(operations);
+        }
+    }
+
+    fn is_powered(&self) -> bool {
+        self.overlay.is_none()
+    }
+
+    fn power_on(&mut self) {
+        self.operations_before_cut = None;
+        self.overlay = None;
+    }
+
+    // same image format as `MemoryImpl`, untouched pages are written as erased
+    fn save(&self, writer: &mut dyn Write) -> io::Result<()> {
+        Header {
+            native_cell_type: self.native_cell_type,
+            block_count: self.block_count,
+        }
+        .write(writer)?;
+        for block in 0..self.block_count {
+            let meta = self.store.block(block);
+            image::write_u8(writer, image::encode_mode(meta.mode))?;
+            image::write_f64(writer, meta.life_used)?;
+            image::write_u8(writer, meta.bad as u8)?;
+            for page in 0..PAGES_PER_BLOCK {
+                image::write_u32(writer, meta.erase_count)?;
+                writer.write_all(self.store.page(block, page).unwrap_or(&ERASED))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, reader: &mut dyn Read) -> io::Result<()> {
+        let header = Header::read(reader)?;
+        if header.block_count != self.block_count
+            || header.native_cell_type != self.native_cell_type
+        {
+            return Err(image::invalid("image geometry differs from the device"));
+        }
+        self.load_body(reader)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::physic_level::memory::MemoryImpl;
+    use crate::physic_level::memory_components::ZeroFluctuate;
+
+    #[test]
+    fn untouched_pages_should_read_as_erased_on_huge_device() {
+        let mut target = SparseMemoryImpl::new(Box::new(ZeroFluctuate), 1 << 40, CellType::Quadro);
+        let last = (1 << 40) - 1;
+
+        target.open_block(last, CellType::Quadro);
+        target.program(Address(last, 127), [64; CELLS_PER_PAGE]);
+
+        assert_eq!([64; CELLS_PER_PAGE], *target.read(Address(last, 127)));
+        assert_eq!([0; CELLS_PER_PAGE], *target.read(Address(last, 126)));
+        assert_eq!([0; SPARE_BYTES_PER_PAGE], *target.read_spare(Address(3, 0)));
+        assert_eq!(None, target.block_mode(3));
+    }
+
+    #[test]
+    fn reset_should_release_pages_and_spend_life() {
+        let mut target = SparseMemoryImpl::new(Box::new(ZeroFluctuate), 8, CellType::Quadro);
+        target.open_block(2, CellType::Single);
+        target.program(Address(2, 0), [64; CELLS_PER_PAGE]);
+
+        target.reset(2);
+
+        assert_eq!([0; CELLS_PER_PAGE], *target.read(Address(2, 0)));
+        assert_eq!(None, target.block_mode(2));
+        assert!(target.block_life_used(2) > 0.0);
+        target.program(Address(2, 0), [32; CELLS_PER_PAGE]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot program non-empty cell")]
+    fn program_should_panic_on_programmed_page() {
+        let mut target = SparseMemoryImpl::new(Box::new(ZeroFluctuate), 8, CellType::Quadro);
+
+        target.program(Address(1, 1), [64; CELLS_PER_PAGE]);
+        target.program(Address(1, 1), [64; CELLS_PER_PAGE]);
+    }
+
+    #[test]
+    fn power_on_should_drop_changes_after_cut() {
+        let mut target = SparseMemoryImpl::new(Box::new(ZeroFluctuate), 8, CellType::Quadro);
+        target.program(Address(1, 0), [64; CELLS_PER_PAGE]);
+        target.open_block(4, CellType::Quadro);
+        target.program(Address(4, 0), [64; CELLS_PER_PAGE]);
+
+        target.cut_power_after(0);
+        target.program(Address(1, 1), [64; CELLS_PER_PAGE]);
+        target.reset(4);
+        assert_eq!([0; CELLS_PER_PAGE], *target.read(Address(4, 0)));
+        target.power_on();
+
+        assert_eq!([64; CELLS_PER_PAGE], *target.read(Address(1, 0)));
+        assert_eq!(0, target.read(Address(1, 1))[CELLS_PER_PAGE - 1]);
+        assert_eq!([64; CELLS_PER_PAGE], *target.read(Address(4, 0)));
+        assert_eq!(Some(CellType::Quadro), target.block_mode(4));
+    }
+
+    #[test]
+    fn image_should_be_shared_with_dense_memory() {
+        let mut dense = MemoryImpl::new(Box::new(ZeroFluctuate), 8, CellType::Quadro);
+        dense.open_block(5, CellType::Double);
+        dense.program(Address(5, 9), [48; CELLS_PER_PAGE]);
+        dense.mark_bad(6);
+        let mut bytes = Vec::new();
+        dense.save(&mut bytes).unwrap();
+
+        let target =
+            SparseMemoryImpl::from_image(Box::new(ZeroFluctuate), &mut &bytes[..]).unwrap();
+        let mut saved = Vec::new();
+        target.save(&mut saved).unwrap();
+
+        assert_eq!([48; CELLS_PER_PAGE], *target.read(Address(5, 9)));
+        assert_eq!(Some(CellType::Double), target.block_mode(5));
+        assert!(target.is_bad(6));
+        assert_eq!(bytes, saved);
+    }
+}
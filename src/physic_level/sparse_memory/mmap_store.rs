@@ -0,0 +1,134 @@
+use crate::config::PAGES_PER_BLOCK;
+use crate::physic_level::image;
+use crate::physic_level::sparse_memory::page_store::BlockMeta;
+use crate::physic_level::sparse_memory::page_store::PageRecord;
+use crate::physic_level::sparse_memory::page_store::PageStore;
+use crate::physic_level::sparse_memory::page_store::PAGE_RECORD_BYTES;
+use memmap2::MmapMut;
+use std::convert::TryInto;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+
+// mode, bad flag, erase count and life used of every block
+const BLOCK_META_BYTES: usize = 14;
+
+// media kept in a sparse file: block metadata first, then every page record, erased
+// pages are zeros so the file system allocates only what was programmed
+pub struct MmapStore {
+    map: MmapMut,
+    block_count: usize,
+}
+
+impl MmapStore {
+    pub fn create(path: &Path, block_count: usize) -> io::Result<MmapStore> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(MmapStore::file_size(block_count))?;
+        MmapStore::map(file, block_count)
+    }
+
+    pub fn open(path: &Path, block_count: usize) -> io::Result<MmapStore> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        if file.metadata()?.len() != MmapStore::file_size(block_count) {
+            return Err(image::invalid("media file size differs from the device"));
+        }
+        MmapStore::map(file, block_count)
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.map.flush()
+    }
+
+    fn map(file: File, block_count: usize) -> io::Result<MmapStore> {
+        // the file is owned by the simulator, nothing else is expected to resize it
+        let map = unsafe { MmapMut::map_mut(&file)? };
+        Ok(MmapStore { map, block_count })
+    }
+
+    fn file_size(block_count: usize) -> u64 {
+        (block_count * (BLOCK_META_BYTES + PAGES_PER_BLOCK * PAGE_RECORD_BYTES)) as u64
+    }
+
+    fn page_offset(&self, block: usize, page: usize) -> usize {
+        self.block_count * BLOCK_META_BYTES + (block * PAGES_PER_BLOCK + page) * PAGE_RECORD_BYTES
+    }
+}
+
+impl PageStore for MmapStore {
+    fn page(&self, block: usize, page: usize) -> Option<&PageRecord> {
+        let offset = self.page_offset(block, page);
+        let record: &PageRecord = self.map[offset..offset + PAGE_RECORD_BYTES]
+            .try_into()
+            .unwrap();
+        Some(record).filter(|record| record.iter().any(|byte| *byte != 0))
+    }
+
+    fn set_page(&mut self, block: usize, page: usize, record: PageRecord) {
+        let offset = self.page_offset(block, page);
+        self.map[offset..offset + PAGE_RECORD_BYTES].copy_from_slice(&record);
+    }
+
+    fn erase_pages(&mut self, block: usize, pages: Range<usize>) {
+        let start = self.page_offset(block, pages.start);
+        let end = self.page_offset(block, pages.end);
+        self.map[start..end].fill(0);
+    }
+
+    fn block(&self, block: usize) -> BlockMeta {
+        let bytes = &self.map[block * BLOCK_META_BYTES..(block + 1) * BLOCK_META_BYTES];
+        BlockMeta {
+            mode: image::decode_mode(bytes[0])
+                .unwrap_or_else(|_| panic!("Corrupted block metadata")),
+            bad: bytes[1] != 0,
+            erase_count: u32::from_le_bytes(bytes[2..6].try_into().unwrap()),
+            life_used: f64::from_le_bytes(bytes[6..14].try_into().unwrap()),
+        }
+    }
+
+    fn set_block(&mut self, block: usize, meta: BlockMeta) {
+        let bytes = &mut self.map[block * BLOCK_META_BYTES..(block + 1) * BLOCK_META_BYTES];
+        bytes[0] = image::encode_mode(meta.mode);
+        bytes[1] = meta.bad as u8;
+        bytes[2..6].copy_from_slice(&meta.erase_count.to_le_bytes());
+        bytes[6..14].copy_from_slice(&meta.life_used.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::controller::CellType;
+
+    #[test]
+    fn open_should_see_pages_written_before() {
+        let path = std::env::temp_dir().join(format!("sdd-mmap-store-{}", std::process::id()));
+        let meta = BlockMeta {
+            mode: Some(CellType::Double),
+            life_used: 0.25,
+            bad: true,
+            erase_count: 3,
+        };
+        {
+            let mut target = MmapStore::create(&path, 16).unwrap();
+            target.set_page(15, 127, [7; PAGE_RECORD_BYTES]);
+            target.set_block(15, meta);
+            target.flush().unwrap();
+        }
+
+        let target = MmapStore::open(&path, 16).unwrap();
+        let wrong_size = MmapStore::open(&path, 8);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(Some(&[7; PAGE_RECORD_BYTES]), target.page(15, 127));
+        assert_eq!(None, target.page(15, 126));
+        assert_eq!(meta, target.block(15));
+        assert!(wrong_size.is_err());
+    }
+}
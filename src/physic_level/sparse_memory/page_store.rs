@@ -0,0 +1,106 @@
+use crate::config::CELLS_PER_PAGE;
+use crate::config::SPARE_BYTES_PER_PAGE;
+use crate::controller::CellType;
+use std::collections::HashMap;
+use std::ops::Range;
+
+// cells followed by the spare area
+pub const PAGE_RECORD_BYTES: usize = CELLS_PER_PAGE + SPARE_BYTES_PER_PAGE;
+pub type PageRecord = [u8; PAGE_RECORD_BYTES];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BlockMeta {
+    pub mode: Option<CellType>,
+    pub life_used: f64,
+    pub bad: bool,
+    pub erase_count: u32,
+}
+
+// backing storage of the sparse memory, untouched blocks and pages read as erased
+pub trait PageStore {
+    // None for a page not programmed since the last erase
+    fn page(&self, block: usize, page: usize) -> Option<&PageRecord>;
+    fn set_page(&mut self, block: usize, page: usize, record: PageRecord);
+    fn erase_pages(&mut self, block: usize, pages: Range<usize>);
+    fn block(&self, block: usize) -> BlockMeta;
+    fn set_block(&mut self, block: usize, meta: BlockMeta);
+}
+
+// keeps only programmed pages and touched blocks in RAM
+#[derive(Default)]
+pub struct HashMapStore {
+    pages: HashMap<(usize, usize), PageRecord>,
+    blocks: HashMap<usize, BlockMeta>,
+}
+
+impl HashMapStore {
+    pub fn new() -> HashMapStore {
+        HashMapStore::default()
+    }
+
+    pub fn allocated_pages(&self) -> usize {
+        self.pages.len()
+    }
+}
+
+impl PageStore for HashMapStore {
+    fn page(&self, block: usize, page: usize) -> Option<&PageRecord> {
+        self.pages.get(&(block, page))
+    }
+
+    fn set_page(&mut self, block: usize, page: usize, record: PageRecord) {
+        self.pages.insert((block, page), record);
+    }
+
+    fn erase_pages(&mut self, block: usize, pages: Range<usize>) {
+        for page in pages {
+            self.pages.remove(&(block, page));
+        }
+    }
+
+    fn block(&self, block: usize) -> BlockMeta {
+        self.blocks.get(&block).copied().unwrap_or_default()
+    }
+
+    fn set_block(&mut self, block: usize, meta: BlockMeta) {
+        if meta == BlockMeta::default() {
+            self.blocks.remove(&block);
+        } else {
+            self.blocks.insert(block, meta);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn erase_pages_should_free_allocated_pages() {
+        let mut target = HashMapStore::new();
+        target.set_page(3, 0, [1; PAGE_RECORD_BYTES]);
+        target.set_page(3, 5, [2; PAGE_RECORD_BYTES]);
+
+        target.erase_pages(3, 0..4);
+
+        assert_eq!(None, target.page(3, 0));
+        assert_eq!(Some(&[2; PAGE_RECORD_BYTES]), target.page(3, 5));
+        assert_eq!(1, target.allocated_pages());
+    }
+
+    #[test]
+    fn block_should_default_to_fresh_erased_block() {
+        let mut target = HashMapStore::new();
+        let meta = BlockMeta {
+            mode: Some(CellType::Triple),
+            life_used: 0.5,
+            bad: false,
+            erase_count: 7,
+        };
+
+        target.set_block(1_000_000_000, meta);
+
+        assert_eq!(meta, target.block(1_000_000_000));
+        assert_eq!(BlockMeta::default(), target.block(5));
+    }
+}
@@ -0,0 +1,603 @@
+use crate::config::CELLS_PER_PAGE;
+use crate::controller::memory_controller::MemoryController;
+use crate::host::block_device::PAGE_BYTES;
+use crate::metric::metric_storage::MetricStorage;
+use crate::metric::MetricType;
+use rand::rngs::StdRng;
+use rand::Rng;
+use rand::SeedableRng;
+use std::collections::VecDeque;
+
+pub const PERCENTILES: [f64; 4] = [50.0, 90.0, 99.0, 99.9];
+// one unit of the controller clock is a microsecond
+const TIME_UNITS_PER_SECOND: f64 = 1_000_000.0;
+// least time a request takes, answered by the controller alone
+const COMMAND_TIME: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessPattern {
+    Sequential,
+    Random,
+}
+
+// how random offsets spread over the device, the hottest addresses are at its start
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AddressDistribution {
+    Uniform,
+    Zipf(f64),
+    // share of the accesses going to the share of the device, both in percent
+    Hotspot { access: f64, space: f64 },
+}
+
+// subset of fio job options, see JobSpec::parse
+#[derive(Clone, Debug, PartialEq)]
+pub struct JobSpec {
+    pub pattern: AccessPattern,
+    pub read_percent: u32,
+    // block size in bytes and its weight
+    pub block_sizes: Vec<(u64, u32)>,
+    pub distribution: AddressDistribution,
+    pub queue_depth: usize,
+    // controller time the job may run for
+    pub runtime: Option<u32>,
+    // bytes the job may transfer
+    pub size: Option<u64>,
+    pub seed: u64,
+}
+
+impl Default for JobSpec {
+    fn default() -> JobSpec {
+        JobSpec {
+            pattern: AccessPattern::Sequential,
+            read_percent: 100,
+            block_sizes: vec![(4096, 1)],
+            distribution: AddressDistribution::Uniform,
+            queue_depth: 1,
+            runtime: None,
+            size: None,
+            seed: 0,
+        }
+    }
+}
+
+impl JobSpec {
+    // "key=value" lines as in a fio job file, '#' and ';' start comments and a [section] header
+    // is skipped; supported are rw, rwmixread, bs, bssplit, random_distribution (random,
+    // zipf:<theta>, zoned:<access>/<space>), iodepth, runtime, size and randseed
+    pub fn parse(text: &str) -> JobSpec {
+        let mut spec = JobSpec::default();
+        let mut mix = None;
+        for line in text.lines() {
+            let line = line.split(['#', ';']).next().unwrap().trim();
+            if line.is_empty() || line.starts_with('[') {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .unwrap_or_else(|| panic!("Job option has to be key=value: {}", line));
+            let (key, value) = (key.trim(), value.trim());
+            match key {
+                "rw" | "readwrite" => {
+                    let (pattern, read_percent) = match value {
+                        "read" => (AccessPattern::Sequential, 100),
+                        "write" => (AccessPattern::Sequential, 0),
+                        "rw" | "readwrite" => (AccessPattern::Sequential, 50),
+                        "randread" => (AccessPattern::Random, 100),
+                        "randwrite" => (AccessPattern::Random, 0),
+                        "randrw" => (AccessPattern::Random, 50),
+                        _ => panic!("Unknown rw value {}", value),
+                    };
+                    spec.pattern = pattern;
+                    spec.read_percent = read_percent;
+                }
+                "rwmixread" => mix = Some(parse_number(value) as u32),
+                "rwmixwrite" => mix = Some(100 - parse_number(value) as u32),
+                "bs" => spec.block_sizes = vec![(parse_size(value), 1)],
+                "bssplit" => {
+                    spec.block_sizes = value
+                        .split(':')
+                        .map(|part| {
+                            let (size, weight) = part.split_once('/').unwrap_or((part, "1"));
+                            (parse_size(size), parse_number(weight) as u32)
+                        })
+                        .collect()
+                }
+                "random_distribution" => {
+                    spec.distribution = match value.split_once(':') {
+                        None if value == "random" => AddressDistribution::Uniform,
+                        Some(("zipf", theta)) => AddressDistribution::Zipf(parse_float(theta)),
+                        Some(("zoned", zone)) => {
+                            let (access, space) = zone
+                                .split_once('/')
+                                .unwrap_or_else(|| panic!("Zoned distribution is access/space"));
+                            AddressDistribution::Hotspot {
+                                access: parse_float(access),
+                                space: parse_float(space),
+                            }
+                        }
+                        _ => panic!("Unknown random_distribution {}", value),
+                    }
+                }
+                "iodepth" => spec.queue_depth = parse_number(value) as usize,
+                "runtime" => spec.runtime = Some(parse_duration(value)),
+                "size" => spec.size = Some(parse_size(value)),
+                "randseed" => spec.seed = parse_number(value),
+                _ => panic!("Unknown job option {}", key),
+            }
+        }
+        // a mix only makes sense for mixed jobs, as in fio
+        if let Some(read_percent) = mix {
+            if spec.read_percent != 0 && spec.read_percent != 100 {
+                spec.read_percent = read_percent.min(100);
+            }
+        }
+        spec
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OperationReport {
+    pub ios: u64,
+    pub bytes: u64,
+    pub iops: f64,
+    // bytes per second
+    pub bandwidth: f64,
+    // latency at every entry of PERCENTILES
+    pub latency_percentiles: Vec<u32>,
+    pub max_latency: u32,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct JobReport {
+    pub elapsed: u32,
+    pub read: OperationReport,
+    pub write: OperationReport,
+}
+
+impl JobReport {
+    // built from the "job_*" series a run put into the storage
+    pub fn from_metrics(metrics: &dyn MetricStorage, elapsed: u32) -> JobReport {
+        JobReport {
+            elapsed,
+            read: operation_report(metrics, "read", elapsed),
+            write: operation_report(metrics, "write", elapsed),
+        }
+    }
+}
+
+// rejection-inversion sampling of ranks 1..=n (Hörmann and Derflinger), it takes constant
+// memory and time whatever the device size
+struct Zipf {
+    n: f64,
+    theta: f64,
+    h_integral_x1: f64,
+    h_integral_n: f64,
+    threshold: f64,
+}
+
+impl Zipf {
+    fn new(n: u64, theta: f64) -> Zipf {
+        if theta.is_nan() || theta < 0.0 {
+            panic!("Zipf theta can't be negative")
+        }
+        let mut zipf = Zipf {
+            n: n as f64,
+            theta,
+            h_integral_x1: 0.0,
+            h_integral_n: 0.0,
+            threshold: 0.0,
+        };
+        zipf.h_integral_x1 = zipf.h_integral(1.5) - 1.0;
+        zipf.h_integral_n = zipf.h_integral(zipf.n + 0.5);
+        zipf.threshold = 2.0 - zipf.h_integral_inverse(zipf.h_integral(2.5) - zipf.h(2.0));
+        zipf
+    }
+
+    fn sample(&self, rng: &mut StdRng) -> u64 {
+        loop {
+            let u = self.h_integral_n + rng.gen::<f64>() * (self.h_integral_x1 - self.h_integral_n);
+            let x = self.h_integral_inverse(u);
+            let rank = (x + 0.5).floor().clamp(1.0, self.n);
+            if rank - x <= self.threshold || u >= self.h_integral(rank + 0.5) - self.h(rank) {
+                return rank as u64;
+            }
+        }
+    }
+
+    fn h(&self, x: f64) -> f64 {
+        (-self.theta * x.ln()).exp()
+    }
+
+    // integral of h from 1 to x
+    fn h_integral(&self, x: f64) -> f64 {
+        let log_x = x.ln();
+        expm1_over((1.0 - self.theta) * log_x) * log_x
+    }
+
+    fn h_integral_inverse(&self, x: f64) -> f64 {
+        let t = (x * (1.0 - self.theta)).max(-1.0);
+        (ln1p_over(t) * x).exp()
+    }
+}
+
+// (e^x - 1) / x, which goes to 1 at zero
+fn expm1_over(x: f64) -> f64 {
+    if x.abs() > 1e-8 {
+        x.exp_m1() / x
+    } else {
+        1.0 + x / 2.0 * (1.0 + x / 3.0 * (1.0 + x / 4.0))
+    }
+}
+
+// ln(1 + x) / x, which goes to 1 at zero
+fn ln1p_over(x: f64) -> f64 {
+    if x.abs() > 1e-8 {
+        x.ln_1p() / x
+    } else {
+        1.0 - x * (0.5 - x * (1.0 / 3.0 - x / 4.0))
+    }
+}
+
+struct AddressGenerator {
+    // offsets are multiples of the smallest block size
+    units: u64,
+    zipf: Option<Zipf>,
+    next: u64,
+}
+
+impl AddressGenerator {
+    fn new(spec: &JobSpec, units: u64) -> AddressGenerator {
+        let zipf = match spec.distribution {
+            AddressDistribution::Zipf(theta) => Some(Zipf::new(units, theta)),
+            _ => None,
+        };
+        AddressGenerator {
+            units,
+            zipf,
+            next: 0,
+        }
+    }
+
+    // first unit of a request covering the given number of units
+    fn next(&mut self, spec: &JobSpec, rng: &mut StdRng, length: u64) -> u64 {
+        let last = self.units - length;
+        if spec.pattern == AccessPattern::Sequential {
+            if self.next > last {
+                self.next = 0;
+            }
+            let unit = self.next;
+            self.next += length;
+            return unit;
+        }
+        let unit = match spec.distribution {
+            AddressDistribution::Uniform => rng.gen_range(0..self.units),
+            AddressDistribution::Zipf(_) => self.zipf.as_ref().unwrap().sample(rng) - 1,
+            AddressDistribution::Hotspot { access, space } => {
+                let hot = ((self.units as f64 * space / 100.0) as u64).clamp(1, self.units);
+                if hot == self.units || rng.gen_bool(access / 100.0) {
+                    rng.gen_range(0..hot)
+                } else {
+                    rng.gen_range(hot..self.units)
+                }
+            }
+        };
+        unit.min(last)
+    }
+}
+
+// requests run one after another on the controller, with a queue depth above one a request is
+// issued when the one depth places before it completes, so its latency includes queueing
+pub fn run_job(
+    controller: &mut dyn MemoryController,
+    spec: &JobSpec,
+    metrics: &mut dyn MetricStorage,
+) -> JobReport {
+    if spec.runtime.is_none() && spec.size.is_none() {
+        panic!("Job needs a runtime or a size limit")
+    }
+    if spec.queue_depth == 0 || spec.block_sizes.is_empty() {
+        panic!("Job needs a queue depth and a block size")
+    }
+    let unit = spec
+        .block_sizes
+        .iter()
+        .map(|(size, _)| *size)
+        .min()
+        .unwrap();
+    if spec
+        .block_sizes
+        .iter()
+        .any(|(size, _)| *size % PAGE_BYTES as u64 != 0 || *size % unit != 0)
+    {
+        panic!("Block sizes have to be multiples of the page and of each other")
+    }
+    let device_bytes = (controller.user_capacity() * PAGE_BYTES) as u64;
+    let largest = spec
+        .block_sizes
+        .iter()
+        .map(|(size, _)| *size)
+        .max()
+        .unwrap();
+    if largest > device_bytes {
+        panic!("Block size is larger than the device")
+    }
+
+    let mut rng = StdRng::seed_from_u64(spec.seed);
+    let mut addresses = AddressGenerator::new(spec, device_bytes / unit);
+    let total_weight: u32 = spec.block_sizes.iter().map(|(_, weight)| weight).sum();
+    let start = controller.time();
+    let mut completions: VecDeque<u32> = VecDeque::new();
+    let mut transferred = 0;
+    let mut request = 0;
+    loop {
+        let elapsed = controller.time() - start;
+        if spec.runtime.is_some_and(|runtime| elapsed >= runtime)
+            || spec.size.is_some_and(|size| transferred >= size)
+        {
+            break;
+        }
+        let mut pick = rng.gen_range(0..total_weight);
+        let block_size = spec
+            .block_sizes
+            .iter()
+            .find(|(_, weight)| {
+                let found = pick < *weight;
+                pick = pick.saturating_sub(*weight);
+                found
+            })
+            .unwrap()
+            .0;
+        let offset = addresses.next(spec, &mut rng, block_size / unit) * unit;
+        let read = rng.gen_range(0..100) < spec.read_percent;
+
+        let started = controller.time();
+        let issued = if completions.len() == spec.queue_depth {
+            completions.pop_front().unwrap()
+        } else {
+            started
+        };
+        let first = (offset / PAGE_BYTES as u64) as usize;
+        for lpn in first..first + block_size as usize / PAGE_BYTES {
+            if read {
+                controller.read_page(lpn);
+            } else {
+                controller.write_page(lpn, page_pattern(lpn, request));
+            }
+        }
+        // reads of unmapped pages leave the clock where it was, which would keep a job limited
+        // by runtime going forever
+        if controller.time() == started {
+            controller.idle(COMMAND_TIME);
+        }
+        let completed = controller.time();
+        completions.push_back(completed);
+        transferred += block_size;
+        request += 1;
+
+        let (operation, metric_type) = if read {
+            ("read", MetricType::Read)
+        } else {
+            ("write", MetricType::Write)
+        };
+        metrics.put_metric(
+            &format!("job_{}_latency", operation),
+            completed - issued,
+            completed,
+            metric_type,
+        );
+        metrics.put_metric(
+            &format!("job_{}_bytes", operation),
+            block_size as u32,
+            completed,
+            metric_type,
+        );
+    }
+    JobReport::from_metrics(metrics, controller.time() - start)
+}
+
+fn operation_report(metrics: &dyn MetricStorage, operation: &str, elapsed: u32) -> OperationReport {
+    let mut latencies: Vec<u32> = metrics
+        .get_metric(&format!("job_{}_latency", operation))
+        .into_iter()
+        .map(|(_, latency)| latency)
+        .collect();
+    let bytes: u64 = metrics
+        .get_metric(&format!("job_{}_bytes", operation))
+        .into_iter()
+        .map(|(_, bytes)| bytes as u64)
+        .sum();
+    latencies.sort_unstable();
+    let ios = latencies.len() as u64;
+    let seconds = elapsed.max(1) as f64 / TIME_UNITS_PER_SECOND;
+    OperationReport {
+        ios,
+        bytes,
+        iops: ios as f64 / seconds,
+        bandwidth: bytes as f64 / seconds,
+        latency_percentiles: PERCENTILES
+            .iter()
+            .map(|p| percentile(&latencies, *p))
+            .collect(),
+        max_latency: latencies.last().copied().unwrap_or(0),
+    }
+}
+
+// nearest rank of sorted values, zero when there are none
+fn percentile(sorted: &[u32], percent: f64) -> u32 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = (percent / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn page_pattern(lpn: usize, request: usize) -> Vec<bool> {
+    (0..CELLS_PER_PAGE)
+        .map(|i| (lpn + request + i).is_multiple_of(3))
+        .collect()
+}
+
+fn parse_number(value: &str) -> u64 {
+    value
+        .parse()
+        .unwrap_or_else(|_| panic!("Malformed number {}", value))
+}
+
+fn parse_float(value: &str) -> f64 {
+    value
+        .parse()
+        .unwrap_or_else(|_| panic!("Malformed number {}", value))
+}
+
+// bytes with an optional k, m or g suffix in powers of 1024
+fn parse_size(value: &str) -> u64 {
+    let lower = value.to_lowercase();
+    let (digits, multiplier) = match lower.trim_end_matches('b').chars().last() {
+        Some('k') => (&lower[..lower.find('k').unwrap()], 1 << 10),
+        Some('m') => (&lower[..lower.find('m').unwrap()], 1 << 20),
+        Some('g') => (&lower[..lower.find('g').unwrap()], 1 << 30),
+        _ => (lower.trim_end_matches('b'), 1),
+    };
+    parse_number(digits) * multiplier
+}
+
+// controller time units, a bare number is seconds as in fio
+fn parse_duration(value: &str) -> u32 {
+    let (digits, units) = if let Some(digits) = value.strip_suffix("us") {
+        (digits, 1)
+    } else if let Some(digits) = value.strip_suffix("ms") {
+        (digits, 1_000)
+    } else {
+        (value.trim_end_matches('s'), 1_000_000)
+    };
+    (parse_number(digits) * units).min(u32::MAX as u64) as u32
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::controller::memory_controller::test_controller;
+    use crate::metric::metric_storage::MetricStorageImpl;
+
+    #[test]
+    fn parse_should_read_fio_job_options() {
+        let res = JobSpec::parse(
+            "[mixed]\n\
+             rw=randrw ; random mix\n\
+             rwmixread=70\n\
+             bssplit=512/30:2k/70\n\
+             random_distribution=zoned:90/10\n\
+             iodepth=4\n\
+             runtime=500ms\n\
+             size=1m\n\
+             randseed=7\n",
+        );
+
+        let expected = JobSpec {
+            pattern: AccessPattern::Random,
+            read_percent: 70,
+            block_sizes: vec![(512, 30), (2048, 70)],
+            distribution: AddressDistribution::Hotspot {
+                access: 90.0,
+                space: 10.0,
+            },
+            queue_depth: 4,
+            runtime: Some(500_000),
+            size: Some(1 << 20),
+            seed: 7,
+        };
+        assert_eq!(expected, res);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown job option ioengine")]
+    fn parse_should_panic_on_unknown_option() {
+        JobSpec::parse("ioengine=libaio");
+    }
+
+    #[test]
+    fn run_job_should_stop_at_size_and_report_bandwidth() {
+        let mut target = test_controller();
+        let mut metrics = MetricStorageImpl::new();
+        let spec = JobSpec::parse("rw=write\nbs=512\nsize=8k");
+
+        let res = run_job(&mut target, &spec, &mut metrics);
+
+        assert_eq!((16, 8192), (res.write.ios, res.write.bytes));
+        assert_eq!(0, res.read.ios);
+        let seconds = res.elapsed as f64 / TIME_UNITS_PER_SECOND;
+        assert!((res.write.bandwidth - 8192.0 / seconds).abs() < 1e-6);
+        assert!(res.write.latency_percentiles[0] <= res.write.max_latency);
+    }
+
+    #[test]
+    fn run_job_should_stop_at_runtime() {
+        let mut target = test_controller();
+        let mut metrics = MetricStorageImpl::new();
+        let spec = JobSpec::parse("rw=randrw\nbs=512\nruntime=20000us");
+
+        let res = run_job(&mut target, &spec, &mut metrics);
+
+        assert!(res.elapsed >= 20_000);
+        assert!(res.read.ios > 0 && res.write.ios > 0);
+    }
+
+    #[test]
+    fn run_job_should_stop_at_runtime_when_reading_fresh_device() {
+        let mut target = test_controller();
+        let mut metrics = MetricStorageImpl::new();
+        let spec = JobSpec::parse("rw=randread\nbs=512\nruntime=1000us");
+
+        let res = run_job(&mut target, &spec, &mut metrics);
+
+        assert!(res.elapsed >= 1000);
+        assert!(res.read.ios > 0);
+    }
+
+    #[test]
+    fn queue_depth_should_add_queueing_to_latency() {
+        let spec = JobSpec::parse("rw=write\nbs=512\nsize=16k");
+        let deep = JobSpec {
+            queue_depth: 8,
+            ..spec.clone()
+        };
+
+        let shallow = run_job(&mut test_controller(), &spec, &mut MetricStorageImpl::new());
+        let res = run_job(&mut test_controller(), &deep, &mut MetricStorageImpl::new());
+
+        assert!(res.write.latency_percentiles[0] > shallow.write.latency_percentiles[0]);
+    }
+
+    #[test]
+    fn zipf_should_favour_start_of_device() {
+        let spec = JobSpec::parse("rw=randread\nbs=512\nrandom_distribution=zipf:1.2");
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut target = AddressGenerator::new(&spec, 100);
+
+        let hits = (0..1000)
+            .filter(|_| target.next(&spec, &mut rng, 1) < 10)
+            .count();
+
+        assert!(hits > 600);
+    }
+
+    #[test]
+    fn zipf_should_sample_ranks_of_huge_device() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let target = Zipf::new(1 << 40, 1.0);
+
+        let ranks: Vec<u64> = (0..1000).map(|_| target.sample(&mut rng)).collect();
+
+        assert!(ranks.iter().all(|rank| (1..=1 << 40).contains(rank)));
+        assert!(ranks.iter().filter(|rank| **rank == 1).count() > 10);
+    }
+
+    #[test]
+    fn percentile_should_use_nearest_rank() {
+        let values: Vec<u32> = (1..=100).collect();
+
+        assert_eq!(50, percentile(&values, 50.0));
+        assert_eq!(100, percentile(&values, 99.9));
+        assert_eq!(0, percentile(&[], 50.0));
+    }
+}
@@ -0,0 +1,478 @@
+use crate::config::CELLS_PER_PAGE;
+use crate::controller::memory_controller::MemoryController;
+use crate::host::block_device::PAGE_BYTES;
+use std::io;
+use std::io::BufRead;
+use std::ops::Range;
+
+// blkparse sectors are always 512 bytes, whatever the logical block size of the device
+const BLKPARSE_SECTOR_BYTES: u64 = 512;
+// MSR timestamps are Windows file times in 100 ns ticks
+const MSR_TICK_NS: u64 = 100;
+// one unit of the controller clock is a microsecond
+const NS_PER_TIME_UNIT: u64 = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Read,
+    Write,
+    Trim,
+}
+
+// byte range of a request, timestamps count nanoseconds from the first record of the trace
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub timestamp: u64,
+    pub operation: Operation,
+    pub offset: u64,
+    pub size: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReplayConfig {
+    // multiplies gaps between requests, 0.5 replays the trace twice as fast
+    pub time_scale: f64,
+    // idles the controller until the scaled timestamp of a request, otherwise requests go back
+    // to back
+    pub honour_timestamps: bool,
+    // offsets past the device wrap around instead of being skipped
+    pub wrap: bool,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> ReplayConfig {
+        ReplayConfig {
+            time_scale: 1.0,
+            honour_timestamps: false,
+            wrap: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ReplayStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub trims: u64,
+    // requests reaching past the device with wrapping turned off, longer than the device, or past
+    // the byte range of any device
+    pub skipped: u64,
+    pub pages_read: u64,
+    pub pages_written: u64,
+    pub pages_trimmed: u64,
+    // from the scaled arrival of a request, or its issue when timestamps are not honoured, until
+    // the controller is done with it
+    pub total_response: u64,
+    pub max_response: u32,
+}
+
+// lines look like "128166372003061629,hm,0,Write,3518365696,4096,1331", the hostname, disk number
+// and response time are ignored
+pub fn parse_msr(reader: impl BufRead) -> io::Result<Vec<TraceRecord>> {
+    let mut records = Vec::new();
+    let mut first = None;
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        if fields.len() < 6 {
+            return Err(invalid_line(number, "expected at least 6 fields"));
+        }
+        let ticks: u64 = parse_field(fields[0], number)?;
+        let operation = match fields[3] {
+            "Read" => Operation::Read,
+            "Write" => Operation::Write,
+            _ => return Err(invalid_line(number, "unknown request type")),
+        };
+        let first = *first.get_or_insert(ticks);
+        records.push(TraceRecord {
+            timestamp: ticks
+                .saturating_sub(first)
+                .checked_mul(MSR_TICK_NS)
+                .ok_or_else(|| invalid_line(number, "timestamp out of range"))?,
+            operation,
+            offset: parse_field(fields[4], number)?,
+            size: parse_field(fields[5], number)?,
+        });
+    }
+    Ok(records)
+}
+
+// default blkparse output, "  8,0  3  1  0.000000000  697  Q  WS 223490 + 8 [kjournald]";
+// only events of the given action (Q when requests are queued, D when issued to the driver) are
+// kept, headers, summaries and requests without sectors like flushes are left out
+pub fn parse_blkparse(reader: impl BufRead, action: &str) -> io::Result<Vec<TraceRecord>> {
+    let mut records = Vec::new();
+    let mut first = None;
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 10 || fields[5] != action || fields[8] != "+" {
+            continue;
+        }
+        let rwbs = fields[6];
+        let operation = if rwbs.contains('D') {
+            Operation::Trim
+        } else if rwbs.contains('W') {
+            Operation::Write
+        } else if rwbs.contains('R') {
+            Operation::Read
+        } else {
+            continue;
+        };
+        let sectors: u64 = parse_field(fields[9], number)?;
+        let sector: u64 = parse_field(fields[7], number)?;
+        if sectors == 0 {
+            continue;
+        }
+        let timestamp = parse_seconds(fields[3], number)?;
+        let first = *first.get_or_insert(timestamp);
+        records.push(TraceRecord {
+            timestamp: timestamp.saturating_sub(first),
+            operation,
+            offset: sector
+                .checked_mul(BLKPARSE_SECTOR_BYTES)
+                .ok_or_else(|| invalid_line(number, "sector out of range"))?,
+            size: sectors
+                .checked_mul(BLKPARSE_SECTOR_BYTES)
+                .ok_or_else(|| invalid_line(number, "sector count out of range"))?,
+        });
+    }
+    Ok(records)
+}
+
+// written pages carry a pattern of their logical page and the request, so reads have something
+// to decode
+pub fn replay(
+    controller: &mut dyn MemoryController,
+    records: &[TraceRecord],
+    config: &ReplayConfig,
+) -> ReplayStats {
+    let mut stats = ReplayStats::default();
+    let capacity = controller.user_capacity() as u64;
+    if capacity == 0 {
+        panic!("Device has no capacity to replay on")
+    }
+    let start = controller.time();
+    for (n, record) in records.iter().enumerate() {
+        let record_end = match record.offset.checked_add(record.size) {
+            Some(end) => end,
+            None => {
+                stats.skipped += 1;
+                continue;
+            }
+        };
+        let page_bytes = PAGE_BYTES as u64;
+        // a trim keeps the pages it only covers in part, reads and writes take them whole
+        let (first, end) = match record.operation {
+            Operation::Trim => (record.offset.div_ceil(page_bytes), record_end / page_bytes),
+            _ => (record.offset / page_bytes, record_end.div_ceil(page_bytes)),
+        };
+        // wrapping a request longer than the device would write its pages over and over
+        if (!config.wrap && end > capacity) || end - first > capacity {
+            stats.skipped += 1;
+            continue;
+        }
+        // a request arriving while the controller is still busy waits for it
+        let issued = if config.honour_timestamps {
+            let scaled = (record.timestamp as f64 * config.time_scale) as u64 / NS_PER_TIME_UNIT;
+            let arrival = start.saturating_add(scaled.min(u32::MAX as u64) as u32);
+            if controller.time() < arrival {
+                controller.idle(arrival - controller.time());
+            }
+            arrival
+        } else {
+            controller.time()
+        };
+
+        let pages = wrapped_ranges(first, end, capacity);
+        let count: u64 = pages.iter().map(|range| range.len() as u64).sum();
+        match record.operation {
+            Operation::Read => {
+                for lpn in pages.into_iter().flatten() {
+                    controller.read_page(lpn);
+                }
+                stats.reads += 1;
+                stats.pages_read += count;
+            }
+            Operation::Write => {
+                for lpn in pages.into_iter().flatten() {
+                    controller.write_page(lpn, page_pattern(lpn, n));
+                }
+                stats.writes += 1;
+                stats.pages_written += count;
+            }
+            Operation::Trim => {
+                if !pages.is_empty() {
+                    controller.deallocate(&pages);
+                }
+                stats.trims += 1;
+                stats.pages_trimmed += count;
+            }
+        }
+
+        let response = controller.time() - issued;
+        stats.total_response += response as u64;
+        stats.max_response = stats.max_response.max(response);
+    }
+    stats
+}
+
+// logical pages first..end folded into the device, at most two ranges as the request is no
+// longer than the device
+fn wrapped_ranges(first: u64, end: u64, capacity: u64) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut position = first;
+    while position < end {
+        let start = position % capacity;
+        let len = (capacity - start).min(end - position);
+        ranges.push(start as usize..(start + len) as usize);
+        position += len;
+    }
+    ranges
+}
+
+fn page_pattern(lpn: usize, request: usize) -> Vec<bool> {
+    (0..CELLS_PER_PAGE)
+        .map(|i| (lpn + request + i).is_multiple_of(3))
+        .collect()
+}
+
+fn parse_field<T: std::str::FromStr>(field: &str, number: usize) -> io::Result<T> {
+    field
+        .parse()
+        .map_err(|_| invalid_line(number, "malformed number"))
+}
+
+// "seconds.nanoseconds" as nanoseconds
+fn parse_seconds(field: &str, number: usize) -> io::Result<u64> {
+    let (seconds, fraction) = field.split_once('.').unwrap_or((field, "0"));
+    if fraction.is_empty() || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid_line(number, "malformed timestamp"));
+    }
+    let seconds: u64 = parse_field(seconds, number)?;
+    let digits = &fraction[..fraction.len().min(9)];
+    let nanos: u64 = parse_field(digits, number)?;
+    seconds
+        .checked_mul(1_000_000_000)
+        .map(|ns| ns + nanos * 10u64.pow(9 - digits.len() as u32))
+        .ok_or_else(|| invalid_line(number, "timestamp out of range"))
+}
+
+fn invalid_line(number: usize, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("trace line {}: {}", number + 1, reason),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::controller::memory_controller::test_controller;
+
+    const MSR: &str = "128166372003061629,hm,1,Write,3518365696,4096,1331\n\
+                       128166372016382155,hm,1,Read,3517247488,512,1195\n";
+
+    const BLKPARSE: &str = "  8,0    3        1     0.000000000   697  Q  WS 223490 + 8 [kjournald]\n\
+                             8,0    3        2     0.000001500   697  G  WS 223490 + 8 [kjournald]\n\
+                             8,0    0        3     0.250000000   700  Q   R 100 + 16 [cat]\n\
+                             8,0    0        4     0.500000000   700  Q   D 64 + 64 [fstrim]\n\
+                             8,0    0        5     0.600000000   700  Q FWS [sync]\n\
+                            CPU0 (8,0):\n\
+                            Reads Queued:           1,        8KiB\n";
+
+    #[test]
+    fn parse_msr_should_read_relative_timestamps_and_byte_ranges() {
+        let res = parse_msr(MSR.as_bytes()).unwrap();
+
+        let expected = vec![
+            TraceRecord {
+                timestamp: 0,
+                operation: Operation::Write,
+                offset: 3518365696,
+                size: 4096,
+            },
+            TraceRecord {
+                timestamp: 13320526 * 100,
+                operation: Operation::Read,
+                offset: 3517247488,
+                size: 512,
+            },
+        ];
+        assert_eq!(expected, res);
+    }
+
+    #[test]
+    fn parse_msr_should_report_malformed_line() {
+        let res = parse_msr("1,hm,0,Write,12,4096,1\n2,hm,0,Flush,0,0,1\n".as_bytes());
+
+        let err = res.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert!(err.to_string().contains("line 2"));
+    }
+
+    #[test]
+    fn parse_msr_should_report_timestamp_out_of_range() {
+        let res =
+            parse_msr("0,hm,0,Write,0,4096,1\n18446744073709551615,hm,0,Read,0,512,1\n".as_bytes());
+
+        let err = res.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert!(err.to_string().contains("line 2"));
+    }
+
+    #[test]
+    fn parse_blkparse_should_report_fraction_that_is_not_digits() {
+        let trace = "  8,0    3        1     0.00000000é   697  Q  WS 223490 + 8 [kjournald]\n";
+
+        let res = parse_blkparse(trace.as_bytes(), "Q");
+
+        assert_eq!(io::ErrorKind::InvalidData, res.unwrap_err().kind());
+    }
+
+    #[test]
+    fn parse_blkparse_should_keep_queued_requests_only() {
+        let res = parse_blkparse(BLKPARSE.as_bytes(), "Q").unwrap();
+
+        let operations: Vec<Operation> = res.iter().map(|r| r.operation).collect();
+        assert_eq!(
+            vec![Operation::Write, Operation::Read, Operation::Trim],
+            operations
+        );
+        assert_eq!(250_000_000, res[1].timestamp);
+        assert_eq!((223490 * 512, 8 * 512), (res[0].offset, res[0].size));
+    }
+
+    #[test]
+    fn replay_should_wrap_offsets_into_device() {
+        let mut target = test_controller();
+        let capacity = target.user_capacity() as u64 * PAGE_BYTES as u64;
+        let records = vec![write(0, capacity - 4, 8), read(0, 0, 4)];
+
+        let res = replay(&mut target, &records, &ReplayConfig::default());
+
+        assert_eq!((1, 1, 0), (res.writes, res.reads, res.skipped));
+        assert_eq!(4, res.pages_written);
+        assert_eq!(page_pattern(0, 0), target.read_page(0));
+    }
+
+    #[test]
+    fn replay_should_skip_requests_past_device_without_wrap() {
+        let mut target = test_controller();
+        let capacity = target.user_capacity() as u64 * PAGE_BYTES as u64;
+        let config = ReplayConfig {
+            wrap: false,
+            ..ReplayConfig::default()
+        };
+
+        let res = replay(&mut target, &[write(0, capacity - 4, 8)], &config);
+
+        assert_eq!((0, 1), (res.writes, res.skipped));
+    }
+
+    #[test]
+    fn replay_should_skip_requests_longer_than_device() {
+        let mut target = test_controller();
+
+        let res = replay(
+            &mut target,
+            &[write(0, 0, u64::MAX / 2)],
+            &ReplayConfig::default(),
+        );
+
+        assert_eq!((0, 1), (res.writes, res.skipped));
+    }
+
+    #[test]
+    fn replay_should_honour_scaled_timestamps() {
+        let records = vec![write(0, 0, 64), write(5_000_000, 64, 64)];
+        let mut fast = test_controller();
+        let mut timed = test_controller();
+        let config = ReplayConfig {
+            time_scale: 2.0,
+            honour_timestamps: true,
+            ..ReplayConfig::default()
+        };
+
+        replay(&mut fast, &records, &ReplayConfig::default());
+        replay(&mut timed, &records, &config);
+
+        assert!(fast.time() < 10_000);
+        assert!(timed.time() >= 10_000);
+    }
+
+    #[test]
+    fn replay_should_trim_pages() {
+        let mut target = test_controller();
+        let records = vec![
+            write(0, 0, 8),
+            TraceRecord {
+                timestamp: 0,
+                operation: Operation::Trim,
+                offset: 2,
+                size: 4,
+            },
+        ];
+
+        let res = replay(&mut target, &records, &ReplayConfig::default());
+
+        assert_eq!(2, res.pages_trimmed);
+        assert_eq!(vec![false; CELLS_PER_PAGE], target.read_page(1));
+        assert_eq!(page_pattern(3, 0), target.read_page(3));
+    }
+
+    #[test]
+    fn replay_should_keep_pages_a_trim_covers_in_part() {
+        let mut target = test_controller();
+        let records = vec![
+            write(0, 0, 8),
+            TraceRecord {
+                timestamp: 0,
+                operation: Operation::Trim,
+                offset: 1,
+                size: 4,
+            },
+        ];
+
+        let res = replay(&mut target, &records, &ReplayConfig::default());
+
+        assert_eq!(1, res.pages_trimmed);
+        assert_eq!(page_pattern(0, 0), target.read_page(0));
+        assert_eq!(vec![false; CELLS_PER_PAGE], target.read_page(1));
+        assert_eq!(page_pattern(2, 0), target.read_page(2));
+    }
+
+    #[test]
+    fn replay_should_skip_request_ending_past_byte_range() {
+        let mut target = test_controller();
+
+        let res = replay(
+            &mut target,
+            &[write(0, u64::MAX, 2)],
+            &ReplayConfig::default(),
+        );
+
+        assert_eq!((0, 1), (res.writes, res.skipped));
+    }
+
+    fn write(timestamp: u64, offset: u64, size: u64) -> TraceRecord {
+        TraceRecord {
+            timestamp,
+            operation: Operation::Write,
+            offset,
+            size,
+        }
+    }
+
+    fn read(timestamp: u64, offset: u64, size: u64) -> TraceRecord {
+        TraceRecord {
+            timestamp,
+            operation: Operation::Read,
+            offset,
+            size,
+        }
+    }
+}